@@ -0,0 +1,867 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    checksum::ChecksumAlgorithm,
+    consts::{
+        KiB,
+        MAXIMUM_OBJECT_SIZE,
+    },
+    create_multipart_upload,
+    migration,
+    object_options::ObjectOptions,
+    output::{
+        OutputFormat,
+        TransferResult,
+    },
+    parse_key_value,
+    parts,
+    result::{
+        bail,
+        AnyhowResultExt,
+        Result,
+        StdResultExt,
+    },
+    s3_uri::S3Uri,
+    sdk,
+    size,
+    spill,
+    state_home,
+    state_lock::StateLock,
+    state_store::StateStore,
+    upload_and_record,
+    State,
+    TransferOptions,
+};
+use anyhow::Context;
+use aws_sdk_s3::types::RequestPayer;
+use clap::{
+    builder::PossibleValuesParser,
+    Args,
+};
+use futures_util::StreamExt;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::{
+    fs::Metadata,
+    io,
+    path::{
+        Path,
+        PathBuf,
+    },
+    time::{
+        Duration,
+        Instant,
+        SystemTime,
+    },
+};
+use tokio::{
+    io::{
+        AsyncRead,
+        AsyncReadExt,
+        AsyncSeekExt,
+    },
+    sync::mpsc,
+};
+use tokio_util::{
+    bytes::{
+        Bytes,
+        BytesMut,
+    },
+    io::StreamReader,
+};
+use tracing::{
+    debug,
+    info,
+    warn,
+};
+
+/// Size of the blocks a tar archive is made up of.
+const BLOCK_SIZE: u64 = 512;
+/// The largest number the numeric fields of a ustar header can hold, which are 11 octal digits.
+const MAXIMUM_USTAR_NUMBER: u64 = 0o77777777777;
+/// Size of the chunks the files are read in while the archive is created.
+const CHUNK_SIZE: usize = 256 * KiB as usize;
+/// Number of chunks that are read ahead of the spilling of the archive.
+const READ_AHEAD_CHUNKS: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+}
+
+impl EntryKind {
+    /// The type flag of the entry in its ustar header.
+    fn type_flag(self) -> u8 {
+        match self {
+            EntryKind::File => b'0',
+            EntryKind::Directory => b'5',
+            EntryKind::Symlink => b'2',
+        }
+    }
+}
+
+/// A file, directory or symbolic link within the archive, as listed when the upload was started.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct Entry {
+    /// Path relative to the archived directory, with `/` as separator.
+    path: String,
+    kind: EntryKind,
+    /// Target of a symbolic link.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    link_target: Option<String>,
+    /// Size of a file, zero for directories and symbolic links.
+    size: u64,
+    mode: u32,
+    /// Modification time in seconds since the Unix epoch.
+    modified: u64,
+    /// Nanoseconds of the modification time within its second, absent in state-files written
+    /// before they were recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    modified_nanoseconds: Option<u32>,
+    /// Offset of the header of the entry within the archive.
+    offset: u64,
+}
+
+impl Entry {
+    /// Returns the header of the entry, preceded by a PAX extended header if the entry can't be
+    /// described by a ustar header alone, e.g. because its path is too long.
+    fn header(&self) -> Vec<u8> {
+        let mut path = self.path.clone();
+        if self.kind == EntryKind::Directory {
+            path.push('/');
+        }
+        let link_target = self.link_target.as_deref().unwrap_or_default();
+
+        let mut records = vec![];
+        if path.len() > 100 || !path.is_ascii() {
+            records.push(("path", path.clone()));
+        }
+        if link_target.len() > 100 || !link_target.is_ascii() {
+            records.push(("linkpath", link_target.to_owned()));
+        }
+        if self.size > MAXIMUM_USTAR_NUMBER {
+            records.push(("size", self.size.to_string()));
+        }
+        if self.modified > MAXIMUM_USTAR_NUMBER {
+            records.push(("mtime", self.modified.to_string()));
+        }
+
+        let mut header = vec![];
+        if !records.is_empty() {
+            let extended_header = records
+                .iter()
+                .flat_map(|(key, value)| pax_record(key, value))
+                .collect::<Vec<_>>();
+            header.extend(ustar_header(
+                b"././@PaxHeader",
+                b'x',
+                b"",
+                extended_header.len() as u64,
+                0o644,
+                self.modified,
+            ));
+            header.extend(&extended_header);
+            header.resize(padded(header.len() as u64) as usize, 0);
+        }
+        header.extend(ustar_header(
+            path.as_bytes(),
+            self.kind.type_flag(),
+            link_target.as_bytes(),
+            self.size,
+            self.mode,
+            self.modified,
+        ));
+        header
+    }
+
+    /// Number of bytes the entry takes up within the archive, including its header.
+    fn len(&self) -> u64 {
+        self.header().len() as u64 + padded(self.size)
+    }
+
+    /// Opens the file of the entry, failing if it has changed since the upload was started.
+    async fn open(&self, directory: &Path) -> io::Result<tokio::fs::File> {
+        let path = directory.join(&self.path);
+        let file = tokio::fs::File::open(&path).await.map_err(|error| {
+            io::Error::new(
+                error.kind(),
+                format!("Failed to open {}: {}", path.display(), error),
+            )
+        })?;
+        let metadata = file.metadata().await?;
+        let modified = modified(&metadata);
+        // Comparing whole seconds only would miss a file that is modified within the second it
+        // was listed in.
+        if metadata.len() != self.size
+            || modified.as_secs() != self.modified
+            || self
+                .modified_nanoseconds
+                .is_some_and(|nanoseconds| nanoseconds != modified.subsec_nanos())
+        {
+            return Err(io::Error::other(format!(
+                "The file {} has changed since the upload was started",
+                path.display(),
+            )));
+        }
+        Ok(file)
+    }
+}
+
+/// A tar archive of a directory, which is created on the fly while it is uploaded.
+///
+/// The entries of the directory are listed when the upload is started, and this manifest is kept
+/// in the state-file together with the offset of every entry within the archive. Since the archive
+/// is created from the manifest alone, always with the same headers, a resumed upload can continue
+/// the archive at any offset without reading the files that have been uploaded already. The
+/// archive is uploaded like a stream read from stdin: every part is spilled to disk before it is
+/// uploaded.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct Archive {
+    /// The entries in the order they appear in the archive.
+    entries: Vec<Entry>,
+    /// Size of the archive in bytes.
+    pub(crate) size: u64,
+}
+
+impl Archive {
+    /// Lists the entries of the directory, skipping any path that starts with one of the excluded
+    /// paths.
+    ///
+    /// Directories are listed before their contents, and the contents of every directory are
+    /// sorted by name. Symbolic links are archived as links, rather than being followed.
+    async fn of(directory: &Path, excluded: &[&Path]) -> Result<Self> {
+        let mut entries = vec![];
+        let mut offset = 0;
+        let mut pending = children(directory, None).await?;
+        while let Some((path, relative_path)) = pending.pop() {
+            let path_bytes = path.as_os_str().as_encoded_bytes();
+            if excluded
+                .iter()
+                .any(|excluded| path_bytes.starts_with(excluded.as_os_str().as_encoded_bytes()))
+            {
+                debug!(
+                    "Not archiving {}, which belongs to the upload",
+                    path.display()
+                );
+                continue;
+            }
+
+            let metadata = tokio::fs::symlink_metadata(&path)
+                .await
+                .into_unrecoverable()?;
+            let (kind, link_target) = if metadata.is_file() {
+                (EntryKind::File, None)
+            } else if metadata.is_dir() {
+                pending.extend(children(&path, Some(&relative_path)).await?);
+                (EntryKind::Directory, None)
+            } else if metadata.is_symlink() {
+                let link_target = tokio::fs::read_link(&path).await.into_unrecoverable()?;
+                let Some(link_target) = link_target.to_str() else {
+                    bail!(
+                        "The target of the symbolic link {} is not valid UTF-8 and can't be archived",
+                        path.display(),
+                    );
+                };
+                (EntryKind::Symlink, Some(link_target.to_owned()))
+            } else {
+                warn!(
+                    "Not archiving {}, which is neither a file, a directory nor a symbolic link",
+                    path.display(),
+                );
+                continue;
+            };
+            let entry = Entry {
+                path: relative_path,
+                kind,
+                link_target,
+                size: if kind == EntryKind::File {
+                    metadata.len()
+                } else {
+                    0
+                },
+                mode: mode(&metadata),
+                modified: modified(&metadata).as_secs(),
+                modified_nanoseconds: Some(modified(&metadata).subsec_nanos()),
+                offset,
+            };
+            offset += entry.len();
+            entries.push(entry);
+        }
+
+        // The archive ends with two blocks of zeros.
+        let size = offset + 2 * BLOCK_SIZE;
+        debug!(
+            "Listed {} entries, the archive has {} bytes",
+            entries.len(),
+            size
+        );
+        Ok(Self { entries, size })
+    }
+
+    /// Verifies that none of the files that remain to be uploaded has changed since the upload was
+    /// started, as the archive can't be continued otherwise.
+    pub(crate) async fn verify(&self, state: &State) -> Result<()> {
+        let uploaded_bytes = state.uploaded_bytes();
+        for entry in &self.entries {
+            if entry.kind != EntryKind::File || entry.offset + entry.len() <= uploaded_bytes {
+                continue;
+            }
+            if let Err(error) = entry.open(&state.file_to_upload).await {
+                bail!(
+                    "{}. The upload cannot be resumed, and should be aborted! Upload ID: {}",
+                    error,
+                    state.upload_id,
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates the archive of the upload, returning a reader of the archive that starts where the
+    /// upload has to continue.
+    pub(crate) async fn reader(&self, state: &State) -> Result<impl AsyncRead + Send + 'static> {
+        let Some(spill_directory) = &state.spill_directory else {
+            bail!("Archives require a spill directory");
+        };
+        let stream_offset = spill::stream_offset(
+            spill_directory,
+            state.last_successful_part + 1,
+            state.file_size_in_bytes,
+        )
+        .await?;
+        if stream_offset > 0 {
+            debug!(
+                "Continuing the archive at byte {} of {}",
+                stream_offset, self.size,
+            );
+        }
+
+        let (sender, receiver) = mpsc::channel(READ_AHEAD_CHUNKS);
+        let archive = self.clone();
+        let directory = state.file_to_upload.clone();
+        tokio::spawn(async move {
+            if let Err(error) = archive.write(&directory, stream_offset, &sender).await {
+                // If the reader is gone, there is no one to report the error to anyway.
+                let _ = sender.send(Err(error)).await;
+            }
+        });
+        // The spill reads the stream again after it has ended, to find out that there is no further
+        // part, so the stream has to keep ending.
+        let chunks = futures_util::stream::unfold(receiver, |mut receiver| async move {
+            receiver.recv().await.map(|chunk| (chunk, receiver))
+        })
+        .fuse();
+        Ok(StreamReader::new(chunks))
+    }
+
+    /// Sends the chunks of the archive, starting at the given offset.
+    async fn write(
+        &self,
+        directory: &Path,
+        offset: u64,
+        sender: &mpsc::Sender<io::Result<Bytes>>,
+    ) -> io::Result<()> {
+        let first_entry = self
+            .entries
+            .partition_point(|entry| entry.offset <= offset)
+            .saturating_sub(1);
+        // The bytes still to skip before the offset is reached, counted from the first entry.
+        let mut skip = offset
+            - self
+                .entries
+                .get(first_entry)
+                .map_or(0, |entry| entry.offset);
+        for entry in &self.entries[first_entry..] {
+            skip = send_skipping(sender, entry.header(), skip).await?;
+            if entry.size > skip {
+                let mut file = entry.open(directory).await?;
+                file.seek(io::SeekFrom::Start(skip)).await?;
+                let mut reader = file.take(entry.size - skip);
+                let mut remaining = entry.size - skip;
+                loop {
+                    let mut chunk = BytesMut::with_capacity(CHUNK_SIZE);
+                    let read = reader.read_buf(&mut chunk).await?;
+                    if read == 0 {
+                        break;
+                    }
+                    remaining -= read as u64;
+                    send(sender, chunk.freeze()).await?;
+                }
+                if remaining > 0 {
+                    return Err(io::Error::other(format!(
+                        "The file {} has been truncated since the upload was started",
+                        directory.join(&entry.path).display(),
+                    )));
+                }
+                skip = 0;
+            } else {
+                skip -= entry.size;
+            }
+            let padding = padded(entry.size) - entry.size;
+            skip = send_skipping(sender, vec![0; padding as usize], skip).await?;
+        }
+        send_skipping(sender, vec![0; 2 * BLOCK_SIZE as usize], skip).await?;
+        Ok(())
+    }
+}
+
+/// Sends a chunk of the archive to the reader.
+async fn send(sender: &mpsc::Sender<io::Result<Bytes>>, chunk: Bytes) -> io::Result<()> {
+    sender
+        .send(Ok(chunk))
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+}
+
+/// Sends the bytes after the first `skip` of them, returning the number of bytes that remain to be
+/// skipped afterwards.
+async fn send_skipping(
+    sender: &mpsc::Sender<io::Result<Bytes>>,
+    bytes: Vec<u8>,
+    skip: u64,
+) -> io::Result<u64> {
+    let length = bytes.len() as u64;
+    if skip >= length {
+        return Ok(skip - length);
+    }
+    send(sender, Bytes::from(bytes).slice(skip as usize..)).await?;
+    Ok(0)
+}
+
+/// Returns the entries of a directory, as absolute paths and as paths relative to the archived
+/// directory, sorted such that the first entry is the last one.
+async fn children(directory: &Path, relative_path: Option<&str>) -> Result<Vec<(PathBuf, String)>> {
+    let mut children = vec![];
+    let mut read_dir = tokio::fs::read_dir(directory)
+        .await
+        .with_context(|| format!("Failed to read directory {}", directory.display()))
+        .into_unrecoverable()?;
+    while let Some(dir_entry) = read_dir.next_entry().await.into_unrecoverable()? {
+        let path = dir_entry.path();
+        let Some(name) = dir_entry.file_name().to_str().map(ToOwned::to_owned) else {
+            bail!(
+                "The path {} is not valid UTF-8 and can't be archived",
+                path.display(),
+            );
+        };
+        let child_relative_path = match relative_path {
+            Some(relative_path) => format!("{}/{}", relative_path, name),
+            None => name,
+        };
+        children.push((path, child_relative_path));
+    }
+    children.sort_by(|(_, a), (_, b)| b.cmp(a));
+    Ok(children)
+}
+
+/// Returns the size rounded up to whole blocks.
+fn padded(size: u64) -> u64 {
+    size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE
+}
+
+#[cfg(unix)]
+fn mode(metadata: &Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn mode(metadata: &Metadata) -> u32 {
+    match (metadata.is_dir(), metadata.permissions().readonly()) {
+        (true, _) => 0o755,
+        (false, true) => 0o444,
+        (false, false) => 0o644,
+    }
+}
+
+/// Returns the modification time since the Unix epoch, or zero if it isn't available.
+fn modified(metadata: &Metadata) -> Duration {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .unwrap_or_default()
+}
+
+/// Returns a ustar header with the given fields, truncating those that don't fit into it.
+///
+/// The owner of every entry is left empty, so that the archive doesn't depend on the users of the
+/// system it was created on.
+fn ustar_header(
+    name: &[u8],
+    type_flag: u8,
+    link_name: &[u8],
+    size: u64,
+    mode: u32,
+    modified: u64,
+) -> [u8; BLOCK_SIZE as usize] {
+    let mut header = [0; BLOCK_SIZE as usize];
+    copy_truncated(&mut header[0..100], name);
+    octal(&mut header[100..108], mode as u64);
+    octal(&mut header[108..116], 0);
+    octal(&mut header[116..124], 0);
+    octal(&mut header[124..136], size.min(MAXIMUM_USTAR_NUMBER));
+    octal(&mut header[136..148], modified.min(MAXIMUM_USTAR_NUMBER));
+    header[156] = type_flag;
+    copy_truncated(&mut header[157..257], link_name);
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    // The checksum is calculated with the checksum field itself filled with spaces.
+    header[148..156].fill(b' ');
+    let checksum = header.iter().map(|&byte| byte as u32).sum::<u32>();
+    header[148..155].copy_from_slice(format!("{:06o}\0", checksum).as_bytes());
+    header
+}
+
+fn copy_truncated(field: &mut [u8], value: &[u8]) {
+    let length = value.len().min(field.len());
+    field[..length].copy_from_slice(&value[..length]);
+}
+
+/// Writes the value as zero-padded octal number, terminated by a NUL byte.
+fn octal(field: &mut [u8], value: u64) {
+    let digits = field.len() - 1;
+    field[..digits].copy_from_slice(format!("{:0digits$o}", value).as_bytes());
+    field[digits] = 0;
+}
+
+/// Returns a record of a PAX extended header, which starts with its own length in decimal digits.
+fn pax_record(key: &str, value: &str) -> Vec<u8> {
+    // The length of the record without the length itself, which includes the space, the equals
+    // sign and the trailing newline.
+    let unprefixed = key.len() + value.len() + 3;
+    let mut length = unprefixed;
+    while length != unprefixed + length.to_string().len() {
+        length = unprefixed + length.to_string().len();
+    }
+    format!("{} {}={}\n", length, key, value).into_bytes()
+}
+
+#[derive(Debug, Args)]
+pub(crate) struct UploadTar {
+    /// The directory to upload as a tar archive.
+    ///
+    /// The paths within the archive are relative to this directory, i.e. extracting the archive
+    /// restores the contents of the directory.
+    #[arg(long)]
+    dir: PathBuf,
+    /// The S3 URI to upload the archive to, e.g. `s3://my-bucket/backups/data.tar`.
+    ///
+    /// If the key is empty or ends with `/`, the name of the directory followed by `.tar` is
+    /// appended to it.
+    #[arg(value_name = "S3_URI")]
+    destination: S3Uri,
+    /// Path to where the state-file will be saved.
+    ///
+    /// Besides the progress of the upload, the state-file holds the list of all files in the
+    /// archive. Defaults to a file in `$XDG_STATE_HOME/persevere/uploads/` named after a hash of
+    /// the bucket, key and directory.
+    #[arg(long)]
+    state_file: Option<PathBuf>,
+    /// Directory to spill the parts of the archive to.
+    ///
+    /// A directory named after the state-file is created within it, which will hold at most one
+    /// part at a time. Defaults to the directory of the state-file.
+    #[arg(long)]
+    spill_dir: Option<PathBuf>,
+    /// Override the automatically determined part size, e.g. `64MiB` or `1GiB`.
+    #[arg(long, value_parser = size::parse_size)]
+    override_part_size: Option<u64>,
+    /// Label to attach to the upload, in the form `key=value`.
+    #[arg(long = "label", value_name = "KEY=VALUE", value_parser = parse_key_value)]
+    labels: Vec<(String, String)>,
+    /// Confirm that you will be charged for the requests to a requester-pays bucket.
+    #[arg(long, value_parser = PossibleValuesParser::new(RequestPayer::values()))]
+    request_payer: Option<String>,
+    /// Verify the integrity of every part end-to-end with a checksum of the given algorithm.
+    #[arg(long, value_enum)]
+    checksum_algorithm: Option<ChecksumAlgorithm>,
+    // The content type defaults to `application/x-tar`.
+    #[command(flatten)]
+    object_options: ObjectOptions,
+    #[command(flatten)]
+    transfer_options: TransferOptions,
+    /// The format to print the result of the upload in.
+    #[arg(long, value_enum, default_value_t)]
+    pub(crate) output: OutputFormat,
+}
+
+impl UploadTar {
+    pub(crate) async fn run(self) -> Result<()> {
+        let output = self.output;
+        let result = self.transfer().await?;
+        output.print_result(&result);
+        Ok(())
+    }
+
+    async fn transfer(mut self) -> Result<TransferResult> {
+        debug!("Running upload-tar command: {:?}", self);
+        let started = Instant::now();
+        let directory = self
+            .dir
+            .canonicalize()
+            .context("Failed to canonicalize the directory")
+            .into_unrecoverable()?;
+        if !directory.is_dir() {
+            bail!("{} is not a directory", directory.display());
+        }
+        let archive_name = directory
+            .file_name()
+            .and_then(|name| name.to_str())
+            .map(|name| format!("{}.tar", name));
+        let Some(s3_key) = self.destination.key_for_file(archive_name.as_deref()) else {
+            bail!(
+                "The S3 URI {} doesn't include the key to upload the archive to",
+                self.destination,
+            );
+        };
+        let s3_bucket = self.destination.bucket.clone();
+
+        let state_file = match self.state_file.take() {
+            Some(state_file) => state_file,
+            None => {
+                let state_file =
+                    state_home::default_state_file(&s3_bucket, &s3_key, &directory).await?;
+                info!("Using state-file: {}", state_file.display());
+                state_file
+            }
+        };
+        let store = StateStore::new(state_file);
+        let _lock = StateLock::acquire(store.file())?;
+        if store.exists().await? {
+            bail!("The state-file already exists, and we don't allow starting a new upload against the same file. If you want to resume the upload, use the 'resume' command instead. If you want to start a new upload, please remove the state-file first, or use a different one.");
+        }
+
+        let spill_directory =
+            spill::create_directory(self.spill_dir.as_deref(), store.file()).await?;
+        // The state-file and the files next to it, as well as the spilled parts, change during the
+        // upload and must not end up in the archive, should they be within the directory.
+        let state_file = std::path::absolute(store.file()).into_unrecoverable()?;
+        info!("Listing the contents of {}", directory.display());
+        let archive = Archive::of(&directory, &[&state_file, &spill_directory]).await?;
+        if archive.size > MAXIMUM_OBJECT_SIZE {
+            spill::remove_directory(&spill_directory).await?;
+            bail!("The archive of the directory exceeds the maximum object size of S3 and thus can't be uploaded");
+        }
+        let part_size = parts::choose_part_size(archive.size, self.override_part_size)?;
+
+        let config = sdk::load_config().await;
+        let s3 = sdk::s3_client(&config);
+        let mut object_options = self.object_options;
+        object_options
+            .content_type
+            .get_or_insert_with(|| "application/x-tar".to_owned());
+
+        let mut state = State {
+            version: migration::STATE_VERSION,
+            s3_bucket,
+            s3_key,
+            file_to_upload: directory,
+            // Like for uploads from stdin, the size only covers the parts read so far.
+            file_size_in_bytes: 0,
+            part_size,
+            number_of_parts: 0,
+            upload_id: String::new(),
+            last_successful_part: 0,
+            completed_parts: vec![],
+            labels: self.labels.into_iter().collect(),
+            object_options,
+            headers: vec![],
+            spill_directory: Some(spill_directory),
+            request_payer: self.request_payer,
+            checksum_algorithm: self.checksum_algorithm,
+            fingerprint: None,
+            auto_tune: None,
+            aws_profile: Some(sdk::profile_name()),
+            aws_region: config.region().map(ToString::to_string),
+            use_fips_endpoint: Some(config.use_fips().unwrap_or(false)),
+            use_dualstack_endpoint: Some(config.use_dual_stack().unwrap_or(false)),
+            copy_source: None,
+            encryption: None,
+            compression: None,
+            archive: Some(archive),
+            split: None,
+            replicas: vec![],
+        };
+
+        create_multipart_upload(&s3, &mut state).await?;
+
+        upload_and_record(
+            &s3,
+            "upload-tar",
+            &store,
+            &mut state,
+            &self.transfer_options,
+            started,
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns an empty directory for the test of the given name.
+    fn directory(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("persevere-archive-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    fn entry(path: &str, kind: EntryKind, size: u64) -> Entry {
+        Entry {
+            path: path.to_owned(),
+            kind,
+            link_target: None,
+            size,
+            mode: 0o644,
+            modified: 1_700_000_000,
+            modified_nanoseconds: Some(0),
+            offset: 0,
+        }
+    }
+
+    /// Returns the bytes of the archive from the given offset on.
+    async fn written(archive: &Archive, directory: &Path, offset: u64) -> Vec<u8> {
+        let (sender, mut receiver) = mpsc::channel(READ_AHEAD_CHUNKS);
+        let writing = async move { archive.write(directory, offset, &sender).await };
+        let reading = async {
+            let mut bytes = vec![];
+            while let Some(chunk) = receiver.recv().await {
+                bytes.extend(chunk.unwrap());
+            }
+            bytes
+        };
+        let (result, bytes) = tokio::join!(writing, reading);
+        result.unwrap();
+        bytes
+    }
+
+    #[test]
+    fn ustar_headers_have_a_valid_checksum() {
+        let header = ustar_header(b"file.txt", b'0', b"", 1234, 0o644, 1_700_000_000);
+        let stored = std::str::from_utf8(&header[148..154]).unwrap();
+        let stored = u32::from_str_radix(stored, 8).unwrap();
+        assert_eq!(&header[154..156], b"\0 ");
+
+        let mut unsummed = header;
+        unsummed[148..156].fill(b' ');
+        let checksum = unsummed.iter().map(|&byte| byte as u32).sum::<u32>();
+        assert_eq!(stored, checksum);
+    }
+
+    #[test]
+    fn long_and_non_ascii_paths_are_stored_in_a_pax_header() {
+        let short = entry("file.txt", EntryKind::File, 0);
+        assert_eq!(short.header().len() as u64, BLOCK_SIZE);
+
+        for path in ["a".repeat(150), "Grüße.txt".to_owned()] {
+            let header = entry(&path, EntryKind::File, 0).header();
+            assert_eq!(header.len() as u64, 3 * BLOCK_SIZE);
+            assert_eq!(header[156], b'x');
+            let record = pax_record("path", &path);
+            let extended_header = &header[BLOCK_SIZE as usize..];
+            assert_eq!(&extended_header[..record.len()], &record[..]);
+            // The ustar header of the entry itself follows the padded extended header.
+            assert_eq!(header[2 * BLOCK_SIZE as usize + 156], b'0');
+        }
+    }
+
+    #[test]
+    fn pax_records_start_with_their_own_length() {
+        // "path" with a value of 90 bytes makes a record of 99 bytes. One more byte would make it
+        // 100 bytes, which doesn't exist, as the length itself grows by a digit to 101.
+        assert_eq!(pax_record("path", &"x".repeat(90)).len(), 99);
+        assert_eq!(pax_record("path", &"x".repeat(91)).len(), 101);
+        assert_eq!(pax_record("path", &"x".repeat(989)).len(), 999);
+        assert_eq!(pax_record("path", &"x".repeat(990)).len(), 1001);
+
+        for value_length in 0..1100 {
+            let record = pax_record("path", &"x".repeat(value_length));
+            let (length, _) = std::str::from_utf8(&record)
+                .unwrap()
+                .split_once(' ')
+                .unwrap();
+            assert_eq!(length.parse::<usize>().unwrap(), record.len());
+            assert!(record.ends_with(b"\n"));
+        }
+    }
+
+    #[tokio::test]
+    async fn entry_lengths_match_the_written_archive() {
+        let directory = directory("lengths");
+        std::fs::write(directory.join("small.txt"), b"hello").unwrap();
+        std::fs::create_dir(directory.join("nested")).unwrap();
+        std::fs::write(directory.join("nested/empty"), b"").unwrap();
+        std::fs::write(directory.join("nested/block"), vec![1; 513]).unwrap();
+        std::fs::write(directory.join("b".repeat(120)), b"long").unwrap();
+        std::fs::write(directory.join("Grüße.txt"), b"non-ascii").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("small.txt", directory.join("link")).unwrap();
+
+        let archive = Archive::of(&directory, &[]).await.unwrap();
+        let bytes = written(&archive, &directory, 0).await;
+        assert_eq!(bytes.len() as u64, archive.size);
+        let mut offset = 0;
+        for entry in &archive.entries {
+            assert_eq!(entry.offset, offset);
+            let header = entry.header();
+            let start = entry.offset as usize;
+            assert_eq!(&bytes[start..start + header.len()], &header[..]);
+            offset += entry.len();
+        }
+        assert_eq!(offset + 2 * BLOCK_SIZE, archive.size);
+
+        // Continuing the archive in the middle of an entry yields the rest of the same archive.
+        let resumed_at = archive.entries[2].offset + 100;
+        let resumed = written(&archive, &directory, resumed_at).await;
+        assert_eq!(&resumed[..], &bytes[resumed_at as usize..]);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn changes_within_the_same_second_are_detected() {
+        let directory = directory("changes");
+        std::fs::write(directory.join("file.txt"), b"hello").unwrap();
+        let archive = Archive::of(&directory, &[]).await.unwrap();
+        let entry = &archive.entries[0];
+        entry.open(&directory).await.unwrap();
+
+        let nanoseconds = entry.modified_nanoseconds.unwrap();
+        let modified = SystemTime::UNIX_EPOCH
+            + Duration::new(entry.modified, (nanoseconds + 1) % 1_000_000_000);
+        std::fs::File::options()
+            .write(true)
+            .open(directory.join("file.txt"))
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        assert!(entry.open(&directory).await.is_err());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
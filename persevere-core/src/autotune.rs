@@ -0,0 +1,127 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    consts::{
+        MAXIMUM_NUMBER_OF_PARTS,
+        MAXIMUM_PART_SIZE,
+        MINIMUM_PART_SIZE,
+    },
+    parts::Part,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::time::Duration;
+use tracing::debug;
+
+/// Duration the upload of a single part should take, which the part size is tuned towards.
+///
+/// Long enough for the overhead of each request to be negligible, short enough to not lose much
+/// progress when a part fails.
+const TARGET_PART_DURATION: Duration = Duration::from_secs(30);
+
+/// Part sizes of an upload that tunes its part size to the observed throughput (`--auto-tune`).
+///
+/// The upload starts with the smallest possible part size. After every part, the size of the next
+/// part is chosen such that its upload takes about [`TARGET_PART_DURATION`], growing or shrinking
+/// by at most a factor of two per part, and halving whenever a part had to be retried.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct AutoTune {
+    /// Size of every completed part, in order.
+    part_sizes: Vec<u64>,
+    /// Size of the next part to upload.
+    next_part_size: u64,
+}
+
+impl AutoTune {
+    pub(crate) fn new(initial_part_size: u64) -> Self {
+        Self {
+            part_sizes: vec![],
+            next_part_size: initial_part_size,
+        }
+    }
+
+    /// Number of bytes in all completed parts.
+    pub(crate) fn uploaded_bytes(&self) -> u64 {
+        self.part_sizes.iter().sum()
+    }
+
+    /// Returns the part with the given number if it has been completed, or if it is the next part
+    /// to upload.
+    pub(crate) fn part(&self, number: u64, file_size: u64) -> Option<Part> {
+        let index = number.checked_sub(1)? as usize;
+        let offset = self.part_sizes.iter().take(index).sum();
+        let size = match self.part_sizes.get(index) {
+            Some(size) => *size,
+            None if index == self.part_sizes.len() && offset < file_size => {
+                self.next_part_size.min(file_size - offset)
+            }
+            None => return None,
+        };
+        Some(Part {
+            number: number as i32,
+            offset,
+            size,
+        })
+    }
+
+    /// Estimated total number of parts, assuming the remaining parts are of the next part size.
+    pub(crate) fn estimated_number_of_parts(&self, file_size: u64) -> u64 {
+        let remaining = file_size.saturating_sub(self.uploaded_bytes());
+        self.part_sizes.len() as u64 + remaining.div_ceil(self.next_part_size)
+    }
+
+    /// Records a completed part and chooses the size of the next part from how long it took.
+    pub(crate) fn part_completed(
+        &mut self,
+        part: &Part,
+        duration: Duration,
+        retries: u32,
+        file_size: u64,
+    ) {
+        self.part_sizes.push(part.size);
+
+        let current = self.next_part_size;
+        let tuned = if retries > 0 {
+            current / 2
+        } else {
+            let bytes_per_second = part.size as f64 / duration.as_secs_f64().max(0.001);
+            ((bytes_per_second * TARGET_PART_DURATION.as_secs_f64()) as u64)
+                .clamp(current / 2, current.saturating_mul(2))
+        };
+
+        // The remaining bytes still have to fit into the parts S3 allows.
+        let remaining = file_size.saturating_sub(self.uploaded_bytes());
+        let remaining_parts = MAXIMUM_NUMBER_OF_PARTS
+            .saturating_sub(self.part_sizes.len() as u64)
+            .max(1);
+        let smallest = MINIMUM_PART_SIZE.max(remaining.div_ceil(remaining_parts));
+        self.next_part_size = tuned.min(MAXIMUM_PART_SIZE).max(smallest);
+        debug!(
+            "Part {} took {:.1}s, next part size: {} bytes",
+            part.number,
+            duration.as_secs_f64(),
+            self.next_part_size,
+        );
+    }
+
+    /// Forgets all but the first `parts` completed parts, which have to be uploaded again.
+    pub(crate) fn truncate(&mut self, parts: usize) {
+        self.part_sizes.truncate(parts);
+    }
+}
@@ -0,0 +1,517 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    checksum::ChecksumAlgorithm,
+    headers::{
+        self,
+        Header,
+    },
+    object_options::ObjectOptions,
+    output::OutputFormat,
+    parse_key_value,
+    progress::ProgressReporter,
+    result::{
+        bail,
+        AnyhowResultExt,
+        Error,
+        Result,
+        StdResultExt,
+    },
+    size,
+    spill,
+    throttle::RateLimiter,
+    verbosity,
+    write_json_atomically,
+    Resume,
+    SharedReporter,
+    TransferOptions,
+    Upload,
+};
+use anyhow::Context;
+use aws_sdk_s3::types::RequestPayer;
+use clap::{
+    builder::PossibleValuesParser,
+    Args,
+};
+use futures_util::{
+    stream::FuturesUnordered,
+    StreamExt,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::{
+    collections::BTreeMap,
+    num::NonZeroUsize,
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::{
+        Arc,
+        OnceLock,
+    },
+};
+use tracing::{
+    debug,
+    error,
+    info,
+    warn,
+};
+
+/// Name of the file within the state directory that tracks the progress of the whole batch.
+const BATCH_STATE_FILE: &str = "batch.json";
+
+/// A single file to upload, as listed in the manifest.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Entry {
+    file_to_upload: PathBuf,
+    s3_bucket: String,
+    s3_key: String,
+    /// Labels of this entry, in addition to the ones provided on the command line.
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+    /// Properties of the object, which replace the ones provided on the command line entirely.
+    #[serde(default)]
+    object_options: Option<ObjectOptions>,
+}
+
+impl Entry {
+    pub(crate) fn new(file_to_upload: PathBuf, s3_bucket: String, s3_key: String) -> Self {
+        Self {
+            file_to_upload,
+            s3_bucket,
+            s3_key,
+            labels: BTreeMap::new(),
+            object_options: None,
+        }
+    }
+
+    pub(crate) fn with_labels(self, labels: BTreeMap<String, String>) -> Self {
+        Self { labels, ..self }
+    }
+
+    pub(crate) fn file_to_upload(&self) -> &Path {
+        &self.file_to_upload
+    }
+
+    pub(crate) fn s3_bucket(&self) -> &str {
+        &self.s3_bucket
+    }
+
+    pub(crate) fn s3_key(&self) -> &str {
+        &self.s3_key
+    }
+}
+
+/// Progress of the whole batch, which is persisted in the state directory.
+#[derive(Debug, Deserialize, Serialize)]
+struct BatchState {
+    entries: Vec<BatchEntry>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct BatchEntry {
+    file_to_upload: PathBuf,
+    s3_bucket: String,
+    s3_key: String,
+    status: EntryStatus,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case", tag = "status")]
+enum EntryStatus {
+    Pending,
+    Completed,
+    Failed { error: String },
+}
+
+impl BatchState {
+    /// Reads the state of the batch kept in the given directory, if a batch has been started there.
+    async fn from_directory(state_dir: &Path) -> Result<Option<Self>> {
+        let contents = match tokio::fs::read(state_dir.join(BATCH_STATE_FILE)).await {
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            result => result
+                .context("Failed to read batch state file")
+                .into_unrecoverable()?,
+        };
+        serde_json::from_slice(&contents)
+            .context("Failed to deserialize batch state file")
+            .into_unrecoverable()
+    }
+
+    async fn write_to_file(&mut self, file: &Path) -> Result<()> {
+        tokio::task::block_in_place(|| write_json_atomically(file, self, "batch state file"))
+    }
+
+    /// Returns whether the batch was started for the same files and destinations as the given
+    /// entries.
+    fn matches(&self, entries: &[Entry]) -> bool {
+        self.entries.len() == entries.len()
+            && self.entries.iter().zip(entries).all(|(entry, listed)| {
+                entry.file_to_upload == listed.file_to_upload
+                    && entry.s3_bucket == listed.s3_bucket
+                    && entry.s3_key == listed.s3_key
+            })
+    }
+}
+
+/// Options shared by all uploads of a batch.
+#[derive(Debug, Args)]
+pub(crate) struct UploadOptions {
+    /// Explicit part-size to use for every file, e.g. `64MiB` or `1GiB`.
+    ///
+    /// See `upload --help` for details.
+    #[arg(long, value_parser = size::parse_size)]
+    override_part_size: Option<u64>,
+    /// Tune the part size to the observed throughput during the upload of every file.
+    #[arg(long, conflicts_with = "override_part_size")]
+    auto_tune: bool,
+    /// Label to attach to every upload, in the form `key=value`.
+    #[arg(long = "label", value_name = "KEY=VALUE", value_parser = parse_key_value)]
+    labels: Vec<(String, String)>,
+    #[command(flatten)]
+    object_options: ObjectOptions,
+    /// Additional HTTP header to send with every S3 request, in the form `name: value`.
+    #[arg(long = "header", value_name = "NAME: VALUE", value_parser = headers::parse_header)]
+    pub(crate) headers: Vec<Header>,
+    /// Confirm that you will be charged for the requests to a requester-pays bucket.
+    #[arg(long, value_parser = PossibleValuesParser::new(RequestPayer::values()))]
+    pub(crate) request_payer: Option<String>,
+    /// Verify the integrity of every part end-to-end with a checksum of the given algorithm.
+    #[arg(long, value_enum)]
+    checksum_algorithm: Option<ChecksumAlgorithm>,
+    #[command(flatten)]
+    transfer_options: TransferOptions,
+    /// The rate limiter shared by all uploads, so that `--limit-rate` applies to the batch as a
+    /// whole.
+    #[arg(skip)]
+    rate_limiter: OnceLock<Option<RateLimiter>>,
+}
+
+impl UploadOptions {
+    /// The S3 client to transfer with, if one was given through the library.
+    pub(crate) fn client(&self) -> Option<&aws_sdk_s3::Client> {
+        self.transfer_options.client.as_ref()
+    }
+
+    /// Returns the transfer options of a single upload, sharing the rate limit with the others.
+    fn transfer_options(&self) -> TransferOptions {
+        let rate_limiter = self
+            .rate_limiter
+            .get_or_init(|| self.transfer_options.rate_limiter());
+        TransferOptions {
+            rate_limiter: rate_limiter.clone(),
+            ..self.transfer_options.clone()
+        }
+    }
+
+    /// The reporter the progress of the uploads is reported to, unless they are given their own.
+    pub(crate) fn reporter(&self) -> Arc<dyn ProgressReporter> {
+        self.transfer_options.progress_reporter()
+    }
+
+    /// Uploads a single entry, resuming its upload if it was interrupted previously.
+    pub(crate) async fn upload(&self, entry: Entry, state_file: &Path) -> Result<()> {
+        self.upload_with(entry, state_file, self.transfer_options())
+            .await
+    }
+
+    /// Uploads a single entry like [`Self::upload`], reporting its progress to the given reporter.
+    pub(crate) async fn upload_reporting_to(
+        &self,
+        entry: Entry,
+        state_file: &Path,
+        reporter: Arc<dyn ProgressReporter>,
+    ) -> Result<()> {
+        let transfer_options = TransferOptions {
+            reporter: Some(SharedReporter(reporter)),
+            ..self.transfer_options()
+        };
+        self.upload_with(entry, state_file, transfer_options).await
+    }
+
+    async fn upload_with(
+        &self,
+        entry: Entry,
+        state_file: &Path,
+        transfer_options: TransferOptions,
+    ) -> Result<()> {
+        if tokio::fs::try_exists(state_file)
+            .await
+            .into_unrecoverable()?
+        {
+            return Resume {
+                state_file: Some(state_file.to_owned()),
+                state_uri: None,
+                s3_bucket: None,
+                s3_key: None,
+                file_to_upload: None,
+                force: false,
+                encryption_key_file: None,
+                output: OutputFormat::Text,
+                transfer_options,
+            }
+            .run()
+            .await;
+        }
+
+        Upload {
+            file: None,
+            destination: None,
+            s3_bucket: Some(entry.s3_bucket),
+            s3_key: Some(entry.s3_key),
+            file_to_upload: Some(entry.file_to_upload),
+            override_part_size: self.override_part_size,
+            auto_tune: self.auto_tune,
+            expected_object_size: None,
+            state_file: Some(state_file.to_owned()),
+            state_uri: None,
+            spill_dir: None,
+            labels: self.labels.iter().cloned().chain(entry.labels).collect(),
+            object_options: entry
+                .object_options
+                .unwrap_or_else(|| self.object_options.clone()),
+            headers: self.headers.clone(),
+            request_payer: self.request_payer.clone(),
+            checksum_algorithm: self.checksum_algorithm,
+            encryption_key_file: None,
+            compress: None,
+            split: false,
+            replicate_to: vec![],
+            output: OutputFormat::Text,
+            transfer_options,
+        }
+        .run()
+        .await
+    }
+}
+
+/// Returns the entries of the batch that has been started in the given directory, if any.
+///
+/// The entries only identify the files and destinations, they don't carry labels or object options
+/// of their own.
+pub(crate) async fn started_entries(state_dir: &Path) -> Result<Option<Vec<Entry>>> {
+    Ok(BatchState::from_directory(state_dir)
+        .await?
+        .map(|batch_state| {
+            batch_state
+                .entries
+                .into_iter()
+                .map(|entry| Entry::new(entry.file_to_upload, entry.s3_bucket, entry.s3_key))
+                .collect()
+        }))
+}
+
+/// Uploads the given entries, up to `jobs` at the same time, keeping track of the progress in
+/// `state_dir`.
+///
+/// If a batch has been started in `state_dir` before, it is resumed: entries that have been
+/// uploaded already are skipped, the interrupted uploads are resumed and failed entries are
+/// retried. Once all entries have been uploaded, the state directory is removed.
+pub(crate) async fn run(
+    state_dir: &Path,
+    entries: Vec<Entry>,
+    options: &UploadOptions,
+    jobs: NonZeroUsize,
+) -> Result<()> {
+    tokio::fs::create_dir_all(state_dir)
+        .await
+        .context("Failed to create state directory")
+        .into_unrecoverable()?;
+    let batch_state_file = state_dir.join(BATCH_STATE_FILE);
+    let mut batch_state = match BatchState::from_directory(state_dir).await? {
+        Some(batch_state) => {
+            if !batch_state.matches(&entries) {
+                bail!("The batch in the state directory was started for different files. Use a different state directory to start a new batch.");
+            }
+            info!("Resuming the batch from {}", batch_state_file.display());
+            batch_state
+        }
+        None => {
+            let mut batch_state = BatchState {
+                entries: entries
+                    .iter()
+                    .map(|entry| BatchEntry {
+                        file_to_upload: entry.file_to_upload.clone(),
+                        s3_bucket: entry.s3_bucket.clone(),
+                        s3_key: entry.s3_key.clone(),
+                        status: EntryStatus::Pending,
+                    })
+                    .collect(),
+            };
+            batch_state.write_to_file(&batch_state_file).await?;
+            batch_state
+        }
+    };
+
+    let total = entries.len();
+    let mut pending = vec![];
+    for (index, entry) in entries.into_iter().enumerate() {
+        if let EntryStatus::Completed = batch_state.entries[index].status {
+            debug!("Entry {} of {} was already uploaded", index + 1, total);
+        } else {
+            pending.push((index, entry));
+        }
+    }
+    let mut pending = pending.into_iter();
+    let mut running = FuturesUnordered::new();
+    // Once an upload has been paused or interrupted, no further ones are started, but the ones
+    // running already are waited for, as they are stopped as well.
+    let mut stopped = None;
+    loop {
+        while stopped.is_none() && running.len() < jobs.get() {
+            let Some((index, entry)) = pending.next() else {
+                break;
+            };
+            info!(
+                "Uploading entry {} of {}: {} to s3://{}/{}",
+                index + 1,
+                total,
+                entry.file_to_upload.display(),
+                entry.s3_bucket,
+                entry.s3_key,
+            );
+            let state_file = state_dir.join(format!("entry-{:05}.state", index + 1));
+            running.push(async move {
+                let result = options.upload(entry, &state_file).await;
+                (index, state_file, result)
+            });
+        }
+        let Some((index, state_file, result)) = running.next().await else {
+            break;
+        };
+        batch_state.entries[index].status = match &result {
+            Ok(()) => EntryStatus::Completed,
+            Err(Error::Paused) | Err(Error::Interrupted) => {
+                stopped.get_or_insert(result);
+                continue;
+            }
+            Err(error) => {
+                error!(
+                    "Failed to upload entry {} of {}: {}",
+                    index + 1,
+                    total,
+                    error
+                );
+                if let Error::Unrecoverable(_) = error {
+                    // The multipart upload has been aborted or was never created, so the next
+                    // attempt has to start from scratch.
+                    remove_file(&state_file).await?;
+                }
+                EntryStatus::Failed {
+                    error: error.to_string(),
+                }
+            }
+        };
+        batch_state.write_to_file(&batch_state_file).await?;
+    }
+    if let Some(result) = stopped {
+        info!(target: verbosity::SUMMARY, "To resume the batch, run the same command again");
+        return result;
+    }
+
+    let failed = batch_state
+        .entries
+        .iter()
+        .filter(|entry| matches!(entry.status, EntryStatus::Failed { .. }))
+        .count();
+    if failed > 0 {
+        error!("To retry the failed entries, run the same command again");
+        return Err(anyhow::anyhow!(
+            "Failed to upload {} of {} entries of the batch",
+            failed,
+            total,
+        ))
+        .into_retryable();
+    }
+
+    info!(target: verbosity::SUMMARY, "Successfully uploaded all {} entries of the batch", total);
+    debug!("Removing state directory: {}", state_dir.display());
+    remove_file(&batch_state_file).await?;
+    if let Err(err) = tokio::fs::remove_dir(state_dir).await {
+        warn!("Failed to remove the state directory: {}", err);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Args)]
+pub(crate) struct UploadBatch {
+    /// Path to the manifest listing the files to upload.
+    ///
+    /// The manifest is a JSON array of objects with the keys `file_to_upload`, `s3_bucket` and
+    /// `s3_key`, and optionally `labels` (an object of additional labels) and `object_options`
+    /// (which replace the object options provided on the command line for this entry, e.g.
+    /// `{"storage_class": "GLACIER"}`). Relative paths are resolved against the directory of the
+    /// manifest.
+    #[arg(long)]
+    manifest: PathBuf,
+    /// Directory to keep the state of the batch in.
+    ///
+    /// The directory holds the progress of the whole batch as well as one state-file per entry, and
+    /// is removed once all entries have been uploaded successfully. Running the same command again
+    /// with the same state directory resumes the batch.
+    #[arg(long)]
+    state_dir: PathBuf,
+    /// How many files to upload at the same time.
+    ///
+    /// The uploads share `--limit-rate` between them, so that they don't use more bandwidth
+    /// together than a single upload would.
+    #[arg(long, default_value = "1")]
+    jobs: NonZeroUsize,
+    #[command(flatten)]
+    upload_options: UploadOptions,
+}
+
+impl UploadBatch {
+    pub(crate) async fn run(&self) -> Result<()> {
+        debug!("Running upload-batch command: {:?}", self);
+
+        let entries = self.read_manifest().await?;
+        run(&self.state_dir, entries, &self.upload_options, self.jobs).await
+    }
+
+    async fn read_manifest(&self) -> Result<Vec<Entry>> {
+        let contents = tokio::fs::read(&self.manifest)
+            .await
+            .context("Failed to read manifest")
+            .into_unrecoverable()?;
+        let mut entries: Vec<Entry> = serde_json::from_slice(&contents)
+            .context("Failed to deserialize manifest")
+            .into_unrecoverable()?;
+        if entries.is_empty() {
+            bail!("The manifest doesn't list any files to upload");
+        }
+        let manifest_directory = self.manifest.parent().unwrap_or(Path::new(""));
+        for entry in &mut entries {
+            if entry.file_to_upload == Path::new(spill::STDIN) {
+                bail!("Uploading from stdin is not supported in a batch");
+            }
+            entry.file_to_upload = manifest_directory.join(&entry.file_to_upload);
+        }
+        Ok(entries)
+    }
+}
+
+pub(crate) async fn remove_file(file: &Path) -> Result<()> {
+    match tokio::fs::remove_file(file).await {
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        result => result.into_unrecoverable(),
+    }
+}
@@ -0,0 +1,129 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::size::parse_size;
+use std::{
+    fmt::{
+        Display,
+        Formatter,
+    },
+    str::FromStr,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+/// How often the state-file is written while a transfer is in progress.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum CheckpointInterval {
+    /// After every given number of completed parts.
+    Parts(u64),
+    /// Whenever at least the given duration has passed since the last checkpoint.
+    Duration(Duration),
+    /// Whenever at least the given number of bytes has been transferred since the last checkpoint.
+    Bytes(u64),
+}
+
+impl Default for CheckpointInterval {
+    fn default() -> Self {
+        CheckpointInterval::Parts(1)
+    }
+}
+
+impl Display for CheckpointInterval {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CheckpointInterval::Parts(parts) => write!(f, "{}", parts),
+            CheckpointInterval::Duration(duration) => write!(f, "{}s", duration.as_secs()),
+            CheckpointInterval::Bytes(bytes) => write!(f, "{}bytes", bytes),
+        }
+    }
+}
+
+impl FromStr for CheckpointInterval {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+        let split = value
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(value.len());
+        let (amount, unit) = value.split_at(split);
+        let amount: u64 = amount
+            .parse()
+            .map_err(|_| format!("expected a number followed by a unit, got `{}`", value))?;
+        if amount == 0 {
+            return Err("the checkpoint interval must be greater than zero".to_owned());
+        }
+        match unit.trim() {
+            "" | "part" | "parts" => Ok(CheckpointInterval::Parts(amount)),
+            "s" | "sec" | "second" | "seconds" => {
+                Ok(CheckpointInterval::Duration(Duration::from_secs(amount)))
+            }
+            unit => parse_size(value)
+                .map(CheckpointInterval::Bytes)
+                .map_err(|_| {
+                    format!(
+                        "unknown unit `{}`, expected `parts`, `seconds` or a size unit like `MiB`",
+                        unit,
+                    )
+                }),
+        }
+    }
+}
+
+/// Keeps track of the progress since the last checkpoint, to decide when the next one is due.
+pub(crate) struct Checkpointer {
+    interval: CheckpointInterval,
+    parts: u64,
+    bytes: u64,
+    last_checkpoint: Instant,
+}
+
+impl Checkpointer {
+    pub(crate) fn new(interval: CheckpointInterval) -> Self {
+        Self {
+            interval,
+            parts: 0,
+            bytes: 0,
+            last_checkpoint: Instant::now(),
+        }
+    }
+
+    /// Records a completed part, returning whether a checkpoint is due.
+    pub(crate) fn part_completed(&mut self, size: u64) -> bool {
+        self.parts += 1;
+        self.bytes += size;
+        match self.interval {
+            CheckpointInterval::Parts(parts) => self.parts >= parts,
+            CheckpointInterval::Duration(duration) => self.last_checkpoint.elapsed() >= duration,
+            CheckpointInterval::Bytes(bytes) => self.bytes >= bytes,
+        }
+    }
+
+    /// Whether there is progress that has not been checkpointed yet.
+    pub(crate) fn is_dirty(&self) -> bool {
+        self.parts > 0
+    }
+
+    /// Records that a checkpoint was written.
+    pub(crate) fn checkpointed(&mut self) {
+        self.parts = 0;
+        self.bytes = 0;
+        self.last_checkpoint = Instant::now();
+    }
+}
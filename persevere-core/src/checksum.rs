@@ -0,0 +1,717 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    consts::MiB,
+    parts::{
+        self,
+        PartPlan,
+    },
+    result::{
+        bail,
+        AnyhowResultExt,
+        Result,
+        StdResultExt,
+    },
+};
+use aws_sdk_s3::{
+    operation::{
+        complete_multipart_upload::CompleteMultipartUploadOutput,
+        put_object::PutObjectOutput,
+    },
+    types::{
+        Checksum,
+        CompletedPart,
+        ObjectPart,
+        ServerSideEncryption,
+    },
+};
+use aws_smithy_checksums::http::HttpChecksum;
+use aws_smithy_types::base64;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::{
+    path::Path,
+    pin::Pin,
+    sync::{
+        Arc,
+        Mutex,
+    },
+    task::{
+        Context,
+        Poll,
+    },
+};
+use tokio::io::{
+    AsyncRead,
+    AsyncReadExt,
+    ReadBuf,
+};
+use tracing::debug;
+
+/// Algorithm of the checksums that are calculated locally for every part, and verified against the
+/// checksums S3 calculated for the data it received.
+///
+/// CRC64NVME is missing, since the SDK only supports it from aws-sdk-s3 1.69.0 on.
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize, clap::ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ChecksumAlgorithm {
+    Crc32,
+    Crc32c,
+    Sha1,
+    Sha256,
+}
+
+impl ChecksumAlgorithm {
+    /// The algorithm as it is passed to S3.
+    pub(crate) fn sdk(&self) -> aws_sdk_s3::types::ChecksumAlgorithm {
+        match self {
+            ChecksumAlgorithm::Crc32 => aws_sdk_s3::types::ChecksumAlgorithm::Crc32,
+            ChecksumAlgorithm::Crc32c => aws_sdk_s3::types::ChecksumAlgorithm::Crc32C,
+            ChecksumAlgorithm::Sha1 => aws_sdk_s3::types::ChecksumAlgorithm::Sha1,
+            ChecksumAlgorithm::Sha256 => aws_sdk_s3::types::ChecksumAlgorithm::Sha256,
+        }
+    }
+
+    /// The algorithm S3 reports for a multipart upload, if it is one Persevere supports.
+    pub(crate) fn from_sdk(algorithm: &aws_sdk_s3::types::ChecksumAlgorithm) -> Option<Self> {
+        match algorithm {
+            aws_sdk_s3::types::ChecksumAlgorithm::Crc32 => Some(ChecksumAlgorithm::Crc32),
+            aws_sdk_s3::types::ChecksumAlgorithm::Crc32C => Some(ChecksumAlgorithm::Crc32c),
+            aws_sdk_s3::types::ChecksumAlgorithm::Sha1 => Some(ChecksumAlgorithm::Sha1),
+            aws_sdk_s3::types::ChecksumAlgorithm::Sha256 => Some(ChecksumAlgorithm::Sha256),
+            _ => None,
+        }
+    }
+
+    fn implementation(&self) -> Box<dyn HttpChecksum> {
+        match self {
+            ChecksumAlgorithm::Crc32 => aws_smithy_checksums::ChecksumAlgorithm::Crc32,
+            ChecksumAlgorithm::Crc32c => aws_smithy_checksums::ChecksumAlgorithm::Crc32c,
+            ChecksumAlgorithm::Sha1 => aws_smithy_checksums::ChecksumAlgorithm::Sha1,
+            ChecksumAlgorithm::Sha256 => aws_smithy_checksums::ChecksumAlgorithm::Sha256,
+        }
+        .into_impl()
+    }
+}
+
+/// A response of S3 that carries the checksums of the data S3 received.
+pub(crate) trait ReturnedChecksums {
+    fn checksum(&self, algorithm: ChecksumAlgorithm) -> Option<&str>;
+}
+
+impl ReturnedChecksums for CompletedPart {
+    fn checksum(&self, algorithm: ChecksumAlgorithm) -> Option<&str> {
+        match algorithm {
+            ChecksumAlgorithm::Crc32 => self.checksum_crc32(),
+            ChecksumAlgorithm::Crc32c => self.checksum_crc32_c(),
+            ChecksumAlgorithm::Sha1 => self.checksum_sha1(),
+            ChecksumAlgorithm::Sha256 => self.checksum_sha256(),
+        }
+    }
+}
+
+impl ReturnedChecksums for CompleteMultipartUploadOutput {
+    fn checksum(&self, algorithm: ChecksumAlgorithm) -> Option<&str> {
+        match algorithm {
+            ChecksumAlgorithm::Crc32 => self.checksum_crc32(),
+            ChecksumAlgorithm::Crc32c => self.checksum_crc32_c(),
+            ChecksumAlgorithm::Sha1 => self.checksum_sha1(),
+            ChecksumAlgorithm::Sha256 => self.checksum_sha256(),
+        }
+    }
+}
+
+impl ReturnedChecksums for PutObjectOutput {
+    fn checksum(&self, algorithm: ChecksumAlgorithm) -> Option<&str> {
+        match algorithm {
+            ChecksumAlgorithm::Crc32 => self.checksum_crc32(),
+            ChecksumAlgorithm::Crc32c => self.checksum_crc32_c(),
+            ChecksumAlgorithm::Sha1 => self.checksum_sha1(),
+            ChecksumAlgorithm::Sha256 => self.checksum_sha256(),
+        }
+    }
+}
+
+impl ReturnedChecksums for Checksum {
+    fn checksum(&self, algorithm: ChecksumAlgorithm) -> Option<&str> {
+        match algorithm {
+            ChecksumAlgorithm::Crc32 => self.checksum_crc32(),
+            ChecksumAlgorithm::Crc32c => self.checksum_crc32_c(),
+            ChecksumAlgorithm::Sha1 => self.checksum_sha1(),
+            ChecksumAlgorithm::Sha256 => self.checksum_sha256(),
+        }
+    }
+}
+
+impl ReturnedChecksums for ObjectPart {
+    fn checksum(&self, algorithm: ChecksumAlgorithm) -> Option<&str> {
+        match algorithm {
+            ChecksumAlgorithm::Crc32 => self.checksum_crc32(),
+            ChecksumAlgorithm::Crc32c => self.checksum_crc32_c(),
+            ChecksumAlgorithm::Sha1 => self.checksum_sha1(),
+            ChecksumAlgorithm::Sha256 => self.checksum_sha256(),
+        }
+    }
+}
+
+/// Calculates a checksum over all bytes that are passed through a [`ChecksumReader`].
+///
+/// The hasher is shared with the reader, so the checksum can still be retrieved after the reader
+/// has been handed to the SDK.
+#[derive(Clone)]
+pub(crate) struct Hasher(Arc<Mutex<Option<Box<dyn HttpChecksum>>>>);
+
+impl Hasher {
+    pub(crate) fn new(algorithm: ChecksumAlgorithm) -> Self {
+        Self::from_implementation(algorithm.implementation())
+    }
+
+    /// A hasher calculating the MD5 digest, which S3 uses as the ETag of unencrypted and SSE-S3
+    /// encrypted parts and objects.
+    pub(crate) fn md5() -> Self {
+        Self::from_implementation(aws_smithy_checksums::ChecksumAlgorithm::Md5.into_impl())
+    }
+
+    fn from_implementation(implementation: Box<dyn HttpChecksum>) -> Self {
+        Self(Arc::new(Mutex::new(Some(implementation))))
+    }
+
+    fn update(&self, bytes: &[u8]) {
+        if let Some(checksum) = self.0.lock().expect("poisoned lock").as_mut() {
+            checksum.update(bytes);
+        }
+    }
+
+    fn finalize(&self) -> Vec<u8> {
+        self.0
+            .lock()
+            .expect("poisoned lock")
+            .take()
+            .map(|checksum| checksum.finalize().to_vec())
+            .unwrap_or_default()
+    }
+
+    /// Returns the base64-encoded checksum of all bytes that were passed through the hasher.
+    pub(crate) fn checksum(&self) -> String {
+        base64::encode(self.finalize())
+    }
+
+    /// Verifies that the checksum S3 returned matches the checksum calculated locally.
+    ///
+    /// A mismatch is a retryable error, since it means that the data S3 received is not the data
+    /// that was read from the file, and sending it again is likely to succeed.
+    pub(crate) fn verify(
+        &self,
+        algorithm: ChecksumAlgorithm,
+        what: &str,
+        returned: &impl ReturnedChecksums,
+    ) -> Result<()> {
+        let calculated = self.checksum();
+        match returned.checksum(algorithm) {
+            Some(returned) if returned == calculated => Ok(()),
+            Some(returned) => Err(anyhow::anyhow!(
+                "The {} checksum S3 calculated for {} ({}) doesn't match the checksum calculated locally ({})",
+                algorithm.sdk(),
+                what,
+                returned,
+                calculated,
+            ))
+            .into_retryable(),
+            None => Err(anyhow::anyhow!(
+                "S3 did not return a {} checksum for {}",
+                algorithm.sdk(),
+                what,
+            ))
+            .into_retryable(),
+        }
+    }
+
+    /// Verifies that the ETag S3 returned is the MD5 digest calculated locally.
+    ///
+    /// The ETag is only an MD5 digest if the data isn't encrypted with a KMS key, otherwise there is
+    /// nothing to verify. A mismatch is unrecoverable, since it is more likely to be caused by a
+    /// store that calculates ETags differently than by corrupted data, in which case sending the
+    /// data again would fail the same way forever.
+    pub(crate) fn verify_e_tag(
+        &self,
+        what: &str,
+        e_tag: Option<&str>,
+        encryption: Option<&ServerSideEncryption>,
+    ) -> Result<()> {
+        if !e_tag_is_md5(encryption) {
+            return Ok(());
+        }
+        let calculated = hex(&self.finalize());
+        match e_tag.map(|e_tag| e_tag.trim_matches('"')) {
+            Some(e_tag) if e_tag.eq_ignore_ascii_case(&calculated) => Ok(()),
+            e_tag => bail!(
+                "The ETag S3 returned for {} ({}) doesn't match the MD5 digest calculated locally ({}). If the store doesn't use MD5 digests as ETags, use `--no-verify-etag`.",
+                what,
+                e_tag.unwrap_or("<none>"),
+                calculated,
+            ),
+        }
+    }
+}
+
+/// Wraps a reader and passes every chunk read from it through the given [`Hasher`]s.
+pub(crate) struct ChecksumReader<R> {
+    inner: R,
+    hashers: Vec<Hasher>,
+}
+
+impl<R> ChecksumReader<R> {
+    pub(crate) fn new(inner: R, hashers: Vec<Hasher>) -> Self {
+        Self { inner, hashers }
+    }
+}
+
+impl<R> AsyncRead for ChecksumReader<R>
+where
+    R: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled_before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        for hasher in &self.hashers {
+            hasher.update(&buf.filled()[filled_before..]);
+        }
+        result
+    }
+}
+
+/// Verifies the ETag and, if a checksum algorithm is used, the checksum S3 returned for the
+/// completed multipart upload against the values expected from the individual parts.
+///
+/// Since the checksums of the individual parts have been verified against the data read locally
+/// when they were uploaded, this ensures that S3 assembled exactly the parts that were uploaded.
+/// The ETag is only verified if `verify_e_tag` is set.
+pub(crate) fn verify_multipart_upload(
+    parts: &[CompletedPart],
+    algorithm: Option<ChecksumAlgorithm>,
+    verify_e_tag: bool,
+    output: &CompleteMultipartUploadOutput,
+) -> Result<()> {
+    if verify_e_tag && e_tag_is_md5(output.server_side_encryption()) {
+        let mut digests = Vec::with_capacity(parts.len() * 16);
+        for part in parts {
+            let e_tag = part.e_tag().unwrap_or_default().trim_matches('"');
+            match unhex(e_tag) {
+                Some(digest) => digests.extend(digest),
+                None => {
+                    debug!(
+                        "ETag of part {:?} is not an MD5 digest, not verifying the ETag of the object",
+                        part.part_number(),
+                    );
+                    digests.clear();
+                    break;
+                }
+            }
+        }
+        if !digests.is_empty() {
+            let md5 = Hasher::md5();
+            md5.update(&digests);
+            let expected = format!("{}-{}", hex(&md5.finalize()), parts.len());
+            let returned = output.e_tag().unwrap_or_default().trim_matches('"');
+            if !returned.eq_ignore_ascii_case(&expected) {
+                bail!(
+                    "The ETag of the completed object ({}) doesn't match the ETag expected from the uploaded parts ({}). The object in S3 is likely corrupt!",
+                    returned,
+                    expected,
+                );
+            }
+            debug!("Verified the ETag of the completed object: {}", expected);
+        }
+    }
+
+    if let Some(algorithm) = algorithm {
+        let hasher = Hasher::new(algorithm);
+        for part in parts {
+            let checksum = part
+                .checksum(algorithm)
+                .and_then(|checksum| base64::decode(checksum).ok());
+            let Some(checksum) = checksum else {
+                bail!(
+                    "Part {:?} has no valid {} checksum, unable to verify the checksum of the completed object",
+                    part.part_number(),
+                    algorithm.sdk(),
+                );
+            };
+            hasher.update(&checksum);
+        }
+        let expected = format!("{}-{}", base64::encode(hasher.finalize()), parts.len());
+        match output.checksum(algorithm) {
+            Some(returned) if returned == expected => {
+                debug!(
+                    "Verified the {} checksum of the completed object: {}",
+                    algorithm.sdk(),
+                    expected,
+                );
+            }
+            returned => bail!(
+                "The {} checksum of the completed object ({}) doesn't match the checksum expected from the uploaded parts ({}). The object in S3 is likely corrupt!",
+                algorithm.sdk(),
+                returned.unwrap_or("<none>"),
+                expected,
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+/// Calculates the ETag S3 assigns to an unencrypted object with the contents of `file`, if it is
+/// uploaded with a single request (`plan` is `None`) or as a multipart upload of the given parts.
+pub(crate) async fn e_tag_of_file(file: &Path, plan: Option<PartPlan>) -> Result<String> {
+    let digests = match plan {
+        Some(plan) => {
+            let part_sizes: Vec<u64> = plan.parts_from(1).map(|part| part.size).collect();
+            FileDigests::of(file, &part_sizes, true, None).await?
+        }
+        None => {
+            let size = tokio::fs::metadata(file).await.into_unrecoverable()?.len();
+            FileDigests::of(file, &[size], false, None).await?
+        }
+    };
+    Ok(digests.e_tag())
+}
+
+/// Returns how an object with the given size and ETag was most likely uploaded: with a single
+/// request (`Some(None)`) or as a multipart upload of the returned parts.
+///
+/// The part size isn't recorded in S3, so only the part sizes Persevere chooses by default and the
+/// ones commonly used by other tools are considered. `None` is returned if the ETag can't be
+/// reproduced locally.
+pub(crate) fn expected_part_plan(size: u64, e_tag: &str) -> Option<Option<PartPlan>> {
+    let Some((digest, number_of_parts)) = e_tag.split_once('-') else {
+        return (e_tag.len() == 32).then_some(None);
+    };
+    let number_of_parts: u64 = number_of_parts.parse().ok()?;
+    if digest.len() != 32 || number_of_parts == 0 {
+        return None;
+    }
+    let candidates = [
+        parts::choose_part_size(size, None).ok(),
+        Some(8 * MiB),
+        Some(size.div_ceil(number_of_parts).div_ceil(MiB) * MiB),
+        Some(size.div_ceil(number_of_parts)),
+    ];
+    candidates
+        .into_iter()
+        .flatten()
+        .filter(|part_size| *part_size > 0)
+        .map(|part_size| PartPlan::new(size, part_size))
+        .find(|plan| plan.number_of_parts() == number_of_parts)
+        .map(Some)
+}
+
+/// The digests S3 calculates for an object, calculated locally for the contents of a file.
+pub(crate) struct FileDigests {
+    /// Whether the object was uploaded as a multipart upload, even if it consists of a single part.
+    multipart: bool,
+    algorithm: Option<ChecksumAlgorithm>,
+    part_md5s: Vec<Vec<u8>>,
+    part_checksums: Vec<Vec<u8>>,
+    /// The checksum of the whole file, as S3 calculates it for single requests.
+    full_checksum: Option<Vec<u8>>,
+}
+
+impl FileDigests {
+    /// Reads the file once, calculating the MD5 digest and, if an algorithm is given, the checksum
+    /// of every part of the given sizes.
+    pub(crate) async fn of(
+        file: &Path,
+        part_sizes: &[u64],
+        multipart: bool,
+        algorithm: Option<ChecksumAlgorithm>,
+    ) -> Result<Self> {
+        let mut file = tokio::fs::File::open(file).await.into_unrecoverable()?;
+        let full = algorithm.map(Hasher::new);
+        let mut part_md5s = Vec::with_capacity(part_sizes.len());
+        let mut part_checksums = Vec::with_capacity(part_sizes.len());
+        for part_size in part_sizes {
+            let md5 = Hasher::md5();
+            let checksum = algorithm.map(Hasher::new);
+            let hashers = [Some(md5.clone()), checksum.clone(), full.clone()]
+                .into_iter()
+                .flatten()
+                .collect();
+            let read = tokio::io::copy(
+                &mut ChecksumReader::new((&mut file).take(*part_size), hashers),
+                &mut tokio::io::sink(),
+            )
+            .await
+            .into_unrecoverable()?;
+            if read != *part_size {
+                bail!("The file is smaller than the parts it was expected to consist of");
+            }
+            part_md5s.push(md5.finalize());
+            part_checksums.extend(checksum.map(|checksum| checksum.finalize()));
+        }
+        Ok(Self {
+            multipart,
+            algorithm,
+            part_md5s,
+            part_checksums,
+            full_checksum: full.map(|full| full.finalize()),
+        })
+    }
+
+    /// The ETag of the object, if the data isn't encrypted with a KMS key.
+    pub(crate) fn e_tag(&self) -> String {
+        if !self.multipart {
+            return self
+                .part_md5s
+                .first()
+                .map(|md5| hex(md5))
+                .unwrap_or_default();
+        }
+        let md5 = Hasher::md5();
+        md5.update(&self.part_md5s.concat());
+        format!("{}-{}", hex(&md5.finalize()), self.part_md5s.len())
+    }
+
+    /// The base64-encoded checksum of the (0-based) part.
+    pub(crate) fn part_checksum(&self, index: usize) -> Option<String> {
+        self.part_checksums.get(index).map(base64::encode)
+    }
+
+    /// Whether the checksum S3 returned for the object matches the file.
+    ///
+    /// The checksum of a multipart upload is usually the checksum of the checksums of its parts,
+    /// but objects uploaded with single requests, or by tools using full-object CRC checksums,
+    /// carry the checksum of the whole file. Both are accepted, with or without the `-N` suffix
+    /// for the number of parts.
+    pub(crate) fn matches_checksum(&self, returned: &str) -> bool {
+        let returned = returned
+            .split_once('-')
+            .map_or(returned, |(checksum, _)| checksum);
+        if self
+            .full_checksum
+            .as_ref()
+            .is_some_and(|full| base64::encode(full) == returned)
+        {
+            return true;
+        }
+        match self.algorithm {
+            Some(algorithm) if self.multipart => {
+                let hasher = Hasher::new(algorithm);
+                hasher.update(&self.part_checksums.concat());
+                base64::encode(hasher.finalize()) == returned
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Whether S3 uses the MD5 digest of the data as ETag, which is not the case for data encrypted
+/// with a KMS key.
+pub(crate) fn e_tag_is_md5(encryption: Option<&ServerSideEncryption>) -> bool {
+    !matches!(
+        encryption,
+        Some(ServerSideEncryption::AwsKms | ServerSideEncryption::AwsKmsDsse)
+    )
+}
+
+/// Returns the hex-encoded SHA-256 digest of the bytes.
+pub(crate) fn sha256_hex(bytes: &[u8]) -> String {
+    let mut sha256 = aws_smithy_checksums::ChecksumAlgorithm::Sha256.into_impl();
+    sha256.update(bytes);
+    hex(&sha256.finalize())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(value: &str) -> Option<Vec<u8>> {
+    if value.len() != 32 || !value.is_ascii() {
+        return None;
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(&value[index..index + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Data of `size` bytes split into parts of `part_size` bytes, and the ETag and the composite
+    /// CRC32C and SHA-256 checksums S3 returns for the completed multipart upload of it, as
+    /// calculated independently of this module.
+    const CASES: [(usize, usize, &str, &str, &str); 3] = [
+        (
+            10,
+            10,
+            "b6cdee8398f0a0d3b9dbfa62169589fc-1",
+            "2Kyc1w==-1",
+            "NNR53ALy4XuR/qK9tVFRzVC8s1Osyo5nXln+k26BqNg=-1",
+        ),
+        (
+            10,
+            5,
+            "0c8f687827b7a6269e2f23f0956d1fa5-2",
+            "7YkD7A==-2",
+            "CyQgqo3VHoWUXDYW5KWMKkNYj+QCOSpeqi72byjBoDU=-2",
+        ),
+        // The last part is shorter than the others.
+        (
+            23,
+            5,
+            "235584c2e0def876f3bab0b6e302f7f6-5",
+            "5a16tA==-5",
+            "E+yR983tM0sDsknUL0HSf97QyUMiaAyqG3yX0B8bNUc=-5",
+        ),
+    ];
+
+    fn data(size: usize) -> Vec<u8> {
+        (0..size).map(|index| (index * 7 % 256) as u8).collect()
+    }
+
+    fn digest(hasher: Hasher, bytes: &[u8]) -> Hasher {
+        hasher.update(bytes);
+        hasher
+    }
+
+    /// Returns the parts as S3 returns them once they have been uploaded.
+    fn completed_parts(data: &[u8], part_size: usize) -> Vec<CompletedPart> {
+        data.chunks(part_size)
+            .enumerate()
+            .map(|(index, part)| {
+                CompletedPart::builder()
+                    .part_number(index as i32 + 1)
+                    .e_tag(format!(
+                        "\"{}\"",
+                        hex(&digest(Hasher::md5(), part).finalize())
+                    ))
+                    .checksum_crc32_c(
+                        digest(Hasher::new(ChecksumAlgorithm::Crc32c), part).checksum(),
+                    )
+                    .checksum_sha256(
+                        digest(Hasher::new(ChecksumAlgorithm::Sha256), part).checksum(),
+                    )
+                    .build()
+            })
+            .collect()
+    }
+
+    fn output(e_tag: &str, crc32c: &str, sha256: &str) -> CompleteMultipartUploadOutput {
+        CompleteMultipartUploadOutput::builder()
+            .e_tag(format!("\"{}\"", e_tag))
+            .checksum_crc32_c(crc32c)
+            .checksum_sha256(sha256)
+            .build()
+    }
+
+    #[test]
+    fn completed_multipart_uploads_are_verified() {
+        for (size, part_size, e_tag, crc32c, sha256) in CASES {
+            let parts = completed_parts(&data(size), part_size);
+            let completed = output(e_tag, crc32c, sha256);
+            for algorithm in [
+                None,
+                Some(ChecksumAlgorithm::Crc32c),
+                Some(ChecksumAlgorithm::Sha256),
+            ] {
+                verify_multipart_upload(&parts, algorithm, true, &completed).unwrap();
+            }
+
+            let other_e_tag = output(&e_tag.replace('-', "0-"), crc32c, sha256);
+            assert!(verify_multipart_upload(&parts, None, true, &other_e_tag).is_err());
+            verify_multipart_upload(&parts, None, false, &other_e_tag).unwrap();
+            let other_crc32c = output(e_tag, sha256, sha256);
+            let algorithm = Some(ChecksumAlgorithm::Crc32c);
+            assert!(verify_multipart_upload(&parts, algorithm, false, &other_crc32c).is_err());
+
+            // The parts have to be assembled in the order they were uploaded in.
+            if parts.len() > 1 {
+                let mut reordered = parts.clone();
+                reordered.swap(0, 1);
+                assert!(verify_multipart_upload(&reordered, None, true, &completed).is_err());
+                assert!(verify_multipart_upload(&reordered, algorithm, false, &completed).is_err());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn file_digests_match_the_completed_multipart_upload() {
+        let file = std::env::temp_dir().join(format!("persevere-digests-{}", std::process::id()));
+        for (size, part_size, e_tag, crc32c, sha256) in CASES {
+            std::fs::write(&file, data(size)).unwrap();
+            let part_sizes: Vec<_> = PartPlan::new(size as u64, part_size as u64)
+                .parts_from(1)
+                .map(|part| part.size)
+                .collect();
+            for (algorithm, checksum) in [
+                (ChecksumAlgorithm::Crc32c, crc32c),
+                (ChecksumAlgorithm::Sha256, sha256),
+            ] {
+                let digests = FileDigests::of(&file, &part_sizes, true, Some(algorithm))
+                    .await
+                    .unwrap();
+                assert_eq!(digests.e_tag(), e_tag);
+                assert!(digests.matches_checksum(checksum));
+            }
+        }
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn part_plans_are_derived_from_e_tags() {
+        let md5 = "235584c2e0def876f3bab0b6e302f7f6";
+        assert_eq!(expected_part_plan(23, md5), Some(None));
+
+        // Only parts of 5 bytes make 5 parts of 23 bytes, with a short last part.
+        let plan = expected_part_plan(23, &format!("{}-5", md5))
+            .unwrap()
+            .unwrap();
+        assert_eq!(plan, PartPlan::new(23, 5));
+        assert_eq!(plan.part(5).unwrap().size, 3);
+
+        // Parts of 8 MiB, as used by the AWS CLI, with a short last part.
+        let plan = expected_part_plan(100 * MiB, &format!("{}-13", md5))
+            .unwrap()
+            .unwrap();
+        assert_eq!(plan, PartPlan::new(100 * MiB, 8 * MiB));
+        assert_eq!(plan.part(13).unwrap().size, 4 * MiB);
+
+        let plan = expected_part_plan(10, &format!("{}-1", md5))
+            .unwrap()
+            .unwrap();
+        assert_eq!(plan.number_of_parts(), 1);
+
+        for e_tag in [
+            "",
+            "abc",
+            "abc-2",
+            &format!("{}-0", md5),
+            &format!("{}-x", md5),
+        ] {
+            assert_eq!(expected_part_plan(23, e_tag), None, "{}", e_tag);
+        }
+    }
+}
@@ -0,0 +1,166 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    consts::{
+        KiB,
+        MiB,
+    },
+    size,
+};
+use aws_sdk_s3::primitives::ByteStream;
+use http_body::{
+    Body,
+    Frame,
+    SizeHint,
+};
+use std::{
+    pin::Pin,
+    sync::Mutex,
+    task::{
+        ready,
+        Context,
+        Poll,
+    },
+};
+use tokio::io::AsyncRead;
+use tokio_util::bytes::{
+    Bytes,
+    BytesMut,
+};
+
+/// How many buffers the pool keeps for reuse at most. Buffers returned to a full pool are freed.
+const MAXIMUM_POOLED_BUFFERS: usize = 16;
+
+/// Buffers of bodies that have been dropped, which the bodies of the next parts reuse.
+static BUFFER_POOL: Mutex<Vec<BytesMut>> = Mutex::new(Vec::new());
+
+/// Parses the size of the buffer bodies are streamed through, which has to be between 4 KiB and
+/// 16 MiB.
+pub(crate) fn parse_buffer_size(value: &str) -> Result<usize, String> {
+    let buffer_size = size::parse_size(value)?;
+    if !(4 * KiB..=16 * MiB).contains(&buffer_size) {
+        return Err("the buffer size has to be between 4KiB and 16MiB".to_owned());
+    }
+    Ok(buffer_size as usize)
+}
+
+/// Takes a buffer with at least `buffer_size` bytes of capacity from the pool, or allocates a new
+/// one if the pool is empty.
+fn take_buffer(buffer_size: usize) -> BytesMut {
+    let pooled = BUFFER_POOL.lock().expect("poisoned lock").pop();
+    match pooled {
+        Some(mut buffer) => {
+            buffer.clear();
+            buffer.reserve(buffer_size);
+            buffer
+        }
+        None => BytesMut::with_capacity(buffer_size),
+    }
+}
+
+fn return_buffer(buffer: BytesMut) {
+    let mut pool = BUFFER_POOL.lock().expect("poisoned lock");
+    if pool.len() < MAXIMUM_POOLED_BUFFERS {
+        pool.push(buffer);
+    }
+}
+
+/// A body streamed from a reader through a buffer of a fixed size.
+///
+/// Every frame is split off the buffer without copying it. Once the frame has been sent and
+/// dropped, the next read reclaims its space, so that a part is streamed through the same
+/// allocation. The buffer is returned to a pool when the body is dropped, for the next part to
+/// reuse.
+///
+/// The body reports its exact length, which the SDK requires to be able to calculate a checksum
+/// while sending it, transmitting the checksum as a trailer.
+struct ReaderBody<R> {
+    reader: R,
+    buffer: BytesMut,
+    buffer_size: usize,
+    /// Number of bytes the reader has yet to yield.
+    remaining: u64,
+}
+
+impl<R> Body for ReaderBody<R>
+where
+    R: AsyncRead + Unpin,
+{
+    type Data = Bytes;
+    type Error = std::io::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = &mut *self;
+        if this.remaining == 0 {
+            return Poll::Ready(None);
+        }
+        this.buffer.reserve(this.buffer_size);
+        let read = ready!(tokio_util::io::poll_read_buf(
+            Pin::new(&mut this.reader),
+            cx,
+            &mut this.buffer,
+        ))?;
+        if read == 0 {
+            return Poll::Ready(Some(Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("the body ended {} bytes early", this.remaining),
+            ))));
+        }
+        this.remaining = this.remaining.saturating_sub(read as u64);
+        Poll::Ready(Some(Ok(Frame::data(this.buffer.split().freeze()))))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.remaining == 0
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        SizeHint::with_exact(self.remaining)
+    }
+}
+
+impl<R> Drop for ReaderBody<R> {
+    fn drop(&mut self) {
+        return_buffer(std::mem::take(&mut self.buffer));
+    }
+}
+
+/// Extends the [`ByteStream`] type with helper methods.
+pub(crate) trait ByteStreamExt {
+    /// Creates a new dynamic `ByteStream` from an [`AsyncRead`] instance that will yield exactly
+    /// `length` bytes, streamed through a pooled buffer of `buffer_size` bytes.
+    fn from_reader<R>(reader: R, length: u64, buffer_size: usize) -> ByteStream
+    where
+        R: AsyncRead + Send + Sync + Unpin + 'static;
+}
+
+impl ByteStreamExt for ByteStream {
+    fn from_reader<R>(reader: R, length: u64, buffer_size: usize) -> ByteStream
+    where
+        R: AsyncRead + Send + Sync + Unpin + 'static,
+    {
+        ByteStream::from_body_1_x(ReaderBody {
+            reader,
+            buffer: take_buffer(buffer_size),
+            buffer_size,
+            remaining: length,
+        })
+    }
+}
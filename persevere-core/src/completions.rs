@@ -0,0 +1,48 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    result::{
+        Result,
+        StdResultExt,
+    },
+    Cli,
+};
+use clap::{
+    Args,
+    CommandFactory,
+};
+use clap_complete::Shell;
+use std::io::Write;
+
+#[derive(Debug, Args)]
+pub(crate) struct Completions {
+    /// The shell to generate the completions for.
+    #[arg(value_enum)]
+    shell: Shell,
+}
+
+impl Completions {
+    pub(crate) async fn run(&self) -> Result<()> {
+        let mut command = Cli::command();
+        let name = command.get_name().to_owned();
+        // Generated into a buffer first, since writing to stdout directly panics on errors, e.g. when
+        // the output is piped into `head`.
+        let mut script = Vec::new();
+        clap_complete::generate(self.shell, &mut command, name, &mut script);
+        std::io::stdout().write_all(&script).into_unrecoverable()
+    }
+}
@@ -0,0 +1,213 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    checksum::{
+        ChecksumReader,
+        Hasher,
+    },
+    consts::MiB,
+    headers,
+    result::{
+        bail,
+        AnyhowResultExt,
+        Result,
+        StdResultExt,
+    },
+    spill,
+    State,
+};
+use anyhow::Context;
+use async_compression::{
+    tokio::bufread::ZstdEncoder,
+    Level,
+};
+use aws_sdk_s3::types::ServerSideEncryption;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::fmt;
+use tokio::io::{
+    AsyncRead,
+    AsyncReadExt,
+    BufReader,
+};
+use tracing::debug;
+
+/// The compression level zstd uses by default.
+const DEFAULT_ZSTD_LEVEL: i32 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum CompressionAlgorithm {
+    Zstd,
+}
+
+/// The algorithm and level to compress a file with, as given through `--compress`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub(crate) struct Codec {
+    algorithm: CompressionAlgorithm,
+    level: i32,
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.algorithm {
+            CompressionAlgorithm::Zstd => write!(f, "zstd:{}", self.level),
+        }
+    }
+}
+
+/// Parses a codec in the form `zstd` or `zstd:<level>`, where the level ranges from 1 to 22.
+pub(crate) fn parse_codec(value: &str) -> Result<Codec, String> {
+    let (algorithm, level) = match value.split_once(':') {
+        Some((algorithm, level)) => (algorithm, Some(level)),
+        None => (value, None),
+    };
+    if !algorithm.eq_ignore_ascii_case("zstd") {
+        return Err(format!(
+            "unsupported compression algorithm `{}`, only `zstd` is supported",
+            algorithm,
+        ));
+    }
+    let level = match level {
+        Some(level) => level
+            .parse()
+            .ok()
+            .filter(|level| (1..=22).contains(level))
+            .ok_or_else(|| format!("invalid zstd level `{}`, expected 1 to 22", level))?,
+        None => DEFAULT_ZSTD_LEVEL,
+    };
+    Ok(Codec {
+        algorithm: CompressionAlgorithm::Zstd,
+        level,
+    })
+}
+
+/// Compression of a file on the fly, before it is split into parts.
+///
+/// The size of the compressed stream is only known once the file has been compressed completely, so
+/// it is uploaded like a stream read from stdin: every part is spilled to disk before it is
+/// uploaded. To resume the upload, the file is compressed again from its start, and the bytes that
+/// have already been uploaded are skipped. This relies on the same version of zstd compressing the
+/// same file to the same stream, which is verified against the ETags of the uploaded parts.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct Compression {
+    #[serde(flatten)]
+    codec: Codec,
+    /// Size of the uncompressed file.
+    pub(crate) file_size: u64,
+    /// Version of zstd the upload was started with.
+    zstd_version: u32,
+}
+
+impl Compression {
+    pub(crate) fn new(codec: Codec, file_size: u64) -> Self {
+        Self {
+            codec,
+            file_size,
+            zstd_version: zstd_safe::version_number(),
+        }
+    }
+
+    /// The value of the `Content-Encoding` of the compressed object.
+    pub(crate) fn content_encoding(&self) -> &'static str {
+        match self.codec.algorithm {
+            CompressionAlgorithm::Zstd => "zstd",
+        }
+    }
+
+    /// The largest size the file can be compressed to, which can be slightly larger than the file
+    /// itself if it doesn't compress at all.
+    pub(crate) fn max_compressed_size(&self) -> u64 {
+        zstd_safe::compress_bound(self.file_size as usize) as u64
+    }
+
+    /// Compresses the file of the upload, returning a reader of the compressed stream that starts
+    /// where the upload has to continue.
+    pub(crate) async fn reader(&self, state: &State) -> Result<impl AsyncRead + Send + 'static> {
+        if self.zstd_version != zstd_safe::version_number() {
+            bail!(
+                "The upload was compressed with zstd {}, but this build of Persevere uses zstd {}, which may compress the file differently, so the upload can't be resumed",
+                self.zstd_version,
+                zstd_safe::version_number(),
+            );
+        }
+        let file = tokio::fs::File::open(&state.file_to_upload)
+            .await
+            .into_unrecoverable()?;
+        let mut reader = ZstdEncoder::with_quality(
+            BufReader::with_capacity(MiB as usize, file),
+            Level::Precise(self.codec.level),
+        );
+
+        let Some(spill_directory) = &state.spill_directory else {
+            bail!("Compressed uploads require a spill directory");
+        };
+        let stream_offset = spill::stream_offset(
+            spill_directory,
+            state.last_successful_part + 1,
+            state.file_size_in_bytes,
+        )
+        .await?;
+        if stream_offset > 0 {
+            debug!(
+                "Compressing the first {} bytes of the stream again to continue after them",
+                stream_offset,
+            );
+        }
+        // The ETags are only the MD5 digests of the compressed parts if they aren't encrypted.
+        let encryption = state
+            .object_options
+            .sse
+            .as_deref()
+            .map(ServerSideEncryption::from);
+        let verify =
+            state.encryption.is_none() && !headers::encrypt_with_customer_key(&state.headers);
+        let mut skipped = 0;
+        for completed_part in &state.completed_parts {
+            let md5 = Hasher::md5();
+            let size = state.part_size.min(stream_offset - skipped);
+            skipped += tokio::io::copy(
+                &mut ChecksumReader::new((&mut reader).take(size), vec![md5.clone()]),
+                &mut tokio::io::sink(),
+            )
+            .await
+            .context("Failed to compress the file")
+            .into_unrecoverable()?;
+            if verify
+                && md5
+                    .verify_e_tag("the part", completed_part.e_tag(), encryption.as_ref())
+                    .is_err()
+            {
+                bail!(
+                    "Compressing the file again didn't reproduce part {} that was uploaded already, so the upload can't be resumed",
+                    completed_part.part_number().unwrap_or_default(),
+                );
+            }
+        }
+        // Skip a part that a previous run has spilled completely, but not uploaded yet.
+        tokio::io::copy(
+            &mut (&mut reader).take(stream_offset - skipped),
+            &mut tokio::io::sink(),
+        )
+        .await
+        .context("Failed to compress the file")
+        .into_unrecoverable()?;
+        Ok(reader)
+    }
+}
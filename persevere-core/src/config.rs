@@ -0,0 +1,232 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    duration::parse_duration,
+    result::{
+        bail,
+        AnyhowResultExt,
+        Result,
+    },
+    size::parse_size,
+    smtp,
+};
+use anyhow::Context;
+use serde::Deserialize;
+use std::{
+    ffi::OsString,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+/// Defaults for the options of Persevere, as they are set in its configuration file.
+///
+/// Options given on the command line always take precedence over the configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct Config {
+    /// Default for `--override-part-size`.
+    part_size: Option<String>,
+    /// Default for `--max-retries`.
+    max_retries: Option<u32>,
+    /// Default for `--retry-backoff`.
+    retry_backoff: Option<String>,
+    /// Default for `--profile`.
+    profile: Option<String>,
+    /// Default for `--region`.
+    region: Option<String>,
+    /// Default for `--endpoint-url`.
+    endpoint_url: Option<String>,
+    /// Default for `--force-path-style`.
+    force_path_style: Option<bool>,
+    /// Default for `--use-fips-endpoint`.
+    use_fips_endpoint: Option<bool>,
+    /// Default for `--use-dualstack-endpoint`.
+    use_dualstack_endpoint: Option<bool>,
+    /// Default for `--ca-bundle`.
+    ca_bundle: Option<PathBuf>,
+    /// Default for `--no-verify-tls`.
+    no_verify_tls: Option<bool>,
+    /// Default for `--proxy`.
+    proxy: Option<String>,
+    /// Default for `--connect-timeout`.
+    connect_timeout: Option<String>,
+    /// Default for `--read-timeout`.
+    read_timeout: Option<String>,
+    /// Default for `--stall-timeout`.
+    stall_timeout: Option<String>,
+    /// Default for `--notify-email`.
+    notify_email: Option<String>,
+    /// Default for `--smtp-server`.
+    smtp_server: Option<String>,
+    /// Default for `--smtp-from`.
+    smtp_from: Option<String>,
+    /// Directory to keep the state in, instead of `$XDG_STATE_HOME/persevere`.
+    pub(crate) state_directory: Option<PathBuf>,
+}
+
+impl Config {
+    /// Reads the configuration from the given file, or from the default location if no file was
+    /// given.
+    ///
+    /// Only an explicitly given file has to exist.
+    pub(crate) fn load(file: Option<&Path>) -> Result<Self> {
+        let (file, explicit) = match file {
+            Some(file) => (file.to_owned(), true),
+            None => match config_file() {
+                Some(file) => (file, false),
+                None => return Ok(Config::default()),
+            },
+        };
+        let contents = match std::fs::read_to_string(&file) {
+            Err(error) if !explicit && error.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Config::default());
+            }
+            result => result
+                .with_context(|| format!("Failed to read configuration file {}", file.display()))
+                .into_unrecoverable()?,
+        };
+        let config: Config = toml::from_str(&contents)
+            .with_context(|| format!("Failed to parse configuration file {}", file.display()))
+            .into_unrecoverable()?;
+        config.validate(&file)?;
+        Ok(config)
+    }
+
+    /// Rejects values that would only be rejected once they are used as the default of an option,
+    /// which would make for a confusing error.
+    fn validate(&self, file: &Path) -> Result<()> {
+        if let Some(Err(err)) = self.part_size.as_deref().map(parse_size) {
+            bail!("Invalid part-size in {}: {}", file.display(), err);
+        }
+        for (key, value) in [
+            ("retry-backoff", &self.retry_backoff),
+            ("connect-timeout", &self.connect_timeout),
+            ("read-timeout", &self.read_timeout),
+            ("stall-timeout", &self.stall_timeout),
+        ] {
+            if let Some(Err(err)) = value.as_deref().map(parse_duration) {
+                bail!("Invalid {} in {}: {}", key, file.display(), err);
+            }
+        }
+        if let Some(Err(err)) = self.smtp_server.as_deref().map(smtp::parse_server) {
+            bail!("Invalid smtp-server in {}: {}", file.display(), err);
+        }
+        Ok(())
+    }
+
+    /// Sets the defaults of the configuration on the options of the command and all of its
+    /// subcommands.
+    pub(crate) fn apply(&self, command: clap::Command) -> clap::Command {
+        self.defaults()
+            .into_iter()
+            .fold(command, |command, (id, value)| {
+                with_default(command, id, &value)
+            })
+    }
+
+    /// The defaults by the ID of the option they apply to.
+    fn defaults(&self) -> Vec<(&'static str, String)> {
+        [
+            ("override_part_size", self.part_size.clone()),
+            (
+                "max_retries",
+                self.max_retries.map(|value| value.to_string()),
+            ),
+            ("retry_backoff", self.retry_backoff.clone()),
+            ("profile", self.profile.clone()),
+            ("region", self.region.clone()),
+            ("endpoint_url", self.endpoint_url.clone()),
+            (
+                "force_path_style",
+                self.force_path_style.map(|value| value.to_string()),
+            ),
+            (
+                "use_fips_endpoint",
+                self.use_fips_endpoint.map(|value| value.to_string()),
+            ),
+            (
+                "use_dualstack_endpoint",
+                self.use_dualstack_endpoint.map(|value| value.to_string()),
+            ),
+            (
+                "ca_bundle",
+                self.ca_bundle
+                    .as_ref()
+                    .map(|path| path.display().to_string()),
+            ),
+            (
+                "no_verify_tls",
+                self.no_verify_tls.map(|value| value.to_string()),
+            ),
+            ("proxy", self.proxy.clone()),
+            ("connect_timeout", self.connect_timeout.clone()),
+            ("read_timeout", self.read_timeout.clone()),
+            ("stall_timeout", self.stall_timeout.clone()),
+            ("notify_email", self.notify_email.clone()),
+            ("smtp_server", self.smtp_server.clone()),
+            ("smtp_from", self.smtp_from.clone()),
+        ]
+        .into_iter()
+        .filter_map(|(id, value)| Some((id, value?)))
+        .collect()
+    }
+}
+
+/// Returns the configuration file given through `--config` in the arguments.
+///
+/// The configuration file has to be read before the arguments are parsed, since it sets the
+/// defaults of the options.
+pub(crate) fn config_argument(args: impl IntoIterator<Item = OsString>) -> Option<PathBuf> {
+    let mut args = args.into_iter().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        } else if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        } else if let Some(file) = arg.to_str().and_then(|arg| arg.strip_prefix("--config=")) {
+            return Some(PathBuf::from(file));
+        }
+    }
+    None
+}
+
+/// Returns the default location of the configuration file.
+///
+/// This is `$XDG_CONFIG_HOME/persevere/config.toml`, falling back to
+/// `~/.config/persevere/config.toml`.
+fn config_file() -> Option<PathBuf> {
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config_home.join("persevere").join("config.toml"))
+}
+
+fn with_default(mut command: clap::Command, id: &str, value: &str) -> clap::Command {
+    if command.get_arguments().any(|arg| arg.get_id() == id) {
+        command = command.mut_arg(id, |arg| arg.default_value(value.to_owned()));
+    }
+    let subcommands: Vec<_> = command
+        .get_subcommands()
+        .map(|subcommand| subcommand.get_name().to_owned())
+        .collect();
+    subcommands.iter().fold(command, |command, name| {
+        command.mut_subcommand(name, |subcommand| with_default(subcommand, id, value))
+    })
+}
@@ -0,0 +1,347 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    consts::MAXIMUM_OBJECT_SIZE,
+    migration,
+    object_options::ObjectOptions,
+    output::{
+        OutputFormat,
+        TransferResult,
+    },
+    parts::{
+        self,
+        Part,
+        PartPlan,
+    },
+    progress::ProgressReporter,
+    result::{
+        bail,
+        AnyhowResultExt,
+        Result,
+        StdResultExt,
+    },
+    s3_uri::S3Uri,
+    sdk,
+    size,
+    state_home,
+    state_lock::StateLock,
+    state_store::StateStore,
+    upload_and_record,
+    State,
+    TransferOptions,
+};
+use anyhow::Context;
+use aws_sdk_s3::{
+    error::ProvideErrorMetadata,
+    types::{
+        CompletedPart,
+        RequestPayer,
+    },
+};
+use clap::{
+    builder::PossibleValuesParser,
+    Args,
+};
+use percent_encoding::{
+    utf8_percent_encode,
+    AsciiSet,
+    NON_ALPHANUMERIC,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::Instant,
+};
+use tracing::{
+    debug,
+    info,
+};
+
+/// Characters of the bucket and key that are left as they are in the `x-amz-copy-source` header.
+const COPY_SOURCE_UNRESERVED: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/')
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+/// The object a multipart upload copies its parts from, instead of reading them from a file.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct CopySource {
+    s3_bucket: String,
+    s3_key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    version_id: Option<String>,
+    /// ETag of the source object when the copy was started.
+    ///
+    /// All parts are copied on the condition that the source still has this ETag, such that a
+    /// source that is overwritten in the meantime fails the copy instead of mixing the parts of
+    /// both objects.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    e_tag: Option<String>,
+}
+
+impl CopySource {
+    /// Returns the value of the `x-amz-copy-source` header for the source object.
+    fn header(&self) -> String {
+        let mut header = format!(
+            "{}/{}",
+            self.s3_bucket,
+            utf8_percent_encode(&self.s3_key, COPY_SOURCE_UNRESERVED),
+        );
+        if let Some(version_id) = &self.version_id {
+            header.push_str("?versionId=");
+            header.push_str(&utf8_percent_encode(version_id, NON_ALPHANUMERIC).to_string());
+        }
+        header
+    }
+
+    /// Copies the bytes of the part from the source object into the multipart upload.
+    pub(crate) async fn copy_part(
+        &self,
+        s3: &aws_sdk_s3::Client,
+        state: &State,
+        part: Part,
+        reporter: &Arc<dyn ProgressReporter>,
+    ) -> Result<CompletedPart> {
+        reporter.part_started(&part, state.number_of_parts);
+        let output = match s3
+            .upload_part_copy()
+            .bucket(&state.s3_bucket)
+            .key(&state.s3_key)
+            .upload_id(&state.upload_id)
+            .part_number(part.number)
+            .copy_source(self.header())
+            .copy_source_range(format!("bytes={}-{}", part.offset, part.end() - 1))
+            .set_copy_source_if_match(self.e_tag.clone())
+            .set_request_payer(state.request_payer())
+            .send()
+            .await
+        {
+            Err(error) if error.code() == Some("PreconditionFailed") => bail!(
+                "The source object s3://{}/{} has changed since the copy was started, so the copy can't be continued. Upload ID: {}",
+                self.s3_bucket,
+                self.s3_key,
+                state.upload_id,
+            ),
+            result => result.into_retryable()?,
+        };
+        let Some(copy_part_result) = output.copy_part_result else {
+            bail!("S3 returned no result for copying part {}", part.number);
+        };
+
+        reporter.bytes_transferred(&part, part.size);
+        reporter.part_completed(&part, state.number_of_parts);
+        Ok(CompletedPart::builder()
+            .set_checksum_crc32(copy_part_result.checksum_crc32)
+            .set_checksum_crc32_c(copy_part_result.checksum_crc32_c)
+            .set_checksum_sha1(copy_part_result.checksum_sha1)
+            .set_checksum_sha256(copy_part_result.checksum_sha256)
+            .set_e_tag(copy_part_result.e_tag)
+            .part_number(part.number)
+            .build())
+    }
+}
+
+#[derive(Debug, Args)]
+pub(crate) struct Copy {
+    /// S3 URI of the object to copy, e.g. `s3://source-bucket/path/big.iso`.
+    #[arg(value_name = "SOURCE")]
+    source: S3Uri,
+    /// S3 URI to copy the object to, e.g. `s3://destination-bucket/path/`.
+    ///
+    /// If the URI ends with `/`, the last segment of the key of the source is appended.
+    #[arg(value_name = "DESTINATION")]
+    destination: S3Uri,
+    /// The version of the source object to copy, instead of its latest version.
+    #[arg(long)]
+    source_version_id: Option<String>,
+    /// Path to where the state-file will be saved.
+    ///
+    /// Defaults to a file named after the source and destination in the state directory of
+    /// Persevere.
+    #[arg(long)]
+    state_file: Option<PathBuf>,
+    /// Override the automatically determined part size, e.g. `64MiB` or `1GiB`.
+    #[arg(long, value_parser = size::parse_size)]
+    override_part_size: Option<u64>,
+    /// Confirm that you will be charged for the requests to a requester-pays bucket.
+    #[arg(long, value_parser = PossibleValuesParser::new(RequestPayer::values()))]
+    request_payer: Option<String>,
+    // The content type, metadata, cache control, content disposition and content encoding default
+    // to the ones of the source object.
+    #[command(flatten)]
+    object_options: ObjectOptions,
+    #[command(flatten)]
+    transfer_options: TransferOptions,
+    /// The format to print the result of the copy in.
+    #[arg(long, value_enum, default_value_t)]
+    pub(crate) output: OutputFormat,
+}
+
+impl Copy {
+    pub(crate) async fn run(self) -> Result<()> {
+        let output = self.output;
+        let result = self.transfer().await?;
+        output.print_result(&result);
+        Ok(())
+    }
+
+    async fn transfer(mut self) -> Result<TransferResult> {
+        debug!("Running copy command: {:?}", self);
+        let started = Instant::now();
+        let source_name = self
+            .source
+            .key
+            .rsplit('/')
+            .next()
+            .filter(|name| !name.is_empty());
+        let Some(s3_key) = self.destination.key_for_file(source_name) else {
+            bail!(
+                "The S3 URI {} doesn't include the key to copy the object to",
+                self.destination,
+            );
+        };
+        let s3_bucket = self.destination.bucket.clone();
+        let source = self.source.to_string();
+
+        let state_file = match self.state_file.take() {
+            Some(state_file) => state_file,
+            None => {
+                let state_file =
+                    state_home::default_state_file(&s3_bucket, &s3_key, source.as_ref()).await?;
+                info!("Using state-file: {}", state_file.display());
+                state_file
+            }
+        };
+        let store = StateStore::new(state_file);
+        let _lock = StateLock::acquire(store.file())?;
+        if store.exists().await? {
+            bail!("The state-file already exists, and we don't allow starting a new copy against the same file. If you want to resume the copy, use the 'resume' command instead. If you want to start a new copy, please remove the state-file first, or use a different one.");
+        }
+
+        let config = sdk::load_config().await;
+        let s3 = sdk::s3_client(&config);
+        let request_payer = self.request_payer.as_deref().map(RequestPayer::from);
+        let head = s3
+            .head_object()
+            .bucket(&self.source.bucket)
+            .key(&self.source.key)
+            .set_version_id(self.source_version_id.clone())
+            .set_request_payer(request_payer)
+            .send()
+            .await
+            .with_context(|| format!("Failed to read the source object {}", source))
+            .into_retryable()?;
+        let file_size_in_bytes = head.content_length.unwrap_or_default() as u64;
+        if file_size_in_bytes == 0 {
+            bail!(
+                "The source object {} is empty, there is nothing to copy in parts",
+                source
+            );
+        }
+        if file_size_in_bytes > MAXIMUM_OBJECT_SIZE {
+            bail!(
+                "The source object exceeds the maximum object size of S3 and thus can't be copied"
+            );
+        }
+        let part_size = parts::choose_part_size(file_size_in_bytes, self.override_part_size)?;
+
+        // Unlike the `CopyObject` request, a multipart upload doesn't take over any of the headers
+        // of the source object.
+        let mut object_options = self.object_options;
+        if object_options.metadata.is_empty() {
+            object_options.metadata = head.metadata.unwrap_or_default().into_iter().collect();
+        }
+        object_options.content_type = object_options.content_type.or(head.content_type);
+        object_options.cache_control = object_options.cache_control.or(head.cache_control);
+        object_options.content_disposition = object_options
+            .content_disposition
+            .or(head.content_disposition);
+        object_options.content_encoding = object_options.content_encoding.or(head.content_encoding);
+
+        let mut state = State {
+            version: migration::STATE_VERSION,
+            s3_bucket,
+            s3_key,
+            file_to_upload: PathBuf::from(&source),
+            file_size_in_bytes,
+            part_size,
+            number_of_parts: PartPlan::new(file_size_in_bytes, part_size).number_of_parts(),
+            upload_id: String::new(),
+            last_successful_part: 0,
+            completed_parts: vec![],
+            labels: Default::default(),
+            object_options,
+            headers: vec![],
+            spill_directory: None,
+            request_payer: self.request_payer,
+            checksum_algorithm: None,
+            fingerprint: None,
+            auto_tune: None,
+            aws_profile: Some(sdk::profile_name()),
+            aws_region: config.region().map(ToString::to_string),
+            use_fips_endpoint: Some(config.use_fips().unwrap_or(false)),
+            use_dualstack_endpoint: Some(config.use_dual_stack().unwrap_or(false)),
+            copy_source: Some(CopySource {
+                s3_bucket: self.source.bucket,
+                s3_key: self.source.key,
+                version_id: self.source_version_id.or(head.version_id),
+                e_tag: head.e_tag,
+            }),
+            encryption: None,
+            compression: None,
+            archive: None,
+            split: None,
+            replicas: vec![],
+        };
+
+        let multipart_upload = state
+            .object_options
+            .apply_to(
+                s3.create_multipart_upload()
+                    .bucket(&state.s3_bucket)
+                    .key(&state.s3_key)
+                    .set_request_payer(state.request_payer()),
+            )
+            .send()
+            .await
+            .into_retryable()?;
+        state.upload_id = multipart_upload
+            .upload_id
+            .context("Creating multipart upload probably failed, because no upload ID was returned")
+            .into_retryable()?;
+        info!(
+            "Created multipart upload with ID {} for: s3://{}/{}, copying from {}",
+            state.upload_id, state.s3_bucket, state.s3_key, source,
+        );
+
+        upload_and_record(
+            &s3,
+            "copy",
+            &store,
+            &mut state,
+            &self.transfer_options,
+            started,
+        )
+        .await
+    }
+}
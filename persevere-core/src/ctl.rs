@@ -0,0 +1,309 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    batch::Entry,
+    parse_key_value,
+    result::{
+        bail,
+        AnyhowResultExt,
+        Result,
+    },
+    s3_uri::S3Uri,
+    serve::{
+        Transfer,
+        TransferStatus,
+    },
+    size::format_size,
+};
+use anyhow::Context;
+use clap::{
+    Args,
+    Subcommand,
+};
+use hyper::{
+    header::{
+        AUTHORIZATION,
+        CONTENT_TYPE,
+    },
+    Body,
+    Client,
+    Method,
+    Request,
+    Uri,
+};
+use serde::{
+    de::DeserializeOwned,
+    Deserialize,
+    Serialize,
+};
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+};
+use tracing::debug;
+
+#[derive(Debug, Args)]
+pub(crate) struct Ctl {
+    /// Address the `serve` command listens on, as given to it through `--listen`.
+    #[arg(
+        long,
+        global = true,
+        value_name = "HOST:PORT",
+        default_value = "127.0.0.1:8420"
+    )]
+    server: String,
+    /// Authenticate with the token in the given file, if the `serve` command was started with
+    /// `--token-file`.
+    #[arg(long, global = true, value_name = "FILE")]
+    token_file: Option<PathBuf>,
+    /// Print the transfers as JSON, one document per line.
+    #[arg(long, global = true)]
+    json: bool,
+    #[command(subcommand)]
+    command: CtlCommand,
+}
+
+#[derive(Debug, Subcommand)]
+enum CtlCommand {
+    /// Submit a file to upload.
+    ///
+    /// The file is resolved relative to the current directory, and has to be readable by the
+    /// `serve` command.
+    Submit {
+        /// Path to the file to upload.
+        file: PathBuf,
+        /// Where to upload the file to, e.g. `s3://my-bucket/backups/`, in which case the name of
+        /// the file is appended to the key.
+        destination: S3Uri,
+        /// Label to attach to the upload, in the form `key=value`.
+        #[arg(long = "label", value_name = "KEY=VALUE", value_parser = parse_key_value)]
+        labels: Vec<(String, String)>,
+    },
+    /// List the transfers and their status.
+    List,
+    /// Pause a queued or running transfer, which can be resumed later on.
+    ///
+    /// A running transfer pauses once the part in progress has finished.
+    Pause {
+        /// ID of the transfer.
+        id: u64,
+    },
+    /// Queue a paused or failed transfer again.
+    Resume {
+        /// ID of the transfer.
+        id: u64,
+    },
+    /// Abort a transfer, removing the parts that have been uploaded already from S3.
+    ///
+    /// A running transfer is aborted once the part in progress has finished.
+    #[command(visible_alias = "cancel")]
+    Abort {
+        /// ID of the transfer.
+        id: u64,
+    },
+    /// Show how many transfers there are in every status.
+    Stats,
+}
+
+/// The number of transfers in every status.
+#[derive(Debug, Default, Serialize)]
+struct Stats {
+    queued: u64,
+    running: u64,
+    paused: u64,
+    completed: u64,
+    failed: u64,
+    aborting: u64,
+    aborted: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiError {
+    error: String,
+}
+
+impl Ctl {
+    pub(crate) async fn run(&self) -> Result<()> {
+        debug!("Running ctl command: {:?}", self);
+
+        match &self.command {
+            CtlCommand::Submit {
+                file,
+                destination,
+                labels,
+            } => {
+                let file = std::path::absolute(file)
+                    .context("Failed to resolve the path of the file")
+                    .into_unrecoverable()?;
+                let file_name = file.file_name().and_then(|file_name| file_name.to_str());
+                let Some(s3_key) = destination.key_for_file(file_name) else {
+                    bail!(
+                        "Can't derive the key to upload {} to from {}",
+                        file.display(),
+                        destination,
+                    );
+                };
+                let entry = Entry::new(file, destination.bucket.clone(), s3_key)
+                    .with_labels(labels.iter().cloned().collect::<BTreeMap<_, _>>());
+                let body = serde_json::to_vec(&entry)
+                    .context("Failed to serialize the transfer")
+                    .into_unrecoverable()?;
+                let transfer: Transfer =
+                    self.request(Method::POST, "/transfers", Some(body)).await?;
+                self.print(&transfer)?;
+            }
+            CtlCommand::List => {
+                let transfers: Vec<Transfer> =
+                    self.request(Method::GET, "/transfers", None).await?;
+                for transfer in &transfers {
+                    self.print(transfer)?;
+                }
+            }
+            CtlCommand::Pause { id } => self.act(*id, "pause").await?,
+            CtlCommand::Resume { id } => self.act(*id, "resume").await?,
+            CtlCommand::Abort { id } => self.act(*id, "abort").await?,
+            CtlCommand::Stats => {
+                let transfers: Vec<Transfer> =
+                    self.request(Method::GET, "/transfers", None).await?;
+                let mut stats = Stats::default();
+                for transfer in &transfers {
+                    *match transfer.status {
+                        TransferStatus::Queued => &mut stats.queued,
+                        TransferStatus::Running => &mut stats.running,
+                        TransferStatus::Paused => &mut stats.paused,
+                        TransferStatus::Completed => &mut stats.completed,
+                        TransferStatus::Failed { .. } => &mut stats.failed,
+                        TransferStatus::Aborting => &mut stats.aborting,
+                        TransferStatus::Aborted => &mut stats.aborted,
+                    } += 1;
+                }
+                if self.json {
+                    println!(
+                        "{}",
+                        serde_json::to_string(&stats)
+                            .context("Failed to serialize the statistics")
+                            .into_unrecoverable()?
+                    );
+                } else {
+                    println!(
+                        "{} queued, {} running, {} paused, {} completed, {} failed, {} aborting, {} aborted",
+                        stats.queued,
+                        stats.running,
+                        stats.paused,
+                        stats.completed,
+                        stats.failed,
+                        stats.aborting,
+                        stats.aborted,
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Requests the transfer with the given ID to pause, resume or abort.
+    async fn act(&self, id: u64, action: &str) -> Result<()> {
+        let transfer: Transfer = self
+            .request(Method::POST, &format!("/transfers/{}/{}", id, action), None)
+            .await?;
+        self.print(&transfer)
+    }
+
+    /// Sends a request to the API of the `serve` command, and returns the response.
+    async fn request<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<Vec<u8>>,
+    ) -> Result<T> {
+        let uri: Uri = format!("http://{}{}", self.server, path)
+            .parse()
+            .with_context(|| format!("Invalid server address {}", self.server))
+            .into_unrecoverable()?;
+        let mut request = Request::builder().method(method).uri(uri);
+        if body.is_some() {
+            request = request.header(CONTENT_TYPE, "application/json");
+        }
+        if let Some(token_file) = &self.token_file {
+            let token = std::fs::read_to_string(token_file)
+                .with_context(|| format!("Failed to read the token file {}", token_file.display()))
+                .into_unrecoverable()?;
+            request = request.header(AUTHORIZATION, format!("Bearer {}", token.trim()));
+        }
+        let request = request
+            .body(body.map(Body::from).unwrap_or_else(Body::empty))
+            .context("Failed to build the request")
+            .into_unrecoverable()?;
+
+        let response = Client::new()
+            .request(request)
+            .await
+            .with_context(|| {
+                format!(
+                    "Failed to reach the `serve` command on {}, is it running?",
+                    self.server,
+                )
+            })
+            .into_retryable()?;
+        let status = response.status();
+        let contents = hyper::body::to_bytes(response.into_body())
+            .await
+            .context("Failed to read the response")
+            .into_retryable()?;
+        if !status.is_success() {
+            match serde_json::from_slice::<ApiError>(&contents) {
+                Ok(error) => bail!("{}", error.error),
+                Err(_) => bail!("The server responded with {}", status),
+            }
+        }
+        serde_json::from_slice(&contents)
+            .context("Failed to deserialize the response")
+            .into_unrecoverable()
+    }
+
+    fn print(&self, transfer: &Transfer) -> Result<()> {
+        if self.json {
+            println!(
+                "{}",
+                serde_json::to_string(transfer)
+                    .context("Failed to serialize the transfer")
+                    .into_unrecoverable()?
+            );
+            return Ok(());
+        }
+        println!(
+            "{}  {}  {}  s3://{}/{}",
+            transfer.id,
+            transfer.status.name(),
+            transfer.request.file_to_upload().display(),
+            transfer.request.s3_bucket(),
+            transfer.request.s3_key(),
+        );
+        if let TransferStatus::Failed { error } = &transfer.status {
+            println!("  {}", error);
+        }
+        if let Some(progress) = &transfer.progress {
+            println!(
+                "  {} of {} uploaded",
+                format_size(progress.transferred_bytes),
+                format_size(progress.total_bytes),
+            );
+        }
+        Ok(())
+    }
+}
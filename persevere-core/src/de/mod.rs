@@ -15,3 +15,4 @@
 // SPDX-License-Identifier: Apache-2.0
 
 pub(crate) mod completed_parts;
+pub(crate) mod key_values;
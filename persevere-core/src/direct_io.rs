@@ -0,0 +1,226 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    path::Path,
+    sync::Once,
+};
+use tracing::warn;
+
+/// Warns that direct I/O isn't available, once per process.
+fn warn_unsupported(file: &Path, reason: &str) {
+    static WARNED: Once = Once::new();
+    WARNED.call_once(|| {
+        warn!(
+            "Can't read {} with direct I/O, {}. Reading it through the page cache instead.",
+            file.display(),
+            reason,
+        );
+    });
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) use linux::DirectReader;
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::warn_unsupported;
+    use std::{
+        fs::File,
+        future::Future,
+        io,
+        os::unix::fs::FileExt,
+        path::Path,
+        pin::Pin,
+        task::{
+            ready,
+            Context,
+            Poll,
+        },
+    };
+    use tokio::{
+        io::{
+            AsyncRead,
+            ReadBuf,
+        },
+        task::JoinHandle,
+    };
+
+    /// Alignment of the offsets and buffers of direct reads, which is a multiple of the logical
+    /// block size of common devices.
+    const ALIGNMENT: usize = 4096;
+
+    /// Number of bytes read from the file at once, which is a multiple of [`ALIGNMENT`].
+    const CHUNK_SIZE: usize = 1024 * 1024;
+
+    /// Reads a range of a file opened with `O_DIRECT`, in aligned chunks on the blocking thread
+    /// pool.
+    ///
+    /// Reading huge files through the page cache evicts the pages of everything else on the host,
+    /// although every byte is only read once. Direct reads leave the page cache alone, at the
+    /// expense of offsets and buffers that have to be aligned to the block size of the device.
+    #[derive(Debug)]
+    pub(crate) struct DirectReader {
+        /// The file and the buffer, while no read is in progress.
+        idle: Option<(File, Vec<u8>)>,
+        reading: Option<JoinHandle<io::Result<Chunk>>>,
+        /// Offset of the next chunk to read, which is aligned.
+        position: u64,
+        /// Number of bytes at the start of the next chunk that precede the range.
+        skip: usize,
+        /// Number of bytes of the range that have yet to be returned.
+        remaining: u64,
+        /// The bytes of the last chunk that have yet to be returned, relative to the start of the
+        /// aligned part of the buffer.
+        available: std::ops::Range<usize>,
+    }
+
+    /// The file and the buffer after a read, with the number of bytes read into the buffer.
+    type Chunk = (File, Vec<u8>, usize);
+
+    /// Returns the offset of the aligned chunk within the buffer.
+    fn aligned_start(buffer: &[u8]) -> usize {
+        buffer.as_ptr().align_offset(ALIGNMENT)
+    }
+
+    impl DirectReader {
+        /// Opens `file` for reading `size` bytes starting at `offset`, or returns `None` if the
+        /// file system doesn't support direct I/O.
+        pub(crate) async fn open(file: &Path, offset: u64, size: u64) -> io::Result<Option<Self>> {
+            let opened = tokio::fs::OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_DIRECT)
+                .open(file)
+                .await;
+            let opened = match opened {
+                Ok(opened) => opened.into_std().await,
+                Err(error) if error.raw_os_error() == Some(libc::EINVAL) => {
+                    warn_unsupported(file, "as its file system doesn't support it");
+                    return Ok(None);
+                }
+                Err(error) => return Err(error),
+            };
+            let skip = offset % ALIGNMENT as u64;
+            Ok(Some(Self {
+                idle: Some((opened, vec![0; CHUNK_SIZE + ALIGNMENT])),
+                reading: None,
+                position: offset - skip,
+                skip: skip as usize,
+                remaining: size,
+                available: 0..0,
+            }))
+        }
+    }
+
+    impl AsyncRead for DirectReader {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let this = &mut *self;
+            loop {
+                if !this.available.is_empty() {
+                    let Some((_, buffer)) = &this.idle else {
+                        unreachable!("bytes are only available while no read is in progress");
+                    };
+                    let length = this
+                        .available
+                        .len()
+                        .min(buf.remaining())
+                        .min(this.remaining.try_into().unwrap_or(usize::MAX));
+                    let start = aligned_start(buffer) + this.available.start;
+                    buf.put_slice(&buffer[start..start + length]);
+                    this.available.start += length;
+                    this.remaining -= length as u64;
+                    return Poll::Ready(Ok(()));
+                }
+                if this.remaining == 0 {
+                    return Poll::Ready(Ok(()));
+                }
+
+                let reading = this.reading.get_or_insert_with(|| {
+                    let (file, mut buffer) = this.idle.take().expect("no read in progress");
+                    let position = this.position;
+                    tokio::task::spawn_blocking(move || {
+                        let start = aligned_start(&buffer);
+                        let read =
+                            file.read_at(&mut buffer[start..start + CHUNK_SIZE], position)?;
+                        Ok((file, buffer, read))
+                    })
+                });
+                let result = ready!(Pin::new(reading).poll(cx));
+                this.reading = None;
+                let (file, buffer, read) = result.map_err(io::Error::other)??;
+                this.idle = Some((file, buffer));
+                if read == 0 {
+                    // The file is shorter than expected, which the reader of the part detects.
+                    this.remaining = 0;
+                    return Poll::Ready(Ok(()));
+                }
+                this.position += read as u64;
+                this.available = this.skip.min(read)..read;
+                this.skip = 0;
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) use other::DirectReader;
+
+#[cfg(not(target_os = "linux"))]
+mod other {
+    use super::warn_unsupported;
+    use std::{
+        io,
+        path::Path,
+        pin::Pin,
+        task::{
+            Context,
+            Poll,
+        },
+    };
+    use tokio::io::{
+        AsyncRead,
+        ReadBuf,
+    };
+
+    /// Never constructed, as direct I/O is only supported on Linux.
+    #[derive(Debug)]
+    pub(crate) enum DirectReader {}
+
+    impl DirectReader {
+        pub(crate) async fn open(
+            file: &Path,
+            _offset: u64,
+            _size: u64,
+        ) -> io::Result<Option<Self>> {
+            warn_unsupported(file, "as it is only supported on Linux");
+            Ok(None)
+        }
+    }
+
+    impl AsyncRead for DirectReader {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            match *self {}
+        }
+    }
+}
@@ -0,0 +1,618 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    checksum,
+    result::{
+        bail,
+        AnyhowResultExt,
+        Result,
+        StdResultExt,
+    },
+    s3_uri::S3Uri,
+    sdk,
+    size::format_size,
+    verbosity,
+};
+use anyhow::Context;
+use aws_sdk_s3::types::RequestPayer;
+use aws_smithy_types::base64;
+use clap::{
+    builder::PossibleValuesParser,
+    Args,
+};
+use ring::{
+    aead::{
+        Aad,
+        LessSafeKey,
+        Nonce,
+        UnboundKey,
+        AES_256_GCM,
+        NONCE_LEN,
+    },
+    rand::{
+        SecureRandom,
+        SystemRandom,
+    },
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::{
+    collections::HashMap,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+use tokio::io::{
+    AsyncRead,
+    AsyncReadExt,
+    AsyncWrite,
+    AsyncWriteExt,
+    BufReader,
+    BufWriter,
+};
+use tokio_util::bytes::Bytes;
+use tracing::{
+    debug,
+    info,
+};
+
+/// Length of the random prefix of the nonces, which are completed by the 4-byte part number.
+const NONCE_PREFIX_LEN: usize = NONCE_LEN - 4;
+
+/// Length of the authentication tag appended to the ciphertext of every part.
+pub(crate) const TAG_LEN: u64 = 16;
+
+/// Prefix of the object metadata that describes how the object was encrypted, which is required to
+/// decrypt it.
+const METADATA_PREFIX: &str = "persevere-encryption";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub(crate) enum EncryptionAlgorithm {
+    #[serde(rename = "aes-256-gcm")]
+    Aes256Gcm,
+}
+
+/// What the ciphertext of every part is authenticated with, besides the key and the nonce.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum AssociatedData {
+    /// Nothing, as for uploads started by previous versions of Persevere.
+    #[default]
+    None,
+    /// The big-endian, 1-based part number followed by a byte that is 1 for the last part and 0
+    /// for all others: parts that are reordered, duplicated or missing from the end of the object
+    /// fail to decrypt.
+    PartNumberAndLastFlag,
+}
+
+impl AssociatedData {
+    fn name(self) -> &'static str {
+        match self {
+            AssociatedData::None => "none",
+            AssociatedData::PartNumberAndLastFlag => "part-number-and-last-flag",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        [AssociatedData::None, AssociatedData::PartNumberAndLastFlag]
+            .into_iter()
+            .find(|associated_data| associated_data.name() == name)
+    }
+
+    fn of_part(self, number: i32, last: bool) -> Aad<Vec<u8>> {
+        match self {
+            AssociatedData::None => Aad::from(vec![]),
+            AssociatedData::PartNumberAndLastFlag => {
+                let mut data = (number as u32).to_be_bytes().to_vec();
+                data.push(u8::from(last));
+                Aad::from(data)
+            }
+        }
+    }
+}
+
+/// Client-side encryption of the parts of an upload.
+///
+/// Every part is encrypted on its own with AES-256-GCM, so that it can be retried and resumed
+/// independently of the other parts. The nonce of a part is made up of a random prefix, chosen
+/// once per upload, and the part number: retrying a part thus produces the same ciphertext, while
+/// no two parts share a nonce. The object consists of the ciphertext of every part followed by its
+/// authentication tag.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct Encryption {
+    algorithm: EncryptionAlgorithm,
+    /// What the parts are authenticated with, which is nothing for uploads started by previous
+    /// versions of Persevere.
+    #[serde(default)]
+    associated_data: AssociatedData,
+    /// Path to the file holding the key, which is never stored in the state-file.
+    pub(crate) key_file: PathBuf,
+    /// Identifies the key without revealing it: the first 16 hex-digits of its SHA-256 digest.
+    key_id: String,
+    /// The base64-encoded random prefix of the nonces.
+    nonce_prefix: String,
+}
+
+impl Encryption {
+    /// Starts encrypting a new upload with the key in the given file.
+    pub(crate) fn new(key_file: &Path) -> Result<Self> {
+        let key = read_key(key_file)?;
+        let mut nonce_prefix = [0; NONCE_PREFIX_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce_prefix)
+            .map_err(|_| anyhow::anyhow!("Failed to generate a random nonce"))
+            .into_unrecoverable()?;
+        Ok(Self {
+            algorithm: EncryptionAlgorithm::Aes256Gcm,
+            associated_data: AssociatedData::PartNumberAndLastFlag,
+            key_file: key_file
+                .canonicalize()
+                .context("Failed to canonicalize the path of the key file")
+                .into_unrecoverable()?,
+            key_id: key_id(&key),
+            nonce_prefix: base64::encode(nonce_prefix),
+        })
+    }
+
+    /// Reads the key, verifying that it is the key the upload was started with.
+    pub(crate) fn cipher(&self) -> Result<Cipher> {
+        let key = read_key(&self.key_file)?;
+        if key_id(&key) != self.key_id {
+            bail!(
+                "The key in {} is not the key the upload was started with (key ID {})",
+                self.key_file.display(),
+                self.key_id,
+            );
+        }
+        Cipher::new(&key, &self.nonce_prefix, self.associated_data)
+    }
+
+    /// The object metadata that describes how the object is encrypted.
+    ///
+    /// Together with the key, this is everything required to decrypt the object: it consists of
+    /// chunks of `part-size` bytes of plaintext plus the tag, each encrypted with the nonce prefix
+    /// followed by the big-endian, 1-based number of the chunk, and authenticated with the
+    /// associated data.
+    pub(crate) fn metadata(&self, part_size: u64) -> Vec<(String, String)> {
+        let algorithm = match self.algorithm {
+            EncryptionAlgorithm::Aes256Gcm => "aes-256-gcm",
+        };
+        vec![
+            (METADATA_PREFIX.to_owned(), algorithm.to_owned()),
+            (format!("{METADATA_PREFIX}-key-id"), self.key_id.clone()),
+            (
+                format!("{METADATA_PREFIX}-nonce-prefix"),
+                self.nonce_prefix.clone(),
+            ),
+            (
+                format!("{METADATA_PREFIX}-part-size"),
+                part_size.to_string(),
+            ),
+            (
+                format!("{METADATA_PREFIX}-associated-data"),
+                self.associated_data.name().to_owned(),
+            ),
+        ]
+    }
+}
+
+/// Encrypts the parts of an upload with the key read from the key file.
+pub(crate) struct Cipher {
+    key: LessSafeKey,
+    nonce_prefix: [u8; NONCE_PREFIX_LEN],
+    associated_data: AssociatedData,
+}
+
+impl Cipher {
+    /// Creates the cipher for the given key and base64-encoded nonce prefix.
+    fn new(key: &[u8], nonce_prefix: &str, associated_data: AssociatedData) -> Result<Self> {
+        let Ok(nonce_prefix) = base64::decode(nonce_prefix).unwrap_or_default().try_into() else {
+            bail!("The nonce prefix {:?} is invalid", nonce_prefix);
+        };
+        let key = UnboundKey::new(&AES_256_GCM, key)
+            .map_err(|_| anyhow::anyhow!("Invalid key"))
+            .into_unrecoverable()?;
+        Ok(Cipher {
+            key: LessSafeKey::new(key),
+            nonce_prefix,
+            associated_data,
+        })
+    }
+
+    fn nonce(&self, number: i32) -> Nonce {
+        let mut nonce = [0; NONCE_LEN];
+        nonce[..NONCE_PREFIX_LEN].copy_from_slice(&self.nonce_prefix);
+        nonce[NONCE_PREFIX_LEN..].copy_from_slice(&(number as u32).to_be_bytes());
+        Nonce::assume_unique_for_key(nonce)
+    }
+
+    /// Encrypts the plaintext of a part, returning the ciphertext followed by the tag.
+    ///
+    /// Whether the part is the last one of the object is authenticated along with its number. The
+    /// plaintext is encrypted in place, unless it is shared, so that a part is only held in memory
+    /// once: its buffer should have room for the tag, as the ones read by `read_exactly` do.
+    pub(crate) fn encrypt_part(&self, number: i32, last: bool, plaintext: Bytes) -> Result<Bytes> {
+        let mut in_out = Vec::from(plaintext);
+        self.key
+            .seal_in_place_append_tag(
+                self.nonce(number),
+                self.associated_data.of_part(number, last),
+                &mut in_out,
+            )
+            .map_err(|_| anyhow::anyhow!("Failed to encrypt part {}", number))
+            .into_unrecoverable()?;
+        Ok(in_out.into())
+    }
+
+    /// Decrypts the ciphertext of a part followed by its tag in place, returning the plaintext.
+    fn decrypt_part<'a>(
+        &self,
+        number: i32,
+        last: bool,
+        in_out: &'a mut [u8],
+    ) -> Result<&'a mut [u8]> {
+        match self.key.open_in_place(
+            self.nonce(number),
+            self.associated_data.of_part(number, last),
+            in_out,
+        ) {
+            Ok(plaintext) => Ok(plaintext),
+            Err(_) => bail!(
+                "Failed to decrypt part {}: the object is corrupt, incomplete or was encrypted with another key",
+                number,
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+pub(crate) struct Decrypt {
+    /// The encrypted object, downloaded from S3, e.g. with `aws s3 cp`.
+    #[arg(long)]
+    file: PathBuf,
+    /// The file holding the key the object was encrypted with.
+    #[arg(long, value_name = "KEY_FILE")]
+    key_file: PathBuf,
+    /// Where to write the decrypted contents to.
+    #[arg(long)]
+    output: PathBuf,
+    /// S3 URI of the object, e.g. `s3://my-bucket/path/big.iso`, whose metadata describes how it
+    /// was encrypted.
+    #[arg(value_name = "OBJECT")]
+    object: S3Uri,
+    /// The version of the object the file was downloaded from, instead of its latest version.
+    #[arg(long)]
+    version_id: Option<String>,
+    /// Confirm that you will be charged for the requests to a requester-pays bucket.
+    #[arg(long, value_parser = PossibleValuesParser::new(RequestPayer::values()))]
+    request_payer: Option<String>,
+}
+
+/// How an object was encrypted, as described by its metadata.
+#[derive(Debug)]
+struct ObjectEncryption {
+    key_id: String,
+    nonce_prefix: String,
+    part_size: u64,
+    associated_data: AssociatedData,
+}
+
+impl ObjectEncryption {
+    fn from_metadata(metadata: &HashMap<String, String>) -> Result<Self> {
+        let Some(algorithm) = metadata.get(METADATA_PREFIX) else {
+            bail!(
+                "The object was not encrypted by Persevere, it has no `{}` metadata",
+                METADATA_PREFIX,
+            );
+        };
+        let value = |name: &str| {
+            let key = format!("{METADATA_PREFIX}-{name}");
+            match metadata.get(&key) {
+                Some(value) => Ok(value.clone()),
+                None => bail!("The object has no `{}` metadata", key),
+            }
+        };
+        if algorithm != "aes-256-gcm" {
+            bail!(
+                "The object is encrypted with {}, which is not supported",
+                algorithm
+            );
+        }
+        let part_size = value("part-size")?;
+        let Ok(part_size) = part_size.parse() else {
+            bail!(
+                "The part size {:?} in the metadata of the object is invalid",
+                part_size
+            );
+        };
+        // Objects uploaded by previous versions of Persevere don't authenticate any data.
+        let associated_data = match metadata.get(&format!("{METADATA_PREFIX}-associated-data")) {
+            None => AssociatedData::None,
+            Some(name) => match AssociatedData::from_name(name) {
+                Some(associated_data) => associated_data,
+                None => bail!(
+                    "The object authenticates {:?}, which is not supported",
+                    name
+                ),
+            },
+        };
+        Ok(Self {
+            key_id: value("key-id")?,
+            nonce_prefix: value("nonce-prefix")?,
+            part_size,
+            associated_data,
+        })
+    }
+}
+
+impl Decrypt {
+    pub(crate) async fn run(&self) -> Result<()> {
+        debug!("Running decrypt command: {:?}", self);
+        let config = sdk::load_config().await;
+        let s3 = sdk::s3_client(&config);
+
+        let head = s3
+            .head_object()
+            .bucket(&self.object.bucket)
+            .key(&self.object.key)
+            .set_version_id(self.version_id.clone())
+            .set_request_payer(self.request_payer.as_deref().map(RequestPayer::from))
+            .send()
+            .await
+            .with_context(|| format!("Failed to read the metadata of {}", self.object))
+            .into_retryable()?;
+        let encryption =
+            ObjectEncryption::from_metadata(head.metadata().unwrap_or(&HashMap::new()))
+                .with_context(|| format!("Can't decrypt {}", self.object))
+                .into_unrecoverable()?;
+        let key = read_key(&self.key_file)?;
+        if key_id(&key) != encryption.key_id {
+            bail!(
+                "The key in {} is not the key {} was encrypted with (key ID {})",
+                self.key_file.display(),
+                self.object,
+                encryption.key_id,
+            );
+        }
+        let cipher = Cipher::new(&key, &encryption.nonce_prefix, encryption.associated_data)?;
+
+        let input = tokio::fs::File::open(&self.file)
+            .await
+            .with_context(|| format!("Failed to open {}", self.file.display()))
+            .into_unrecoverable()?;
+        let size = input.metadata().await.into_unrecoverable()?.len();
+        let object_size = head.content_length().unwrap_or_default() as u64;
+        if size != object_size {
+            bail!(
+                "The size of {} ({}) doesn't match the size of {} ({}), download the object completely first",
+                self.file.display(),
+                format_size(size),
+                self.object,
+                format_size(object_size),
+            );
+        }
+        let output = tokio::fs::File::create(&self.output)
+            .await
+            .with_context(|| format!("Failed to create {}", self.output.display()))
+            .into_unrecoverable()?;
+        let mut output = BufWriter::new(output);
+        let result = async {
+            let plaintext_size = decrypt(
+                &cipher,
+                encryption.part_size,
+                size,
+                BufReader::new(input),
+                &mut output,
+            )
+            .await?;
+            output.flush().await.into_unrecoverable()?;
+            output.get_ref().sync_all().await.into_unrecoverable()?;
+            Ok(plaintext_size)
+        }
+        .await;
+        let plaintext_size = match result {
+            Ok(plaintext_size) => plaintext_size,
+            Err(error) => {
+                // The decrypted contents can't be trusted unless all of them were authenticated.
+                let _ = tokio::fs::remove_file(&self.output).await;
+                return Err(error);
+            }
+        };
+
+        info!(target: verbosity::SUMMARY,
+            "Decrypted {} into {} ({})",
+            self.file.display(),
+            self.output.display(),
+            format_size(plaintext_size),
+        );
+        Ok(())
+    }
+}
+
+/// Decrypts an object of `size` bytes read from `input`, which was encrypted in parts of
+/// `part_size` bytes of plaintext, writing the plaintext to `output`.
+///
+/// Returns the size of the plaintext.
+async fn decrypt(
+    cipher: &Cipher,
+    part_size: u64,
+    size: u64,
+    mut input: impl AsyncRead + Unpin,
+    mut output: impl AsyncWrite + Unpin,
+) -> Result<u64> {
+    let chunk_size = part_size + TAG_LEN;
+    let number_of_parts = size.div_ceil(chunk_size).max(1);
+    if number_of_parts > i32::MAX as u64 {
+        bail!(
+            "The object is too large for a part size of {}",
+            format_size(part_size)
+        );
+    }
+    let mut buffer = Vec::with_capacity(chunk_size.min(size) as usize);
+    let mut plaintext_size = 0;
+    for number in 1..=number_of_parts {
+        buffer.resize(
+            (size - (number - 1) * chunk_size).min(chunk_size) as usize,
+            0,
+        );
+        input
+            .read_exact(&mut buffer)
+            .await
+            .context("Failed to read the encrypted object")
+            .into_unrecoverable()?;
+        let plaintext =
+            cipher.decrypt_part(number as i32, number == number_of_parts, &mut buffer)?;
+        output.write_all(plaintext).await.into_unrecoverable()?;
+        plaintext_size += plaintext.len() as u64;
+    }
+    Ok(plaintext_size)
+}
+
+/// Reads a 256-bit key from the file, either as 32 raw bytes or as 64 hex-digits, e.g. as created
+/// by `openssl rand -hex 32`.
+fn read_key(key_file: &Path) -> Result<Vec<u8>> {
+    let contents = std::fs::read(key_file)
+        .with_context(|| format!("Failed to read the key file {}", key_file.display()))
+        .into_unrecoverable()?;
+    if contents.len() == 32 {
+        return Ok(contents);
+    }
+    let hex = String::from_utf8_lossy(&contents);
+    let hex = hex.trim();
+    let key: Option<Vec<u8>> = (hex.len() == 64 && hex.is_ascii())
+        .then(|| {
+            (0..hex.len())
+                .step_by(2)
+                .map(|index| u8::from_str_radix(&hex[index..index + 2], 16).ok())
+                .collect()
+        })
+        .flatten();
+    match key {
+        Some(key) => Ok(key),
+        None => bail!(
+            "The key file {} must hold a 256-bit key, either as 32 raw bytes or as 64 hex-digits",
+            key_file.display(),
+        ),
+    }
+}
+
+fn key_id(key: &[u8]) -> String {
+    checksum::sha256_hex(key)[..16].to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cipher() -> Cipher {
+        Cipher::new(
+            &[7; 32],
+            &base64::encode([1; NONCE_PREFIX_LEN]),
+            AssociatedData::PartNumberAndLastFlag,
+        )
+        .unwrap()
+    }
+
+    /// Encrypts the plaintext in parts of `part_size` bytes, the way an upload does.
+    fn encrypt(cipher: &Cipher, plaintext: &[u8], part_size: usize) -> Vec<u8> {
+        let parts: Vec<_> = plaintext.chunks(part_size).collect();
+        let parts = if parts.is_empty() {
+            vec![&[][..]]
+        } else {
+            parts
+        };
+        parts
+            .iter()
+            .enumerate()
+            .flat_map(|(index, part)| {
+                cipher
+                    .encrypt_part(
+                        index as i32 + 1,
+                        index + 1 == parts.len(),
+                        Bytes::copy_from_slice(part),
+                    )
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    async fn decrypt_all(cipher: &Cipher, ciphertext: &[u8], part_size: usize) -> Result<Vec<u8>> {
+        let mut plaintext = vec![];
+        let size = decrypt(
+            cipher,
+            part_size as u64,
+            ciphertext.len() as u64,
+            ciphertext,
+            &mut plaintext,
+        )
+        .await?;
+        assert_eq!(size, plaintext.len() as u64);
+        Ok(plaintext)
+    }
+
+    async fn assert_round_trip(size: usize, part_size: usize) {
+        let cipher = cipher();
+        let plaintext: Vec<u8> = (0..size).map(|index| index as u8).collect();
+        let ciphertext = encrypt(&cipher, &plaintext, part_size);
+        assert_eq!(
+            ciphertext.len(),
+            size + size.div_ceil(part_size).max(1) * TAG_LEN as usize,
+        );
+        assert_eq!(
+            decrypt_all(&cipher, &ciphertext, part_size).await.unwrap(),
+            plaintext,
+        );
+    }
+
+    #[tokio::test]
+    async fn round_trip_single_part() {
+        assert_round_trip(0, 100).await;
+        assert_round_trip(42, 100).await;
+        assert_round_trip(100, 100).await;
+    }
+
+    #[tokio::test]
+    async fn round_trip_several_parts() {
+        assert_round_trip(300, 100).await;
+    }
+
+    #[tokio::test]
+    async fn round_trip_shorter_last_part() {
+        assert_round_trip(301, 100).await;
+        assert_round_trip(399, 100).await;
+    }
+
+    #[tokio::test]
+    async fn reordered_or_truncated_parts_fail_to_decrypt() {
+        let cipher = cipher();
+        let plaintext = vec![42; 300];
+        let ciphertext = encrypt(&cipher, &plaintext, 100);
+        let chunk_size = 100 + TAG_LEN as usize;
+
+        // Dropping the last part leaves an object whose new last part is not flagged as such.
+        let truncated = &ciphertext[..2 * chunk_size];
+        assert!(decrypt_all(&cipher, truncated, 100).await.is_err());
+
+        let mut reordered = ciphertext[chunk_size..2 * chunk_size].to_vec();
+        reordered.extend_from_slice(&ciphertext[..chunk_size]);
+        reordered.extend_from_slice(&ciphertext[2 * chunk_size..]);
+        assert!(decrypt_all(&cipher, &reordered, 100).await.is_err());
+    }
+}
@@ -0,0 +1,118 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    checksum::{
+        ChecksumAlgorithm,
+        ChecksumReader,
+        Hasher,
+    },
+    consts::MiB,
+    parts::PartPlan,
+    result::{
+        Result,
+        StdResultExt,
+    },
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::{
+    path::Path,
+    time::{
+        Duration,
+        Instant,
+        SystemTime,
+    },
+};
+use tokio::io::{
+    AsyncReadExt,
+    AsyncSeekExt,
+};
+use tracing::debug;
+
+/// Number of bytes sampled at the start and at the end of every part.
+const SAMPLE_SIZE: u64 = MiB;
+
+/// Identifies the contents of a file cheaply, to detect modifications of the file between the start
+/// of an upload and resuming it.
+///
+/// Comparing the size of the file alone misses modifications that keep the size intact, which
+/// would silently result in a corrupt object. Hashing the entire file on every resume on the other
+/// hand would take too long for the files Persevere is meant for, which is why only the start and
+/// the end of every part are sampled, alongside the modification time of the file.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct Fingerprint {
+    /// Modification time of the file as duration since the Unix epoch, if the platform supports it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    modified: Option<Duration>,
+    /// CRC32C checksum over the sampled bytes of all parts.
+    samples: String,
+}
+
+impl Fingerprint {
+    /// Calculates the fingerprint of the file, sampling every part of the given plan.
+    pub(crate) async fn of(file: &Path, plan: PartPlan) -> Result<Self> {
+        let started = Instant::now();
+        let mut file = tokio::fs::File::open(file).await.into_unrecoverable()?;
+        let modified = file
+            .metadata()
+            .await
+            .into_unrecoverable()?
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(SystemTime::UNIX_EPOCH).ok());
+
+        let hasher = Hasher::new(ChecksumAlgorithm::Crc32c);
+        for part in plan.parts_from(1) {
+            let head = part.size.min(SAMPLE_SIZE);
+            let tail_start = (part.offset + head).max(part.end().saturating_sub(SAMPLE_SIZE));
+            for (start, size) in [(part.offset, head), (tail_start, part.end() - tail_start)] {
+                if size == 0 {
+                    continue;
+                }
+                file.seek(tokio::io::SeekFrom::Start(start))
+                    .await
+                    .into_unrecoverable()?;
+                let mut reader = ChecksumReader::new((&mut file).take(size), vec![hasher.clone()]);
+                tokio::io::copy(&mut reader, &mut tokio::io::sink())
+                    .await
+                    .into_unrecoverable()?;
+            }
+        }
+        debug!(
+            "Calculated the fingerprint of the file in {:.1}s",
+            started.elapsed().as_secs_f64(),
+        );
+
+        Ok(Self {
+            modified,
+            samples: hasher.checksum(),
+        })
+    }
+
+    /// Describes how this fingerprint differs from the fingerprint of the file at a later point.
+    pub(crate) fn difference(&self, current: &Fingerprint) -> Option<&'static str> {
+        if self.samples != current.samples {
+            Some("the contents of the file have changed")
+        } else if self.modified != current.modified {
+            Some("the modification time of the file has changed")
+        } else {
+            None
+        }
+    }
+}
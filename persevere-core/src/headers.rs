@@ -0,0 +1,142 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::sdk;
+use aws_config::SdkConfig;
+use aws_sdk_s3::{
+    config::{
+        interceptors::BeforeTransmitInterceptorContextMut,
+        ConfigBag,
+        Intercept,
+        RuntimeComponents,
+    },
+    error::BoxError,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+/// Headers that are managed by the SDK itself, mostly as part of signing the request, and thus must
+/// not be overridden.
+const RESERVED_HEADERS: &[&str] = &[
+    "x-amz-content-sha256",
+    "x-amz-date",
+    "x-amz-decoded-content-length",
+    "x-amz-security-token",
+    "x-amz-sdk-checksum-algorithm",
+    "x-amz-trailer",
+    "x-amz-user-agent",
+];
+
+/// An additional HTTP header that is sent with every S3 request of a transfer.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct Header {
+    pub(crate) name: String,
+    pub(crate) value: String,
+}
+
+/// Parses a header in the form `name: value`, as it is provided on the command line.
+///
+/// Only extension headers, i.e. headers starting with `x-`, are allowed, excluding headers that the
+/// SDK manages itself.
+pub(crate) fn parse_header(header: &str) -> Result<Header, String> {
+    let Some((name, value)) = header.split_once(':') else {
+        return Err(format!("expected `name: value`, got `{}`", header));
+    };
+    let name = name.trim().to_ascii_lowercase();
+    let value = value.trim();
+
+    if !name.starts_with("x-")
+        || !name
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-')
+    {
+        return Err(format!(
+            "only extension headers consisting of alphanumeric characters and dashes and starting with `x-` are allowed, got `{}`",
+            name,
+        ));
+    }
+    if RESERVED_HEADERS.contains(&name.as_str()) || name.starts_with("x-amz-checksum-") {
+        return Err(format!(
+            "the header `{}` is managed by Persevere and can't be overridden",
+            name,
+        ));
+    }
+    if !value.bytes().all(|byte| (b' '..=b'~').contains(&byte)) {
+        return Err(format!(
+            "the value of header `{}` must only contain printable ASCII characters",
+            name,
+        ));
+    }
+
+    Ok(Header {
+        name,
+        value: value.to_owned(),
+    })
+}
+
+/// Adds the configured headers to every request before it is signed.
+#[derive(Debug)]
+struct HeaderInterceptor(Vec<Header>);
+
+impl Intercept for HeaderInterceptor {
+    fn name(&self) -> &'static str {
+        "HeaderInterceptor"
+    }
+
+    fn modify_before_signing(
+        &self,
+        context: &mut BeforeTransmitInterceptorContextMut<'_>,
+        _runtime_components: &RuntimeComponents,
+        _cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        let headers = context.request_mut().headers_mut();
+        for header in &self.0 {
+            headers.insert(header.name.clone(), header.value.clone());
+        }
+        Ok(())
+    }
+}
+
+/// Whether the headers encrypt the data with a key provided by the customer (SSE-C), in which case
+/// the ETags S3 returns aren't the MD5 digests of the data.
+pub(crate) fn encrypt_with_customer_key(headers: &[Header]) -> bool {
+    headers
+        .iter()
+        .any(|header| header.name == "x-amz-server-side-encryption-customer-algorithm")
+}
+
+/// Creates an S3 client that sends the given headers with every request.
+pub(crate) fn s3_client(config: &SdkConfig, headers: &[Header]) -> aws_sdk_s3::Client {
+    let mut s3_config = sdk::s3_config(config);
+    if !headers.is_empty() {
+        s3_config = s3_config.interceptor(HeaderInterceptor(headers.to_vec()));
+    }
+    aws_sdk_s3::Client::from_conf(s3_config.build())
+}
+
+/// Returns the given S3 client, extended to send the given headers with every request.
+pub(crate) fn with_headers(s3: &aws_sdk_s3::Client, headers: &[Header]) -> aws_sdk_s3::Client {
+    if headers.is_empty() {
+        return s3.clone();
+    }
+    let s3_config = s3
+        .config()
+        .to_builder()
+        .interceptor(HeaderInterceptor(headers.to_vec()));
+    aws_sdk_s3::Client::from_conf(s3_config.build())
+}
@@ -0,0 +1,295 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::result::Error;
+use aws_sdk_s3::{
+    config::http::HttpResponse,
+    error::{
+        ErrorMetadata,
+        ProvideErrorMetadata,
+        SdkError,
+    },
+    operation::{
+        abort_multipart_upload::AbortMultipartUploadError,
+        complete_multipart_upload::CompleteMultipartUploadError,
+        create_multipart_upload::CreateMultipartUploadError,
+        get_object::GetObjectError,
+        get_object_attributes::GetObjectAttributesError,
+        head_object::HeadObjectError,
+        list_multipart_uploads::ListMultipartUploadsError,
+        list_parts::ListPartsError,
+        put_object::PutObjectError,
+        restore_object::RestoreObjectError,
+        upload_part::UploadPartError,
+        upload_part_copy::UploadPartCopyError,
+    },
+    types::error::{
+        NoSuchBucket,
+        NoSuchKey,
+        NoSuchUpload,
+    },
+};
+
+/// The S3 operations Persevere performs, used to tailor hints to the action that failed.
+#[derive(Clone, Copy, Debug)]
+enum Operation {
+    CreateMultipartUpload,
+    UploadPart,
+    CompleteMultipartUpload,
+    AbortMultipartUpload,
+    ListParts,
+    ListMultipartUploads,
+    PutObject,
+    UploadPartCopy,
+    HeadObject,
+    GetObject,
+    GetObjectAttributes,
+    RestoreObject,
+}
+
+impl Operation {
+    fn of(error: &(dyn std::error::Error + 'static)) -> Option<Self> {
+        if error.is::<CreateMultipartUploadError>() {
+            Some(Operation::CreateMultipartUpload)
+        } else if error.is::<UploadPartError>() {
+            Some(Operation::UploadPart)
+        } else if error.is::<CompleteMultipartUploadError>() {
+            Some(Operation::CompleteMultipartUpload)
+        } else if error.is::<AbortMultipartUploadError>() {
+            Some(Operation::AbortMultipartUpload)
+        } else if error.is::<ListPartsError>() {
+            Some(Operation::ListParts)
+        } else if error.is::<ListMultipartUploadsError>() {
+            Some(Operation::ListMultipartUploads)
+        } else if error.is::<PutObjectError>() {
+            Some(Operation::PutObject)
+        } else if error.is::<UploadPartCopyError>() {
+            Some(Operation::UploadPartCopy)
+        } else if error.is::<HeadObjectError>() {
+            Some(Operation::HeadObject)
+        } else if error.is::<GetObjectError>() {
+            Some(Operation::GetObject)
+        } else if error.is::<GetObjectAttributesError>() {
+            Some(Operation::GetObjectAttributes)
+        } else if error.is::<RestoreObjectError>() {
+            Some(Operation::RestoreObject)
+        } else {
+            None
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Operation::CreateMultipartUpload => "CreateMultipartUpload",
+            Operation::UploadPart => "UploadPart",
+            Operation::CompleteMultipartUpload => "CompleteMultipartUpload",
+            Operation::AbortMultipartUpload => "AbortMultipartUpload",
+            Operation::ListParts => "ListParts",
+            Operation::ListMultipartUploads => "ListMultipartUploads",
+            Operation::PutObject => "PutObject",
+            Operation::UploadPartCopy => "UploadPartCopy",
+            Operation::HeadObject => "HeadObject",
+            Operation::GetObject => "GetObject",
+            Operation::GetObjectAttributes => "GetObjectAttributes",
+            Operation::RestoreObject => "RestoreObject",
+        }
+    }
+
+    /// The IAM action that has to be allowed for this operation to succeed.
+    ///
+    /// `UploadPartCopy` additionally requires `s3:PutObject` on the destination, which is covered
+    /// by `CreateMultipartUpload` failing first if it is missing.
+    fn iam_action(&self) -> &'static str {
+        match self {
+            Operation::CreateMultipartUpload
+            | Operation::UploadPart
+            | Operation::CompleteMultipartUpload
+            | Operation::PutObject => "s3:PutObject",
+            Operation::AbortMultipartUpload => "s3:AbortMultipartUpload",
+            Operation::ListParts => "s3:ListMultipartUploadParts",
+            Operation::ListMultipartUploads => "s3:ListBucketMultipartUploads",
+            // `HeadObject` is authorized through `s3:GetObject` as well.
+            Operation::UploadPartCopy | Operation::HeadObject | Operation::GetObject => {
+                "s3:GetObject"
+            }
+            Operation::GetObjectAttributes => "s3:GetObjectAttributes",
+            Operation::RestoreObject => "s3:RestoreObject",
+        }
+    }
+
+    /// Whether the operation is part of uploading an object.
+    fn is_upload(&self) -> bool {
+        matches!(
+            self,
+            Operation::CreateMultipartUpload
+                | Operation::UploadPart
+                | Operation::CompleteMultipartUpload
+                | Operation::AbortMultipartUpload
+                | Operation::ListParts
+                | Operation::PutObject
+        )
+    }
+
+    /// The resource the IAM action has to be allowed on.
+    fn resource(&self) -> &'static str {
+        match self {
+            _ if self.is_upload() => "the S3-object ARN you are uploading to",
+            Operation::ListMultipartUploads => "the ARN of the bucket",
+            Operation::UploadPartCopy => "the S3-object ARN you are copying from",
+            _ => "the S3-object ARN",
+        }
+    }
+}
+
+/// Returns the error code and message of the first S3 service error found in the error chain.
+fn service_error(error: &anyhow::Error) -> Option<(Option<Operation>, &str, &str)> {
+    let mut operation = None;
+    for cause in error.chain() {
+        operation = operation.or_else(|| Operation::of(cause));
+
+        // Responses to `HeadObject` have no body, so the status is all there is to go by.
+        if let Some(SdkError::ServiceError(error)) =
+            cause.downcast_ref::<SdkError<HeadObjectError, HttpResponse>>()
+        {
+            if error.err().code().is_none() && error.raw().status().as_u16() == 403 {
+                return Some((Some(Operation::HeadObject), "AccessDenied", ""));
+            }
+        }
+
+        let metadata = if let Some(metadata) = cause.downcast_ref::<ErrorMetadata>() {
+            metadata.meta()
+        } else if let Some(error) = cause.downcast_ref::<NoSuchUpload>() {
+            error.meta()
+        } else if let Some(error) = cause.downcast_ref::<NoSuchBucket>() {
+            error.meta()
+        } else if let Some(error) = cause.downcast_ref::<NoSuchKey>() {
+            error.meta()
+        } else {
+            continue;
+        };
+        if let Some(code) = metadata.code() {
+            return Some((operation, code, metadata.message().unwrap_or_default()));
+        }
+    }
+    None
+}
+
+/// Whether the error is S3 asking to reduce the request rate, e.g. through a `503 Slow Down`.
+pub(crate) fn is_throttling(error: &Error) -> bool {
+    matches!(
+        error.inner().and_then(service_error),
+        Some((
+            _,
+            "SlowDown" | "ServiceUnavailable" | "Throttling" | "RequestLimitExceeded",
+            _,
+        )),
+    )
+}
+
+/// Turns a conditional write that S3 refused into an unrecoverable error, as it keeps failing for
+/// as long as the object it must not overwrite exists.
+pub(crate) fn precondition_failed_is_unrecoverable(error: Error) -> Error {
+    match error {
+        Error::Retryable(err)
+            if matches!(
+                service_error(&err),
+                Some((_, "PreconditionFailed" | "ConditionalRequestConflict", _)),
+            ) =>
+        {
+            Error::Unrecoverable(err)
+        }
+        error => error,
+    }
+}
+
+/// Returns a human-readable hint on how to remediate the given error, if it is a known failure.
+///
+/// The errors returned by the AWS SDK are accurate, but rarely tell you what you have to change to
+/// make the upload work, so this hint is meant to be printed alongside the raw error.
+pub(crate) fn hint_for(error: &Error) -> Option<String> {
+    let (operation, code, message) = service_error(error.inner()?)?;
+
+    let hint = match code {
+        "AccessDenied" if message.contains("kms:") || message.contains("KMS") => {
+            "The bucket or object is encrypted with a KMS key you are not allowed to use. \
+            Multipart uploads to SSE-KMS encrypted buckets require both `kms:GenerateDataKey` and \
+            `kms:Decrypt` on the KMS key, in addition to `s3:PutObject` and \
+            `s3:AbortMultipartUpload` on the S3 object."
+                .to_owned()
+        }
+        code if code.starts_with("KMS.") => {
+            "The KMS key used to encrypt the object could not be used. Verify that the key exists, \
+            is enabled, and that its key policy allows `kms:GenerateDataKey` and `kms:Decrypt` for \
+            the identity you are uploading with."
+                .to_owned()
+        }
+        "AccessDenied" | "AllAccessDisabled" => match operation {
+            Some(operation) => {
+                let mut hint = format!(
+                    "You are not allowed to perform `{}`. Make sure the identity you are using is \
+                    allowed the IAM action `{}` on {}, and that no bucket policy, SCP or \
+                    permission boundary denies it.",
+                    operation.name(),
+                    operation.iam_action(),
+                    operation.resource(),
+                );
+                if operation.is_upload() {
+                    hint.push_str(
+                        " Persevere requires `s3:PutObject` and `s3:AbortMultipartUpload`.",
+                    );
+                }
+                hint
+            }
+            None => "Make sure the identity you are uploading with is allowed the IAM actions \
+                `s3:PutObject` and `s3:AbortMultipartUpload` on the S3-object ARN you are \
+                uploading to, and that no bucket policy, SCP or permission boundary denies them."
+                .to_owned(),
+        },
+        "NoSuchBucket" => "The bucket does not exist. Check the spelling of the bucket name, and \
+            make sure you are using credentials for the account and region the bucket lives in."
+            .to_owned(),
+        "NoSuchUpload" => "The multipart upload no longer exists. It was either completed, \
+            aborted, or removed by a lifecycle rule. The upload can't be resumed: remove the \
+            state-file and start a new upload."
+            .to_owned(),
+        "PreconditionFailed" | "ConditionalRequestConflict" => match operation {
+            Some(Operation::CompleteMultipartUpload) => "An object already exists under the key, \
+                and `--if-none-match` prevents overwriting it, so the multipart upload was \
+                aborted. Remove the state-file and upload to another key instead."
+                .to_owned(),
+            _ => {
+                "An object already exists under the key, and `--if-none-match` prevents \
+                overwriting it. Upload to another key instead, or remove the existing object first."
+                    .to_owned()
+            }
+        },
+        "RequestTimeTooSkewed" => "The clock of your system differs too much from the time of \
+            AWS. Synchronize your system clock (e.g. through NTP) and resume the upload."
+            .to_owned(),
+        "SignatureDoesNotMatch" => "The request signature does not match. This is usually caused \
+            by a wrong `AWS_SECRET_ACCESS_KEY`, or by a system clock that is out of sync. Verify \
+            your credentials and synchronize your system clock (e.g. through NTP)."
+            .to_owned(),
+        "InvalidAccessKeyId" => "The access key ID does not exist. Verify the credentials (or \
+            `AWS_PROFILE`) you are using."
+            .to_owned(),
+        "ExpiredToken" | "TokenRefreshRequired" => "Your credentials have expired. Refresh them \
+            (e.g. through `aws sso login`) and resume the upload."
+            .to_owned(),
+        _ => return None,
+    };
+    Some(hint)
+}
@@ -0,0 +1,258 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    result::{
+        AnyhowResultExt,
+        Error,
+        Result,
+        StdResultExt,
+    },
+    state_home,
+    State,
+};
+use anyhow::Context;
+use aws_sdk_s3::primitives::{
+    DateTime,
+    DateTimeFormat,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::{
+    collections::BTreeMap,
+    path::{
+        Path,
+        PathBuf,
+    },
+    time::{
+        Duration,
+        SystemTime,
+    },
+};
+use tokio::io::AsyncWriteExt;
+use tracing::{
+    debug,
+    warn,
+};
+
+/// Environment variable that overrides the location of the history file.
+const HISTORY_FILE_ENV: &str = "PERSEVERE_HISTORY_FILE";
+
+/// Outcome of a single invocation of a transfer command.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Outcome {
+    Completed,
+    Paused,
+    Interrupted,
+    FailedRetryable,
+    FailedUnrecoverable,
+    Aborted,
+}
+
+impl Outcome {
+    pub(crate) fn of<T>(result: &Result<T>) -> Self {
+        match result {
+            Ok(_) => Outcome::Completed,
+            Err(error) => Outcome::of_error(error),
+        }
+    }
+
+    pub(crate) fn of_error(error: &Error) -> Self {
+        match error {
+            Error::Paused => Outcome::Paused,
+            Error::Interrupted => Outcome::Interrupted,
+            Error::Retryable(_) => Outcome::FailedRetryable,
+            Error::Unrecoverable(_) => Outcome::FailedUnrecoverable,
+        }
+    }
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Completed => "completed",
+            Outcome::Paused => "paused",
+            Outcome::Interrupted => "interrupted",
+            Outcome::FailedRetryable => "failed (retryable)",
+            Outcome::FailedUnrecoverable => "failed (unrecoverable)",
+            Outcome::Aborted => "aborted",
+        }
+    }
+}
+
+/// A single record in the transfer history.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct Entry {
+    /// RFC 3339 timestamp of when the command finished.
+    pub(crate) finished_at: String,
+    pub(crate) command: String,
+    pub(crate) outcome: Outcome,
+    pub(crate) s3_bucket: String,
+    pub(crate) s3_key: String,
+    pub(crate) file: PathBuf,
+    /// Empty for files that were small enough to be uploaded with a single request.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub(crate) upload_id: String,
+    pub(crate) file_size_in_bytes: u64,
+    /// Number of bytes that have been uploaded successfully, across all invocations.
+    pub(crate) bytes_uploaded: u64,
+    /// Wall-clock duration of this invocation.
+    pub(crate) duration_seconds: f64,
+    pub(crate) e_tag: Option<String>,
+    pub(crate) error: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) labels: BTreeMap<String, String>,
+}
+
+impl Entry {
+    pub(crate) fn new(command: &str, outcome: Outcome, state: &State, duration: Duration) -> Self {
+        Self {
+            finished_at: DateTime::from(SystemTime::now())
+                .fmt(DateTimeFormat::DateTime)
+                .unwrap_or_default(),
+            command: command.to_owned(),
+            outcome,
+            s3_bucket: state.s3_bucket.clone(),
+            s3_key: state.s3_key.clone(),
+            file: state.file_to_upload.clone(),
+            upload_id: state.upload_id.clone(),
+            file_size_in_bytes: state.file_size_in_bytes,
+            bytes_uploaded: state.uploaded_bytes(),
+            duration_seconds: duration.as_secs_f64(),
+            e_tag: None,
+            error: None,
+            labels: state.labels.clone(),
+        }
+    }
+
+    pub(crate) fn with_e_tag(mut self, e_tag: Option<String>) -> Self {
+        self.e_tag = e_tag;
+        self
+    }
+
+    pub(crate) fn with_error(mut self, error: Option<&Error>) -> Self {
+        self.error = error.and_then(Error::inner).map(|err| format!("{:#}", err));
+        self
+    }
+
+    /// Human-readable, single-line summary of this entry.
+    pub(crate) fn summary(&self) -> String {
+        let mut summary = format!(
+            "{}  {:<22}  {:<7}  s3://{}/{}  {} of {} bytes in {:.0}s",
+            self.finished_at,
+            self.outcome.as_str(),
+            self.command,
+            self.s3_bucket,
+            self.s3_key,
+            self.bytes_uploaded,
+            self.file_size_in_bytes,
+            self.duration_seconds,
+        );
+        if let Some(e_tag) = &self.e_tag {
+            summary.push_str(&format!("  ETag: {}", e_tag));
+        }
+        if !self.labels.is_empty() {
+            let labels: Vec<_> = self
+                .labels
+                .iter()
+                .map(|(key, value)| format!("{}={}", key, value))
+                .collect();
+            summary.push_str(&format!("  Labels: {}", labels.join(",")));
+        }
+        if let Some(error) = &self.error {
+            summary.push_str(&format!("  Error: {}", error));
+        }
+        summary
+    }
+}
+
+/// Returns the path of the history file.
+///
+/// This is `$PERSEVERE_HISTORY_FILE` if set, otherwise `$XDG_STATE_HOME/persevere/history.jsonl`,
+/// falling back to `~/.local/state/persevere/history.jsonl`.
+pub(crate) fn history_file() -> Option<PathBuf> {
+    if let Some(file) = std::env::var_os(HISTORY_FILE_ENV) {
+        return Some(PathBuf::from(file));
+    }
+    Some(state_home::state_directory()?.join("history.jsonl"))
+}
+
+/// Appends the entry to the history file.
+///
+/// Failing to write the history must never fail the transfer itself, which is why errors are only
+/// logged.
+pub(crate) async fn record(entry: Entry) {
+    let Some(file) = history_file() else {
+        debug!("Unable to determine the location of the history file, not recording transfer");
+        return;
+    };
+    if let Err(err) = append(&file, &entry).await {
+        warn!(
+            "Failed to record transfer in history file {}: {}",
+            file.display(),
+            err,
+        );
+    }
+}
+
+async fn append(file: &Path, entry: &Entry) -> Result<()> {
+    if let Some(parent) = file.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .into_unrecoverable()?;
+    }
+    let mut line = serde_json::to_vec(entry)
+        .context("Failed to serialize history entry")
+        .into_unrecoverable()?;
+    line.push(b'\n');
+
+    let mut history = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(file)
+        .await
+        .into_unrecoverable()?;
+    history.write_all(&line).await.into_unrecoverable()?;
+    history.flush().await.into_unrecoverable()
+}
+
+/// Reads all entries from the history file, oldest first.
+///
+/// Lines that can't be deserialized, e.g. one left incomplete by a crash while it was appended,
+/// are skipped with a warning, so that they don't hide the rest of the history.
+pub(crate) async fn read(file: &Path) -> Result<Vec<Entry>> {
+    let contents = match tokio::fs::read(file).await {
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        result => result.into_unrecoverable()?,
+    };
+    let mut entries = vec![];
+    for (index, line) in contents.split(|&byte| byte == b'\n').enumerate() {
+        if line.trim_ascii().is_empty() {
+            continue;
+        }
+        match serde_json::from_slice(line) {
+            Ok(entry) => entries.push(entry),
+            Err(error) => warn!(
+                "Skipping line {} of the history file {}, which can't be deserialized: {}",
+                index + 1,
+                file.display(),
+                error,
+            ),
+        }
+    }
+    Ok(entries)
+}
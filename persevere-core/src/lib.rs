@@ -0,0 +1,3083 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+mod archive;
+mod autotune;
+mod batch;
+mod checkpoint;
+mod checksum;
+mod clock;
+mod compat;
+mod completions;
+mod compression;
+mod config;
+mod consts;
+mod copy;
+mod ctl;
+mod de;
+mod direct_io;
+mod duration;
+mod encryption;
+mod fingerprint;
+mod headers;
+mod hints;
+mod history;
+mod metrics;
+mod migration;
+mod notify;
+mod object_options;
+#[cfg(feature = "otel")]
+mod otel;
+mod output;
+mod parts;
+mod progress;
+mod proxy;
+mod readahead;
+mod reconcile;
+mod replicate;
+mod restore;
+mod result;
+mod retry;
+mod s3_uri;
+mod sdk;
+mod serve;
+mod signals;
+mod size;
+mod smtp;
+mod spill;
+mod split;
+mod stall;
+mod state_home;
+mod state_lock;
+mod state_store;
+mod status;
+mod sync;
+mod throttle;
+mod uploader;
+mod uploads;
+mod verbosity;
+mod verify;
+mod watch;
+
+pub use crate::{
+    clock::Clock,
+    history::Outcome,
+    output::TransferResult,
+    parts::Part,
+    progress::{
+        ProgressReporter,
+        TransferEvent,
+    },
+    result::Error,
+    uploader::Uploader,
+};
+
+use crate::{
+    archive::Archive,
+    autotune::AutoTune,
+    checkpoint::{
+        CheckpointInterval,
+        Checkpointer,
+    },
+    checksum::{
+        ChecksumAlgorithm,
+        ChecksumReader,
+        Hasher,
+    },
+    clock::SharedClock,
+    compat::ByteStreamExt,
+    compression::{
+        Codec,
+        Compression,
+    },
+    config::Config,
+    consts::{
+        MAXIMUM_NUMBER_OF_PARTS,
+        MAXIMUM_OBJECT_SIZE,
+        MAXIMUM_PART_NUMBER,
+        MINIMUM_PART_SIZE,
+    },
+    copy::CopySource,
+    direct_io::DirectReader,
+    encryption::Encryption,
+    fingerprint::Fingerprint,
+    headers::Header,
+    metrics::MetricsReporter,
+    notify::NotifyOptions,
+    object_options::ObjectOptions,
+    output::OutputFormat,
+    parts::PartPlan,
+    progress::{
+        ProgressFormat,
+        ProgressReader,
+    },
+    readahead::Readahead,
+    replicate::{
+        Replica,
+        ReplicaTarget,
+    },
+    result::{
+        bail,
+        AnyhowResultExt,
+        Result,
+        StdResultExt,
+    },
+    retry::RetryOptions,
+    s3_uri::S3Uri,
+    sdk::SdkOptions,
+    signals::Signals,
+    spill::Spill,
+    split::Split,
+    stall::StallDetector,
+    state_lock::StateLock,
+    state_store::StateStore,
+    throttle::{
+        RateLimiter,
+        ThrottledReader,
+    },
+    verbosity::Verbosity,
+};
+use anyhow::Context;
+use aws_config::SdkConfig;
+use aws_sdk_s3::{
+    error::ProvideErrorMetadata,
+    operation::{
+        complete_multipart_upload::CompleteMultipartUploadOutput,
+        put_object::PutObjectOutput,
+    },
+    primitives::ByteStream,
+    types::{
+        CompletedMultipartUpload,
+        CompletedPart,
+        RequestPayer,
+    },
+};
+use clap::{
+    builder::PossibleValuesParser,
+    Args,
+    CommandFactory,
+    FromArgMatches,
+    Parser,
+    Subcommand,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::{
+    collections::BTreeMap,
+    io::IsTerminal,
+    net::SocketAddr,
+    path::{
+        Path,
+        PathBuf,
+    },
+    process::ExitCode,
+    sync::Arc,
+    time::Instant,
+};
+use tokio::io::{
+    AsyncBufReadExt,
+    AsyncRead,
+    AsyncReadExt,
+    AsyncSeekExt,
+};
+use tokio_util::{
+    bytes::Bytes,
+    either::Either,
+    sync::CancellationToken,
+};
+use tracing::{
+    debug,
+    error,
+    info,
+    warn,
+    Instrument,
+};
+use tracing_subscriber::prelude::*;
+
+#[derive(Debug, Deserialize, Serialize)]
+struct State {
+    /// Version of the format of the state-file, see [`migration::STATE_VERSION`].
+    version: u64,
+    s3_bucket: String,
+    s3_key: String,
+    file_to_upload: PathBuf,
+    file_size_in_bytes: u64,
+    part_size: u64,
+    number_of_parts: u64,
+    upload_id: String,
+    last_successful_part: u64,
+    #[serde(with = "de::completed_parts")]
+    completed_parts: Vec<CompletedPart>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    labels: BTreeMap<String, String>,
+    #[serde(default)]
+    object_options: ObjectOptions,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    headers: Vec<Header>,
+    /// Directory the parts are spilled to, if the data is read from stdin or compressed.
+    ///
+    /// For these uploads, `file_size_in_bytes` and `number_of_parts` only cover the parts that
+    /// have been read so far.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    spill_directory: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    request_payer: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    checksum_algorithm: Option<ChecksumAlgorithm>,
+    /// Fingerprint of the file when the upload was started, absent for uploads from stdin and for
+    /// state-files of older versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    fingerprint: Option<Fingerprint>,
+    /// The sizes of the parts, if they are tuned to the throughput during the upload. Otherwise,
+    /// all parts are `part_size` bytes large.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    auto_tune: Option<AutoTune>,
+    /// The AWS profile and region the upload was started with, absent for state-files of older
+    /// versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    aws_profile: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    aws_region: Option<String>,
+    /// Whether the upload was started against the FIPS or dual-stack endpoints of S3, absent for
+    /// state-files of older versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    use_fips_endpoint: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    use_dualstack_endpoint: Option<bool>,
+    /// The object the parts are copied from, if this is a copy within S3 rather than an upload.
+    ///
+    /// For copies, `file_to_upload` holds the S3 URI of the source, which is only shown to the
+    /// user.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    copy_source: Option<CopySource>,
+    /// How the parts are encrypted on the client, if they are.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encryption: Option<Encryption>,
+    /// How the file is compressed before it is split into parts, if it is.
+    ///
+    /// Compressed files are uploaded like streams read from stdin, so `spill_directory` is set, and
+    /// `file_size_in_bytes` only covers the compressed parts read so far.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    compression: Option<Compression>,
+    /// The tar archive of a directory that is uploaded, if the upload is of a directory.
+    ///
+    /// Archives are uploaded like streams read from stdin as well, with `file_to_upload` holding
+    /// the path of the directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    archive: Option<Archive>,
+    /// How the file is split into multiple objects, if it is larger than the maximum object size.
+    ///
+    /// For split files, the state describes the object currently being uploaded: `s3_key` and
+    /// `file_size_in_bytes` are the key and size of the object, not of the file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    split: Option<Split>,
+    /// Other objects the file is uploaded to alongside this one, each through a multipart upload
+    /// of its own.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    replicas: Vec<Replica>,
+}
+
+/// Logs if the variant of the S3 endpoints requested now differs from the one the upload was started
+/// with, which keeps being used.
+fn log_changed_endpoints(variant: &str, requested: Option<bool>, started_with: bool) {
+    if requested.unwrap_or(false) == started_with {
+        return;
+    }
+    let message = if started_with {
+        format!(
+            "The upload was started against the {variant} endpoints of S3, so it keeps using them"
+        )
+    } else {
+        format!("The upload was started without the {variant} endpoints of S3, so it keeps using the regular endpoints")
+    };
+    // Only a variant that was requested explicitly is overridden, otherwise this is expected.
+    if requested.is_some() {
+        warn!("{}", message);
+    } else {
+        info!("{}", message);
+    }
+}
+
+impl State {
+    fn request_payer(&self) -> Option<RequestPayer> {
+        self.request_payer.as_deref().map(RequestPayer::from)
+    }
+
+    /// Returns the configuration of the AWS SDK to continue the upload with, which sends the
+    /// requests to the same variant of the S3 endpoints as the upload was started with.
+    fn sdk_config(&self, config: &SdkConfig) -> SdkConfig {
+        let mut builder = config.to_builder();
+        if let Some(use_fips_endpoint) = self.use_fips_endpoint {
+            log_changed_endpoints("FIPS", config.use_fips(), use_fips_endpoint);
+            builder.set_use_fips(Some(use_fips_endpoint));
+        }
+        if let Some(use_dualstack_endpoint) = self.use_dualstack_endpoint {
+            log_changed_endpoints(
+                "dual-stack",
+                config.use_dual_stack(),
+                use_dualstack_endpoint,
+            );
+            builder.set_use_dual_stack(Some(use_dualstack_endpoint));
+        }
+        builder.build()
+    }
+
+    /// Whether the data is read from stdin, as opposed to a stream that Persevere can produce again
+    /// itself when resuming the upload.
+    fn reads_stdin(&self) -> bool {
+        self.spill_directory.is_some() && self.compression.is_none() && self.archive.is_none()
+    }
+
+    /// Returns the parts of the file that its fingerprint samples, or `None` for uploads from
+    /// stdin and of directories, which have no file to fingerprint.
+    fn fingerprint_plan(&self) -> Option<PartPlan> {
+        match (&self.compression, &self.spill_directory) {
+            (None, Some(_)) => None,
+            _ => Some(PartPlan::new(self.local_file_size(), self.part_size)),
+        }
+    }
+
+    /// Size of the local file, which differs from the size of the object for compressed and split
+    /// files.
+    fn local_file_size(&self) -> u64 {
+        match (&self.compression, &self.split) {
+            (Some(compression), _) => compression.file_size,
+            (None, Some(split)) => split.file_size,
+            (None, None) => self.file_size_in_bytes,
+        }
+    }
+
+    /// Returns the part with the given number, as long as it is known upfront.
+    fn part(&self, number: u64) -> Option<Part> {
+        match &self.auto_tune {
+            Some(auto_tune) => auto_tune.part(number, self.file_size_in_bytes),
+            None => PartPlan::new(self.file_size_in_bytes, self.part_size).part(number),
+        }
+    }
+
+    /// Number of bytes in all parts that have been uploaded successfully.
+    fn uploaded_bytes(&self) -> u64 {
+        match &self.auto_tune {
+            Some(auto_tune) => auto_tune.uploaded_bytes(),
+            None => (self.last_successful_part * self.part_size).min(self.file_size_in_bytes),
+        }
+    }
+
+    async fn from_file(file: impl AsRef<Path>) -> Result<Self> {
+        let contents = tokio::fs::read(file)
+            .await
+            .context("Failed to open state file")
+            .into_unrecoverable()?;
+        Self::from_slice(&contents)
+    }
+
+    /// Deserializes a state-file, migrating it from previous versions of Persevere.
+    fn from_slice(contents: &[u8]) -> Result<Self> {
+        let mut state = serde_json::from_slice(contents)
+            .context("Failed to parse state file")
+            .into_unrecoverable()?;
+        migration::migrate(&mut state)?;
+        serde_json::from_value(state)
+            .context("Failed to deserialize state file")
+            .into_unrecoverable()
+    }
+
+    // NOTE: `self` is taken mutably here, even though it isn't required by the method itself. By
+    //       requiring mutability, we guarantee that there is only ever one task that can write the
+    //       state file at a time, ensuring the file is always in a consistent state that.
+    async fn write_to_file(&mut self, file: impl AsRef<Path>) -> Result<()> {
+        let file = file.as_ref().to_owned();
+
+        // serde_json does not support asynchronous writers, so we make sure to spawn the task such
+        // that it doesn't block the executor.
+        tokio::task::block_in_place(|| write_json_atomically(&file, self, "state file"))
+    }
+}
+
+/// Writes `value` as JSON to `file`, replacing its previous contents atomically.
+///
+/// The JSON is written to a temporary file next to `file` first, which is synced and then renamed
+/// over `file`. A crash at any point thus leaves either the previous or the new contents behind,
+/// never a partially written file.
+fn write_json_atomically(file: &Path, value: &impl Serialize, what: &str) -> Result<()> {
+    let mut temporary_file = file.as_os_str().to_owned();
+    temporary_file.push(".tmp");
+    let temporary_file = PathBuf::from(temporary_file);
+
+    let result = (|| {
+        let mut writer = std::io::BufWriter::new(
+            std::fs::File::create(&temporary_file)
+                .with_context(|| format!("Failed to open {}", what))
+                .into_unrecoverable()?,
+        );
+        serde_json::to_writer(&mut writer, value)
+            .with_context(|| format!("Failed to serialize {}", what))
+            .into_unrecoverable()?;
+
+        // We make sure the state is actually persisted before we continue, such that a power loss
+        // can't leave us with a state-file that is older than what S3 has received.
+        writer
+            .into_inner()
+            .map_err(|err| err.into_error())
+            .with_context(|| format!("Failed to write {}", what))
+            .into_unrecoverable()?
+            .sync_all()
+            .with_context(|| format!("Failed to sync {}", what))
+            .into_unrecoverable()?;
+        std::fs::rename(&temporary_file, file)
+            .with_context(|| format!("Failed to replace {}", what))
+            .into_unrecoverable()
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temporary_file);
+    }
+    result?;
+    // The rename itself is only persisted once the directory has been synced.
+    sync_parent_directory(file)
+}
+
+/// Syncs the directory containing `file`, which persists the creation or removal of `file`.
+///
+/// This is only supported on Unix-like systems, and a no-op everywhere else.
+fn sync_parent_directory(file: &Path) -> Result<()> {
+    #[cfg(unix)]
+    {
+        let parent = match file.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        std::fs::File::open(parent)
+            .and_then(|directory| directory.sync_all())
+            .context("Failed to sync directory of state file")
+            .into_unrecoverable()?;
+    }
+    #[cfg(not(unix))]
+    let _ = file;
+    Ok(())
+}
+
+/// Parses a `key=value` pair as it is used for labels, metadata and tags on the command line.
+fn parse_key_value(value: &str) -> std::result::Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_owned(), value.to_owned())),
+        _ => Err(format!("expected `key=value`, got `{}`", value)),
+    }
+}
+
+/// Returns the path of the file that requests a running transfer for `state_file` to pause.
+fn pause_request_file(state_file: &Path) -> PathBuf {
+    let mut file_name = state_file.as_os_str().to_owned();
+    file_name.push(".pause");
+    PathBuf::from(file_name)
+}
+
+/// With Persevere you can upload huge files to S3 without worrying about network interruptions or
+/// other issues. Persevere will allow you to resume the upload where it was left off, even in the
+/// case of a system crash during upload.
+///
+/// The contents of the file you upload are always streamed, which means the memory usage of
+/// Persevere is minimal, usually below 10 MB. This makes it possible to upload files of any size
+/// supported by S3, even if they are larger than the available memory of your system.
+///
+/// Exit codes: 0 on success, 3 if the transfer was paused, 10 if it failed with an error that
+/// resuming it may overcome (e.g. a network error), 20 if it failed with an error that requires
+/// attention (e.g. missing permissions or a changed file) and 130 if it was stopped by a signal.
+///
+/// Source: <https://github.com/takkt-ag/persevere>
+#[derive(Debug, Parser)]
+#[command(name = "persevere", version, max_term_width = 100)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+    /// Path to the configuration file, which sets defaults for the options of all commands.
+    ///
+    /// Defaults to `$XDG_CONFIG_HOME/persevere/config.toml` (by default
+    /// `~/.config/persevere/config.toml`), which is only read if it exists.
+    // Only declared for the help and to accept the option, the file is read before the arguments
+    // are parsed through `config::config_argument`.
+    #[allow(dead_code)]
+    #[arg(long, global = true, value_name = "FILE")]
+    config: Option<PathBuf>,
+    #[command(flatten)]
+    sdk: SdkOptions,
+    #[command(flatten)]
+    verbosity: Verbosity,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Upload a file to S3.
+    ///
+    /// Persevere will take care of uploading the file in a manner that is resilient, such that
+    /// intermittent errors do not result in losing all progress on the upload, as well as
+    /// resumable, e.g. in case the system you are uploading crashed or there is a more persistent,
+    /// but still recoverable, error.
+    ///
+    /// This is achieved through a state-file which keeps track of the state of the upload. Resuming
+    /// an upload is done through the `resume` subcommand, by providing the same state-file again.
+    ///
+    /// You need the following AWS permissions for the S3-object ARN you are trying to upload to:
+    ///
+    /// * `s3:PutObject`
+    /// * `s3:AbortMultipartUpload`
+    ///
+    /// Persevere will automatically discover valid AWS credentials like most AWS SDKs. This means
+    /// you can provide environment variables such as `AWS_PROFILE` to select the profile you want
+    /// to upload a file with, or provide the `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`
+    /// directly.
+    Upload(Box<Upload>),
+    /// Resume the upload of a file to S3.
+    ///
+    /// You only have to provide the state-file of a previous invocation to `upload`, and Persevere
+    /// will resume your upload where it left off.
+    ///
+    /// You can not provide any other parameters to modify how the upload is handled, all choices
+    /// made when you started the upload have to remain the same. If you modify the state-file
+    /// manually, chances are you'll either have the upload fail outright, or you'll end up with a
+    /// corrupt object in S3 (and won't know that it is corrupt).
+    ///
+    /// You need the following AWS permissions for the S3-object ARN you are trying to upload to:
+    ///
+    /// * `s3:PutObject`
+    /// * `s3:AbortMultipartUpload`
+    ///
+    /// Persevere will automatically discover valid AWS credentials like most AWS SDKs. This means
+    /// you can provide environment variables such as `AWS_PROFILE` to select the profile you want
+    /// to upload a file with, or provide the `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`
+    /// directly.
+    Resume(Box<Resume>),
+    /// Upload multiple files to S3, as listed in a manifest.
+    ///
+    /// The files are uploaded one after another with the options provided on the command line,
+    /// each of them as resilient as with the `upload` subcommand. The state of every upload, as
+    /// well as the progress of the batch as a whole, is kept in a state directory: if the batch is
+    /// interrupted or some entries fail, running the same command again resumes the interrupted
+    /// upload and retries the failed entries, skipping the ones that have been uploaded already.
+    ///
+    /// You need the same AWS permissions as for the `upload` subcommand, for every S3-object ARN
+    /// listed in the manifest.
+    UploadBatch(Box<batch::UploadBatch>),
+    /// Upload a directory to S3 as a tar archive, without creating the archive on disk first.
+    ///
+    /// The archive is created on the fly while it is uploaded, as resilient and resumable as with
+    /// the `upload` subcommand. The contents of the directory are listed when the upload is
+    /// started, and this manifest is kept in the state-file: to resume the upload through the
+    /// `resume` subcommand, the archive is created again from the manifest, starting where the
+    /// upload left off. This requires the files that remain to be uploaded to stay unchanged.
+    ///
+    /// You need the same AWS permissions as for the `upload` subcommand.
+    UploadTar(Box<archive::UploadTar>),
+    /// Upload the files of a local directory to S3 that are new or have changed.
+    ///
+    /// Every file within the directory is compared with the object under the same relative key
+    /// below the given prefix: files that don't exist in S3, differ in size or have been modified
+    /// after the object was uploaded are transferred, each of them as resilient as with the
+    /// `upload` subcommand. With `--checksum`, the contents are compared instead of the
+    /// modification time.
+    ///
+    /// The list of files to transfer and the state of every upload are kept in a state directory:
+    /// if the sync is interrupted or some files fail, running the same command again resumes it.
+    ///
+    /// You need the same AWS permissions as for the `upload` subcommand, and additionally
+    /// `s3:ListBucket` for the bucket.
+    Sync(Box<sync::Sync>),
+    /// Watch a local directory and upload every new file that appears in it.
+    ///
+    /// The directory is scanned repeatedly. Every new or changed file is uploaded once its size and
+    /// modification time haven't changed for the settle time, each of them as resilient as with the
+    /// `upload` subcommand.
+    ///
+    /// The files that have been uploaded and the state of every upload in progress are kept in a
+    /// state directory: if the command is stopped, running the same command again resumes where it
+    /// left off instead of uploading files again.
+    ///
+    /// You need the same AWS permissions as for the `upload` subcommand.
+    Watch(Box<watch::Watch>),
+    /// Serve an HTTP API to submit and manage transfers, which are run in the background.
+    ///
+    /// Transfers are submitted with `POST /transfers` and a JSON object of the same form as the
+    /// entries of an `upload-batch` manifest, with an absolute `file_to_upload`. They are run one
+    /// after another in the order they were submitted, each of them as resilient as with the
+    /// `upload` subcommand. `GET /transfers` and `GET /transfers/{id}` show the transfers and their
+    /// status, and `POST /transfers/{id}/pause`, `.../resume` and `.../abort` manage them. A
+    /// read-only dashboard of the transfers is served on `/`.
+    ///
+    /// The transfers are kept in a state directory: running the same command again continues with
+    /// the transfers that haven't finished yet.
+    ///
+    /// You need the same AWS permissions as for the `upload` subcommand.
+    Serve(Box<serve::Serve>),
+    /// Manage the transfers of the `serve` command through its HTTP API.
+    ///
+    /// Submits files to upload, lists the transfers with their status, and pauses, resumes or
+    /// aborts them, without having to craft the requests to the API yourself.
+    Ctl(ctl::Ctl),
+    /// Adopt a multipart upload whose state-file was lost, and continue it.
+    ///
+    /// If the state-file of an upload was lost, e.g. together with the host that was uploading, the
+    /// multipart upload still exists in S3. This subcommand finds the multipart upload in progress
+    /// for the given key, verifies the parts S3 already holds against the file, rebuilds the
+    /// state-file and continues the upload after the last matching part.
+    ///
+    /// The checksum algorithm of the multipart upload is used for the remaining parts as well. The
+    /// labels of the original invocation are not known to S3, and thus can't be restored.
+    ///
+    /// You need the following AWS permissions, in addition to the ones required by `upload`:
+    ///
+    /// * `s3:ListBucketMultipartUploads` for the bucket ARN
+    /// * `s3:ListMultipartUploadParts` for the S3-object ARN
+    Adopt(Box<uploads::Adopt>),
+    /// Copy an object within S3, e.g. to a bucket in another region, without downloading it.
+    ///
+    /// The object is copied in parts through a multipart upload, with the same state-file based
+    /// resume semantics as the `upload` subcommand: an interrupted copy is continued through the
+    /// `resume` subcommand, or aborted through the `abort` subcommand.
+    ///
+    /// You need the following AWS permissions:
+    ///
+    /// * `s3:GetObject` for the source S3-object ARN
+    /// * `s3:PutObject` and `s3:AbortMultipartUpload` for the destination S3-object ARN
+    Copy(Box<copy::Copy>),
+    /// Restore an object from the S3 Glacier or Intelligent-Tiering archive storage classes.
+    ///
+    /// Archived objects can't be read, and thus not copied, until they have been restored, which
+    /// can take from minutes to days depending on the storage class and retrieval tier. This
+    /// subcommand requests the restore of an archived object, and with `--wait` polls until it has
+    /// been restored. Objects that aren't archived or have been restored already are left as they
+    /// are.
+    ///
+    /// You need the following AWS permissions for the S3-object ARN:
+    ///
+    /// * `s3:GetObject`
+    /// * `s3:RestoreObject`
+    Restore(restore::Restore),
+    /// Verify that an object in S3 matches a local file, without downloading it.
+    ///
+    /// The file is read in the same parts the object was uploaded in, as listed by S3, and the
+    /// ETag and checksum S3 holds for the object are compared with the ones calculated locally.
+    /// This allows you to audit past uploads, e.g. to confirm that nothing was silently truncated.
+    /// The command fails if the object doesn't match the file.
+    ///
+    /// You need the following AWS permissions for the S3-object ARN:
+    ///
+    /// * `s3:GetObject`
+    /// * `s3:GetObjectAttributes`
+    Verify(verify::Verify),
+    /// Decrypt an object that was uploaded with `--encryption-key-file`.
+    ///
+    /// Download the object first, e.g. with `aws s3 cp`, and provide it together with the key file.
+    /// How the object was encrypted is read from its metadata in S3. The decrypted contents are
+    /// only kept if every part of the object could be authenticated, so that a corrupt, incomplete
+    /// or tampered object is detected. If the upload was also compressed, the decrypted contents
+    /// are the compressed file.
+    ///
+    /// You need the `s3:GetObject` permission for the S3-object ARN.
+    Decrypt(encryption::Decrypt),
+    /// List the multipart uploads in progress in a bucket.
+    ///
+    /// Multipart uploads that have been started but neither completed nor aborted keep their parts
+    /// in S3, which you are charged storage for. For every such upload, the key, upload ID, time it
+    /// was initiated and the parts uploaded so far are listed, which allows you to find stale
+    /// uploads, e.g. of hosts that crashed. This includes uploads not started by Persevere.
+    ///
+    /// You need the following AWS permissions:
+    ///
+    /// * `s3:ListBucketMultipartUploads` for the bucket ARN
+    /// * `s3:ListMultipartUploadParts` for the S3-object ARNs
+    ListUploads(uploads::ListUploads),
+    /// Abort the multipart uploads in a bucket that were started longer ago than a given age.
+    ///
+    /// Multipart uploads of crashed hosts or abandoned state-files are never completed, but S3
+    /// charges storage for their parts until they are aborted. This subcommand aborts all multipart
+    /// uploads that were initiated before the given age, regardless of whether they were started by
+    /// Persevere. Make sure the age is larger than the time any upload you still intend to resume
+    /// can take, and use `--dry-run` to check which uploads would be aborted first.
+    ///
+    /// You need the following AWS permissions:
+    ///
+    /// * `s3:ListBucketMultipartUploads` for the bucket ARN
+    /// * `s3:AbortMultipartUpload` for the S3-object ARNs
+    Cleanup(uploads::Cleanup),
+    /// Abort the upload of a file to S3.
+    ///
+    /// If you previously started an upload using the `upload` subcommand which has failed with a
+    /// recoverable error, but you no longer want to finish the upload you can invoke this
+    /// subcommand with the state-file. The multipart-upload with AWS will then be aborted (which
+    /// ensures the partial upload no longer creates any cost) and the state-file will be removed.
+    ///
+    /// You need the following AWS permissions for the S3-object ARN you are trying to upload to:
+    ///
+    /// * `s3:PutObject`
+    /// * `s3:AbortMultipartUpload`
+    ///
+    /// Persevere will automatically discover valid AWS credentials like most AWS SDKs. This means
+    /// you can provide environment variables such as `AWS_PROFILE` to select the profile you want
+    /// to upload a file with, or provide the `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`
+    /// directly.
+    Abort(Abort),
+    /// Pause a running upload.
+    ///
+    /// Provide the state-file of an upload that is currently running, and Persevere will signal
+    /// the running process to finish the part it is currently uploading, write the state-file and
+    /// exit with status 3. This allows you to free up bandwidth without having to kill the process
+    /// and hoping the state-file is up-to-date.
+    ///
+    /// This command returns immediately, it does not wait for the running process to pause. The
+    /// paused upload can be continued at any time through the `resume` subcommand.
+    Pause(Pause),
+    /// Show the status of an upload.
+    ///
+    /// Provide the state-file of an upload, whether it is running, paused or has failed, and
+    /// Persevere will show how far along it is: the bytes uploaded and remaining, the parts, the
+    /// upload ID and the destination. The remaining time is estimated from the throughput of the
+    /// previous attempts of the upload, as recorded in the transfer history.
+    ///
+    /// This command only reads the state-file and doesn't access S3.
+    Status(status::Status),
+    /// Show the history of transfers.
+    ///
+    /// Every invocation of `upload`, `resume`, `adopt` and `abort` is recorded in a local history
+    /// file, including the destination, number of bytes, duration, resulting ETag and outcome.
+    /// This serves as an audit trail of the transfers performed on this system.
+    ///
+    /// The history is stored in `$XDG_STATE_HOME/persevere/history.jsonl` (by default
+    /// `~/.local/state/persevere/history.jsonl`), one JSON document per line. You can change the
+    /// location through the `PERSEVERE_HISTORY_FILE` environment variable.
+    History(History),
+    /// Generate the completions of Persevere for a shell.
+    ///
+    /// The completion script is printed to stdout. How to install it depends on the shell, e.g.
+    /// for bash:
+    ///
+    /// persevere completions bash > ~/.local/share/bash-completion/completions/persevere
+    Completions(completions::Completions),
+}
+
+impl Command {
+    /// Runs the command.
+    async fn run(self) -> Result<()> {
+        match self {
+            Command::Upload(cmd) => cmd.run().await,
+            Command::Resume(cmd) => cmd.run().await,
+            Command::UploadBatch(cmd) => cmd.run().await,
+            Command::UploadTar(cmd) => cmd.run().await,
+            Command::Sync(cmd) => cmd.run().await,
+            Command::Watch(cmd) => cmd.run().await,
+            Command::Serve(cmd) => cmd.run().await,
+            Command::Ctl(cmd) => cmd.run().await,
+            Command::Adopt(cmd) => cmd.run().await,
+            Command::Copy(cmd) => cmd.run().await,
+            Command::Restore(cmd) => cmd.run().await,
+            Command::Verify(cmd) => cmd.run().await,
+            Command::Decrypt(cmd) => cmd.run().await,
+            Command::Abort(cmd) => cmd.run().await,
+            Command::ListUploads(cmd) => cmd.run().await,
+            Command::Cleanup(cmd) => cmd.run().await,
+            Command::Pause(cmd) => cmd.run().await,
+            Command::Status(cmd) => cmd.run().await,
+            Command::History(cmd) => cmd.run().await,
+            Command::Completions(cmd) => cmd.run().await,
+        }
+    }
+
+    /// The format the result of the command is printed in.
+    fn output(&self) -> OutputFormat {
+        match self {
+            Command::Upload(cmd) => cmd.output,
+            Command::Resume(cmd) => cmd.output,
+            Command::UploadTar(cmd) => cmd.output,
+            Command::Copy(cmd) => cmd.output,
+            _ => OutputFormat::Text,
+        }
+    }
+}
+
+/// Options that influence how a transfer is performed, which can differ between the initial upload
+/// and subsequent resumes.
+#[derive(Clone, Debug, Args)]
+struct TransferOptions {
+    /// Hold the bytes of the part currently being uploaded in memory.
+    ///
+    /// By default, the contents of each part are streamed from the file, which means a retry of a
+    /// failed part has to read the part from the file again. If the file is on a slow source like
+    /// NFS or a spun-down archive disk, this can be costly. With this option, a part is read into
+    /// memory once, and retries will re-send the part from memory.
+    ///
+    /// The memory used is bounded by `--memory-limit`: if the part-size exceeds the limit, parts
+    /// will be streamed from the file as usual.
+    #[arg(long)]
+    buffer_parts_in_memory: bool,
+    /// Maximum amount of memory to use for buffering parts, e.g. `512MiB` or `2GiB`.
+    #[arg(long, default_value = "1GiB", value_parser = size::parse_size)]
+    memory_limit: u64,
+    /// Size of the buffer the contents of a part are streamed through, e.g. `256KiB`.
+    ///
+    /// Larger buffers need fewer reads per part, at the expense of memory. The buffers are reused
+    /// across parts.
+    #[arg(long, default_value = "64KiB", value_parser = compat::parse_buffer_size)]
+    buffer_size: usize,
+    /// Read the given number of parts ahead into memory while the current part is being uploaded.
+    ///
+    /// This hides the time it takes to open the file and read the start of the next part, which is
+    /// noticeable on slow storage. The parts read ahead count against `--memory-limit`. Uploads from
+    /// stdin, compressed or archived uploads and uploads with `--auto-tune` don't read ahead, as
+    /// their parts are only known once they are read.
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    readahead: u64,
+    /// Read the file with direct I/O (`O_DIRECT`), bypassing the page cache.
+    ///
+    /// Uploading a huge file through the page cache evicts everything else from it, although every
+    /// byte of the file is only read once. This is only supported on Linux: on other platforms, or if
+    /// the file system doesn't support direct I/O, the file is read through the page cache as usual.
+    #[arg(long)]
+    direct_io: bool,
+    /// How to report the progress of the transfer.
+    ///
+    /// Log messages are always written to stderr, which allows you to consume the `ndjson`
+    /// progress updates from stdout.
+    #[arg(long, value_enum, default_value_t)]
+    progress: ProgressFormat,
+    /// How often the progress is checkpointed in the state-file.
+    ///
+    /// Accepts a number of parts (`10` or `10parts`), seconds (`30s`) or a size (`1GiB`).
+    /// By default, the state-file is written after every part. When the upload stops, for whichever
+    /// reason, any progress that has not been checkpointed yet is written to the state-file.
+    #[arg(long, default_value_t)]
+    checkpoint_every: CheckpointInterval,
+    /// Limit the throughput of the transfer, e.g. `512KiB` or `50MiB` per second.
+    ///
+    /// Use this to keep the transfer from saturating the network connection of the host, starving
+    /// other traffic. Commands running several transfers, like `upload-batch`, `sync`, `watch` or
+    /// `serve`, share one limit between all of their transfers, including the ones `upload-batch`
+    /// and `sync` run at the same time with `--jobs`.
+    #[arg(long, value_parser = throttle::parse_rate)]
+    limit_rate: Option<u64>,
+    /// Consider a part stalled once none of its bytes could be sent for this long, e.g. `30s`.
+    ///
+    /// A stalled part is retried like any other failed part, instead of waiting for the operating
+    /// system to give up on the hung connection, which can take hours. Set it to `0` to disable the
+    /// detection.
+    #[arg(long, default_value = "2min", value_parser = duration::parse_duration)]
+    stall_timeout: std::time::Duration,
+    /// Don't verify that the ETags S3 returns are the MD5 digests of the data.
+    ///
+    /// Some S3-compatible stores don't use MD5 digests as ETags, so every part would fail the
+    /// verification. The ETags are never verified for data encrypted with a KMS key or with a key
+    /// provided through the `x-amz-server-side-encryption-customer-*` headers, and checksums of
+    /// `--checksum-algorithm` are verified regardless.
+    #[arg(long)]
+    no_verify_etag: bool,
+    #[command(flatten)]
+    retry: RetryOptions,
+    /// Serve metrics of the transfer in the Prometheus text format on the given address, e.g.
+    /// `127.0.0.1:9090`.
+    ///
+    /// The metrics, like the bytes sent, the parts completed and failed, the throughput and the
+    /// ETA, are served at `/metrics` for as long as the transfer is running, labelled with the
+    /// bucket and key of the object.
+    #[arg(long, value_name = "ADDRESS")]
+    metrics_listen: Option<SocketAddr>,
+    /// Write the metrics of the transfer in the Prometheus text format to the given file, e.g. for
+    /// the textfile collector of node_exporter.
+    ///
+    /// The file is replaced every 15 seconds while the transfer is running, and once more when it
+    /// has finished, after which it is left in place.
+    #[arg(long, value_name = "PATH")]
+    metrics_textfile: Option<PathBuf>,
+    #[command(flatten)]
+    notify: NotifyOptions,
+    /// Receives the progress of the transfer instead of the reporter chosen through `--progress`,
+    /// if the transfer is run through the library.
+    #[arg(skip)]
+    reporter: Option<SharedReporter>,
+    /// The S3 client to transfer with instead of one created from the AWS configuration, if the
+    /// transfer is run through the library.
+    #[arg(skip)]
+    client: Option<aws_sdk_s3::Client>,
+    /// The rate limiter shared with other transfers, instead of one for this transfer only.
+    #[arg(skip)]
+    rate_limiter: Option<RateLimiter>,
+    /// Pauses the transfer once it is cancelled, like a pause request does, if the transfer is run
+    /// through the library.
+    #[arg(skip)]
+    cancellation: Option<CancellationToken>,
+    /// The clock the backoff between retries, `--max-duration` and `--limit-rate` are measured
+    /// with.
+    #[arg(skip)]
+    clock: SharedClock,
+    /// The options of the AWS SDK given through the library, which replace the ones of the command
+    /// line.
+    #[arg(skip)]
+    sdk: Option<SdkOptions>,
+    /// Whether SIGINT and SIGTERM stop the transfer gracefully. Embedders handle the signals of
+    /// their process themselves.
+    #[arg(skip = true)]
+    stop_on_signal: bool,
+    /// Whether the outcome of the transfer is recorded in the transfer history. Embedders opt into
+    /// it through the library.
+    #[arg(skip = true)]
+    record_history: bool,
+}
+
+/// A [`ProgressReporter`] given through the library, which receives the progress of a transfer.
+#[derive(Clone)]
+struct SharedReporter(Arc<dyn ProgressReporter>);
+
+impl std::fmt::Debug for SharedReporter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SharedReporter")
+    }
+}
+
+impl TransferOptions {
+    /// Returns the reporter given through the library, or the one chosen through `--progress`.
+    fn progress_reporter(&self) -> Arc<dyn ProgressReporter> {
+        match &self.reporter {
+            Some(SharedReporter(reporter)) => Arc::clone(reporter),
+            None => self.progress.reporter(),
+        }
+    }
+
+    /// Returns the reporter for the progress of the transfer of the given state, which also
+    /// exports the metrics of the transfer if requested.
+    fn reporter(&self, state: &State) -> Result<Arc<dyn ProgressReporter>> {
+        let reporter = self.progress_reporter();
+        if self.metrics_listen.is_none() && self.metrics_textfile.is_none() {
+            return Ok(reporter);
+        }
+        Ok(Arc::new(MetricsReporter::start(
+            reporter,
+            &state.s3_bucket,
+            &state.s3_key,
+            self.metrics_listen,
+            self.metrics_textfile.clone(),
+        )?))
+    }
+
+    /// Whether the ETags S3 returns for the upload of the given state are verified to be the MD5
+    /// digests of the data.
+    fn verifies_e_tags(&self, state: &State) -> bool {
+        !self.no_verify_etag && !headers::encrypt_with_customer_key(&state.headers)
+    }
+
+    /// Loads the configuration of the AWS SDK, with the options given through the library if any.
+    async fn load_config(&self) -> Result<SdkConfig> {
+        match &self.sdk {
+            Some(sdk) => sdk.load_config().await,
+            None => Ok(sdk::load_config().await),
+        }
+    }
+
+    /// Returns the name of the AWS profile the configuration is loaded from.
+    fn profile_name(&self) -> String {
+        match &self.sdk {
+            Some(sdk) => sdk.profile_name(),
+            None => sdk::profile_name(),
+        }
+    }
+
+    /// Returns the S3 client given through the library, or one for the AWS configuration.
+    fn s3_client(&self, config: &SdkConfig) -> aws_sdk_s3::Client {
+        self.client.clone().unwrap_or_else(|| match &self.sdk {
+            Some(sdk) => aws_sdk_s3::Client::from_conf(sdk.s3_config(config).build()),
+            None => sdk::s3_client(config),
+        })
+    }
+
+    /// Returns the rate limiter that enforces `--limit-rate`, if it is given.
+    fn rate_limiter(&self) -> Option<RateLimiter> {
+        self.rate_limiter.clone().or_else(|| {
+            self.limit_rate
+                .map(|rate| RateLimiter::new(rate, self.clock.clone()))
+        })
+    }
+
+    /// Verifies that the parts of the upload, which are always held in memory if they are
+    /// encrypted, fit into the memory limit.
+    fn verify_memory_limit(&self, state: &State) -> Result<()> {
+        if state.encryption.is_some() && state.part_size + encryption::TAG_LEN > self.memory_limit {
+            bail!(
+                "Encrypted parts are held in memory, but the part size of {} exceeds the memory limit of {}, raise it with `--memory-limit`",
+                size::format_size(state.part_size),
+                size::format_size(self.memory_limit),
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Default for TransferOptions {
+    /// The defaults of the command line options.
+    fn default() -> Self {
+        #[derive(Parser)]
+        struct Defaults {
+            #[command(flatten)]
+            transfer_options: TransferOptions,
+        }
+        Defaults::parse_from(["persevere"]).transfer_options
+    }
+}
+
+#[derive(Debug, Args)]
+struct Upload {
+    /// Path to the local file to upload, as an alternative to `--file-to-upload`.
+    #[arg(
+        value_name = "FILE",
+        required_unless_present = "file_to_upload",
+        conflicts_with = "file_to_upload"
+    )]
+    file: Option<PathBuf>,
+    /// The S3 URI to upload the file to, e.g. `s3://my-bucket/path/big.iso`, as an alternative to
+    /// `--s3-bucket` and `--s3-key`.
+    ///
+    /// If the key is empty or ends with `/`, the name of the file is appended to it.
+    #[arg(
+        value_name = "S3_URI",
+        requires = "file",
+        conflicts_with_all = ["s3_bucket", "s3_key"]
+    )]
+    destination: Option<S3Uri>,
+    /// The name of the S3 bucket to upload the file to.
+    #[arg(long, required_unless_present = "destination", requires = "s3_key")]
+    s3_bucket: Option<String>,
+    /// The S3 key where to upload the file to.
+    #[arg(long, required_unless_present = "destination", requires = "s3_bucket")]
+    s3_key: Option<String>,
+    /// Path to the local file to upload to S3, or `-` to upload the data piped into stdin.
+    ///
+    /// Data read from stdin is spilled to disk part by part (see `--spill-dir`), so that failed
+    /// parts can be retried and interrupted uploads resumed. To resume such an upload, pipe the
+    /// remaining data, starting at the byte offset logged when the upload was interrupted, into the
+    /// `resume` command.
+    #[arg(long, required_unless_present = "file")]
+    file_to_upload: Option<PathBuf>,
+    /// Explicit part-size to use, e.g. `64MiB` or `1GiB`.
+    ///
+    /// If not provided, Persevere will choose the smallest part-size possible by default, which is
+    /// either 5 MB (the minimum S3 requires) or the smallest each part can be to allow the file to
+    /// be uploaded within 10,000 parts (the maximum S3 allows).
+    ///
+    /// Smaller part-sizes make you lose less progress in case something fails, but it usually also
+    /// means that you might not achieve as much throughput as your network would allow. In cases
+    /// where you want to optimize for throughput, and don't care too much about losing progress
+    /// within an individual part, you can increase the part-size.
+    ///
+    /// The maximum part-size S3 supports is 5 GB. Persevere will inform you if the part-size you
+    /// have chosen is too small for either the file you are trying to upload, or smaller than AWS's
+    /// limit. It will also inform you if you have chosen a part-size that is too large and not
+    /// supported by S3.
+    ///
+    /// With `--encryption-key-file`, the part-size is the size of the encrypted parts uploaded to
+    /// S3, which hold 16 bytes less of the file to make room for the authentication tag.
+    #[arg(long, value_parser = size::parse_size)]
+    override_part_size: Option<u64>,
+    /// Tune the part size to the observed throughput during the upload.
+    ///
+    /// The upload starts with the smallest possible part size, and the size of every following part
+    /// is chosen such that it takes about 30 seconds to upload, shrinking again if parts fail.
+    /// This finds a good trade-off between throughput and the progress lost on failures, without
+    /// you having to choose a part size upfront. Not supported for uploads from stdin.
+    #[arg(long, conflicts_with = "override_part_size")]
+    auto_tune: bool,
+    /// The size the object is expected to have when uploading from stdin or with `--compress`,
+    /// e.g. `20GiB`, to choose the part-size for.
+    ///
+    /// The size of a stream is only known once it has ended, so by default its parts are chosen
+    /// large enough for the largest object S3 supports (5 TiB), which makes for parts of about
+    /// 525 MiB. With this option, the smallest part-size that allows for an object of the given
+    /// size is chosen instead. The stream may end up smaller than expected, but the upload fails
+    /// once the stream exceeds the 10,000 parts S3 allows, so leave some headroom. Ignored for
+    /// uploads of files that aren't compressed, whose size is known.
+    #[arg(long, value_name = "SIZE", value_parser = size::parse_size, conflicts_with = "auto_tune")]
+    expected_object_size: Option<u64>,
+    /// Path to where the state-file will be saved.
+    ///
+    /// The state-file is used to make resumable uploads possible. It will automatically be removed
+    /// if the upload finishes successfully.
+    ///
+    /// Files smaller than the minimum part-size of 5 MiB are uploaded with a single request, for
+    /// which no state-file is written: should such an upload fail, simply run it again.
+    ///
+    /// Defaults to a file in `$XDG_STATE_HOME/persevere/uploads/` (or
+    /// `~/.local/state/persevere/uploads/`) named after a hash of the bucket, key and file, which
+    /// the `resume` command finds again when given the same bucket, key and file.
+    #[arg(long)]
+    state_file: Option<PathBuf>,
+    /// Additionally store the state of the upload in S3, e.g. `s3://my-bucket/state/big.iso.json`.
+    ///
+    /// The state is stored whenever the state-file is written, which allows resuming the upload
+    /// from another machine that has access to the same file, e.g. through a network mount, by
+    /// providing the same `--state-uri` to `resume`. The state is removed from S3 once the upload
+    /// has finished. Without `--state-file`, the local state-file defaults to a file in
+    /// `$XDG_STATE_HOME/persevere/uploads/` named after a hash of this URI.
+    #[arg(long, value_name = "S3_URI")]
+    state_uri: Option<S3Uri>,
+    /// Directory to spill the parts to when uploading from stdin or with `--compress`.
+    ///
+    /// A directory named after the state-file is created within it, which will hold at most one
+    /// part at a time. Defaults to the directory of the state-file.
+    #[arg(long)]
+    spill_dir: Option<PathBuf>,
+    /// Label to attach to the upload, in the form `key=value`.
+    ///
+    /// Labels are stored in the state-file and the transfer history, and allow you to attribute
+    /// transfers, e.g. to a team (`--label team=genomics`). They are not sent to S3. This option
+    /// can be provided multiple times.
+    #[arg(long = "label", value_name = "KEY=VALUE", value_parser = parse_key_value)]
+    labels: Vec<(String, String)>,
+    #[command(flatten)]
+    object_options: ObjectOptions,
+    /// Additional HTTP header to send with every S3 request of the upload, in the form
+    /// `name: value`.
+    ///
+    /// This is an escape hatch that allows you to use S3 features or extensions of S3-compatible
+    /// services that Persevere doesn't support through dedicated options yet. Only extension headers
+    /// starting with `x-` are allowed, and headers managed by Persevere itself (like
+    /// `x-amz-date` or `x-amz-checksum-*`) can't be overridden. The headers are stored in the
+    /// state-file and will be used for resuming or aborting the upload as well.
+    ///
+    /// This option can be provided multiple times.
+    #[arg(long = "header", value_name = "NAME: VALUE", value_parser = headers::parse_header)]
+    headers: Vec<Header>,
+    /// Confirm that you will be charged for the requests to a requester-pays bucket.
+    ///
+    /// The setting is stored in the state-file and will be used for resuming or aborting the upload
+    /// as well.
+    #[arg(long, value_parser = PossibleValuesParser::new(RequestPayer::values()))]
+    request_payer: Option<String>,
+    /// Verify the integrity of every part end-to-end with a checksum of the given algorithm.
+    ///
+    /// The checksum is calculated locally while the part is read from the file, and sent to S3
+    /// alongside the part. S3 rejects the part if the data it received doesn't match the checksum,
+    /// and Persevere verifies that the checksum S3 calculated matches the one calculated locally.
+    /// The algorithm is stored in the state-file and will be used for resuming the upload as well.
+    /// CRC64NVME is not supported yet, since it requires aws-sdk-s3 1.69.0, while Persevere is
+    /// built with 1.56.0.
+    #[arg(long, value_enum)]
+    checksum_algorithm: Option<ChecksumAlgorithm>,
+    /// Encrypt the file on the client with AES-256-GCM before uploading it, using the 256-bit key
+    /// in the given file.
+    ///
+    /// The key file holds either 32 raw bytes or 64 hex-digits, e.g. as created by
+    /// `openssl rand -hex 32`. Every part is encrypted on its own, so the upload stays resumable.
+    /// The path of the key file and an identifier of the key are stored in the state-file, but not
+    /// the key itself. The information required to decrypt the object, except for the key, is
+    /// stored in its metadata, and `persevere decrypt` decrypts the downloaded object. Parts are
+    /// always buffered in memory to encrypt them, so the part-size has to fit into
+    /// `--memory-limit`. Not supported with `--auto-tune`.
+    #[arg(long, value_name = "KEY_FILE", conflicts_with = "auto_tune")]
+    encryption_key_file: Option<PathBuf>,
+    /// Compress the file on the fly before uploading it, e.g. `zstd` or `zstd:19`.
+    ///
+    /// Only zstd is supported, with levels from 1 to 22 (3 by default). The object's
+    /// `Content-Encoding` is set to `zstd`, unless you provide another one. Like data read from
+    /// stdin, the compressed parts are spilled to disk (see `--spill-dir`) so that they can be
+    /// retried. To resume the upload, the file is compressed again up to where the upload left off,
+    /// which requires the same version of Persevere. Not supported for uploads from stdin or with
+    /// `--auto-tune`.
+    #[arg(long, value_name = "ALGORITHM[:LEVEL]", value_parser = compression::parse_codec, conflicts_with = "auto_tune")]
+    compress: Option<Codec>,
+    /// Upload a file larger than the maximum object size of S3 (5 TiB) as multiple objects.
+    ///
+    /// The file is split into objects of 5 TiB each, which are named after the key with a suffix,
+    /// e.g. `big.img.part0001`, `big.img.part0002`, and uploaded one after another under the same
+    /// state-file. Alongside them, a JSON manifest named `big.img.manifest.json` lists the objects
+    /// and their sizes, which have to be concatenated in order to get the file back. Files that fit
+    /// into a single object are uploaded as usual. Not supported for uploads from stdin or with
+    /// `--auto-tune`, `--encryption-key-file` or `--compress`.
+    #[arg(long, conflicts_with_all = ["auto_tune", "encryption_key_file", "compress"])]
+    split: bool,
+    /// Additionally upload the file to the given S3 URI, e.g. `s3://my-backup-bucket/big.iso`.
+    ///
+    /// The file is only read once: every part is uploaded to all destinations concurrently, each
+    /// through a multipart upload of its own, as long as the part fits into `--memory-limit`.
+    /// Prefix the URI with the region of the bucket if it differs from the one of the upload, e.g.
+    /// `eu-central-1=s3://my-backup-bucket/big.iso`. The progress of every destination is kept in
+    /// the state-file, so that resuming the upload only uploads the parts each destination is
+    /// missing. This option can be provided multiple times. Not supported for uploads from stdin or
+    /// with `--auto-tune`, `--compress` or `--split`.
+    #[arg(
+        long,
+        value_name = "[REGION=]S3_URI",
+        value_parser = replicate::parse_target,
+        conflicts_with_all = ["auto_tune", "compress", "split"],
+    )]
+    replicate_to: Vec<ReplicaTarget>,
+    /// Print the result of the upload to stdout once it has finished.
+    ///
+    /// With `json`, a single JSON document is printed: the bucket, key, ETag, version ID, size,
+    /// duration, number of parts and checksums of the object on success, or the error and whether
+    /// the upload can be resumed on failure.
+    #[arg(long, value_enum, default_value_t)]
+    output: OutputFormat,
+    #[command(flatten)]
+    transfer_options: TransferOptions,
+}
+
+impl Upload {
+    async fn run(self) -> Result<()> {
+        let output = self.output;
+        let result = self.transfer().await?;
+        output.print_result(&result);
+        Ok(())
+    }
+
+    async fn transfer(mut self) -> Result<TransferResult> {
+        debug!("Running upload command: {:?}", self);
+        let started = Instant::now();
+        let (mut file_to_upload, s3_bucket, s3_key) = self.source_and_destination()?;
+        let from_stdin = file_to_upload == Path::new(spill::STDIN);
+        if !from_stdin {
+            file_to_upload = file_to_upload
+                .canonicalize()
+                .context("Failed to canonicalize file path")
+                .into_unrecoverable()?;
+        }
+        let state_file = match (self.state_file.take(), &self.state_uri) {
+            (Some(state_file), _) => state_file,
+            (None, state_uri) => {
+                let state_file = match state_uri {
+                    Some(state_uri) => state_home::remote_state_file(state_uri).await?,
+                    None => {
+                        state_home::default_state_file(&s3_bucket, &s3_key, &file_to_upload).await?
+                    }
+                };
+                info!("Using state-file: {}", state_file.display());
+                state_file
+            }
+        };
+
+        let config = self.transfer_options.load_config().await?;
+        let s3 = headers::with_headers(&self.transfer_options.s3_client(&config), &self.headers);
+        let store = StateStore::new(state_file).with_remote(
+            self.transfer_options.s3_client(&config),
+            self.state_uri.take(),
+        );
+        let _lock = StateLock::acquire(store.file())?;
+
+        debug!("Verifying that the state-file doesn't exist yet. If it does, we don't allow the start of a new upload against the same file.");
+        if store.exists().await? {
+            bail!("The state-file already exists, and we don't allow starting a new upload against the same file. If you want to resume the upload, use the 'resume' command instead. If you want to start a new upload, please remove the state-file first, or use a different one.");
+        }
+
+        let compression = match self.compress {
+            Some(_) if from_stdin => {
+                bail!("Compressing with `--compress` is not supported for uploads from stdin, compress the data before piping it into Persevere instead");
+            }
+            Some(codec) => {
+                let file = tokio::fs::File::open(&file_to_upload)
+                    .await
+                    .into_unrecoverable()?;
+                let file_size = file.metadata().await.into_unrecoverable()?.len();
+                Some(Compression::new(codec, file_size))
+            }
+            None => None,
+        };
+        // The size of a compressed file is only known once it has been compressed completely, so
+        // it is uploaded like a stream read from stdin.
+        let spill_directory = if from_stdin || compression.is_some() {
+            if self.auto_tune {
+                bail!("Tuning the part size with `--auto-tune` is not supported for uploads from stdin");
+            }
+            Some(spill::create_directory(self.spill_dir.as_deref(), store.file()).await?)
+        } else {
+            None
+        };
+
+        let file_size_in_bytes = if spill_directory.is_some() {
+            // The size is only known once the stream has been read completely.
+            0
+        } else {
+            let file = tokio::fs::File::open(&file_to_upload)
+                .await
+                .into_unrecoverable()?;
+            file.metadata().await.into_unrecoverable()?.len()
+        };
+        let split = if file_size_in_bytes > MAXIMUM_OBJECT_SIZE {
+            if !self.split {
+                bail!("File exceeds the maximum object size of S3 and thus can't be uploaded as a single object, use `--split` to upload it as multiple objects")
+            }
+            Some(Split::new(s3_key.clone(), file_size_in_bytes))
+        } else {
+            if self.split {
+                info!("File fits into a single object, uploading it without splitting it");
+            }
+            None
+        };
+        let replicas = std::mem::take(&mut self.replicate_to)
+            .into_iter()
+            .map(|target| Replica::new(target, &file_to_upload))
+            .collect::<Result<Vec<_>>>()?;
+        if !replicas.is_empty() && from_stdin {
+            bail!("Replicating the upload with `--replicate-to` is not supported for uploads from stdin");
+        }
+        if !replicas.is_empty() && file_size_in_bytes == 0 {
+            bail!("Replicating the upload with `--replicate-to` is not supported for empty files");
+        }
+        // Files smaller than the minimum part size can't be uploaded through a multipart upload, so
+        // they are uploaded as a single part with a regular `PutObject` request instead. Replicated
+        // files are always uploaded through multipart uploads, which can consist of a single part
+        // of any size.
+        let single_request = spill_directory.is_none()
+            && file_size_in_bytes < MINIMUM_PART_SIZE
+            && replicas.is_empty();
+        // Every encrypted part grows by its authentication tag, which the parts uploaded to S3 have
+        // to make room for: they are chosen for the largest size the encrypted file can have, and
+        // hold the tag's length less of the file.
+        let encryption_overhead = if self.encryption_key_file.is_some() && !single_request {
+            encryption::TAG_LEN
+        } else {
+            0
+        };
+        let part_size = if single_request {
+            file_size_in_bytes
+        } else if spill_directory.is_some() {
+            // Without knowing the size of the stream upfront, the part size has to allow for the
+            // largest object S3 supports, or the largest size a compressed file can have, unless
+            // the expected size or the part size is chosen explicitly.
+            let max_stream_size = compression
+                .as_ref()
+                .map_or(MAXIMUM_OBJECT_SIZE, |compression| {
+                    compression.max_compressed_size().min(MAXIMUM_OBJECT_SIZE)
+                });
+            let stream_size = match (self.expected_object_size, self.override_part_size) {
+                (Some(expected_object_size), _) => expected_object_size.min(max_stream_size),
+                (None, Some(part_size)) => part_size,
+                (None, None) => max_stream_size,
+            };
+            let part_size = parts::choose_part_size(
+                stream_size + encryption_overhead * MAXIMUM_NUMBER_OF_PARTS,
+                self.override_part_size,
+            )?;
+            let supported_size = (part_size * MAXIMUM_NUMBER_OF_PARTS).min(MAXIMUM_OBJECT_SIZE);
+            if supported_size >= max_stream_size {
+                debug!("The part size of {} allows for any stream", part_size);
+            } else if self.expected_object_size.is_some() {
+                info!(
+                    "Uploading the stream in parts of {}, which allows for up to {}",
+                    size::format_size(part_size),
+                    size::format_size(supported_size),
+                );
+            } else {
+                warn!(
+                    "With a part size of {}, the upload fails if the stream exceeds {} ({} parts), use `--expected-object-size` to allow for larger streams",
+                    size::format_size(part_size),
+                    size::format_size(supported_size),
+                    MAXIMUM_NUMBER_OF_PARTS,
+                );
+            }
+            part_size
+        } else {
+            if self.expected_object_size.is_some() {
+                info!("The size of the file is known, ignoring `--expected-object-size`");
+            }
+            // The parts of a split file are chosen for the objects it is split into.
+            parts::choose_part_size(
+                file_size_in_bytes.min(MAXIMUM_OBJECT_SIZE)
+                    + encryption_overhead * MAXIMUM_NUMBER_OF_PARTS,
+                self.override_part_size,
+            )?
+        };
+        let part_size = part_size - encryption_overhead;
+
+        let encryption = self
+            .encryption_key_file
+            .as_deref()
+            .map(Encryption::new)
+            .transpose()?;
+        let mut object_options = self.object_options;
+        if let Some(compression) = &compression {
+            object_options
+                .content_encoding
+                .get_or_insert_with(|| compression.content_encoding().to_owned());
+        }
+        if let Some(encryption) = &encryption {
+            object_options
+                .metadata
+                .extend(encryption.metadata(part_size));
+        }
+
+        let mut state = State {
+            version: migration::STATE_VERSION,
+            s3_bucket,
+            s3_key,
+            file_to_upload,
+            file_size_in_bytes,
+            part_size,
+            number_of_parts: if single_request {
+                1
+            } else if spill_directory.is_some() {
+                0
+            } else {
+                PartPlan::new(file_size_in_bytes, part_size).number_of_parts()
+            },
+            upload_id: String::new(),
+            last_successful_part: 0,
+            completed_parts: vec![],
+            labels: self.labels.into_iter().collect(),
+            object_options,
+            headers: self.headers,
+            spill_directory,
+            request_payer: self.request_payer,
+            checksum_algorithm: self.checksum_algorithm,
+            fingerprint: None,
+            auto_tune: self.auto_tune.then(|| AutoTune::new(part_size)),
+            aws_profile: Some(self.transfer_options.profile_name()),
+            aws_region: match &self.transfer_options.client {
+                Some(client) => client.config().region().map(ToString::to_string),
+                None => config.region().map(ToString::to_string),
+            },
+            use_fips_endpoint: Some(config.use_fips().unwrap_or(false)),
+            use_dualstack_endpoint: Some(config.use_dual_stack().unwrap_or(false)),
+            copy_source: None,
+            encryption,
+            compression,
+            archive: None,
+            split,
+            replicas,
+        };
+
+        if single_request {
+            info!(
+                "File is smaller than the minimum part size of {} bytes, uploading it with a single request",
+                MINIMUM_PART_SIZE,
+            );
+            return put_object_and_record(&s3, &mut state, &self.transfer_options, started).await;
+        }
+        if let Some(plan) = state.fingerprint_plan() {
+            state.fingerprint = Some(Fingerprint::of(&state.file_to_upload, plan).await?);
+        }
+
+        self.transfer_options.verify_memory_limit(&state)?;
+        split::start_object(&mut state);
+        create_multipart_upload(&s3, &mut state).await?;
+
+        upload_and_record(
+            &s3,
+            "upload",
+            &store,
+            &mut state,
+            &self.transfer_options,
+            started,
+        )
+        .await
+    }
+
+    /// Returns the file to upload and the bucket and key to upload it to, whether they were
+    /// provided as positional arguments or through the options.
+    fn source_and_destination(&mut self) -> Result<(PathBuf, String, String)> {
+        let Some(file_to_upload) = self.file.take().or_else(|| self.file_to_upload.take()) else {
+            bail!("No file to upload was provided");
+        };
+        let (s3_bucket, s3_key) = match self.destination.take() {
+            Some(destination) => {
+                // There is no file name to append for data read from stdin.
+                let file_name = if file_to_upload == Path::new(spill::STDIN) {
+                    None
+                } else {
+                    file_to_upload
+                        .file_name()
+                        .and_then(|file_name| file_name.to_str())
+                };
+                let Some(s3_key) = destination.key_for_file(file_name) else {
+                    bail!(
+                        "The S3 URI {} doesn't include the key to upload the file to",
+                        destination,
+                    );
+                };
+                (destination.bucket, s3_key)
+            }
+            None => match (self.s3_bucket.take(), self.s3_key.take()) {
+                (Some(s3_bucket), Some(s3_key)) => (s3_bucket, s3_key),
+                _ => bail!("No S3 bucket and key to upload the file to were provided"),
+            },
+        };
+        Ok((file_to_upload, s3_bucket, s3_key))
+    }
+}
+
+#[derive(Debug, Args)]
+struct Resume {
+    /// Path to where the state-file of a previous upload.
+    ///
+    /// This state-file is used to resume the upload in question. The state-file will automatically
+    /// be removed if the upload finishes successfully.
+    ///
+    /// If the upload was started without `--state-file`, provide the same `--s3-bucket`, `--s3-key`
+    /// and `--file-to-upload` instead, which its state-file is found by.
+    #[arg(
+        long,
+        required_unless_present_any = ["state_uri", "s3_bucket"],
+        conflicts_with_all = ["s3_bucket", "s3_key", "file_to_upload"]
+    )]
+    state_file: Option<PathBuf>,
+    /// The S3 URI the state of the upload is stored at, if it was started with `--state-uri`.
+    ///
+    /// The state is read from S3 unless the local state-file exists, which allows resuming the
+    /// upload on another machine than the one it was started on.
+    #[arg(
+        long,
+        value_name = "S3_URI",
+        conflicts_with_all = ["s3_bucket", "s3_key", "file_to_upload"]
+    )]
+    state_uri: Option<S3Uri>,
+    /// The name of the S3 bucket the file is uploaded to, to find the default state-file by.
+    #[arg(long, requires_all = ["s3_key", "file_to_upload"])]
+    s3_bucket: Option<String>,
+    /// The S3 key the file is uploaded to, to find the default state-file by.
+    #[arg(long, requires_all = ["s3_bucket", "file_to_upload"])]
+    s3_key: Option<String>,
+    /// Path to the local file that is uploaded, to find the default state-file by.
+    #[arg(long, requires_all = ["s3_bucket", "s3_key"])]
+    file_to_upload: Option<PathBuf>,
+    /// Resume the upload even if the file appears to have been modified since the upload was
+    /// started.
+    ///
+    /// Only use this if you are certain the contents of the file are still the same, e.g. because
+    /// the file was merely copied or touched. Otherwise, the object in S3 will be corrupt.
+    ///
+    /// Encrypted uploads can't be forced: a part of a changed file would be encrypted with the
+    /// same nonce as before, which breaks the encryption.
+    #[arg(long)]
+    force: bool,
+    /// The file holding the key the upload is encrypted with, if it has been moved since the
+    /// upload was started with `--encryption-key-file`.
+    #[arg(long, value_name = "KEY_FILE")]
+    encryption_key_file: Option<PathBuf>,
+    /// Print the result of the upload to stdout once it has finished.
+    ///
+    /// With `json`, a single JSON document is printed: the bucket, key, ETag, version ID, size,
+    /// duration, number of parts and checksums of the object on success, or the error and whether
+    /// the upload can be resumed on failure.
+    #[arg(long, value_enum, default_value_t)]
+    output: OutputFormat,
+    #[command(flatten)]
+    transfer_options: TransferOptions,
+}
+
+/// Warns if the upload is resumed with a different AWS profile or region than it was started with,
+/// since the multipart upload most likely can't be found through them.
+fn warn_on_changed_aws_environment(state: &State, profile: &str, config: &SdkConfig) {
+    if let Some(aws_profile) = &state.aws_profile {
+        let current = profile;
+        if *aws_profile != current {
+            warn!(
+                "The upload was started with the AWS profile {}, but is resumed with the profile {}. Use `--profile {}` if the upload can't be found.",
+                aws_profile, current, aws_profile,
+            );
+        }
+    }
+    if let Some(aws_region) = &state.aws_region {
+        let current = config.region().map(ToString::to_string);
+        if current.as_ref() != Some(aws_region) {
+            warn!(
+                "The upload was started in the AWS region {}, but is resumed in the region {}. Use `--region {}` if the upload can't be found.",
+                aws_region,
+                current.as_deref().unwrap_or("<none>"),
+                aws_region,
+            );
+        }
+    }
+}
+
+impl Resume {
+    async fn run(&self) -> Result<()> {
+        let result = self.transfer().await?;
+        self.output.print_result(&result);
+        Ok(())
+    }
+
+    async fn transfer(&self) -> Result<TransferResult> {
+        debug!("Running resume command: {:?}", self);
+        let started = Instant::now();
+
+        let config = self.transfer_options.load_config().await?;
+        let store = match &self.state_file {
+            None if self.state_uri.is_none() => StateStore::new(self.default_state_file().await?),
+            state_file => {
+                StateStore::open(
+                    state_file.clone(),
+                    self.state_uri.clone(),
+                    self.transfer_options.s3_client(&config),
+                )
+                .await?
+            }
+        };
+        let _lock = StateLock::acquire(store.file())?;
+        let mut state = store.read().await?;
+        warn_on_changed_aws_environment(&state, &self.transfer_options.profile_name(), &config);
+        // The part that was in flight would be encrypted again under the same key and nonce, but
+        // with different plaintext, which would reveal both plaintexts and allow forging parts.
+        if self.force && state.encryption.is_some() {
+            bail!(
+                "An encrypted upload can't be resumed with `--force`, as parts of a changed file would be encrypted with nonces that have been used already. Abort the upload and start it again instead. Upload ID: {}",
+                state.upload_id,
+            );
+        }
+        match (&mut state.encryption, &self.encryption_key_file) {
+            (Some(encryption), Some(key_file)) => {
+                let previous_key_file = std::mem::replace(
+                    &mut encryption.key_file,
+                    key_file
+                        .canonicalize()
+                        .context("Failed to canonicalize the path of the key file")
+                        .into_unrecoverable()?,
+                );
+                // A wrong key must not replace the key file in the state-file.
+                if let Err(error) = encryption.cipher() {
+                    encryption.key_file = previous_key_file;
+                    return Err(error);
+                }
+            }
+            (None, Some(_)) => {
+                bail!("The upload is not encrypted, so `--encryption-key-file` can't be used");
+            }
+            _ => {}
+        }
+        self.transfer_options.verify_memory_limit(&state)?;
+        if let (Some(spill_directory), true) = (&state.spill_directory, state.reads_stdin()) {
+            let stream_offset = spill::stream_offset(
+                spill_directory,
+                state.last_successful_part + 1,
+                state.file_size_in_bytes,
+            )
+            .await?;
+            info!(
+                "Resuming an upload from stdin. The data piped into this command must start at byte offset {} of the original stream.",
+                stream_offset,
+            );
+        } else if let Some(copy_source) = &state.copy_source {
+            debug!("Resuming a copy from {:?}", copy_source);
+        } else if let Some(archive) = &state.archive {
+            archive.verify(&state).await?;
+        } else {
+            let current_file_size_in_bytes = {
+                let file = tokio::fs::File::open(&state.file_to_upload)
+                    .await
+                    .into_unrecoverable()?;
+                file.metadata().await.into_unrecoverable()?.len()
+            };
+            let file_size_in_bytes = state.local_file_size();
+            if current_file_size_in_bytes != file_size_in_bytes {
+                bail!(
+                "The file has changed since the last upload. The file size was {} bytes, but is now {} bytes. The upload cannot be resumed, and should be aborted! Upload ID: {}",
+                file_size_in_bytes,
+                current_file_size_in_bytes,
+                    state.upload_id,
+                );
+            }
+            self.verify_fingerprint(&store, &mut state).await?;
+        }
+
+        let s3 = headers::with_headers(
+            &self.transfer_options.s3_client(&state.sdk_config(&config)),
+            &state.headers,
+        );
+
+        if reconcile::reconcile(&s3, &mut state).await? {
+            store.write(&mut state).await?;
+        }
+
+        upload_and_record(
+            &s3,
+            "resume",
+            &store,
+            &mut state,
+            &self.transfer_options,
+            started,
+        )
+        .await
+    }
+
+    /// Verifies that the file has not been modified since the upload was started, which the size
+    /// of the file alone can't tell.
+    async fn verify_fingerprint(&self, store: &StateStore, state: &mut State) -> Result<()> {
+        let (Some(fingerprint), Some(plan)) = (&state.fingerprint, state.fingerprint_plan()) else {
+            debug!("The state-file has no fingerprint of the file, skipping verification");
+            return Ok(());
+        };
+        let current = Fingerprint::of(&state.file_to_upload, plan).await?;
+        let Some(difference) = fingerprint.difference(&current) else {
+            return Ok(());
+        };
+        if !self.force {
+            bail!(
+                "The file has changed since the last upload: {}. The upload cannot be resumed, and should be aborted! If you are certain the contents of the file are unchanged, you can resume the upload with `--force`. Upload ID: {}",
+                difference,
+                state.upload_id,
+            );
+        }
+        warn!(
+            "The file has changed since the last upload ({}), resuming anyway as requested.",
+            difference,
+        );
+        // The forced resume accepts the file as it is now, so later resumes compare against it.
+        state.fingerprint = Some(current);
+        store.write(state).await
+    }
+
+    /// Returns the default state-file of the upload of the file to the bucket and key.
+    async fn default_state_file(&self) -> Result<PathBuf> {
+        let (Some(s3_bucket), Some(s3_key), Some(file_to_upload)) =
+            (&self.s3_bucket, &self.s3_key, &self.file_to_upload)
+        else {
+            bail!("Either the state-file, the state URI or the bucket, key and file of the upload have to be provided");
+        };
+        let file_to_upload = if file_to_upload == Path::new(spill::STDIN) {
+            file_to_upload.clone()
+        } else {
+            file_to_upload
+                .canonicalize()
+                .context("Failed to canonicalize file path")
+                .into_unrecoverable()?
+        };
+        let state_file = state_home::default_state_file(s3_bucket, s3_key, &file_to_upload).await?;
+        if !tokio::fs::try_exists(&state_file)
+            .await
+            .into_unrecoverable()?
+        {
+            bail!(
+                "There is no upload of {} to s3://{}/{} to resume: the state-file {} doesn't exist",
+                file_to_upload.display(),
+                s3_bucket,
+                s3_key,
+                state_file.display(),
+            );
+        }
+        info!("Using state-file: {}", state_file.display());
+        Ok(state_file)
+    }
+}
+
+#[derive(Debug, Args)]
+struct Abort {
+    /// Path to where the state-file of a previous upload.
+    ///
+    /// This state-file is used to abort the upload in question. The state-file will automatically
+    /// be removed after the upload has been aborted, unless `--keep-state-file` is given.
+    #[arg(long, required_unless_present = "state_uri")]
+    state_file: Option<PathBuf>,
+    /// The S3 URI the state of the upload is stored at, if it was started with `--state-uri`.
+    ///
+    /// The state is removed from S3 as well after the upload has been aborted.
+    #[arg(long, value_name = "S3_URI")]
+    state_uri: Option<S3Uri>,
+    /// Keep the state-file (and the state in S3) after the upload has been aborted, e.g. to keep a
+    /// record of it.
+    #[arg(long)]
+    keep_state_file: bool,
+    /// Only show how many parts and bytes aborting the upload would discard, without aborting it.
+    #[arg(long)]
+    dry_run: bool,
+    /// Abort the upload without asking for confirmation first.
+    ///
+    /// This is required if stdin is not a terminal, e.g. when running from scripts.
+    #[arg(long, short)]
+    yes: bool,
+    /// The S3 client to abort the upload with instead of one created from the AWS configuration,
+    /// if the upload is aborted through the library.
+    #[arg(skip)]
+    client: Option<aws_sdk_s3::Client>,
+    /// Whether aborting the upload is recorded in the transfer history. Embedders opt into it
+    /// through the library.
+    #[arg(skip = true)]
+    record_history: bool,
+}
+
+impl Abort {
+    async fn run(&self) -> Result<()> {
+        debug!("Running abort command: {:?}", self);
+        let started = Instant::now();
+
+        let config = sdk::load_config().await;
+        let store = StateStore::open(
+            self.state_file.clone(),
+            self.state_uri.clone(),
+            self.client
+                .clone()
+                .unwrap_or_else(|| sdk::s3_client(&config)),
+        )
+        .await?;
+        let _lock = StateLock::acquire(store.file())?;
+        let state = store.read().await?;
+        let s3 = match &self.client {
+            Some(client) => headers::with_headers(client, &state.headers),
+            None => headers::s3_client(&state.sdk_config(&config), &state.headers),
+        };
+
+        // Listing the parts verifies that the multipart upload still exists, and tells what
+        // aborting it discards.
+        let uploaded_parts: Option<Vec<_>> = match s3
+            .list_parts()
+            .bucket(&state.s3_bucket)
+            .key(&state.s3_key)
+            .upload_id(&state.upload_id)
+            .set_request_payer(state.request_payer())
+            .into_paginator()
+            .items()
+            .send()
+            .try_collect()
+            .await
+        {
+            Ok(uploaded_parts) => Some(uploaded_parts),
+            Err(error) if error.code() == Some("NoSuchUpload") => {
+                info!(
+                    "The multipart upload with ID {} for s3://{}/{} no longer exists, so there is nothing to abort.",
+                    state.upload_id, state.s3_bucket, state.s3_key,
+                );
+                if !self.dry_run && !self.keep_state_file {
+                    self.remove_state(&store, &state).await?;
+                }
+                return Ok(());
+            }
+            // Aborting didn't need this permission so far, which is why we don't want to fail
+            // just because of it.
+            Err(error) if error.code() == Some("AccessDenied") => {
+                warn!(
+                    "Not allowed to list the parts of the multipart upload (`s3:ListMultipartUploadParts`), so it can't be shown what aborting it discards."
+                );
+                None
+            }
+            Err(error) => {
+                return Err(error)
+                    .context("Failed to list the parts of the multipart upload")
+                    .into_retryable()
+            }
+        };
+        if let Some(uploaded_parts) = uploaded_parts {
+            info!(
+                "Aborting the multipart upload with ID {} for s3://{}/{} discards {} parts ({}).",
+                state.upload_id,
+                state.s3_bucket,
+                state.s3_key,
+                uploaded_parts.len(),
+                size::format_size(
+                    uploaded_parts
+                        .iter()
+                        .map(|part| part.size().unwrap_or_default() as u64)
+                        .sum()
+                ),
+            );
+        }
+        if self.dry_run {
+            return Ok(());
+        }
+        if !self.yes && !confirm("Abort the upload?").await? {
+            info!("Not aborting the upload.");
+            return Ok(());
+        }
+
+        s3.abort_multipart_upload()
+            .bucket(&state.s3_bucket)
+            .key(&state.s3_key)
+            .upload_id(&state.upload_id)
+            .set_request_payer(state.request_payer())
+            .send()
+            .await
+            .into_retryable()?;
+        info!(
+            "Aborted multipart upload with ID {} for: s3://{}/{}",
+            state.upload_id, state.s3_bucket, state.s3_key,
+        );
+        replicate::abort(&replicate::clients(&s3, &state), &state).await?;
+        if self.record_history {
+            history::record(history::Entry::new(
+                "abort",
+                history::Outcome::Aborted,
+                &state,
+                started.elapsed(),
+            ))
+            .await;
+        }
+
+        if !self.keep_state_file {
+            self.remove_state(&store, &state).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes the state of the aborted upload, along with the parts spilled to disk.
+    async fn remove_state(&self, store: &StateStore, state: &State) -> Result<()> {
+        store.remove().await?;
+        if let Some(spill_directory) = &state.spill_directory {
+            spill::remove_directory(spill_directory).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Asks on the terminal whether to go ahead, which only an answer of `y` or `yes` confirms.
+async fn confirm(question: &str) -> Result<bool> {
+    if !std::io::stdin().is_terminal() {
+        bail!("Can't ask for confirmation, as stdin is not a terminal. Pass `--yes` to confirm upfront.");
+    }
+    eprint!("{} [y/N] ", question);
+    let mut answer = String::new();
+    tokio::io::BufReader::new(tokio::io::stdin())
+        .read_line(&mut answer)
+        .await
+        .into_unrecoverable()?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+#[derive(Debug, Args)]
+struct Pause {
+    /// Path to the state-file of the running upload.
+    #[arg(long)]
+    state_file: PathBuf,
+}
+
+impl Pause {
+    async fn run(&self) -> Result<()> {
+        debug!("Running pause command: {:?}", self);
+
+        let state = State::from_file(&self.state_file).await?;
+        let pause_request_file = pause_request_file(&self.state_file);
+        debug!(
+            "Creating pause request file: {}",
+            pause_request_file.display()
+        );
+        tokio::fs::write(&pause_request_file, &state.upload_id)
+            .await
+            .context("Failed to create pause request file")
+            .into_unrecoverable()?;
+        info!(
+            "Requested the upload with ID {} for s3://{}/{} to pause. It will pause once the part currently in progress has finished.",
+            state.upload_id, state.s3_bucket, state.s3_key,
+        );
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Args)]
+struct History {
+    /// Only show transfers to this S3 bucket.
+    #[arg(long)]
+    s3_bucket: Option<String>,
+    /// Only show transfers with this outcome.
+    #[arg(long, value_enum)]
+    outcome: Option<history::Outcome>,
+    /// Only show transfers that have this label, in the form `key=value`.
+    ///
+    /// If provided multiple times, transfers have to match all labels.
+    #[arg(long = "label", value_name = "KEY=VALUE", value_parser = parse_key_value)]
+    labels: Vec<(String, String)>,
+    /// Only show the most recent number of matching transfers.
+    #[arg(long)]
+    last: Option<usize>,
+    /// Print the matching entries as JSON, one document per line.
+    #[arg(long)]
+    json: bool,
+}
+
+impl History {
+    async fn run(&self) -> Result<()> {
+        debug!("Running history command: {:?}", self);
+
+        let Some(history_file) = history::history_file() else {
+            bail!("Unable to determine the location of the history file. Set PERSEVERE_HISTORY_FILE to provide it explicitly.");
+        };
+        let mut entries: Vec<_> = history::read(&history_file)
+            .await?
+            .into_iter()
+            .filter(|entry| {
+                self.s3_bucket
+                    .as_ref()
+                    .is_none_or(|s3_bucket| &entry.s3_bucket == s3_bucket)
+            })
+            .filter(|entry| self.outcome.is_none_or(|outcome| entry.outcome == outcome))
+            .filter(|entry| {
+                self.labels
+                    .iter()
+                    .all(|(key, value)| entry.labels.get(key) == Some(value))
+            })
+            .collect();
+        if let Some(last) = self.last {
+            entries.drain(..entries.len().saturating_sub(last));
+        }
+
+        for entry in entries {
+            if self.json {
+                println!(
+                    "{}",
+                    serde_json::to_string(&entry)
+                        .context("Failed to serialize history entry")
+                        .into_unrecoverable()?
+                );
+            } else {
+                println!("{}", entry.summary());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The multipart upload a part is uploaded to, which is the one of the state or the one of a
+/// replica.
+#[derive(Clone, Copy)]
+struct Destination<'a> {
+    s3: &'a aws_sdk_s3::Client,
+    s3_bucket: &'a str,
+    s3_key: &'a str,
+    upload_id: &'a str,
+}
+
+impl<'a> Destination<'a> {
+    /// Returns the multipart upload of the state.
+    fn of(s3: &'a aws_sdk_s3::Client, state: &'a State) -> Self {
+        Self {
+            s3,
+            s3_bucket: &state.s3_bucket,
+            s3_key: &state.s3_key,
+            upload_id: &state.upload_id,
+        }
+    }
+}
+
+#[tracing::instrument(skip_all)]
+async fn upload_part(
+    destination: Destination<'_>,
+    state: &State,
+    part: Part,
+    buffer: Option<&Bytes>,
+    limiter: Option<&RateLimiter>,
+    reporter: &Arc<dyn ProgressReporter>,
+    options: &TransferOptions,
+) -> Result<CompletedPart> {
+    if let Some(copy_source) = &state.copy_source {
+        return copy_source
+            .copy_part(destination.s3, state, part, reporter)
+            .await;
+    }
+    reporter.part_started(&part, state.number_of_parts);
+    let md5 = Hasher::md5();
+    let hasher = state.checksum_algorithm.map(Hasher::new);
+    let hashers: Vec<_> = std::iter::once(md5.clone()).chain(hasher.clone()).collect();
+    // The buffer holds the ciphertext of encrypted parts, which is larger than the part.
+    let body_size = buffer.map_or(part.size, |buffer| buffer.len() as u64);
+    let stall_detector = StallDetector::new(options.stall_timeout, body_size);
+    let byte_stream = if let Some(buffer) = buffer {
+        debug!("Uploading part from the in-memory buffer");
+        ByteStream::from_reader(
+            stall_detector.reader(ThrottledReader::new(
+                ProgressReader::new(
+                    ChecksumReader::new(std::io::Cursor::new(buffer.clone()), hashers),
+                    part,
+                    Arc::clone(reporter),
+                ),
+                limiter.cloned(),
+            )),
+            body_size,
+            options.buffer_size,
+        )
+    } else {
+        ByteStream::from_reader(
+            stall_detector.reader(ThrottledReader::new(
+                ProgressReader::new(
+                    ChecksumReader::new(open_part(state, &part, options.direct_io).await?, hashers),
+                    part,
+                    Arc::clone(reporter),
+                ),
+                limiter.cloned(),
+            )),
+            part.size,
+            options.buffer_size,
+        )
+    };
+
+    let uploaded_part = stall_detector
+        .send(
+            destination
+                .s3
+                .upload_part()
+                .bucket(destination.s3_bucket)
+                .key(destination.s3_key)
+                .upload_id(destination.upload_id)
+                .part_number(part.number)
+                .set_request_payer(state.request_payer())
+                .set_checksum_algorithm(state.checksum_algorithm.map(|algorithm| algorithm.sdk()))
+                .content_length(body_size as i64)
+                .body(byte_stream)
+                .send(),
+        )
+        .await?;
+    if options.verifies_e_tags(state) {
+        md5.verify_e_tag(
+            &format!("part {}", part.number),
+            uploaded_part.e_tag(),
+            uploaded_part.server_side_encryption(),
+        )?;
+    }
+
+    let completed_part = CompletedPart::builder()
+        .set_checksum_crc32(uploaded_part.checksum_crc32)
+        .set_checksum_crc32_c(uploaded_part.checksum_crc32_c)
+        .set_checksum_sha1(uploaded_part.checksum_sha1)
+        .set_checksum_sha256(uploaded_part.checksum_sha256)
+        .set_e_tag(uploaded_part.e_tag)
+        .part_number(part.number)
+        .build();
+    if let (Some(hasher), Some(algorithm)) = (hasher, state.checksum_algorithm) {
+        hasher.verify(algorithm, &format!("part {}", part.number), &completed_part)?;
+    }
+
+    reporter.part_completed(&part, state.number_of_parts);
+
+    Ok(completed_part)
+}
+
+/// Reader for exactly the bytes of a part, reading through the page cache or with direct I/O.
+type PartReader = Either<tokio::io::Take<tokio::fs::File>, DirectReader>;
+
+/// Opens the file to upload, returning a reader for exactly the bytes of the given part.
+///
+/// Spilled parts are always read through the page cache, as they have just been written.
+async fn open_part(state: &State, part: &Part, direct_io: bool) -> Result<PartReader> {
+    if let Some(spill_directory) = &state.spill_directory {
+        let part_file = spill::part_file(spill_directory, part.number);
+        debug!("Opening spilled part for reading: {}", part_file.display());
+        let file = tokio::fs::File::open(&part_file)
+            .await
+            .into_unrecoverable()?;
+        return Ok(Either::Left(file.take(part.size)));
+    }
+    // The offsets of the parts of split files are relative to the object being uploaded.
+    let offset = part.offset + state.split.as_ref().map_or(0, Split::offset);
+    open_file_part(&state.file_to_upload, offset, part.size, direct_io).await
+}
+
+/// Opens the file for reading `size` bytes starting at `offset`.
+///
+/// With `direct_io`, the file is read with `O_DIRECT` if the platform and file system support it.
+async fn open_file_part(
+    file: &Path,
+    offset: u64,
+    size: u64,
+    direct_io: bool,
+) -> Result<PartReader> {
+    if direct_io {
+        debug!("Opening file for direct reading: {}", file.display());
+        if let Some(reader) = DirectReader::open(file, offset, size)
+            .await
+            .into_unrecoverable()?
+        {
+            return Ok(Either::Right(reader));
+        }
+    }
+    debug!("Opening file for reading: {}", file.display());
+    let mut file = tokio::fs::File::open(file).await.into_unrecoverable()?;
+    debug!("Seeking to the start of the part: {}", offset);
+    file.seek(tokio::io::SeekFrom::Start(offset))
+        .await
+        .into_unrecoverable()?;
+    Ok(Either::Left(file.take(size)))
+}
+
+/// Reads the bytes of the given part into memory.
+async fn read_part(state: &State, part: &Part, direct_io: bool) -> Result<Bytes> {
+    read_exactly(open_part(state, part, direct_io).await?, part).await
+}
+
+/// Reads the bytes of the given part from `reader` into memory, which has to yield all of them.
+///
+/// The buffer has room for the authentication tag, so that an encrypted part can be sealed in
+/// place.
+async fn read_exactly(mut reader: impl AsyncRead + Unpin, part: &Part) -> Result<Bytes> {
+    let mut buffer = Vec::with_capacity((part.size + encryption::TAG_LEN) as usize);
+    reader.read_to_end(&mut buffer).await.into_unrecoverable()?;
+    if buffer.len() as u64 != part.size {
+        bail!(
+            "Expected to read {} bytes for part {}, but only {} bytes could be read. Has the file been modified?",
+            part.size,
+            part.number,
+            buffer.len(),
+        );
+    }
+    Ok(buffer.into())
+}
+
+/// Uploads a file that is too small for a multipart upload with a single `PutObject` request, and
+/// records the outcome in the history.
+///
+/// There is no multipart upload that could be resumed, so no state-file is written: if the upload
+/// fails, it can simply be started again.
+async fn put_object_and_record(
+    s3: &aws_sdk_s3::Client,
+    state: &mut State,
+    options: &TransferOptions,
+    started: Instant,
+) -> Result<TransferResult> {
+    options.notify.verify()?;
+    let reporter = options.reporter(state)?;
+    let result = put_object(s3, state, options, &reporter).await;
+    if result.is_ok() {
+        state.last_successful_part = state.number_of_parts;
+    }
+    reporter.finished(history::Outcome::of(&result));
+
+    let entry = history::Entry::new(
+        "upload",
+        history::Outcome::of(&result),
+        state,
+        started.elapsed(),
+    )
+    .with_e_tag(result.as_ref().ok().and_then(|output| output.e_tag.clone()))
+    .with_error(result.as_ref().err());
+    // Without a multipart upload, there is nothing to resume: the upload is simply run again.
+    notify::send(&options.notify, &entry, None).await;
+    if options.record_history {
+        history::record(entry).await;
+    }
+
+    result.map(|output| {
+        TransferResult::new(
+            state,
+            output.e_tag(),
+            output.version_id(),
+            &output,
+            started.elapsed(),
+        )
+    })
+}
+
+#[tracing::instrument(skip_all)]
+async fn put_object(
+    s3: &aws_sdk_s3::Client,
+    state: &State,
+    options: &TransferOptions,
+    reporter: &Arc<dyn ProgressReporter>,
+) -> Result<PutObjectOutput> {
+    let part = Part {
+        number: 1,
+        offset: 0,
+        size: state.file_size_in_bytes,
+    };
+    reporter.started(state.file_size_in_bytes, 0, state.number_of_parts);
+    // The file is small, so we always read it into memory once, rather than re-reading it from the
+    // file on every attempt.
+    let mut contents = read_part(state, &part, options.direct_io).await?;
+    if let Some(encryption) = &state.encryption {
+        contents = encryption
+            .cipher()?
+            .encrypt_part(part.number, true, contents)?;
+    }
+    let body_size = contents.len() as u64;
+    let limiter = options.rate_limiter();
+
+    let started = options.clock.now();
+    let mut attempt = 1;
+    loop {
+        reporter.part_started(&part, state.number_of_parts);
+        let md5 = Hasher::md5();
+        let hasher = state.checksum_algorithm.map(Hasher::new);
+        let hashers: Vec<_> = std::iter::once(md5.clone()).chain(hasher.clone()).collect();
+        let stall_detector = StallDetector::new(options.stall_timeout, body_size);
+        let request = state
+            .object_options
+            .apply_to_put_object(
+                s3.put_object()
+                    .bucket(&state.s3_bucket)
+                    .key(&state.s3_key)
+                    .set_request_payer(state.request_payer())
+                    .set_checksum_algorithm(
+                        state.checksum_algorithm.map(|algorithm| algorithm.sdk()),
+                    ),
+            )
+            .set_if_none_match(state.object_options.if_none_match())
+            .content_length(body_size as i64)
+            .body(ByteStream::from_reader(
+                stall_detector.reader(ThrottledReader::new(
+                    ProgressReader::new(
+                        ChecksumReader::new(std::io::Cursor::new(contents.clone()), hashers),
+                        part,
+                        Arc::clone(reporter),
+                    ),
+                    limiter.clone(),
+                )),
+                body_size,
+                options.buffer_size,
+            ));
+        let result = stall_detector
+            .send(request.send())
+            .await
+            .map_err(hints::precondition_failed_is_unrecoverable)
+            .and_then(|output| {
+                if options.verifies_e_tags(state) {
+                    md5.verify_e_tag("the file", output.e_tag(), output.server_side_encryption())?;
+                }
+                if let (Some(hasher), Some(algorithm)) = (&hasher, state.checksum_algorithm) {
+                    hasher.verify(algorithm, "the file", &output)?;
+                }
+                Ok(output)
+            });
+        match result {
+            Ok(output) => {
+                reporter.part_completed(&part, state.number_of_parts);
+                info!(target: verbosity::SUMMARY,
+                    "Successfully uploaded the file. ETag: {}",
+                    output.e_tag.as_deref().unwrap_or("<unknown>"),
+                );
+                return Ok(output);
+            }
+            Err(error @ Error::Retryable(_))
+                if options.retry.should_retry(attempt, started, &options.clock) =>
+            {
+                reporter.part_retrying(&part, attempt, &error);
+                options.retry.wait(attempt, &error, &options.clock).await;
+                attempt += 1;
+            }
+            Err(error) => {
+                error!("Failed to upload the file after {} attempts.", attempt);
+                return Err(error);
+            }
+        }
+    }
+}
+
+/// Creates the multipart upload of the object described by the state.
+async fn create_multipart_upload(s3: &aws_sdk_s3::Client, state: &mut State) -> Result<()> {
+    let multipart_upload = state
+        .object_options
+        .apply_to(
+            s3.create_multipart_upload()
+                .bucket(&state.s3_bucket)
+                .key(&state.s3_key)
+                .set_request_payer(state.request_payer())
+                .set_checksum_algorithm(state.checksum_algorithm.map(|algorithm| algorithm.sdk())),
+        )
+        .send()
+        .await
+        .into_retryable()?;
+    state.upload_id = multipart_upload
+        .upload_id
+        .context("Creating multipart upload probably failed, because no upload ID was returned")
+        .into_retryable()?;
+    info!(
+        "Created multipart upload with ID {} for: s3://{}/{}",
+        state.upload_id, state.s3_bucket, state.s3_key,
+    );
+    Ok(())
+}
+
+/// Runs the upload, aborting the multipart upload on unrecoverable errors, and records the outcome
+/// in the history.
+async fn upload_and_record(
+    s3: &aws_sdk_s3::Client,
+    command: &str,
+    store: &StateStore,
+    state: &mut State,
+    options: &TransferOptions,
+    started: Instant,
+) -> Result<TransferResult> {
+    options.notify.verify()?;
+    let reporter = options.reporter(state)?;
+
+    // A pause request that is present before we start uploading is a leftover from a previous run,
+    // which we don't want to act upon.
+    let pause_request_file = pause_request_file(store.file());
+    remove_pause_request_file(&pause_request_file).await?;
+    // A pause request or signal only stops this transfer, not the others sharing the token.
+    let cancellation = options
+        .cancellation
+        .as_ref()
+        .map_or_else(CancellationToken::new, CancellationToken::child_token);
+    let pause_watcher = tokio::spawn(watch_pause_request(
+        pause_request_file,
+        cancellation.clone(),
+    ));
+    let signals = Signals::new();
+    let signal_watcher = options
+        .stop_on_signal
+        .then(|| signals.watch(cancellation.clone()));
+
+    // A split file is uploaded one object after another, all of which but the last are only
+    // recorded in the state, so that the upload can continue with the next one.
+    let result = loop {
+        let result = upload(
+            s3,
+            store,
+            state,
+            options,
+            &reporter,
+            &cancellation,
+            &signals,
+        )
+        .await;
+        let result = settle(s3, state, options, result).await;
+        match (&result, &state.split) {
+            (Ok(output), Some(split)) if !split.is_last_object() => {
+                split::object_completed(state, output.e_tag());
+                store.write(state).await?;
+            }
+            _ => break result,
+        }
+    };
+    pause_watcher.abort();
+    if let Some(signal_watcher) = signal_watcher {
+        signal_watcher.abort();
+    }
+    reporter.finished(history::Outcome::of(&result));
+
+    let entry = history::Entry::new(
+        command,
+        history::Outcome::of(&result),
+        state,
+        started.elapsed(),
+    )
+    .with_e_tag(result.as_ref().ok().and_then(|output| output.e_tag.clone()))
+    .with_error(result.as_ref().err());
+    if options.notify.is_enabled() {
+        let resume_command = match &result {
+            Err(Error::Retryable(_)) => Some(resume_command_after_failure(store, state).await),
+            _ => None,
+        };
+        notify::send(&options.notify, &entry, resume_command.as_deref()).await;
+    }
+    if options.record_history {
+        history::record(entry).await;
+    }
+
+    result.map(|output| {
+        TransferResult::new(
+            state,
+            output.e_tag(),
+            output.version_id(),
+            &output,
+            started.elapsed(),
+        )
+    })
+}
+
+/// Aborts the multipart upload if the upload failed unrecoverably, or verifies the completed
+/// upload otherwise.
+async fn settle(
+    s3: &aws_sdk_s3::Client,
+    state: &State,
+    options: &TransferOptions,
+    result: Result<CompleteMultipartUploadOutput>,
+) -> Result<CompleteMultipartUploadOutput> {
+    match result {
+        // Without an upload ID, the multipart upload was never created, so there is nothing to
+        // abort either.
+        Err(Error::Unrecoverable(err)) if state.upload_id.is_empty() => {
+            Err(Error::Unrecoverable(err))
+        }
+        Err(Error::Unrecoverable(err)) => {
+            error!(
+                "Unrecoverable failure during upload, aborting multipart upload: {}",
+                err,
+            );
+            s3.abort_multipart_upload()
+                .bucket(&state.s3_bucket)
+                .key(&state.s3_key)
+                .upload_id(&state.upload_id)
+                .set_request_payer(state.request_payer())
+                .send()
+                .await
+                .into_retryable()?;
+            replicate::abort(&replicate::clients(s3, state), state).await?;
+            Err(Error::Unrecoverable(err))
+        }
+        // The multipart upload is complete at this point, so there is nothing to abort if the
+        // object S3 assembled turns out not to match the parts that were uploaded.
+        Ok(output) => checksum::verify_multipart_upload(
+            &state.completed_parts,
+            state.checksum_algorithm,
+            options.verifies_e_tags(state),
+            &output,
+        )
+        .map(|_| output),
+        result => result,
+    }
+}
+
+/// Returns the reader of the parts following the current one, if `--readahead` applies to the
+/// upload.
+fn readahead(
+    state: &State,
+    options: &TransferOptions,
+    plan: PartPlan,
+    spilled: bool,
+) -> Option<Readahead> {
+    if options.readahead == 0 {
+        return None;
+    }
+    if spilled || state.auto_tune.is_some() || state.copy_source.is_some() {
+        debug!("The parts of this upload can't be read ahead");
+        return None;
+    }
+    let depth = options
+        .readahead
+        .min(options.memory_limit / state.part_size.max(1));
+    if depth < options.readahead {
+        warn!(
+            "Reading {} parts of {} ahead exceeds the memory limit of {}, reading {} parts ahead instead",
+            options.readahead,
+            size::format_size(state.part_size),
+            size::format_size(options.memory_limit),
+            depth,
+        );
+    }
+    (depth > 0).then(|| {
+        Readahead::new(
+            state.file_to_upload.clone(),
+            state.split.as_ref().map_or(0, Split::offset),
+            plan,
+            depth,
+            options.direct_io,
+        )
+    })
+}
+
+/// Uploads all remaining parts of the file and completes the multipart upload.
+///
+/// Cancelling `cancellation` stops the upload cooperatively: the part currently in progress is
+/// finished and checkpointed in the state-file, after which [`Error::Paused`] is returned, or
+/// [`Error::Interrupted`] if the cancellation came from a signal. A second signal also cancels the
+/// part in progress. The upload can then be resumed from the state-file at any later time.
+#[tracing::instrument(skip_all)]
+async fn upload(
+    s3: &aws_sdk_s3::Client,
+    store: &StateStore,
+    state: &mut State,
+    options: &TransferOptions,
+    reporter: &Arc<dyn ProgressReporter>,
+    cancellation: &CancellationToken,
+    signals: &Signals,
+) -> Result<CompleteMultipartUploadOutput> {
+    debug!(
+        "File size: {} bytes. Part size: {} bytes. Number of parts to upload: {}.",
+        state.file_size_in_bytes, state.part_size, state.number_of_parts,
+    );
+    if state.number_of_parts > MAXIMUM_PART_NUMBER {
+        bail!("The number of parts exceeds the maximum number of parts allowed by S3");
+    }
+
+    // The multipart upload of every object of a split file but the first is only created once the
+    // previous object has been completed.
+    if state.upload_id.is_empty() {
+        create_multipart_upload(s3, state).await?;
+        store.write(state).await?;
+    }
+    let replica_clients = replicate::clients(s3, state);
+    if replicate::create_multipart_uploads(&replica_clients, state).await? {
+        store.write(state).await?;
+    }
+
+    if let Some(compression) = &state.compression {
+        info!(
+            "Compressing the file with {} and uploading it in parts of {} bytes each",
+            compression.content_encoding(),
+            state.part_size,
+        );
+    } else if state.archive.is_some() {
+        info!(
+            "Archiving the directory with tar and uploading it in parts of {} bytes each",
+            state.part_size,
+        );
+    } else if state.spill_directory.is_some() {
+        info!(
+            "Uploading from stdin in parts of {} bytes each",
+            state.part_size
+        );
+    } else if state.copy_source.is_some() {
+        info!(
+            "Copying the object in {} parts of {} bytes each",
+            state.number_of_parts, state.part_size,
+        );
+    } else if state.auto_tune.is_some() {
+        info!(
+            "Uploading the file in parts of at least {} bytes each, tuned to the throughput",
+            state.part_size,
+        );
+    } else {
+        info!(
+            "Uploading the file in {} parts of {} bytes each",
+            state.number_of_parts, state.part_size,
+        );
+    }
+    let buffer_parts_in_memory = if options.buffer_parts_in_memory
+        && state.part_size > options.memory_limit
+    {
+        warn!(
+            "The part size of {} exceeds the memory limit of {}, parts will not be buffered in memory",
+            size::format_size(state.part_size),
+            size::format_size(options.memory_limit),
+        );
+        false
+    } else {
+        options.buffer_parts_in_memory
+    };
+
+    let plan = PartPlan::new(state.file_size_in_bytes, state.part_size);
+    let mut spill = match (&state.spill_directory, &state.compression, &state.archive) {
+        (Some(directory), Some(compression), _) => Some(Spill::with_source(
+            directory.clone(),
+            compression.reader(state).await?,
+        )),
+        (Some(directory), None, Some(archive)) => Some(Spill::with_source(
+            directory.clone(),
+            archive.reader(state).await?,
+        )),
+        (Some(directory), None, None) => Some(Spill::new(directory.clone())),
+        (None, ..) => None,
+    };
+    // Replicas may be missing parts the object of the state already holds, if an upload to them
+    // failed.
+    let mut next_part_number = replicate::first_missing_part(state);
+    let mut offset = state
+        .part(next_part_number)
+        .map_or(state.file_size_in_bytes, |part| part.offset);
+    let mut file_parts = plan.parts_from(next_part_number);
+    let limiter = options.rate_limiter();
+    let cipher = state
+        .encryption
+        .as_ref()
+        .map(Encryption::cipher)
+        .transpose()?;
+    let mut readahead = readahead(state, options, plan, spill.is_some());
+    reporter.started(state.file_size_in_bytes, offset, state.number_of_parts);
+    let mut checkpointer = Checkpointer::new(options.checkpoint_every);
+    let started = options.clock.now();
+    loop {
+        let part = match (&mut spill, &state.auto_tune) {
+            (Some(spill), _) => {
+                spill
+                    .next_part(next_part_number, offset, state.part_size)
+                    .await?
+            }
+            (None, Some(auto_tune)) => auto_tune.part(next_part_number, state.file_size_in_bytes),
+            (None, None) => file_parts.next(),
+        };
+        let Some(part) = part else {
+            break;
+        };
+        next_part_number += 1;
+        let part_number = part.number as u64;
+        if spill.is_some() {
+            if part_number > MAXIMUM_PART_NUMBER {
+                bail!(
+                    "The stream exceeds the {} parts allowed by S3 for a part size of {}, i.e. {}. Start the upload again with a larger `--expected-object-size` or `--override-part-size`.",
+                    MAXIMUM_PART_NUMBER,
+                    size::format_size(state.part_size),
+                    size::format_size(state.part_size * MAXIMUM_PART_NUMBER),
+                );
+            }
+            state.number_of_parts = part_number;
+        }
+        let read_ahead = match &mut readahead {
+            Some(readahead) => Some(readahead.read(&part).await?),
+            None => None,
+        };
+        let buffer = if let Some(cipher) = &cipher {
+            let contents = match read_ahead {
+                Some(contents) => contents,
+                None => read_part(state, &part, options.direct_io).await?,
+            };
+            let last = match &mut spill {
+                Some(spill) => spill.at_end().await?,
+                None => part_number == state.number_of_parts,
+            };
+            Some(cipher.encrypt_part(part.number, last, contents)?)
+        } else if read_ahead.is_some() {
+            read_ahead
+        } else if (buffer_parts_in_memory || !state.replicas.is_empty())
+            && part.size <= options.memory_limit
+            && state.copy_source.is_none()
+        {
+            Some(read_part(state, &part, options.direct_io).await?)
+        } else {
+            None
+        };
+
+        let mut attempt = 1;
+        // Whether the part was uploaded to some of the destinations, while it failed for others.
+        let mut partially_uploaded = false;
+        let last_retry_error = loop {
+            let attempt_started = Instant::now();
+            let (result, replicated) = tokio::select! {
+                result = async {
+                    tokio::join!(
+                        async {
+                            // The object of the state may already hold the part, if only uploading
+                            // it to a replica failed.
+                            if part_number > state.last_successful_part {
+                                Some(upload_part(Destination::of(s3, state), state, part, buffer.as_ref(), limiter.as_ref(), reporter, options).await)
+                            } else {
+                                None
+                            }
+                        },
+                        replicate::upload_missing_part(&replica_clients, state, part, buffer.as_ref(), limiter.as_ref(), options),
+                    )
+                } => result,
+                _ = signals.forced() => {
+                    // Written even if nothing is dirty, as the upload may not have a state-file
+                    // yet if it is interrupted during its first part.
+                    store.write(state).await?;
+                    info!(target: verbosity::SUMMARY,
+                        "Interrupted the upload during part {} of {}. To resume the upload, run the following command:",
+                        part_number, state.number_of_parts,
+                    );
+                    info!(target: verbosity::SUMMARY, "{}", resume_command(store, state, part.end()));
+                    return Err(Error::Interrupted);
+                }
+            };
+            // The part is recorded for every destination it was uploaded to, even if it failed for
+            // others, so that it is only uploaded again to the ones that are still missing it.
+            let (replicas_uploaded, replicated) = replicate::record_uploads(state, replicated);
+            partially_uploaded |= replicas_uploaded;
+            let result = match result {
+                Some(Ok(completed_part)) => {
+                    state.completed_parts.push(completed_part);
+                    state.last_successful_part = part_number;
+                    partially_uploaded = true;
+                    if spill.is_some() {
+                        state.file_size_in_bytes = part.end();
+                    }
+                    if let Some(auto_tune) = &mut state.auto_tune {
+                        auto_tune.part_completed(
+                            &part,
+                            attempt_started.elapsed(),
+                            attempt - 1,
+                            state.file_size_in_bytes,
+                        );
+                        state.number_of_parts =
+                            auto_tune.estimated_number_of_parts(state.file_size_in_bytes);
+                    }
+                    replicated
+                }
+                Some(Err(error)) => match replicated {
+                    Err(error @ Error::Unrecoverable(_)) => Err(error),
+                    _ => Err(error),
+                },
+                None => replicated,
+            };
+            match result {
+                Ok(()) => {
+                    offset = part.end();
+                    break None;
+                }
+                Err(error @ Error::Retryable(_))
+                    if options.retry.should_retry(attempt, started, &options.clock) =>
+                {
+                    reporter.part_retrying(&part, attempt, &error);
+                    options.retry.wait(attempt, &error, &options.clock).await;
+                    attempt += 1;
+                }
+                Err(error @ Error::Retryable(_)) => break Some(error),
+                Err(err) => {
+                    if checkpointer.is_dirty() || partially_uploaded {
+                        store.write(state).await?;
+                    }
+                    return Err(err);
+                }
+            }
+        };
+        // A spilled part can only be removed once its upload has been checkpointed, which is why
+        // uploads from stdin are checkpointed after every part.
+        let checkpoint_due = last_retry_error.is_none()
+            && (checkpointer.part_completed(part.size) || spill.is_some());
+        if checkpoint_due
+            || ((checkpointer.is_dirty() || partially_uploaded)
+                && (last_retry_error.is_some() || cancellation.is_cancelled()))
+        {
+            store.write(state).await?;
+            checkpointer.checkpointed();
+        }
+        if let (true, Some(spill)) = (checkpoint_due, &spill) {
+            spill.remove_part(part.number).await;
+        }
+        if let Some(error) = last_retry_error {
+            error!(
+                "Failed to upload part {} after {} attempts. Multipart upload will not be aborted, to allow resuming.",
+                part_number, attempt,
+            );
+            error!("Process failed with a retryable error. To resume the upload, run the following command:");
+            error!("{}", resume_command(store, state, part.end()));
+            return Err(error);
+        }
+
+        let more_parts = if spill.is_some() {
+            part.size == state.part_size
+        } else {
+            part.end() < state.file_size_in_bytes
+        };
+        if cancellation.is_cancelled() && more_parts {
+            let (stopped, error) = if signals.received() {
+                ("Interrupted", Error::Interrupted)
+            } else {
+                ("Paused", Error::Paused)
+            };
+            info!(target: verbosity::SUMMARY,
+                "{} the upload after part {} of {}. To resume the upload, run the following command:",
+                stopped, part_number, state.number_of_parts,
+            );
+            info!(target: verbosity::SUMMARY, "{}", resume_command(store, state, part.end()));
+            return Err(error);
+        }
+    }
+
+    // Whatever happens from here on, the state-file has to reflect all uploaded parts, so that a
+    // failure to complete the upload can be resumed without re-uploading anything.
+    if checkpointer.is_dirty() {
+        store.write(state).await?;
+    }
+
+    // We verify that the offset we reached matches up with the file size.
+    if offset != state.file_size_in_bytes {
+        bail!("In theory we finished the upload, but in practice there were still more bytes to be read from the file. This is unexpected, and we don't really have a way to recover from this, besides maybe trying to reupload the file.");
+    }
+
+    if state.split.as_ref().is_some_and(Split::is_last_object) {
+        split::put_manifest(s3, state).await?;
+    }
+    replicate::complete(&replica_clients, store, state).await?;
+
+    let completed_multipart_upload = s3
+        .complete_multipart_upload()
+        .bucket(&state.s3_bucket)
+        .key(&state.s3_key)
+        .upload_id(&state.upload_id)
+        .set_request_payer(state.request_payer())
+        .multipart_upload(
+            CompletedMultipartUpload::builder()
+                .set_parts(Some(state.completed_parts.clone()))
+                .build(),
+        )
+        .set_if_none_match(state.object_options.if_none_match())
+        .send()
+        .await
+        .into_retryable()
+        .map_err(hints::precondition_failed_is_unrecoverable)?;
+    info!(target: verbosity::SUMMARY,
+        "Successfully uploaded the file. ETag: {}",
+        completed_multipart_upload
+            .e_tag
+            .as_deref()
+            .unwrap_or("<unknown>"),
+    );
+
+    // The state-file of a split file is still needed for the objects that follow.
+    if state.split.as_ref().is_none_or(Split::is_last_object) {
+        store.remove().await?;
+        if let Some(spill_directory) = &state.spill_directory {
+            spill::remove_directory(spill_directory).await?;
+        }
+    }
+
+    Ok(completed_multipart_upload)
+}
+
+/// Returns the command that resumes the upload, as shown when the upload is interrupted.
+///
+/// Uploads from stdin additionally need the rest of the stream, starting at `stream_offset`, piped
+/// into the command.
+fn resume_command(store: &StateStore, state: &State, stream_offset: u64) -> String {
+    let mut command = format!("persevere resume --state-file '{}'", store.file().display());
+    if let Some(uri) = store.uri() {
+        command.push_str(&format!(" --state-uri '{}'", uri));
+    }
+    if state.reads_stdin() {
+        format!("tail -c +{} <stream> | {}", stream_offset + 1, command)
+    } else {
+        command
+    }
+}
+
+/// Returns the command that resumes the upload after it has failed, starting the stream where the
+/// upload left off for uploads from stdin.
+async fn resume_command_after_failure(store: &StateStore, state: &State) -> String {
+    let stream_offset = match &state.spill_directory {
+        Some(spill_directory) if state.reads_stdin() => spill::stream_offset(
+            spill_directory,
+            state.last_successful_part + 1,
+            state.file_size_in_bytes,
+        )
+        .await
+        .unwrap_or(state.file_size_in_bytes),
+        _ => 0,
+    };
+    resume_command(store, state, stream_offset)
+}
+
+async fn remove_pause_request_file(pause_request_file: &Path) -> Result<()> {
+    match tokio::fs::remove_file(pause_request_file).await {
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        result => result.into_unrecoverable(),
+    }
+}
+
+/// Cancels the transfer once a pause is requested through the `pause` subcommand.
+async fn watch_pause_request(pause_request_file: PathBuf, cancellation: CancellationToken) {
+    loop {
+        tokio::select! {
+            _ = cancellation.cancelled() => return,
+            _ = tokio::time::sleep(PAUSE_REQUEST_POLL_INTERVAL) => {}
+        }
+        if let Ok(true) = tokio::fs::try_exists(&pause_request_file).await {
+            info!("Pause requested, the upload will pause once the current part has finished");
+            if let Err(err) = remove_pause_request_file(&pause_request_file).await {
+                warn!("Failed to remove pause request file: {}", err);
+            }
+            cancellation.cancel();
+            return;
+        }
+    }
+}
+
+/// How often a running transfer checks whether a pause was requested.
+const PAUSE_REQUEST_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Exit code used when the transfer was paused through the `pause` subcommand.
+const EXIT_CODE_PAUSED: u8 = 3;
+
+/// Exit code used when the command failed with an error that resuming the transfer may overcome.
+const EXIT_CODE_RETRYABLE: u8 = 10;
+
+/// Exit code used when the command failed with an error that resuming the transfer won't overcome.
+const EXIT_CODE_UNRECOVERABLE: u8 = 20;
+
+/// Exit code used when the transfer was stopped by SIGINT or SIGTERM, following the shell
+/// convention for processes terminated by SIGINT.
+const EXIT_CODE_INTERRUPTED: u8 = 130;
+
+/// Runs the command line interface of Persevere, as the `persevere` binary does.
+///
+/// This parses the arguments of the process, sets up logging and returns the exit code of the
+/// command that was run.
+pub async fn run_cli() -> ExitCode {
+    let config = match Config::load(config::config_argument(std::env::args_os()).as_deref()) {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("Error: {:?}", error);
+            return ExitCode::FAILURE;
+        }
+    };
+    let matches = config.apply(Cli::command()).get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    if let Some(state_directory) = config.state_directory {
+        state_home::configure_state_directory(state_directory);
+    }
+    #[cfg(feature = "otel")]
+    let otel = otel::Otel::from_env();
+    let registry = tracing_subscriber::registry().with(
+        tracing_subscriber::fmt::layer()
+            .with_writer(progress::log_writer)
+            .compact()
+            .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE)
+            .with_file(false)
+            .with_line_number(false)
+            .with_target(false)
+            .with_filter(cli.verbosity.filter()),
+    );
+    #[cfg(feature = "otel")]
+    let registry = registry.with(otel.as_ref().map(otel::Otel::layer));
+    registry.init();
+
+    #[cfg(feature = "otel")]
+    let span = otel.as_ref().map_or_else(tracing::Span::none, |otel| {
+        otel.root_span(matches.subcommand_name().unwrap_or_default())
+    });
+    #[cfg(not(feature = "otel"))]
+    let span = tracing::Span::none();
+
+    let command = cli.command;
+    let output = command.output();
+    let result = async {
+        match cli.sdk.install() {
+            Ok(()) => command.run().await,
+            Err(error) => Err(error),
+        }
+    }
+    .instrument(span)
+    .await;
+    if let Err(error) = &result {
+        output.print_error(error);
+    }
+    let exit_code = match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(Error::Paused) => ExitCode::from(EXIT_CODE_PAUSED),
+        Err(Error::Interrupted) => ExitCode::from(EXIT_CODE_INTERRUPTED),
+        Err(error) => {
+            if let Some(hint) = hints::hint_for(&error) {
+                error!("Hint: {}", hint);
+            }
+            eprintln!("Error: {:?}", error);
+            match error {
+                Error::Retryable(_) => ExitCode::from(EXIT_CODE_RETRYABLE),
+                _ => ExitCode::from(EXIT_CODE_UNRECOVERABLE),
+            }
+        }
+    };
+    #[cfg(feature = "otel")]
+    if let Some(otel) = otel {
+        otel.shutdown().await;
+    }
+    exit_code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn forced_resumes_of_encrypted_uploads_are_rejected() {
+        let directory =
+            std::env::temp_dir().join(format!("persevere-forced-resume-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let key_file = directory.join("key");
+        std::fs::write(&key_file, [7; 32]).unwrap();
+        let file_to_upload = directory.join("file");
+        std::fs::write(&file_to_upload, b"changed").unwrap();
+        let state_file = directory.join("state.json");
+        let state = serde_json::json!({
+            "version": migration::STATE_VERSION,
+            "s3_bucket": "bucket",
+            "s3_key": "key",
+            "file_to_upload": file_to_upload,
+            "file_size_in_bytes": 7,
+            "part_size": 7,
+            "number_of_parts": 1,
+            "upload_id": "upload",
+            "last_successful_part": 0,
+            "completed_parts": [],
+            "encryption": encryption::Encryption::new(&key_file).unwrap(),
+        });
+        std::fs::write(&state_file, state.to_string()).unwrap();
+
+        #[derive(Parser)]
+        struct Command {
+            #[command(flatten)]
+            resume: Resume,
+        }
+        let state_file_arg = state_file.to_str().unwrap();
+        let resume =
+            Command::parse_from(["persevere", "--state-file", state_file_arg, "--force"]).resume;
+        let error = resume.transfer().await.unwrap_err();
+        assert!(
+            matches!(&error, Error::Unrecoverable(error) if error.to_string().contains("--force")),
+            "{:?}",
+            error,
+        );
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,455 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    history::Outcome,
+    parts::Part,
+    progress::ProgressReporter,
+    result::{
+        AnyhowResultExt,
+        Error,
+        Result,
+    },
+};
+use anyhow::Context;
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{
+        make_service_fn,
+        service_fn,
+    },
+    Body,
+    Request,
+    Response,
+    Server,
+    StatusCode,
+};
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    fmt::Write,
+    net::{
+        SocketAddr,
+        TcpListener,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::{
+        Arc,
+        Mutex,
+    },
+    time::{
+        Duration,
+        Instant,
+        SystemTime,
+    },
+};
+use tokio::task::JoinHandle;
+use tracing::{
+    info,
+    warn,
+};
+
+/// Time span over which the throughput is averaged.
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(60);
+
+/// How often the metrics are written to the textfile while the transfer is running.
+const TEXTFILE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// The content type of the Prometheus text format.
+const CONTENT_TYPE_PROMETHEUS: &str = "text/plain; version=0.0.4";
+
+/// Metrics of a running transfer, as updated through the progress updates.
+#[derive(Debug)]
+struct Metrics {
+    /// Labels identifying the transfer, already rendered as `{bucket="…",key="…"}`.
+    labels: String,
+    started: SystemTime,
+    total_bytes: u64,
+    /// Bytes of all parts that have been completed, either in this or a previous attempt.
+    transferred_bytes: u64,
+    number_of_parts: u64,
+    /// All bytes handed to S3, including those of failed attempts.
+    sent_bytes: u64,
+    /// Bytes sent for the current attempt of the part in progress.
+    bytes_in_attempt: u64,
+    part_in_progress: bool,
+    parts_completed: u64,
+    parts_failed: u64,
+    retries: u64,
+    /// Recent samples of the bytes transferred, including the part in progress, to calculate the
+    /// throughput.
+    samples: VecDeque<(Instant, u64)>,
+    outcome: Option<Outcome>,
+}
+
+impl Metrics {
+    fn new(bucket: &str, key: &str) -> Self {
+        Self {
+            labels: format!(
+                "{{bucket=\"{}\",key=\"{}\"}}",
+                escape_label_value(bucket),
+                escape_label_value(key),
+            ),
+            started: SystemTime::now(),
+            total_bytes: 0,
+            transferred_bytes: 0,
+            number_of_parts: 0,
+            sent_bytes: 0,
+            bytes_in_attempt: 0,
+            part_in_progress: false,
+            parts_completed: 0,
+            parts_failed: 0,
+            retries: 0,
+            samples: VecDeque::new(),
+            outcome: None,
+        }
+    }
+
+    fn sample(&mut self) {
+        let now = Instant::now();
+        self.samples
+            .push_back((now, self.transferred_bytes + self.bytes_in_attempt));
+        while self
+            .samples
+            .front()
+            .is_some_and(|(instant, _)| now.duration_since(*instant) > THROUGHPUT_WINDOW)
+        {
+            self.samples.pop_front();
+        }
+    }
+
+    fn bytes_per_second(&self) -> f64 {
+        let (Some((first_instant, first_bytes)), Some(_)) =
+            (self.samples.front(), self.samples.back())
+        else {
+            return 0.0;
+        };
+        // The throughput is calculated up to now, so that it drops to zero once no more bytes are
+        // sent.
+        let elapsed = first_instant.elapsed().as_secs_f64();
+        let bytes = (self.transferred_bytes + self.bytes_in_attempt).saturating_sub(*first_bytes);
+        if elapsed > 0.0 {
+            bytes as f64 / elapsed
+        } else {
+            0.0
+        }
+    }
+
+    /// Renders the metrics in the Prometheus text format.
+    fn render(&self) -> String {
+        let mut output = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: f64| {
+            let _ = writeln!(output, "# HELP persevere_transfer_{} {}", name, help);
+            let _ = writeln!(output, "# TYPE persevere_transfer_{} {}", name, kind);
+            let _ = writeln!(
+                output,
+                "persevere_transfer_{}{} {}",
+                name, self.labels, value
+            );
+        };
+
+        let started = self
+            .started
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        metric(
+            "start_time_seconds",
+            "gauge",
+            "Time the transfer was started at, as a Unix timestamp.",
+            started.as_secs_f64(),
+        );
+        metric(
+            "size_bytes",
+            "gauge",
+            "Size of the file, or of the data read so far for uploads from stdin.",
+            self.total_bytes as f64,
+        );
+        metric(
+            "transferred_bytes",
+            "gauge",
+            "Bytes of all completed parts, including those of previous attempts of the transfer.",
+            self.transferred_bytes as f64,
+        );
+        metric(
+            "sent_bytes_total",
+            "counter",
+            "Bytes handed to S3, including those of failed attempts.",
+            self.sent_bytes as f64,
+        );
+        metric(
+            "parts",
+            "gauge",
+            "Number of parts of the transfer.",
+            self.number_of_parts as f64,
+        );
+        metric(
+            "parts_completed_total",
+            "counter",
+            "Parts completed by this attempt of the transfer.",
+            self.parts_completed as f64,
+        );
+        metric(
+            "parts_failed_total",
+            "counter",
+            "Failed attempts to transfer a part.",
+            self.parts_failed as f64,
+        );
+        metric(
+            "retries_total",
+            "counter",
+            "Parts that were retried after a failure.",
+            self.retries as f64,
+        );
+        let bytes_per_second = self.bytes_per_second();
+        metric(
+            "throughput_bytes_per_second",
+            "gauge",
+            "Throughput of the transfer, averaged over the last minute.",
+            bytes_per_second,
+        );
+        let remaining_bytes = self
+            .total_bytes
+            .saturating_sub(self.transferred_bytes + self.bytes_in_attempt);
+        if remaining_bytes > 0 && bytes_per_second >= 1.0 {
+            metric(
+                "eta_seconds",
+                "gauge",
+                "Estimated time until the transfer has finished, at the current throughput.",
+                (remaining_bytes as f64 / bytes_per_second).round(),
+            );
+        }
+        metric(
+            "finished",
+            "gauge",
+            "Whether the transfer has finished, successfully or not.",
+            if self.outcome.is_some() { 1.0 } else { 0.0 },
+        );
+        metric(
+            "completed",
+            "gauge",
+            "Whether the transfer has completed successfully.",
+            if self.outcome == Some(Outcome::Completed) {
+                1.0
+            } else {
+                0.0
+            },
+        );
+        output
+    }
+}
+
+/// Escapes a label value for the Prometheus text format.
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Reports progress to another reporter, while keeping track of the metrics of the transfer and
+/// exporting them through `--metrics-listen` and `--metrics-textfile`.
+///
+/// The metrics are no longer served once the reporter is dropped.
+pub(crate) struct MetricsReporter {
+    inner: Arc<dyn ProgressReporter>,
+    metrics: Arc<Mutex<Metrics>>,
+    textfile: Option<PathBuf>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl MetricsReporter {
+    /// Starts exporting the metrics of the transfer of the given object.
+    pub(crate) fn start(
+        inner: Arc<dyn ProgressReporter>,
+        bucket: &str,
+        key: &str,
+        listen: Option<SocketAddr>,
+        textfile: Option<PathBuf>,
+    ) -> Result<Self> {
+        let metrics = Arc::new(Mutex::new(Metrics::new(bucket, key)));
+        let mut tasks = vec![];
+        if let Some(address) = listen {
+            let listener = TcpListener::bind(address)
+                .with_context(|| format!("Failed to listen for metrics on {}", address))
+                .into_unrecoverable()?;
+            let server = Server::from_tcp(listener)
+                .with_context(|| format!("Failed to listen for metrics on {}", address))
+                .into_unrecoverable()?
+                .serve(make_service_fn({
+                    let metrics = Arc::clone(&metrics);
+                    move |_| {
+                        let metrics = Arc::clone(&metrics);
+                        async move {
+                            Ok::<_, Infallible>(service_fn(move |request| {
+                                let response = respond(&metrics, &request);
+                                async move { Ok::<_, Infallible>(response) }
+                            }))
+                        }
+                    }
+                }));
+            info!("Serving metrics on http://{}/metrics", address);
+            tasks.push(tokio::spawn(async move {
+                if let Err(error) = server.await {
+                    warn!("Failed to serve metrics: {}", error);
+                }
+            }));
+        }
+        if let Some(textfile) = &textfile {
+            let metrics = Arc::clone(&metrics);
+            let textfile = textfile.clone();
+            tasks.push(tokio::spawn(async move {
+                loop {
+                    write_textfile(&metrics, &textfile);
+                    tokio::time::sleep(TEXTFILE_INTERVAL).await;
+                }
+            }));
+        }
+        Ok(Self {
+            inner,
+            metrics,
+            textfile,
+            tasks,
+        })
+    }
+
+    fn update(&self, update: impl FnOnce(&mut Metrics)) {
+        let mut metrics = self.metrics.lock().expect("poisoned lock");
+        update(&mut metrics);
+        metrics.sample();
+    }
+}
+
+impl Drop for MetricsReporter {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+impl ProgressReporter for MetricsReporter {
+    fn started(&self, total_bytes: u64, transferred_bytes: u64, number_of_parts: u64) {
+        self.update(|metrics| {
+            metrics.total_bytes = total_bytes;
+            metrics.transferred_bytes = transferred_bytes;
+            metrics.number_of_parts = number_of_parts;
+            // Bytes of a previous attempt of the transfer don't count towards the throughput.
+            metrics.samples.clear();
+        });
+        self.inner
+            .started(total_bytes, transferred_bytes, number_of_parts);
+    }
+
+    fn part_started(&self, part: &Part, number_of_parts: u64) {
+        self.update(|metrics| {
+            metrics.bytes_in_attempt = 0;
+            metrics.part_in_progress = true;
+            metrics.number_of_parts = metrics.number_of_parts.max(number_of_parts);
+        });
+        self.inner.part_started(part, number_of_parts);
+    }
+
+    fn bytes_transferred(&self, part: &Part, bytes: u64) {
+        self.update(|metrics| {
+            metrics.sent_bytes += bytes;
+            metrics.bytes_in_attempt += bytes;
+        });
+        self.inner.bytes_transferred(part, bytes);
+    }
+
+    fn part_retrying(&self, part: &Part, attempt: u32, error: &Error) {
+        self.update(|metrics| {
+            metrics.bytes_in_attempt = 0;
+            metrics.part_in_progress = false;
+            metrics.parts_failed += 1;
+            metrics.retries += 1;
+        });
+        self.inner.part_retrying(part, attempt, error);
+    }
+
+    fn part_completed(&self, part: &Part, number_of_parts: u64) {
+        self.update(|metrics| {
+            metrics.bytes_in_attempt = 0;
+            metrics.part_in_progress = false;
+            metrics.transferred_bytes += part.size;
+            // Uploads from stdin only learn their size as the stream is read.
+            metrics.total_bytes = metrics.total_bytes.max(metrics.transferred_bytes);
+            metrics.parts_completed += 1;
+            metrics.number_of_parts = metrics.number_of_parts.max(number_of_parts);
+        });
+        self.inner.part_completed(part, number_of_parts);
+    }
+
+    fn finished(&self, outcome: Outcome) {
+        self.update(|metrics| {
+            // The part in progress failed for good, rather than being retried.
+            if metrics.part_in_progress
+                && matches!(
+                    outcome,
+                    Outcome::FailedRetryable | Outcome::FailedUnrecoverable
+                )
+            {
+                metrics.parts_failed += 1;
+            }
+            metrics.bytes_in_attempt = 0;
+            metrics.part_in_progress = false;
+            metrics.outcome = Some(outcome);
+        });
+        // The textfile is left behind with the final metrics, so that the outcome can still be
+        // collected once the transfer has finished.
+        if let Some(textfile) = &self.textfile {
+            write_textfile(&self.metrics, textfile);
+        }
+        self.inner.finished(outcome);
+    }
+}
+
+fn respond(metrics: &Mutex<Metrics>, request: &Request<Body>) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    if request.uri().path() == "/metrics" {
+        let body = metrics.lock().expect("poisoned lock").render();
+        response.headers_mut().insert(
+            CONTENT_TYPE,
+            CONTENT_TYPE_PROMETHEUS.parse().expect("valid header"),
+        );
+        *response.body_mut() = Body::from(body);
+    } else {
+        *response.status_mut() = StatusCode::NOT_FOUND;
+    }
+    response
+}
+
+/// Replaces the textfile with the current metrics.
+///
+/// The metrics are written to a temporary file next to it first, so that the textfile collector
+/// never reads a partially written file.
+fn write_textfile(metrics: &Mutex<Metrics>, textfile: &Path) {
+    let body = metrics.lock().expect("poisoned lock").render();
+    let mut temporary_file = textfile.as_os_str().to_owned();
+    temporary_file.push(".tmp");
+    if let Err(error) = std::fs::write(&temporary_file, body)
+        .and_then(|_| std::fs::rename(&temporary_file, textfile))
+    {
+        warn!(
+            "Failed to write the metrics to {}: {}",
+            textfile.display(),
+            error,
+        );
+    }
+}
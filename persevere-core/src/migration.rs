@@ -0,0 +1,81 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::result::{
+    bail,
+    AnyhowResultExt,
+    Result,
+};
+use serde_json::Value;
+
+/// Version of the state-file format written by this version of Persevere.
+///
+/// Increase it whenever a change to the state-file can't be read as is by the previous version,
+/// and add the migration from the previous version to [`migrate`].
+pub(crate) const STATE_VERSION: u64 = 1;
+
+/// Migrates a state-file read from disk to the current [`STATE_VERSION`], such that state-files
+/// written by previous versions of Persevere keep working.
+///
+/// State-files written by a newer version of Persevere are rejected, since this version can't know
+/// what they contain.
+pub(crate) fn migrate(state: &mut Value) -> Result<()> {
+    let Some(state) = state.as_object_mut() else {
+        bail!("The state-file is not a JSON object");
+    };
+    // State-files written before the version was introduced have no version at all.
+    let version = match state.get("version") {
+        None => 0,
+        Some(version) => match version.as_u64() {
+            Some(version) => version,
+            None => bail!("The state-file has an invalid version: {}", version),
+        },
+    };
+    if version > STATE_VERSION {
+        bail!(
+            "The state-file was written by a newer version of Persevere (state-file version {}, while this version supports up to version {}). Please upgrade persevere to continue with this upload.",
+            version,
+            STATE_VERSION,
+        );
+    }
+
+    for version in version..STATE_VERSION {
+        match version {
+            // Version 1 only introduced the version itself.
+            0 => {}
+            _ => unreachable!("no migration from state-file version {}", version),
+        }
+    }
+    state.insert("version".to_owned(), STATE_VERSION.into());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_files_are_migrated_up_to_the_current_version() {
+        let mut state = serde_json::json!({ "upload_id": "abc" });
+        migrate(&mut state).unwrap();
+        assert_eq!(state["version"], STATE_VERSION);
+        assert_eq!(state["upload_id"], "abc");
+
+        let mut state = serde_json::json!({ "version": STATE_VERSION + 1 });
+        assert!(migrate(&mut state).is_err());
+        assert!(migrate(&mut serde_json::json!([])).is_err());
+    }
+}
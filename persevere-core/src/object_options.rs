@@ -0,0 +1,271 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use aws_sdk_s3::{
+    operation::{
+        create_multipart_upload::builders::CreateMultipartUploadFluentBuilder,
+        put_object::builders::PutObjectFluentBuilder,
+    },
+    primitives::{
+        DateTime,
+        DateTimeFormat,
+    },
+    types::{
+        ObjectCannedAcl,
+        ObjectLockLegalHoldStatus,
+        ObjectLockMode,
+        ServerSideEncryption,
+        StorageClass,
+    },
+};
+use clap::{
+    builder::PossibleValuesParser,
+    Args,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+/// Properties of the object that is created in S3.
+///
+/// These are set once when the multipart upload is created, and are persisted in the state-file so
+/// that it is always clear what object a (resumed) upload will result in.
+#[derive(Clone, Debug, Default, Args, Deserialize, Serialize)]
+pub(crate) struct ObjectOptions {
+    /// Redirect requests for the object to another object in the same bucket, or to an external
+    /// URL.
+    ///
+    /// This is only relevant if the bucket is configured as a static website.
+    #[arg(long)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) website_redirect_location: Option<String>,
+    /// The storage class to store the object in.
+    ///
+    /// Defaults to the default storage class of the bucket, which usually is `STANDARD`.
+    #[arg(long, value_parser = PossibleValuesParser::new(StorageClass::values()))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) storage_class: Option<String>,
+    /// User-defined metadata to store with the object, in the form `key=value`.
+    ///
+    /// The metadata is returned as `x-amz-meta-<key>` headers when the object is retrieved. This
+    /// option can be provided multiple times.
+    #[arg(long, value_name = "KEY=VALUE", value_parser = crate::parse_key_value)]
+    #[serde(
+        default,
+        skip_serializing_if = "Vec::is_empty",
+        with = "crate::de::key_values"
+    )]
+    pub(crate) metadata: Vec<(String, String)>,
+    /// The `Content-Type` of the object, e.g. `application/gzip`.
+    #[arg(long)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) content_type: Option<String>,
+    /// The `Cache-Control` header to serve the object with.
+    #[arg(long)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) cache_control: Option<String>,
+    /// The `Content-Disposition` header to serve the object with, e.g.
+    /// `attachment; filename="backup.tar.gz"`.
+    #[arg(long)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) content_disposition: Option<String>,
+    /// The `Content-Encoding` of the object, e.g. `gzip`.
+    #[arg(long)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) content_encoding: Option<String>,
+    /// The server-side encryption to store the object with.
+    ///
+    /// Defaults to the default encryption configured for the bucket.
+    #[arg(long, value_parser = PossibleValuesParser::new(ServerSideEncryption::values()))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) sse: Option<String>,
+    /// ID or ARN of the KMS key to encrypt the object with, if `--sse` is `aws:kms` or
+    /// `aws:kms:dsse`.
+    ///
+    /// Defaults to the AWS managed key for S3 (`aws/s3`).
+    #[arg(long, requires = "sse")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) sse_kms_key_id: Option<String>,
+    /// Use an S3 Bucket Key for the SSE-KMS encryption of the object, which reduces the number of
+    /// requests S3 has to make to KMS.
+    #[arg(long)]
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) bucket_key_enabled: bool,
+    /// Tag to attach to the object, in the form `key=value`.
+    ///
+    /// This option can be provided multiple times, up to the limit of 10 tags S3 allows per object.
+    #[arg(long = "tag", value_name = "KEY=VALUE", value_parser = crate::parse_key_value)]
+    #[serde(
+        default,
+        skip_serializing_if = "Vec::is_empty",
+        with = "crate::de::key_values"
+    )]
+    pub(crate) tags: Vec<(String, String)>,
+    /// The canned ACL to apply to the object, e.g. `bucket-owner-full-control`.
+    #[arg(long, value_parser = PossibleValuesParser::new(ObjectCannedAcl::values()))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) acl: Option<String>,
+    /// Grantees that get `FULL_CONTROL` permission on the object, i.e. `READ`, `READ_ACP` and
+    /// `WRITE_ACP`.
+    ///
+    /// Grantees are specified as S3 expects them, e.g. `id="<canonical user ID>"` or
+    /// `uri="http://acs.amazonaws.com/groups/global/AllUsers"`, separated by commas.
+    #[arg(long)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) grant_full_control: Option<String>,
+    /// Grantees that get `READ` permission on the object, in the same form as for
+    /// `--grant-full-control`.
+    #[arg(long)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) grant_read: Option<String>,
+    /// Grantees that get `READ_ACP` permission on the object, in the same form as for
+    /// `--grant-full-control`.
+    #[arg(long)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) grant_read_acp: Option<String>,
+    /// Grantees that get `WRITE_ACP` permission on the object, in the same form as for
+    /// `--grant-full-control`.
+    #[arg(long)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) grant_write_acp: Option<String>,
+    /// The Object Lock mode to apply to the object, which requires the bucket to have Object Lock
+    /// enabled.
+    #[arg(
+        long,
+        requires = "object_lock_retain_until",
+        value_parser = PossibleValuesParser::new(ObjectLockMode::values()),
+    )]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) object_lock_mode: Option<String>,
+    /// Date and time until which the object is locked, as an RFC 3339 timestamp in UTC, e.g.
+    /// `2031-12-31T00:00:00Z`.
+    #[arg(long, requires = "object_lock_mode", value_parser = parse_timestamp)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) object_lock_retain_until: Option<String>,
+    /// Place a legal hold on the object, which requires the bucket to have Object Lock enabled.
+    #[arg(long)]
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) legal_hold: bool,
+    /// Only create the object if no object exists under the key yet, through S3's conditional
+    /// writes (`If-None-Match: *`).
+    ///
+    /// This makes sure concurrent producers writing to the same key never overwrite each other: if
+    /// an object was written under the key in the meantime, completing the upload fails and the
+    /// existing object is kept.
+    #[arg(long)]
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) if_none_match: bool,
+}
+
+impl ObjectOptions {
+    /// Returns the tags in the URL-encoded form S3 expects them in, e.g. `team=genomics&tier=cold`.
+    fn tagging(&self) -> Option<String> {
+        if self.tags.is_empty() {
+            return None;
+        }
+        let tags: Vec<_> = self
+            .tags
+            .iter()
+            .map(|(key, value)| format!("{}={}", url_encode(key), url_encode(value)))
+            .collect();
+        Some(tags.join("&"))
+    }
+
+    /// Returns the `If-None-Match` condition for the request writing the object, if any.
+    ///
+    /// Unlike the other options, it is not set on `CreateMultipartUpload`, but when the upload is
+    /// completed.
+    pub(crate) fn if_none_match(&self) -> Option<String> {
+        self.if_none_match.then(|| "*".to_owned())
+    }
+}
+
+/// Validates that the value is an RFC 3339 timestamp.
+fn parse_timestamp(value: &str) -> Result<String, String> {
+    DateTime::from_str(value, DateTimeFormat::DateTime)
+        .map(|_| value.to_owned())
+        .map_err(|err| format!("expected an RFC 3339 timestamp: {}", err))
+}
+
+/// Percent-encodes everything but the unreserved characters of RFC 3986.
+fn url_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+/// `CreateMultipartUpload` and `PutObject` accept the same object properties, but through distinct
+/// builder types, so the methods applying the options to either request are generated from a single
+/// definition.
+macro_rules! apply_to {
+    ($($(#[$meta:meta])* $name:ident($builder:ty);)*) => {
+        impl ObjectOptions {
+            $(
+                $(#[$meta])*
+                pub(crate) fn $name(&self, request: $builder) -> $builder {
+                    request
+                        .set_website_redirect_location(self.website_redirect_location.clone())
+                        .set_storage_class(self.storage_class.as_deref().map(StorageClass::from))
+                        .set_metadata(
+                            (!self.metadata.is_empty())
+                                .then(|| self.metadata.iter().cloned().collect()),
+                        )
+                        .set_content_type(self.content_type.clone())
+                        .set_cache_control(self.cache_control.clone())
+                        .set_content_disposition(self.content_disposition.clone())
+                        .set_content_encoding(self.content_encoding.clone())
+                        .set_server_side_encryption(
+                            self.sse.as_deref().map(ServerSideEncryption::from),
+                        )
+                        .set_ssekms_key_id(self.sse_kms_key_id.clone())
+                        .set_bucket_key_enabled(self.bucket_key_enabled.then_some(true))
+                        .set_tagging(self.tagging())
+                        .set_acl(self.acl.as_deref().map(ObjectCannedAcl::from))
+                        .set_grant_full_control(self.grant_full_control.clone())
+                        .set_grant_read(self.grant_read.clone())
+                        .set_grant_read_acp(self.grant_read_acp.clone())
+                        .set_grant_write_acp(self.grant_write_acp.clone())
+                        .set_object_lock_mode(self.object_lock_mode.as_deref().map(ObjectLockMode::from))
+                        // The timestamp has already been validated when it was parsed.
+                        .set_object_lock_retain_until_date(
+                            self.object_lock_retain_until
+                                .as_deref()
+                                .and_then(|timestamp| {
+                                    DateTime::from_str(timestamp, DateTimeFormat::DateTime).ok()
+                                }),
+                        )
+                        .set_object_lock_legal_hold_status(
+                            self.legal_hold.then_some(ObjectLockLegalHoldStatus::On),
+                        )
+                }
+            )*
+        }
+    };
+}
+
+apply_to! {
+    /// Applies the object options to the request creating the multipart upload.
+    apply_to(CreateMultipartUploadFluentBuilder);
+    /// Applies the object options to a single-request upload of a small file.
+    apply_to_put_object(PutObjectFluentBuilder);
+}
@@ -0,0 +1,153 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use opentelemetry::{
+    propagation::TextMapPropagator,
+    trace::TracerProvider as _,
+    KeyValue,
+};
+use opentelemetry_sdk::{
+    propagation::TraceContextPropagator,
+    runtime,
+    trace::{
+        Config,
+        TracerProvider,
+    },
+    Resource,
+};
+use std::collections::HashMap;
+use tracing::{
+    level_filters::LevelFilter,
+    warn,
+    Span,
+    Subscriber,
+};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{
+    filter::Targets,
+    registry::LookupSpan,
+    Layer,
+};
+
+/// Environment variables that configure the endpoint of the OTLP exporter, one of which has to be
+/// set for the spans to be exported.
+const ENDPOINT_VARIABLES: &[&str] = &[
+    "OTEL_EXPORTER_OTLP_ENDPOINT",
+    "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT",
+];
+
+/// Exports the spans of Persevere through OTLP, to make transfers visible in an OpenTelemetry
+/// based observability stack.
+///
+/// The exporter is configured through the standard `OTEL_*` environment variables.
+pub(crate) struct Otel {
+    provider: TracerProvider,
+}
+
+impl Otel {
+    /// Sets up the exporter, if an OTLP endpoint is configured in the environment.
+    pub(crate) fn from_env() -> Option<Self> {
+        if !ENDPOINT_VARIABLES
+            .iter()
+            .any(|variable| std::env::var_os(variable).is_some())
+        {
+            return None;
+        }
+
+        let mut resource = Resource::default();
+        if resource
+            .get("service.name".into())
+            .is_some_and(|name| name.as_str() == "unknown_service")
+        {
+            resource = resource.merge(&Resource::new([KeyValue::new("service.name", "persevere")]));
+        }
+        let provider = opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(opentelemetry_otlp::new_exporter().tonic())
+            .with_trace_config(Config::default().with_resource(resource))
+            .install_batch(runtime::Tokio);
+        match provider {
+            Ok(provider) => Some(Self { provider }),
+            Err(error) => {
+                // Logging is not set up yet at this point.
+                eprintln!(
+                    "Warning: Failed to set up the OpenTelemetry exporter, spans won't be exported: {}",
+                    error,
+                );
+                None
+            }
+        }
+    }
+
+    /// The layer that hands the spans to the exporter.
+    ///
+    /// Besides the spans of Persevere itself, this includes the spans of the AWS SDK for every
+    /// request to S3 and every attempt thereof, so that their latency can be traced.
+    pub(crate) fn layer<S>(&self) -> impl Layer<S>
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        tracing_opentelemetry::layer()
+            .with_tracer(self.provider.tracer("persevere"))
+            .with_filter(
+                Targets::new()
+                    .with_default(LevelFilter::INFO)
+                    .with_target(module_path!(), LevelFilter::TRACE)
+                    .with_target(
+                        "aws_smithy_runtime::client::orchestrator",
+                        LevelFilter::DEBUG,
+                    ),
+            )
+    }
+
+    /// Returns the span to run the given command in, which ties all of its spans together into a
+    /// single trace.
+    ///
+    /// If Persevere is invoked from a pipeline that is traced itself, the trace context can be
+    /// passed through the `TRACEPARENT` (and `TRACESTATE`) environment variables, which makes the
+    /// trace of Persevere part of the trace of the pipeline.
+    pub(crate) fn root_span(&self, command: &str) -> Span {
+        // The span is only of interest for the trace, so it is logged at the trace level.
+        let span = tracing::trace_span!(
+            "persevere",
+            otel.name = format!("persevere {}", command),
+            command,
+        );
+        let carrier: HashMap<String, String> =
+            [("traceparent", "TRACEPARENT"), ("tracestate", "TRACESTATE")]
+                .into_iter()
+                .filter_map(|(field, variable)| {
+                    Some((field.to_owned(), std::env::var(variable).ok()?))
+                })
+                .collect();
+        if !carrier.is_empty() {
+            span.set_parent(TraceContextPropagator::new().extract(&carrier));
+        }
+        span
+    }
+
+    /// Exports the spans that have not been exported yet, and shuts the exporter down.
+    pub(crate) async fn shutdown(self) {
+        // Shutting down blocks until the remaining spans have been exported, which in turn
+        // requires the runtime.
+        let _ = tokio::task::spawn_blocking(move || {
+            if let Err(error) = self.provider.shutdown() {
+                warn!("Failed to export the remaining spans: {}", error);
+            }
+        })
+        .await;
+    }
+}
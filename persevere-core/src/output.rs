@@ -0,0 +1,158 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    checksum::{
+        ChecksumAlgorithm,
+        ReturnedChecksums,
+    },
+    history::Outcome,
+    result::Error,
+    State,
+};
+use clap::ValueEnum;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    time::Duration,
+};
+use tracing::warn;
+
+/// What a transfer command prints to stdout once it has finished.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum OutputFormat {
+    /// Nothing, the progress of the transfer is only logged to stderr.
+    #[default]
+    Text,
+    /// A single JSON document with the result of the transfer, or the error it failed with.
+    Json,
+}
+
+/// The result of a successful transfer, as it is printed with `--output json`.
+#[derive(Debug, Serialize)]
+pub struct TransferResult {
+    s3_bucket: String,
+    s3_key: String,
+    e_tag: Option<String>,
+    /// Only present if versioning is enabled for the bucket.
+    version_id: Option<String>,
+    bytes: u64,
+    /// Wall-clock duration of this invocation.
+    duration_seconds: f64,
+    parts: u64,
+    /// The checksums of the object S3 returned, by algorithm.
+    checksums: BTreeMap<ChecksumAlgorithm, String>,
+}
+
+impl TransferResult {
+    pub(crate) fn new(
+        state: &State,
+        e_tag: Option<&str>,
+        version_id: Option<&str>,
+        checksums: &impl ReturnedChecksums,
+        duration: Duration,
+    ) -> Self {
+        Self {
+            s3_bucket: state.s3_bucket.clone(),
+            s3_key: state.s3_key.clone(),
+            e_tag: e_tag.map(ToOwned::to_owned),
+            version_id: version_id.map(ToOwned::to_owned),
+            bytes: state.file_size_in_bytes,
+            duration_seconds: duration.as_secs_f64(),
+            parts: state.number_of_parts,
+            checksums: ChecksumAlgorithm::value_variants()
+                .iter()
+                .filter_map(|&algorithm| {
+                    Some((algorithm, checksums.checksum(algorithm)?.to_owned()))
+                })
+                .collect(),
+        }
+    }
+
+    /// The bucket the object was uploaded to.
+    pub fn s3_bucket(&self) -> &str {
+        &self.s3_bucket
+    }
+
+    /// The key the object was uploaded to.
+    pub fn s3_key(&self) -> &str {
+        &self.s3_key
+    }
+
+    /// The ETag S3 returned for the object.
+    pub fn e_tag(&self) -> Option<&str> {
+        self.e_tag.as_deref()
+    }
+
+    /// The version ID of the object, if versioning is enabled for the bucket.
+    pub fn version_id(&self) -> Option<&str> {
+        self.version_id.as_deref()
+    }
+
+    /// The number of bytes that were uploaded.
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// How long the upload took in this process.
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs_f64(self.duration_seconds)
+    }
+
+    /// The number of parts the object was uploaded in.
+    pub fn parts(&self) -> u64 {
+        self.parts
+    }
+}
+
+/// The error a transfer failed with, as it is printed with `--output json`.
+#[derive(Debug, Serialize)]
+struct TransferError {
+    outcome: Outcome,
+    error: String,
+    /// Whether the transfer can be continued, e.g. through `resume`.
+    retryable: bool,
+}
+
+impl OutputFormat {
+    /// Prints the result of a successful transfer.
+    pub(crate) fn print_result(&self, result: &TransferResult) {
+        if *self == OutputFormat::Json {
+            print_json(result);
+        }
+    }
+
+    /// Prints the error a transfer failed with.
+    pub(crate) fn print_error(&self, error: &Error) {
+        if *self == OutputFormat::Json {
+            print_json(&TransferError {
+                outcome: Outcome::of_error(error),
+                error: match error.inner() {
+                    Some(err) => format!("{:#}", err),
+                    None => error.to_string(),
+                },
+                retryable: !matches!(error, Error::Unrecoverable(_)),
+            });
+        }
+    }
+}
+
+fn print_json(value: &impl Serialize) {
+    match serde_json::to_string(value) {
+        Ok(json) => println!("{}", json),
+        Err(err) => warn!("Failed to serialize the result of the transfer: {}", err),
+    }
+}
@@ -0,0 +1,273 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    consts::{
+        MAXIMUM_NUMBER_OF_PARTS,
+        MAXIMUM_PART_NUMBER,
+        MAXIMUM_PART_SIZE,
+        MINIMUM_PART_NUMBER,
+        MINIMUM_PART_SIZE,
+    },
+    result::{
+        bail,
+        AnyhowResultExt,
+        Result,
+    },
+    size::format_size,
+};
+
+/// A single part of a multipart transfer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Part {
+    pub(crate) number: i32,
+    /// Offset of the first byte of this part within the file.
+    pub(crate) offset: u64,
+    /// Number of bytes in this part.
+    pub(crate) size: u64,
+}
+
+impl Part {
+    /// The number of this part, starting at 1.
+    pub fn number(&self) -> i32 {
+        self.number
+    }
+
+    /// Offset of the first byte of this part within the file.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Number of bytes in this part.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Offset of the first byte after this part.
+    pub(crate) fn end(&self) -> u64 {
+        self.offset + self.size
+    }
+}
+
+/// Describes how a file of a given size is split into parts of a given size.
+///
+/// All parts have the same size, except for the last part, which holds the remaining bytes and can
+/// thus be smaller (but never empty).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct PartPlan {
+    file_size: u64,
+    part_size: u64,
+}
+
+impl PartPlan {
+    pub(crate) fn new(file_size: u64, part_size: u64) -> Self {
+        assert!(part_size > 0, "part size must not be zero");
+        Self {
+            file_size,
+            part_size,
+        }
+    }
+
+    pub(crate) fn number_of_parts(&self) -> u64 {
+        self.file_size.div_ceil(self.part_size)
+    }
+
+    /// Returns the part with the given (1-based) part number, if it exists in this plan.
+    pub(crate) fn part(&self, number: u64) -> Option<Part> {
+        if number < MINIMUM_PART_NUMBER || number > self.number_of_parts() {
+            return None;
+        }
+        let offset = (number - MINIMUM_PART_NUMBER) * self.part_size;
+        Some(Part {
+            number: number as i32,
+            offset,
+            size: self.part_size.min(self.file_size - offset),
+        })
+    }
+
+    /// Returns all parts, starting with the given (1-based) part number.
+    pub(crate) fn parts_from(&self, first_part_number: u64) -> impl Iterator<Item = Part> + '_ {
+        (first_part_number.max(MINIMUM_PART_NUMBER)..=self.number_of_parts())
+            .filter_map(|number| self.part(number))
+    }
+}
+
+/// Chooses the part-size to upload a file of the given size with.
+///
+/// If `override_part_size` is provided, it is validated against the limits of S3. Otherwise, the
+/// smallest part-size possible is chosen: this is either the minimum part-size S3 requires, or the
+/// smallest part-size that still allows the file to be uploaded within the maximum number of parts.
+pub(crate) fn choose_part_size(file_size: u64, override_part_size: Option<u64>) -> Result<u64> {
+    if let Some(override_part_size) = override_part_size {
+        if override_part_size < MINIMUM_PART_SIZE {
+            bail!(
+                "The part size of {} is too small, it must be at least {}",
+                format_size(override_part_size),
+                format_size(MINIMUM_PART_SIZE),
+            );
+        } else if override_part_size > MAXIMUM_PART_SIZE {
+            bail!(
+                "The part size of {} is too large, it must be at most {}",
+                format_size(override_part_size),
+                format_size(MAXIMUM_PART_SIZE),
+            );
+        }
+        if file_size.div_ceil(override_part_size) > MAXIMUM_PART_NUMBER {
+            bail!(
+                "A part size of {} results in more than the {} parts allowed by S3 for a file of {}",
+                format_size(override_part_size),
+                MAXIMUM_PART_NUMBER,
+                format_size(file_size),
+            );
+        }
+        Ok(override_part_size)
+    } else {
+        // The size of the parts we want to upload must at least be `MINIMUM_PART_SIZE`, but if the
+        // file is so large that this part-size would result in more than `MAXIMUM_NUMBER_OF_PARTS`,
+        // we need to adjust the part size to ensure we don't exceed this limit.
+        let part_size = MINIMUM_PART_SIZE.max(file_size.div_ceil(MAXIMUM_NUMBER_OF_PARTS));
+        if part_size > MAXIMUM_PART_SIZE {
+            bail!("The part size exceeds the maximum part size allowed by S3");
+        }
+        Ok(part_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consts::{
+        GiB,
+        MiB,
+        TiB,
+        MAXIMUM_OBJECT_SIZE,
+    };
+
+    /// Verifies the invariants every plan has to uphold: parts are numbered consecutively starting
+    /// at 1, are contiguous, are never empty, all but the last part have exactly the part size, and
+    /// together they cover exactly the whole file.
+    fn assert_plan_is_valid(file_size: u64, part_size: u64) {
+        let plan = PartPlan::new(file_size, part_size);
+        let parts: Vec<_> = plan.parts_from(MINIMUM_PART_NUMBER).collect();
+
+        assert_eq!(parts.len() as u64, plan.number_of_parts());
+        let mut expected_offset = 0;
+        for (index, part) in parts.iter().enumerate() {
+            assert_eq!(part.number as u64, index as u64 + MINIMUM_PART_NUMBER);
+            assert_eq!(part.offset, expected_offset);
+            assert!(part.size > 0);
+            if index + 1 < parts.len() {
+                assert_eq!(part.size, part_size);
+            } else {
+                assert!(part.size <= part_size);
+            }
+            assert_eq!(plan.part(part.number as u64), Some(*part));
+            expected_offset = part.end();
+        }
+        assert_eq!(expected_offset, file_size);
+
+        assert_eq!(plan.part(0), None);
+        assert_eq!(plan.part(plan.number_of_parts() + 1), None);
+    }
+
+    #[test]
+    fn plans_are_valid_for_all_small_sizes() {
+        for file_size in 0..=512 {
+            for part_size in 1..=64 {
+                assert_plan_is_valid(file_size, part_size);
+            }
+        }
+    }
+
+    #[test]
+    fn plans_are_valid_around_part_boundaries() {
+        for part_size in [MINIMUM_PART_SIZE, 64 * MiB, GiB, MAXIMUM_PART_SIZE] {
+            for parts in [1, 2, 3, 9_999, MAXIMUM_NUMBER_OF_PARTS] {
+                for delta in [-1i64, 0, 1] {
+                    let file_size = (parts * part_size).saturating_add_signed(delta);
+                    // Only checking the number of parts and the last part keeps this test fast.
+                    let plan = PartPlan::new(file_size, part_size);
+                    let last = plan.part(plan.number_of_parts()).unwrap();
+                    assert_eq!(last.end(), file_size);
+                    assert!(last.size > 0 && last.size <= part_size);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn parts_from_skips_completed_parts() {
+        for file_size in 0..=128 {
+            for part_size in 1..=16 {
+                let plan = PartPlan::new(file_size, part_size);
+                for first in 0..=plan.number_of_parts() + 1 {
+                    let parts: Vec<_> = plan.parts_from(first).collect();
+                    let expected_first = first.max(MINIMUM_PART_NUMBER);
+                    assert_eq!(
+                        parts.len() as u64,
+                        (plan.number_of_parts() + 1).saturating_sub(expected_first),
+                    );
+                    if let Some(part) = parts.first() {
+                        assert_eq!(part.number as u64, expected_first);
+                        assert_eq!(part.offset, (expected_first - 1) * part_size);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn chosen_part_size_respects_s3_limits() {
+        let file_sizes = (0..64)
+            .map(|exponent| 1u64 << exponent)
+            .chain([
+                MINIMUM_PART_SIZE * MAXIMUM_NUMBER_OF_PARTS - 1,
+                MINIMUM_PART_SIZE * MAXIMUM_NUMBER_OF_PARTS,
+                MINIMUM_PART_SIZE * MAXIMUM_NUMBER_OF_PARTS + 1,
+                TiB,
+                MAXIMUM_OBJECT_SIZE - 1,
+                MAXIMUM_OBJECT_SIZE,
+            ])
+            .filter(|file_size| *file_size <= MAXIMUM_OBJECT_SIZE);
+        for file_size in file_sizes {
+            let part_size = choose_part_size(file_size, None).unwrap();
+            assert!(part_size >= MINIMUM_PART_SIZE);
+            assert!(part_size <= MAXIMUM_PART_SIZE);
+            let plan = PartPlan::new(file_size, part_size);
+            assert!(plan.number_of_parts() <= MAXIMUM_NUMBER_OF_PARTS);
+            // The chosen part size is the smallest possible: one byte less would need too many
+            // parts, unless we are already at the minimum.
+            if part_size > MINIMUM_PART_SIZE {
+                assert!(file_size.div_ceil(part_size - 1) > MAXIMUM_NUMBER_OF_PARTS);
+            }
+        }
+    }
+
+    #[test]
+    fn override_part_size_is_validated() {
+        assert!(choose_part_size(GiB, Some(MINIMUM_PART_SIZE - 1)).is_err());
+        assert!(choose_part_size(GiB, Some(MAXIMUM_PART_SIZE + 1)).is_err());
+        assert!(choose_part_size(MAXIMUM_OBJECT_SIZE, Some(MINIMUM_PART_SIZE)).is_err());
+        assert_eq!(
+            choose_part_size(GiB, Some(MINIMUM_PART_SIZE)).unwrap(),
+            MINIMUM_PART_SIZE
+        );
+        assert_eq!(
+            choose_part_size(MAXIMUM_OBJECT_SIZE, Some(MAXIMUM_PART_SIZE)).unwrap(),
+            MAXIMUM_PART_SIZE
+        );
+    }
+}
@@ -0,0 +1,463 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    duration::format_duration,
+    history::Outcome,
+    parts::Part,
+    result::Error,
+    size::format_size,
+};
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    io::{
+        IsTerminal,
+        Write,
+    },
+    pin::Pin,
+    sync::{
+        atomic::{
+            AtomicBool,
+            AtomicU64,
+            Ordering,
+        },
+        Arc,
+        Mutex,
+    },
+    task::{
+        Context,
+        Poll,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+use tokio::io::{
+    AsyncRead,
+    ReadBuf,
+};
+use tracing::{
+    info,
+    warn,
+};
+
+/// Receives progress updates from a running transfer.
+///
+/// All methods have an empty default implementation, so implementations only have to handle the
+/// updates they are interested in. Unlike the [`TransferEvent`]s passed to
+/// [`Uploader::on_event`](crate::Uploader::on_event), the updates aren't converted into events,
+/// and `bytes_transferred` is reported for every chunk that is handed to S3.
+pub trait ProgressReporter: Send + Sync {
+    /// The transfer is starting. `transferred_bytes` is non-zero if the transfer is resumed.
+    fn started(&self, _total_bytes: u64, _transferred_bytes: u64, _number_of_parts: u64) {}
+
+    /// The transfer of a part is starting.
+    fn part_started(&self, _part: &Part, _number_of_parts: u64) {}
+
+    /// More bytes of the given part have been handed to S3.
+    fn bytes_transferred(&self, _part: &Part, _bytes: u64) {}
+
+    /// The transfer of a part has failed with a retryable error and will be retried.
+    ///
+    /// Bytes that have been reported through `bytes_transferred` for this attempt have to be
+    /// considered lost.
+    fn part_retrying(&self, _part: &Part, _attempt: u32, _error: &Error) {}
+
+    /// The transfer of a part has finished successfully.
+    fn part_completed(&self, _part: &Part, _number_of_parts: u64) {}
+
+    /// The transfer has finished, with the given outcome.
+    fn finished(&self, _outcome: Outcome) {}
+}
+
+/// How progress is reported on the command line.
+#[derive(Clone, Copy, Debug, Default, clap::ValueEnum)]
+pub(crate) enum ProgressFormat {
+    /// Log the start and completion of each part.
+    #[default]
+    Log,
+    /// Print one JSON document per progress update to stdout.
+    Ndjson,
+    /// Show a progress bar with throughput and ETA on stderr.
+    ///
+    /// Falls back to `log` if stderr is not a terminal.
+    Bar,
+}
+
+impl ProgressFormat {
+    pub(crate) fn reporter(&self) -> Arc<dyn ProgressReporter> {
+        match self {
+            ProgressFormat::Log => Arc::new(EventReporter::new(log_event)),
+            ProgressFormat::Ndjson => Arc::new(EventReporter::new(print_event)),
+            ProgressFormat::Bar if std::io::stderr().is_terminal() => {
+                Arc::new(BarReporter::default())
+            }
+            ProgressFormat::Bar => Arc::new(EventReporter::new(log_event)),
+        }
+    }
+}
+
+/// A progress update of a running transfer.
+///
+/// The number of bytes transferred only covers parts that have been completed, either in this or
+/// a previous attempt of the transfer.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum TransferEvent {
+    /// The transfer is starting. `transferred_bytes` is non-zero if the transfer is resumed.
+    Started {
+        total_bytes: u64,
+        transferred_bytes: u64,
+        number_of_parts: u64,
+    },
+    /// The transfer of a part is starting.
+    PartStarted {
+        part_number: i32,
+        part_size: u64,
+        number_of_parts: u64,
+    },
+    /// More bytes of a part have been handed to S3.
+    ///
+    /// These bytes have to be considered lost if the part is retried afterwards.
+    BytesTransferred { part_number: i32, bytes: u64 },
+    /// The transfer of a part has failed with a retryable error and will be retried.
+    PartRetrying {
+        part_number: i32,
+        attempt: u32,
+        error: String,
+    },
+    /// The transfer of a part has finished successfully.
+    PartCompleted {
+        part_number: i32,
+        part_size: u64,
+        number_of_parts: u64,
+        transferred_bytes: u64,
+    },
+    /// The transfer has finished, with the given outcome.
+    Finished {
+        outcome: Outcome,
+        transferred_bytes: u64,
+    },
+}
+
+/// Reports progress by passing a [`TransferEvent`] for every update to a callback.
+pub(crate) struct EventReporter<F> {
+    transferred_bytes: AtomicU64,
+    callback: F,
+}
+
+impl<F> EventReporter<F>
+where
+    F: Fn(&TransferEvent) + Send + Sync,
+{
+    pub(crate) fn new(callback: F) -> Self {
+        Self {
+            transferred_bytes: AtomicU64::new(0),
+            callback,
+        }
+    }
+}
+
+impl<F> ProgressReporter for EventReporter<F>
+where
+    F: Fn(&TransferEvent) + Send + Sync,
+{
+    fn started(&self, total_bytes: u64, transferred_bytes: u64, number_of_parts: u64) {
+        self.transferred_bytes
+            .store(transferred_bytes, Ordering::Relaxed);
+        (self.callback)(&TransferEvent::Started {
+            total_bytes,
+            transferred_bytes,
+            number_of_parts,
+        });
+    }
+
+    fn part_started(&self, part: &Part, number_of_parts: u64) {
+        (self.callback)(&TransferEvent::PartStarted {
+            part_number: part.number,
+            part_size: part.size,
+            number_of_parts,
+        });
+    }
+
+    fn bytes_transferred(&self, part: &Part, bytes: u64) {
+        (self.callback)(&TransferEvent::BytesTransferred {
+            part_number: part.number,
+            bytes,
+        });
+    }
+
+    fn part_retrying(&self, part: &Part, attempt: u32, error: &Error) {
+        (self.callback)(&TransferEvent::PartRetrying {
+            part_number: part.number,
+            attempt,
+            error: error.to_string(),
+        });
+    }
+
+    fn part_completed(&self, part: &Part, number_of_parts: u64) {
+        let transferred_bytes = self
+            .transferred_bytes
+            .fetch_add(part.size, Ordering::Relaxed)
+            + part.size;
+        (self.callback)(&TransferEvent::PartCompleted {
+            part_number: part.number,
+            part_size: part.size,
+            number_of_parts,
+            transferred_bytes,
+        });
+    }
+
+    fn finished(&self, outcome: Outcome) {
+        (self.callback)(&TransferEvent::Finished {
+            outcome,
+            transferred_bytes: self.transferred_bytes.load(Ordering::Relaxed),
+        });
+    }
+}
+
+/// Reports progress through log messages.
+fn log_event(event: &TransferEvent) {
+    match event {
+        TransferEvent::PartStarted {
+            part_number,
+            part_size,
+            number_of_parts,
+        } => info!(
+            "Starting upload of part {} of {} ({} bytes)...",
+            part_number, number_of_parts, part_size,
+        ),
+        TransferEvent::PartRetrying {
+            part_number,
+            attempt,
+            error,
+        } => warn!(
+            "Failed to upload part {}, retrying (attempt {}): {}",
+            part_number, attempt, error,
+        ),
+        TransferEvent::PartCompleted {
+            part_number,
+            part_size,
+            number_of_parts,
+            ..
+        } => info!(
+            "Finished upload of part {} of {} ({} bytes)",
+            part_number, number_of_parts, part_size,
+        ),
+        _ => {}
+    }
+}
+
+/// Reports progress as newline-delimited JSON on stdout, for consumption by other programs.
+///
+/// Individual byte-updates are not printed, to keep the output at a manageable volume.
+fn print_event(event: &TransferEvent) {
+    if matches!(event, TransferEvent::BytesTransferred { .. }) {
+        return;
+    }
+    if let Ok(mut line) = serde_json::to_vec(event) {
+        line.push(b'\n');
+        let mut stdout = std::io::stdout().lock();
+        let _ = stdout.write_all(&line).and_then(|_| stdout.flush());
+    }
+}
+
+/// Whether a progress bar is currently shown on stderr.
+static BAR_SHOWN: AtomicBool = AtomicBool::new(false);
+
+/// Returns the writer for log messages.
+///
+/// If a progress bar is shown, it is cleared first, so the log message doesn't end up behind the
+/// bar. The bar is drawn again with the next progress update.
+pub(crate) fn log_writer() -> std::io::Stderr {
+    let stderr = std::io::stderr();
+    if BAR_SHOWN.load(Ordering::Relaxed) {
+        let _ = stderr.lock().write_all(b"\r\x1b[2K");
+    }
+    stderr
+}
+
+/// Time span over which the current throughput is averaged.
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(5);
+
+/// Minimum time between two redraws of the progress bar.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Width of the bar itself, in characters.
+const BAR_WIDTH: usize = 30;
+
+/// Reports progress through a progress bar on stderr, updated as the bytes of each part are sent.
+#[derive(Default)]
+pub(crate) struct BarReporter {
+    bar: Mutex<Bar>,
+}
+
+#[derive(Default)]
+struct Bar {
+    /// Total number of bytes, unknown for uploads from stdin.
+    total_bytes: Option<u64>,
+    transferred_bytes: u64,
+    /// Bytes sent for the current attempts of the parts in progress, which are lost on a retry.
+    bytes_in_attempt: u64,
+    /// Recent samples of `transferred_bytes`, to calculate the current throughput.
+    samples: VecDeque<(Instant, u64)>,
+    last_drawn: Option<Instant>,
+}
+
+impl Bar {
+    fn bytes_per_second(&self) -> Option<f64> {
+        let (first_instant, first_bytes) = self.samples.front()?;
+        let (last_instant, last_bytes) = self.samples.back()?;
+        let elapsed = last_instant.duration_since(*first_instant).as_secs_f64();
+        (elapsed > 0.0).then(|| last_bytes.saturating_sub(*first_bytes) as f64 / elapsed)
+    }
+
+    fn draw(&mut self, force: bool) {
+        let now = Instant::now();
+        self.samples.push_back((now, self.transferred_bytes));
+        while self
+            .samples
+            .front()
+            .is_some_and(|(instant, _)| now.duration_since(*instant) > THROUGHPUT_WINDOW)
+        {
+            self.samples.pop_front();
+        }
+        if !force
+            && self
+                .last_drawn
+                .is_some_and(|last_drawn| now.duration_since(last_drawn) < REDRAW_INTERVAL)
+        {
+            return;
+        }
+        self.last_drawn = Some(now);
+
+        let bytes_per_second = self.bytes_per_second();
+        let mut line = match self.total_bytes {
+            Some(total_bytes) => {
+                let fraction = (self.transferred_bytes as f64 / total_bytes as f64).min(1.0);
+                let filled = (fraction * BAR_WIDTH as f64) as usize;
+                format!(
+                    "[{}{}] {:>3.0}%  {} / {}",
+                    "=".repeat(filled),
+                    " ".repeat(BAR_WIDTH - filled),
+                    fraction * 100.0,
+                    format_size(self.transferred_bytes),
+                    format_size(total_bytes),
+                )
+            }
+            None => format!("{} transferred", format_size(self.transferred_bytes)),
+        };
+        if let Some(bytes_per_second) = bytes_per_second {
+            line.push_str(&format!("  {}/s", format_size(bytes_per_second as u64)));
+            if let (Some(total_bytes), true) = (self.total_bytes, bytes_per_second >= 1.0) {
+                let remaining = total_bytes.saturating_sub(self.transferred_bytes) as f64;
+                line.push_str(&format!(
+                    "  ETA {}",
+                    format_duration(Duration::from_secs_f64(remaining / bytes_per_second)),
+                ));
+            }
+        }
+
+        let mut stderr = std::io::stderr().lock();
+        let _ = write!(stderr, "\r\x1b[2K{}", line).and_then(|_| stderr.flush());
+        BAR_SHOWN.store(true, Ordering::Relaxed);
+    }
+}
+
+impl ProgressReporter for BarReporter {
+    fn started(&self, total_bytes: u64, transferred_bytes: u64, _number_of_parts: u64) {
+        let mut bar = self.bar.lock().expect("poisoned lock");
+        // For uploads from stdin, the size only covers what has been read so far.
+        bar.total_bytes = (total_bytes > transferred_bytes).then_some(total_bytes);
+        bar.transferred_bytes = transferred_bytes;
+        bar.draw(true);
+    }
+
+    fn part_started(&self, _part: &Part, _number_of_parts: u64) {
+        self.bar.lock().expect("poisoned lock").bytes_in_attempt = 0;
+    }
+
+    fn bytes_transferred(&self, _part: &Part, bytes: u64) {
+        let mut bar = self.bar.lock().expect("poisoned lock");
+        bar.transferred_bytes += bytes;
+        bar.bytes_in_attempt += bytes;
+        bar.draw(false);
+    }
+
+    fn part_retrying(&self, part: &Part, attempt: u32, error: &Error) {
+        {
+            let mut bar = self.bar.lock().expect("poisoned lock");
+            bar.transferred_bytes -= bar.bytes_in_attempt;
+            bar.bytes_in_attempt = 0;
+        }
+        log_event(&TransferEvent::PartRetrying {
+            part_number: part.number,
+            attempt,
+            error: error.to_string(),
+        });
+    }
+
+    fn part_completed(&self, _part: &Part, _number_of_parts: u64) {
+        let mut bar = self.bar.lock().expect("poisoned lock");
+        bar.bytes_in_attempt = 0;
+        bar.draw(false);
+    }
+
+    fn finished(&self, _outcome: Outcome) {
+        self.bar.lock().expect("poisoned lock").draw(true);
+        BAR_SHOWN.store(false, Ordering::Relaxed);
+        let _ = writeln!(std::io::stderr());
+    }
+}
+
+/// Wraps a reader of a part and reports every chunk read from it as transferred.
+pub(crate) struct ProgressReader<R> {
+    inner: R,
+    part: Part,
+    reporter: Arc<dyn ProgressReporter>,
+}
+
+impl<R> ProgressReader<R> {
+    pub(crate) fn new(inner: R, part: Part, reporter: Arc<dyn ProgressReporter>) -> Self {
+        Self {
+            inner,
+            part,
+            reporter,
+        }
+    }
+}
+
+impl<R> AsyncRead for ProgressReader<R>
+where
+    R: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled_before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = (buf.filled().len() - filled_before) as u64;
+        if read > 0 {
+            self.reporter.bytes_transferred(&self.part, read);
+        }
+        result
+    }
+}
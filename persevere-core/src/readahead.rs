@@ -0,0 +1,123 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    open_file_part,
+    parts::{
+        Part,
+        PartPlan,
+    },
+    read_exactly,
+    result::{
+        AnyhowResultExt,
+        Result,
+    },
+};
+use anyhow::Context;
+use std::{
+    collections::VecDeque,
+    path::PathBuf,
+};
+use tokio::task::JoinHandle;
+use tokio_util::bytes::Bytes;
+
+/// Reads the parts following the one being uploaded into memory in the background, so that the
+/// next part can be sent right away instead of waiting for the file to be opened and read.
+#[derive(Debug)]
+pub(crate) struct Readahead {
+    file: PathBuf,
+    /// Offset of the object being uploaded within the file, which is non-zero for split files.
+    offset: u64,
+    plan: PartPlan,
+    /// How many parts are read ahead at most.
+    depth: u64,
+    direct_io: bool,
+    /// The parts being read, in the order of their part numbers.
+    pending: VecDeque<(i32, JoinHandle<Result<Bytes>>)>,
+}
+
+impl Readahead {
+    pub(crate) fn new(
+        file: PathBuf,
+        offset: u64,
+        plan: PartPlan,
+        depth: u64,
+        direct_io: bool,
+    ) -> Self {
+        Self {
+            file,
+            offset,
+            plan,
+            depth,
+            direct_io,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Returns the contents of the given part, and starts reading the parts after it.
+    pub(crate) async fn read(&mut self, part: &Part) -> Result<Bytes> {
+        while let Some((number, _)) = self.pending.front() {
+            if *number >= part.number {
+                break;
+            }
+            if let Some((_, task)) = self.pending.pop_front() {
+                task.abort();
+            }
+        }
+        let task = match self.pending.front() {
+            Some((number, _)) if *number == part.number => {
+                self.pending.pop_front().map(|(_, task)| task)
+            }
+            _ => None,
+        };
+        let task = task.unwrap_or_else(|| self.spawn(*part));
+
+        let next_number = self
+            .pending
+            .back()
+            .map_or(part.number, |(number, _)| *number) as u64
+            + 1;
+        for number in next_number..=part.number as u64 + self.depth {
+            let Some(next_part) = self.plan.part(number) else {
+                break;
+            };
+            let task = self.spawn(next_part);
+            self.pending.push_back((next_part.number, task));
+        }
+
+        task.await
+            .context("Failed to read the part ahead")
+            .into_unrecoverable()?
+    }
+
+    fn spawn(&self, part: Part) -> JoinHandle<Result<Bytes>> {
+        let file = self.file.clone();
+        let offset = self.offset + part.offset;
+        let direct_io = self.direct_io;
+        tokio::spawn(async move {
+            let reader = open_file_part(&file, offset, part.size, direct_io).await?;
+            read_exactly(reader, &part).await
+        })
+    }
+}
+
+impl Drop for Readahead {
+    fn drop(&mut self) {
+        for (_, task) in &self.pending {
+            task.abort();
+        }
+    }
+}
@@ -0,0 +1,214 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    checksum::{
+        ChecksumReader,
+        Hasher,
+    },
+    open_part,
+    parts::{
+        Part,
+        PartPlan,
+    },
+    result::{
+        bail,
+        AnyhowResultExt,
+        Result,
+        StdResultExt,
+    },
+    spill,
+    State,
+};
+use anyhow::Context;
+use aws_sdk_s3::{
+    error::ProvideErrorMetadata,
+    types::CompletedPart,
+};
+use std::collections::BTreeMap;
+use tracing::{
+    debug,
+    info,
+    warn,
+};
+
+/// Reconciles the state of an upload with the parts S3 actually holds for the multipart upload.
+///
+/// The state-file can diverge from S3, e.g. when the process was killed after a part was uploaded
+/// but before the state-file was written, or when the state-file is only checkpointed every so
+/// often. Completed parts S3 doesn't know about (anymore) are uploaded again, and parts S3 holds
+/// beyond the last completed part are adopted if they match the file locally.
+///
+/// Returns whether the state was changed and thus has to be written to the state-file.
+pub(crate) async fn reconcile(s3: &aws_sdk_s3::Client, state: &mut State) -> Result<bool> {
+    // The next object of a split file has no multipart upload yet, so there is nothing to reconcile.
+    if state.upload_id.is_empty() {
+        return Ok(false);
+    }
+    let uploaded_parts = match s3
+        .list_parts()
+        .bucket(&state.s3_bucket)
+        .key(&state.s3_key)
+        .upload_id(&state.upload_id)
+        .set_request_payer(state.request_payer())
+        .into_paginator()
+        .items()
+        .send()
+        .try_collect()
+        .await
+    {
+        Ok(uploaded_parts) => uploaded_parts,
+        // Listing the parts requires a permission that uploading didn't need so far, which is why
+        // we don't want to fail resuming an upload just because of it.
+        Err(error) if error.code() == Some("AccessDenied") => {
+            warn!(
+                "Not allowed to list the parts of the multipart upload (`s3:ListMultipartUploadParts`), resuming based on the state-file alone."
+            );
+            return Ok(false);
+        }
+        Err(error) => {
+            return Err(error)
+                .context("Failed to list the parts of the multipart upload")
+                .into_retryable()
+        }
+    };
+    let uploaded_parts: BTreeMap<_, _> = uploaded_parts
+        .into_iter()
+        .filter_map(|part| Some((part.part_number?, part)))
+        .collect();
+    debug!("S3 holds {} parts for the upload", uploaded_parts.len());
+
+    let mut changed = false;
+
+    // All parts the state-file considers completed have to exist in S3 exactly as they were
+    // uploaded.
+    let diverged_part = state.completed_parts.iter().find_map(|completed_part| {
+        let number = completed_part.part_number()?;
+        let expected_size = state.part(number as u64).map(|part| part.size as i64);
+        match uploaded_parts.get(&number) {
+            Some(uploaded_part)
+                if uploaded_part.e_tag() == completed_part.e_tag()
+                    && uploaded_part.size() == expected_size =>
+            {
+                None
+            }
+            Some(_) => Some((number, "differs from the part in the state-file")),
+            None => Some((number, "is missing")),
+        }
+    });
+    if let Some((number, reason)) = diverged_part {
+        if state.reads_stdin() {
+            bail!(
+                "Part {} of the upload {} in S3. Since the data was read from stdin, the part can't be uploaded again and the upload has to be aborted. Upload ID: {}",
+                number,
+                reason,
+                state.upload_id,
+            );
+        }
+        warn!(
+            "Part {} of the upload {} in S3, uploading it and all following parts again.",
+            number, reason,
+        );
+        state.completed_parts.truncate(number as usize - 1);
+        state.last_successful_part = number as u64 - 1;
+        if let Some(auto_tune) = &mut state.auto_tune {
+            auto_tune.truncate(number as usize - 1);
+        }
+        // Compressed files and archives are produced again up to the end of the last remaining
+        // part, which makes any part spilled by a previous run obsolete.
+        if let (Some(spill_directory), false) = (&state.spill_directory, state.reads_stdin()) {
+            state.file_size_in_bytes = state.last_successful_part * state.part_size;
+            spill::remove_directory(spill_directory).await?;
+            tokio::fs::create_dir_all(spill_directory)
+                .await
+                .into_unrecoverable()?;
+        }
+        changed = true;
+    }
+
+    // The parts of stdin-uploads are spilled until they are checkpointed, so parts S3 holds beyond
+    // the state-file are simply uploaded again from the spill directory. With tuned part sizes, the
+    // sizes of the parts beyond the state-file are unknown, so they are uploaded again as well, just
+    // like the parts of copies, which have no local data to verify them against, and encrypted
+    // parts, whose ETag isn't the digest of the local data.
+    if state.spill_directory.is_some()
+        || state.auto_tune.is_some()
+        || state.copy_source.is_some()
+        || state.encryption.is_some()
+    {
+        return Ok(changed);
+    }
+    let plan = PartPlan::new(state.file_size_in_bytes, state.part_size);
+
+    let mut adopted = 0;
+    while let Some(part) = plan.part(state.last_successful_part + 1) {
+        let Some(uploaded_part) = uploaded_parts.get(&part.number) else {
+            break;
+        };
+        if uploaded_part.size() != Some(part.size as i64) {
+            debug!(
+                "Part {} in S3 has a different size than expected, not adopting it",
+                part.number,
+            );
+            break;
+        }
+        let completed_part = CompletedPart::builder()
+            .set_checksum_crc32(uploaded_part.checksum_crc32.clone())
+            .set_checksum_crc32_c(uploaded_part.checksum_crc32_c.clone())
+            .set_checksum_sha1(uploaded_part.checksum_sha1.clone())
+            .set_checksum_sha256(uploaded_part.checksum_sha256.clone())
+            .set_e_tag(uploaded_part.e_tag.clone())
+            .part_number(part.number)
+            .build();
+        if let Err(error) = verify_locally(state, part, &completed_part).await {
+            debug!("Not adopting part {} from S3: {}", part.number, error);
+            break;
+        }
+        state.completed_parts.push(completed_part);
+        state.last_successful_part += 1;
+        adopted += 1;
+    }
+    if adopted > 0 {
+        info!(
+            "Adopted {} parts S3 already holds but the state-file was missing.",
+            adopted,
+        );
+        changed = true;
+    }
+
+    Ok(changed)
+}
+
+/// Verifies that a part S3 holds matches the data of the part in the local file.
+///
+/// This requires the ETag of the part to be the MD5 digest of its data, which is not the case for
+/// parts encrypted with a KMS key, so these parts are never adopted.
+async fn verify_locally(state: &State, part: Part, completed_part: &CompletedPart) -> Result<()> {
+    let md5 = Hasher::md5();
+    let hasher = state.checksum_algorithm.map(Hasher::new);
+    let hashers = std::iter::once(md5.clone()).chain(hasher.clone()).collect();
+    let mut reader = ChecksumReader::new(open_part(state, &part, false).await?, hashers);
+    tokio::io::copy(&mut reader, &mut tokio::io::sink())
+        .await
+        .into_unrecoverable()?;
+
+    let what = format!("part {}", part.number);
+    md5.verify_e_tag(&what, completed_part.e_tag(), None)?;
+    if let (Some(hasher), Some(algorithm)) = (hasher, state.checksum_algorithm) {
+        hasher.verify(algorithm, &what, completed_part)?;
+    }
+    Ok(())
+}
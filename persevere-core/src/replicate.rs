@@ -0,0 +1,332 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    hints,
+    parts::Part,
+    progress::ProgressReporter,
+    result::{
+        bail,
+        AnyhowResultExt,
+        Error,
+        Result,
+        StdResultExt,
+    },
+    s3_uri::S3Uri,
+    state_store::StateStore,
+    throttle::RateLimiter,
+    upload_part,
+    verbosity,
+    Destination,
+    State,
+    TransferOptions,
+};
+use aws_sdk_s3::{
+    config::Region,
+    types::{
+        CompletedMultipartUpload,
+        CompletedPart,
+    },
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::{
+    path::Path,
+    sync::Arc,
+};
+use tokio_util::bytes::Bytes;
+use tracing::{
+    error,
+    info,
+};
+
+/// Where to replicate an upload to, as given with `--replicate-to`.
+#[derive(Clone, Debug)]
+pub(crate) struct ReplicaTarget {
+    region: Option<String>,
+    uri: S3Uri,
+}
+
+/// Parses a replica target in the form `[REGION=]S3_URI`.
+pub(crate) fn parse_target(value: &str) -> Result<ReplicaTarget, String> {
+    let (region, uri) = match value.split_once('=') {
+        Some((region, uri)) if !region.starts_with("s3://") => (Some(region.to_owned()), uri),
+        _ => (None, value),
+    };
+    Ok(ReplicaTarget {
+        region,
+        uri: uri.parse()?,
+    })
+}
+
+/// Another object the file is uploaded to alongside the one of the state, through a multipart
+/// upload of its own.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct Replica {
+    s3_bucket: String,
+    s3_key: String,
+    /// The region of the bucket, if it differs from the one the upload was started in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    aws_region: Option<String>,
+    upload_id: String,
+    #[serde(with = "crate::de::completed_parts")]
+    completed_parts: Vec<CompletedPart>,
+    /// The ETag of the object, once the multipart upload has been completed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    e_tag: Option<String>,
+}
+
+impl Replica {
+    pub(crate) fn new(target: ReplicaTarget, file_to_upload: &Path) -> Result<Self> {
+        let file_name = file_to_upload
+            .file_name()
+            .and_then(|file_name| file_name.to_str());
+        let Some(s3_key) = target.uri.key_for_file(file_name) else {
+            bail!(
+                "The S3 URI {} doesn't include the key to replicate the file to",
+                target.uri,
+            );
+        };
+        Ok(Self {
+            s3_bucket: target.uri.bucket,
+            s3_key,
+            aws_region: target.region,
+            upload_id: String::new(),
+            completed_parts: vec![],
+            e_tag: None,
+        })
+    }
+
+    /// Whether the part still has to be uploaded to the replica.
+    fn is_missing(&self, part: &Part) -> bool {
+        self.e_tag.is_none() && self.completed_parts.len() < part.number as usize
+    }
+}
+
+/// Replicas don't report any progress, as the progress reported is the one of reading the file.
+struct Silent;
+
+impl ProgressReporter for Silent {}
+
+/// Returns the clients to send the requests for the replicas with, in the order of the replicas.
+///
+/// Replicas in another region get a client of their own, which is otherwise configured like `s3`.
+pub(crate) fn clients(s3: &aws_sdk_s3::Client, state: &State) -> Vec<aws_sdk_s3::Client> {
+    state
+        .replicas
+        .iter()
+        .map(|replica| match &replica.aws_region {
+            Some(region) => aws_sdk_s3::Client::from_conf(
+                s3.config()
+                    .to_builder()
+                    .region(Region::new(region.clone()))
+                    .build(),
+            ),
+            None => s3.clone(),
+        })
+        .collect()
+}
+
+/// Returns the number of the first part that hasn't been uploaded to all objects yet.
+pub(crate) fn first_missing_part(state: &State) -> u64 {
+    state
+        .replicas
+        .iter()
+        .filter(|replica| replica.e_tag.is_none())
+        .map(|replica| replica.completed_parts.len() as u64)
+        .fold(state.last_successful_part, u64::min)
+        + 1
+}
+
+/// Creates the multipart uploads of the replicas that don't have one yet, returning whether any
+/// was created.
+pub(crate) async fn create_multipart_uploads(
+    clients: &[aws_sdk_s3::Client],
+    state: &mut State,
+) -> Result<bool> {
+    let mut created = false;
+    for (index, s3) in clients.iter().enumerate() {
+        let replica = &state.replicas[index];
+        if !replica.upload_id.is_empty() {
+            continue;
+        }
+        let multipart_upload = state
+            .object_options
+            .apply_to(
+                s3.create_multipart_upload()
+                    .bucket(&replica.s3_bucket)
+                    .key(&replica.s3_key)
+                    .set_request_payer(state.request_payer())
+                    .set_checksum_algorithm(
+                        state.checksum_algorithm.map(|algorithm| algorithm.sdk()),
+                    ),
+            )
+            .send()
+            .await
+            .into_retryable()?;
+        info!(
+            "Created multipart upload with ID {} to replicate the file to s3://{}/{}",
+            multipart_upload.upload_id().unwrap_or_default(),
+            replica.s3_bucket,
+            replica.s3_key,
+        );
+        state.replicas[index].upload_id = multipart_upload.upload_id.unwrap_or_default();
+        created = true;
+    }
+    Ok(created)
+}
+
+/// Uploads the part to all replicas that are missing it, concurrently.
+///
+/// Returns the outcome for every replica the part was uploaded to, by the index of the replica.
+pub(crate) async fn upload_missing_part(
+    clients: &[aws_sdk_s3::Client],
+    state: &State,
+    part: Part,
+    buffer: Option<&Bytes>,
+    limiter: Option<&RateLimiter>,
+    options: &TransferOptions,
+) -> Vec<(usize, Result<CompletedPart>)> {
+    let reporter: Arc<dyn ProgressReporter> = Arc::new(Silent);
+    let uploads = state
+        .replicas
+        .iter()
+        .zip(clients)
+        .enumerate()
+        .filter(|(_, (replica, _))| replica.is_missing(&part))
+        .map(|(index, (replica, s3))| {
+            let reporter = &reporter;
+            async move {
+                let destination = Destination {
+                    s3,
+                    s3_bucket: &replica.s3_bucket,
+                    s3_key: &replica.s3_key,
+                    upload_id: &replica.upload_id,
+                };
+                let result =
+                    upload_part(destination, state, part, buffer, limiter, reporter, options).await;
+                (index, result)
+            }
+        });
+    futures_util::future::join_all(uploads).await
+}
+
+/// Records the part as completed for the replicas it was uploaded to.
+///
+/// Returns whether the part was uploaded to any replica, and the error of the first replica it
+/// failed for, preferring unrecoverable errors.
+pub(crate) fn record_uploads(
+    state: &mut State,
+    uploads: Vec<(usize, Result<CompletedPart>)>,
+) -> (bool, Result<()>) {
+    let mut uploaded = false;
+    let mut result = Ok(());
+    for (index, upload) in uploads {
+        match upload {
+            Ok(completed_part) => {
+                state.replicas[index].completed_parts.push(completed_part);
+                uploaded = true;
+            }
+            Err(error) => {
+                let unrecoverable = matches!(error, Error::Unrecoverable(_))
+                    && !matches!(result, Err(Error::Unrecoverable(_)));
+                if result.is_ok() || unrecoverable {
+                    result = Err(error);
+                }
+            }
+        }
+    }
+    (uploaded, result)
+}
+
+/// Completes the multipart uploads of the replicas, writing the state after each.
+///
+/// The replicas are completed before the object of the state, so that a resumed upload finds all
+/// multipart uploads it still has to complete.
+pub(crate) async fn complete(
+    clients: &[aws_sdk_s3::Client],
+    store: &StateStore,
+    state: &mut State,
+) -> Result<()> {
+    for (index, s3) in clients.iter().enumerate() {
+        let replica = &state.replicas[index];
+        if replica.e_tag.is_some() {
+            continue;
+        }
+        let output = s3
+            .complete_multipart_upload()
+            .bucket(&replica.s3_bucket)
+            .key(&replica.s3_key)
+            .upload_id(&replica.upload_id)
+            .set_request_payer(state.request_payer())
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(replica.completed_parts.clone()))
+                    .build(),
+            )
+            .set_if_none_match(state.object_options.if_none_match())
+            .send()
+            .await
+            .into_retryable()
+            .map_err(hints::precondition_failed_is_unrecoverable)?;
+        info!(target: verbosity::SUMMARY,
+            "Successfully replicated the file to s3://{}/{}. ETag: {}",
+            replica.s3_bucket,
+            replica.s3_key,
+            output.e_tag().unwrap_or("<unknown>"),
+        );
+        state.replicas[index].e_tag = Some(output.e_tag.unwrap_or_default());
+        store.write(state).await?;
+    }
+    Ok(())
+}
+
+/// Aborts the multipart uploads of the replicas that haven't been completed yet.
+pub(crate) async fn abort(clients: &[aws_sdk_s3::Client], state: &State) -> Result<()> {
+    let mut failure = None;
+    for (replica, s3) in state.replicas.iter().zip(clients) {
+        if replica.e_tag.is_some() || replica.upload_id.is_empty() {
+            continue;
+        }
+        let result = s3
+            .abort_multipart_upload()
+            .bucket(&replica.s3_bucket)
+            .key(&replica.s3_key)
+            .upload_id(&replica.upload_id)
+            .set_request_payer(state.request_payer())
+            .send()
+            .await;
+        match result {
+            Ok(_) => info!(
+                "Aborted multipart upload with ID {} for: s3://{}/{}",
+                replica.upload_id, replica.s3_bucket, replica.s3_key,
+            ),
+            Err(err) => {
+                error!(
+                    "Failed to abort multipart upload with ID {} for s3://{}/{}: {}",
+                    replica.upload_id, replica.s3_bucket, replica.s3_key, err,
+                );
+                failure = Some(err);
+            }
+        }
+    }
+    match failure {
+        Some(err) => Err(Error::Retryable(err.into())),
+        None => Ok(()),
+    }
+}
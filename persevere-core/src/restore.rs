@@ -0,0 +1,218 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    duration::{
+        format_duration,
+        parse_duration,
+    },
+    result::{
+        AnyhowResultExt,
+        Result,
+    },
+    s3_uri::S3Uri,
+    sdk,
+    verbosity,
+};
+use anyhow::Context;
+use aws_sdk_s3::{
+    error::ProvideErrorMetadata,
+    operation::head_object::HeadObjectOutput,
+    types::{
+        GlacierJobParameters,
+        RequestPayer,
+        RestoreRequest,
+        StorageClass,
+        Tier,
+    },
+};
+use clap::{
+    builder::PossibleValuesParser,
+    Args,
+};
+use std::time::Duration;
+use tracing::{
+    debug,
+    info,
+};
+
+#[derive(Debug, Args)]
+pub(crate) struct Restore {
+    /// S3 URI of the archived object, e.g. `s3://my-bucket/path/big.iso`.
+    #[arg(value_name = "OBJECT")]
+    object: S3Uri,
+    /// The version of the object to restore, instead of its latest version.
+    #[arg(long)]
+    version_id: Option<String>,
+    /// Number of days the restored copy of the object stays available.
+    ///
+    /// Ignored for objects in the archive tiers of S3 Intelligent-Tiering, which are moved back to
+    /// the frequent access tier instead.
+    #[arg(long, default_value_t = 7)]
+    days: i32,
+    /// How fast the object is restored, at a correspondingly higher price.
+    #[arg(long, default_value = "Standard", value_parser = PossibleValuesParser::new(Tier::values()))]
+    tier: String,
+    /// Wait until the object has been restored, instead of returning once the restore was
+    /// requested.
+    #[arg(long)]
+    wait: bool,
+    /// How often to check whether the object has been restored while waiting, e.g. `15min`.
+    #[arg(long, default_value = "5min", value_parser = parse_duration)]
+    poll_interval: Duration,
+    /// Confirm that you will be charged for the requests to a requester-pays bucket.
+    #[arg(long, value_parser = PossibleValuesParser::new(RequestPayer::values()))]
+    request_payer: Option<String>,
+}
+
+/// How far the restore of an object has progressed.
+#[derive(Debug, PartialEq, Eq)]
+enum RestoreStatus {
+    /// The object is not archived, so it can be read right away.
+    NotArchived,
+    /// The object is archived, and its restore has not been requested yet.
+    Archived,
+    InProgress,
+    /// The object has been restored, and can be read until the given date if there is one.
+    Restored(Option<String>),
+}
+
+impl RestoreStatus {
+    fn of(head: &HeadObjectOutput) -> Self {
+        let archived = matches!(
+            head.storage_class,
+            Some(StorageClass::Glacier | StorageClass::DeepArchive)
+        ) || head.archive_status.is_some();
+        // The `x-amz-restore` header looks like `ongoing-request="false", expiry-date="..."`.
+        match head.restore.as_deref() {
+            Some(restore) if restore.contains("ongoing-request=\"true\"") => {
+                RestoreStatus::InProgress
+            }
+            Some(restore) => RestoreStatus::Restored(
+                restore
+                    .split_once("expiry-date=\"")
+                    .and_then(|(_, expiry_date)| expiry_date.split_once('"'))
+                    .map(|(expiry_date, _)| expiry_date.to_owned()),
+            ),
+            None if archived => RestoreStatus::Archived,
+            None => RestoreStatus::NotArchived,
+        }
+    }
+}
+
+impl Restore {
+    pub(crate) async fn run(&self) -> Result<()> {
+        debug!("Running restore command: {:?}", self);
+        let config = sdk::load_config().await;
+        let s3 = sdk::s3_client(&config);
+
+        let head = self.head_object(&s3).await?;
+        let mut status = RestoreStatus::of(&head);
+        if status == RestoreStatus::Archived {
+            self.request_restore(&s3, head.archive_status.is_some())
+                .await?;
+            status = RestoreStatus::InProgress;
+        }
+        while status == RestoreStatus::InProgress && self.wait {
+            info!(
+                "The restore of {} is in progress, checking again in {}",
+                self.object,
+                format_duration(self.poll_interval),
+            );
+            tokio::time::sleep(self.poll_interval).await;
+            status = RestoreStatus::of(&self.head_object(&s3).await?);
+        }
+
+        match status {
+            RestoreStatus::NotArchived => {
+                info!(target: verbosity::SUMMARY, "{} is not archived, there is nothing to restore", self.object)
+            }
+            RestoreStatus::InProgress => info!(target: verbosity::SUMMARY,
+                "The restore of {} is in progress. Run this command again with `--wait` to wait for it to complete.",
+                self.object,
+            ),
+            RestoreStatus::Restored(Some(expiry_date)) => info!(target: verbosity::SUMMARY,
+                "{} has been restored and is available until {}",
+                self.object, expiry_date,
+            ),
+            RestoreStatus::Restored(None) | RestoreStatus::Archived => {
+                info!(target: verbosity::SUMMARY, "{} has been restored", self.object)
+            }
+        }
+        Ok(())
+    }
+
+    async fn head_object(&self, s3: &aws_sdk_s3::Client) -> Result<HeadObjectOutput> {
+        s3.head_object()
+            .bucket(&self.object.bucket)
+            .key(&self.object.key)
+            .set_version_id(self.version_id.clone())
+            .set_request_payer(self.request_payer.as_deref().map(RequestPayer::from))
+            .send()
+            .await
+            .with_context(|| format!("Failed to read the object {}", self.object))
+            .into_retryable()
+    }
+
+    /// Requests the restore of the archived object.
+    ///
+    /// Objects in the archive tiers of S3 Intelligent-Tiering are restored without a number of
+    /// days, as S3 rejects it for them.
+    async fn request_restore(
+        &self,
+        s3: &aws_sdk_s3::Client,
+        intelligent_tiering: bool,
+    ) -> Result<()> {
+        let restore_request = RestoreRequest::builder()
+            .set_days((!intelligent_tiering).then_some(self.days))
+            .set_glacier_job_parameters(
+                (!intelligent_tiering)
+                    .then(|| {
+                        GlacierJobParameters::builder()
+                            .tier(Tier::from(self.tier.as_str()))
+                            .build()
+                    })
+                    .transpose()
+                    .context("Invalid retrieval tier")
+                    .into_unrecoverable()?,
+            )
+            .build();
+        match s3
+            .restore_object()
+            .bucket(&self.object.bucket)
+            .key(&self.object.key)
+            .set_version_id(self.version_id.clone())
+            .set_request_payer(self.request_payer.as_deref().map(RequestPayer::from))
+            .restore_request(restore_request)
+            .send()
+            .await
+        {
+            Err(error) if error.code() == Some("RestoreAlreadyInProgress") => {
+                debug!("The restore of {} was already requested", self.object);
+            }
+            result => {
+                result
+                    .with_context(|| format!("Failed to request the restore of {}", self.object))
+                    .into_retryable()?;
+                info!(
+                    "Requested the restore of {} with the {} tier",
+                    self.object, self.tier,
+                );
+            }
+        }
+        Ok(())
+    }
+}
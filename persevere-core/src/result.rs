@@ -28,10 +28,33 @@ pub(crate) use bail;
 
 pub(crate) type Result<T, E = Error> = std::result::Result<T, E>;
 
+/// The error of a transfer.
 #[derive(Debug)]
-pub(crate) enum Error {
+pub enum Error {
+    /// The transfer failed, but can be resumed later on.
     Retryable(anyhow::Error),
+    /// The transfer failed, and can't be resumed.
     Unrecoverable(anyhow::Error),
+    /// The transfer was paused on request, after its state was written.
+    Paused,
+    /// The transfer was stopped by SIGINT or SIGTERM, after its state was written.
+    Interrupted,
+}
+
+impl Error {
+    pub(crate) fn inner(&self) -> Option<&anyhow::Error> {
+        match self {
+            Error::Retryable(err) => Some(err),
+            Error::Unrecoverable(err) => Some(err),
+            Error::Paused | Error::Interrupted => None,
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.inner().map(|err| err.as_ref())
+    }
 }
 
 impl Display for Error {
@@ -39,6 +62,8 @@ impl Display for Error {
         match self {
             Error::Retryable(err) => write!(f, "Retryable error: {}", err),
             Error::Unrecoverable(err) => write!(f, "Unrecoverable error: {}", err),
+            Error::Paused => write!(f, "Paused"),
+            Error::Interrupted => write!(f, "Interrupted"),
         }
     }
 }
@@ -0,0 +1,144 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    clock::SharedClock,
+    duration::parse_duration,
+    hints,
+    result::Error,
+};
+use std::time::{
+    Duration,
+    Instant,
+};
+use tracing::info;
+
+/// Upper bound for the wait between two attempts, regardless of the number of retries.
+const MAXIMUM_BACKOFF: Duration = Duration::from_secs(120);
+
+/// Factor the backoff is multiplied with when S3 asks to reduce the request rate.
+const THROTTLING_FACTOR: u32 = 4;
+
+/// How failed parts are retried before the transfer is given up.
+#[derive(Clone, Copy, Debug, clap::Args)]
+pub(crate) struct RetryOptions {
+    /// How often a failed part is retried before the transfer stops.
+    ///
+    /// Once the retries are exhausted, the transfer stops with a retryable error and can be resumed
+    /// from the state-file.
+    #[arg(long, default_value_t = 2)]
+    pub(crate) max_retries: u32,
+    /// Time to wait before the first retry of a part, e.g. `500ms` or `2s`.
+    ///
+    /// The wait doubles with every further retry of the same part (up to two minutes), and is
+    /// randomized to avoid retrying in lockstep. When S3 responds with `503 Slow Down`, the wait is
+    /// four times as long.
+    #[arg(long, default_value = "1s", value_parser = parse_duration)]
+    retry_backoff: Duration,
+    /// Keep retrying failed parts instead of stopping after `--max-retries`.
+    ///
+    /// This lets unattended transfers wait out network outages, rather than stopping with a
+    /// retryable error that has to be resumed by hand. The wait between retries grows up to two
+    /// minutes.
+    #[arg(long)]
+    pub(crate) keep_trying: bool,
+    /// How long `--keep-trying` keeps retrying, e.g. `6h`, counted from when the transfer was
+    /// started or resumed.
+    ///
+    /// Once it has passed, the transfer stops with a retryable error the next time a part fails.
+    /// Without it, failed parts are retried until the transfer is stopped.
+    #[arg(long, requires = "keep_trying", value_parser = parse_duration)]
+    pub(crate) max_duration: Option<Duration>,
+}
+
+impl RetryOptions {
+    /// Whether to retry after the given attempt failed, for a transfer that was started or resumed
+    /// at `started` according to the clock.
+    pub(crate) fn should_retry(&self, attempt: u32, started: Instant, clock: &SharedClock) -> bool {
+        if self.keep_trying {
+            self.max_duration.is_none_or(|max_duration| {
+                clock.now().saturating_duration_since(started) < max_duration
+            })
+        } else {
+            attempt <= self.max_retries
+        }
+    }
+
+    /// Waits before the given retry, which starts at 1 for the first retry after a failure.
+    pub(crate) async fn wait(&self, retry: u32, error: &Error, clock: &SharedClock) {
+        let mut backoff = self
+            .retry_backoff
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)));
+        if hints::is_throttling(error) {
+            backoff = backoff.saturating_mul(THROTTLING_FACTOR);
+        }
+        let backoff = backoff.min(MAXIMUM_BACKOFF);
+        // Half of the backoff is always waited, the other half is random.
+        let delay = backoff / 2 + backoff.mul_f64(fastrand::f64() / 2.0);
+        if !delay.is_zero() {
+            info!("Waiting {:.1}s before retrying", delay.as_secs_f64());
+            clock.sleep(delay).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::Clock;
+    use std::{
+        future::Future,
+        pin::Pin,
+        sync::{
+            Arc,
+            Mutex,
+        },
+    };
+
+    /// A clock that only advances while it is slept on.
+    #[derive(Clone)]
+    struct ManualClock(Arc<Mutex<Instant>>);
+
+    impl Clock for ManualClock {
+        fn now(&self) -> Instant {
+            *self.0.lock().unwrap()
+        }
+
+        fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send + Sync>> {
+            *self.0.lock().unwrap() += duration;
+            Box::pin(std::future::ready(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn max_duration_is_measured_with_the_clock() {
+        let started = Instant::now();
+        let clock = SharedClock::new(ManualClock(Arc::new(Mutex::new(started))));
+        let options = RetryOptions {
+            max_retries: 0,
+            retry_backoff: Duration::from_secs(60),
+            keep_trying: true,
+            max_duration: Some(Duration::from_secs(90)),
+        };
+        let error = Error::Retryable(anyhow::anyhow!("connection reset"));
+
+        assert!(options.should_retry(1, started, &clock));
+        options.wait(1, &error, &clock).await;
+        assert!(options.should_retry(2, started, &clock));
+        options.wait(2, &error, &clock).await;
+        assert!(!options.should_retry(3, started, &clock));
+    }
+}
@@ -0,0 +1,97 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    fmt::{
+        Display,
+        Formatter,
+    },
+    str::FromStr,
+};
+
+/// A location in S3 given as `s3://bucket/key`, where the key can also be a prefix or empty.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct S3Uri {
+    pub(crate) bucket: String,
+    pub(crate) key: String,
+}
+
+impl S3Uri {
+    /// Returns the key to upload a file with the given name to: if the URI points to a "directory",
+    /// i.e. the key is empty or ends with `/`, the file name is appended to it.
+    pub(crate) fn key_for_file(&self, file_name: Option<&str>) -> Option<String> {
+        if self.key.is_empty() || self.key.ends_with('/') {
+            file_name.map(|file_name| format!("{}{}", self.key, file_name))
+        } else {
+            Some(self.key.clone())
+        }
+    }
+}
+
+impl Display for S3Uri {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "s3://{}/{}", self.bucket, self.key)
+    }
+}
+
+impl FromStr for S3Uri {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let Some(path) = value.strip_prefix("s3://") else {
+            return Err(format!(
+                "expected an S3 URI like `s3://bucket/key`, got `{}`",
+                value
+            ));
+        };
+        let (bucket, key) = path.split_once('/').unwrap_or((path, ""));
+        if bucket.is_empty() {
+            return Err(format!("the S3 URI `{}` is missing a bucket", value));
+        }
+        Ok(S3Uri {
+            bucket: bucket.to_owned(),
+            key: key.to_owned(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn s3_uris_are_parsed_into_bucket_and_key() {
+        let uri: S3Uri = "s3://my-bucket/path/big.iso".parse().unwrap();
+        assert_eq!(uri.bucket, "my-bucket");
+        assert_eq!(uri.key, "path/big.iso");
+        assert_eq!(
+            uri.key_for_file(Some("other.iso")).as_deref(),
+            Some("path/big.iso")
+        );
+
+        let uri: S3Uri = "s3://my-bucket/path/".parse().unwrap();
+        assert_eq!(
+            uri.key_for_file(Some("big.iso")).as_deref(),
+            Some("path/big.iso")
+        );
+        let uri: S3Uri = "s3://my-bucket".parse().unwrap();
+        assert_eq!(uri.key, "");
+        assert_eq!(uri.key_for_file(None), None);
+
+        assert!("my-bucket/path".parse::<S3Uri>().is_err());
+        assert!("s3:///path".parse::<S3Uri>().is_err());
+    }
+}
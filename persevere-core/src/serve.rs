@@ -0,0 +1,975 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    batch::{
+        self,
+        Entry,
+        UploadOptions,
+    },
+    consts::KiB,
+    history::Outcome,
+    parts::Part,
+    pause_request_file,
+    progress::ProgressReporter,
+    remove_pause_request_file,
+    result::{
+        bail,
+        AnyhowResultExt,
+        Error,
+        Result,
+    },
+    signals::{
+        next_signal,
+        Listener,
+    },
+    spill,
+    verbosity,
+    write_json_atomically,
+    Abort,
+    Pause,
+};
+use anyhow::Context;
+use clap::Args;
+use hyper::{
+    body::HttpBody,
+    header::{
+        AUTHORIZATION,
+        CONTENT_SECURITY_POLICY,
+        CONTENT_TYPE,
+        HOST,
+        ORIGIN,
+        X_FRAME_OPTIONS,
+    },
+    service::{
+        make_service_fn,
+        service_fn,
+    },
+    Body,
+    Method,
+    Request,
+    Response,
+    Server,
+    StatusCode,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    net::{
+        SocketAddr,
+        TcpListener,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::Arc,
+};
+use tokio::sync::{
+    Mutex,
+    Notify,
+};
+use tracing::{
+    debug,
+    error,
+    info,
+    warn,
+};
+
+/// Name of the file within the state directory that tracks the submitted transfers.
+const SERVE_STATE_FILE: &str = "serve.json";
+
+/// The largest request body the API accepts, which is plenty for a single transfer.
+const MAXIMUM_REQUEST_SIZE: u64 = 64 * KiB;
+
+/// The read-only web UI showing the transfers, which is served on `/`.
+const DASHBOARD: &str = include_str!("dashboard.html");
+
+#[derive(Debug, Args)]
+pub(crate) struct Serve {
+    /// Address to serve the API and the dashboard on, e.g. `127.0.0.1:8420`.
+    ///
+    /// Without `--token-file`, the API doesn't authenticate its clients, so it can only listen on a
+    /// loopback address then. Requests have to be addressed to this address in their `Host`
+    /// header, unless it is an unspecified address like `0.0.0.0`, and requests that carry an
+    /// `Origin` header, which browsers send with the requests of other websites, are rejected.
+    #[arg(long, default_value = "127.0.0.1:8420")]
+    listen: SocketAddr,
+    /// Require clients to authenticate with the token in the given file, which they send as
+    /// `Authorization: Bearer <token>`.
+    ///
+    /// Leading and trailing whitespace of the file is ignored, so a token created with e.g.
+    /// `openssl rand -hex 32 > token` works as is.
+    #[arg(long, value_name = "FILE")]
+    token_file: Option<PathBuf>,
+    /// Directory to keep the state in.
+    ///
+    /// The directory holds the list of submitted transfers and the state-file of every transfer
+    /// that has been started, so that restarting the command continues with the transfers that
+    /// haven't finished yet.
+    #[arg(long)]
+    state_dir: PathBuf,
+    #[command(flatten)]
+    upload_options: UploadOptions,
+}
+
+/// The submitted transfers, which are persisted in the state directory.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct ServeState {
+    /// The ID the next submitted transfer is assigned.
+    next_id: u64,
+    transfers: BTreeMap<u64, Transfer>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct Transfer {
+    pub(crate) id: u64,
+    #[serde(flatten)]
+    pub(crate) status: TransferStatus,
+    /// The transfer as it was submitted.
+    pub(crate) request: Entry,
+    /// The progress of the transfer while it is running, which is only part of the responses of
+    /// the API.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) progress: Option<Progress>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub(crate) struct Progress {
+    pub(crate) total_bytes: u64,
+    /// Bytes that have been handed to S3, including the ones of previous attempts and of the
+    /// parts in progress.
+    pub(crate) transferred_bytes: u64,
+}
+
+/// Keeps track of the progress of the running transfer for the API, and passes it on to the
+/// reporter chosen through `--progress`.
+struct ProgressTracker {
+    reporter: Arc<dyn ProgressReporter>,
+    state: std::sync::Mutex<TrackedProgress>,
+}
+
+#[derive(Debug, Default)]
+struct TrackedProgress {
+    total_bytes: u64,
+    completed_bytes: u64,
+    /// The bytes that have been handed to S3 for each of the parts in progress.
+    parts_in_progress: BTreeMap<i32, u64>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case", tag = "status")]
+pub(crate) enum TransferStatus {
+    Queued,
+    Running,
+    Paused,
+    Completed,
+    Failed {
+        error: String,
+    },
+    /// The multipart upload of the transfer is being aborted.
+    Aborting,
+    Aborted,
+}
+
+impl ServeState {
+    async fn from_file(file: &Path) -> Result<Self> {
+        let contents = match tokio::fs::read(file).await {
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Self::default())
+            }
+            result => result
+                .context("Failed to read serve state file")
+                .into_unrecoverable()?,
+        };
+        serde_json::from_slice(&contents)
+            .context("Failed to deserialize serve state file")
+            .into_unrecoverable()
+    }
+}
+
+/// The transfers together with the requests concerning the one that is running.
+#[derive(Debug)]
+struct Transfers {
+    serve_state: ServeState,
+    /// Whether the running transfer was asked to pause through the API.
+    pause_requested: bool,
+    /// Whether the running transfer was asked to abort through the API.
+    abort_requested: bool,
+    /// Whether the process is stopping, in which case no further transfer is started.
+    stopping: bool,
+    /// The progress of the running transfer.
+    progress: Option<Arc<ProgressTracker>>,
+}
+
+/// Runs the submitted transfers one after another, in the order they were submitted.
+#[derive(Debug)]
+struct Scheduler {
+    state_dir: PathBuf,
+    /// The S3 client to abort transfers with, if the transfers use one given through the library.
+    client: Option<aws_sdk_s3::Client>,
+    transfers: Mutex<Transfers>,
+    /// Wakes the scheduler up once a transfer has been queued, or the process is stopping.
+    wake_up: Notify,
+}
+
+/// An error the API responds with.
+#[derive(Debug)]
+struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    fn conflict(transfer: &Transfer, action: &str) -> Self {
+        Self::new(
+            StatusCode::CONFLICT,
+            format!(
+                "Can't {} transfer {} while it is {}",
+                action,
+                transfer.id,
+                transfer.status.name(),
+            ),
+        )
+    }
+}
+
+/// Decides which requests the API accepts, to keep it from being used by anyone but the services
+/// that are meant to submit transfers.
+#[derive(Debug)]
+struct Guard {
+    listen: SocketAddr,
+    token: Option<String>,
+}
+
+impl Guard {
+    fn new(listen: SocketAddr, token_file: Option<&Path>) -> Result<Self> {
+        let token = match token_file {
+            Some(token_file) => {
+                let token = std::fs::read_to_string(token_file)
+                    .with_context(|| {
+                        format!("Failed to read the token file {}", token_file.display())
+                    })
+                    .into_unrecoverable()?;
+                let token = token.trim();
+                if token.is_empty() {
+                    bail!("The token file {} is empty", token_file.display());
+                }
+                Some(token.to_owned())
+            }
+            // Anyone who can reach the API could upload any file the process can read to any
+            // bucket its credentials can write to.
+            None if !listen.ip().is_loopback() => bail!(
+                "Listening on {} requires `--token-file`, since the API would be reachable by other hosts without authenticating them",
+                listen,
+            ),
+            None => None,
+        };
+        Ok(Self { listen, token })
+    }
+
+    /// Rejects requests of other websites, which any website could make the browser send to the
+    /// API, requests that aren't addressed to the API, as is the case for DNS rebinding, and
+    /// requests that don't carry the token, if one is required.
+    ///
+    /// Browsers send the `Origin` header with every request of another website, but not with the
+    /// `GET` requests of the dashboard to the API it was loaded from. The dashboard itself doesn't
+    /// require the token, since it has to ask for it first.
+    fn check(&self, request: &Request<Body>, authenticate: bool) -> Result<(), ApiError> {
+        let header = |name| {
+            request
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+        };
+        if request.headers().contains_key(ORIGIN) {
+            return Err(ApiError::new(
+                StatusCode::FORBIDDEN,
+                "Requests with an `Origin` header are not allowed",
+            ));
+        }
+        if !self.listen.ip().is_unspecified() {
+            let expected = self.listen.to_string();
+            let localhost = format!("localhost:{}", self.listen.port());
+            let host = header(HOST).unwrap_or_default();
+            if host != expected && !(self.listen.ip().is_loopback() && host == localhost) {
+                return Err(ApiError::new(
+                    StatusCode::FORBIDDEN,
+                    format!("Requests have to be addressed to {}", expected),
+                ));
+            }
+        }
+        if let Some(token) = self.token.as_ref().filter(|_| authenticate) {
+            let authorized = header(AUTHORIZATION)
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .is_some_and(|provided| {
+                    ring::constant_time::verify_slices_are_equal(
+                        provided.trim().as_bytes(),
+                        token.as_bytes(),
+                    )
+                    .is_ok()
+                });
+            if !authorized {
+                return Err(ApiError::new(
+                    StatusCode::UNAUTHORIZED,
+                    "Missing or invalid bearer token",
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl From<Error> for ApiError {
+    fn from(error: Error) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, error.to_string())
+    }
+}
+
+impl ProgressTracker {
+    fn new(reporter: Arc<dyn ProgressReporter>) -> Self {
+        Self {
+            reporter,
+            state: std::sync::Mutex::default(),
+        }
+    }
+
+    fn progress(&self) -> Progress {
+        let state = self.state.lock().expect("poisoned lock");
+        Progress {
+            total_bytes: state.total_bytes,
+            transferred_bytes: state.completed_bytes
+                + state.parts_in_progress.values().sum::<u64>(),
+        }
+    }
+
+    fn update(&self, update: impl FnOnce(&mut TrackedProgress)) {
+        update(&mut self.state.lock().expect("poisoned lock"));
+    }
+}
+
+impl std::fmt::Debug for ProgressTracker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProgressTracker")
+            .field("state", &self.state)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ProgressReporter for ProgressTracker {
+    fn started(&self, total_bytes: u64, transferred_bytes: u64, number_of_parts: u64) {
+        self.update(|state| {
+            *state = TrackedProgress {
+                total_bytes,
+                completed_bytes: transferred_bytes,
+                parts_in_progress: BTreeMap::new(),
+            }
+        });
+        self.reporter
+            .started(total_bytes, transferred_bytes, number_of_parts);
+    }
+
+    fn part_started(&self, part: &Part, number_of_parts: u64) {
+        self.update(|state| {
+            state.parts_in_progress.insert(part.number(), 0);
+        });
+        self.reporter.part_started(part, number_of_parts);
+    }
+
+    fn bytes_transferred(&self, part: &Part, bytes: u64) {
+        self.update(|state| {
+            *state.parts_in_progress.entry(part.number()).or_default() += bytes;
+        });
+        self.reporter.bytes_transferred(part, bytes);
+    }
+
+    fn part_retrying(&self, part: &Part, attempt: u32, error: &Error) {
+        self.update(|state| {
+            state.parts_in_progress.insert(part.number(), 0);
+        });
+        self.reporter.part_retrying(part, attempt, error);
+    }
+
+    fn part_completed(&self, part: &Part, number_of_parts: u64) {
+        self.update(|state| {
+            state.parts_in_progress.remove(&part.number());
+            state.completed_bytes += part.size();
+        });
+        self.reporter.part_completed(part, number_of_parts);
+    }
+
+    fn finished(&self, outcome: Outcome) {
+        self.reporter.finished(outcome);
+    }
+}
+
+impl Transfers {
+    /// Returns the transfer as the API shows it, with its progress if it is running.
+    fn show(&self, transfer: &Transfer) -> Transfer {
+        let mut transfer = transfer.clone();
+        if let TransferStatus::Running = transfer.status {
+            transfer.progress = self.progress.as_ref().map(|progress| progress.progress());
+        }
+        transfer
+    }
+}
+
+impl TransferStatus {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            TransferStatus::Queued => "queued",
+            TransferStatus::Running => "running",
+            TransferStatus::Paused => "paused",
+            TransferStatus::Completed => "completed",
+            TransferStatus::Failed { .. } => "failed",
+            TransferStatus::Aborting => "aborting",
+            TransferStatus::Aborted => "aborted",
+        }
+    }
+}
+
+impl Scheduler {
+    async fn new(state_dir: PathBuf, client: Option<aws_sdk_s3::Client>) -> Result<Self> {
+        tokio::fs::create_dir_all(&state_dir)
+            .await
+            .context("Failed to create state directory")
+            .into_unrecoverable()?;
+        let mut serve_state = ServeState::from_file(&state_dir.join(SERVE_STATE_FILE)).await?;
+        for transfer in serve_state.transfers.values_mut() {
+            match transfer.status {
+                // The transfer was running when the process stopped, and is resumed from its
+                // state-file.
+                TransferStatus::Running => transfer.status = TransferStatus::Queued,
+                // The abort was interrupted, and can be requested again.
+                TransferStatus::Aborting => transfer.status = TransferStatus::Paused,
+                _ => {}
+            }
+        }
+        Ok(Self {
+            state_dir,
+            client,
+            transfers: Mutex::new(Transfers {
+                serve_state,
+                pause_requested: false,
+                abort_requested: false,
+                stopping: false,
+                progress: None,
+            }),
+            wake_up: Notify::new(),
+        })
+    }
+
+    fn state_file(&self, id: u64) -> PathBuf {
+        self.state_dir.join(format!("transfer-{:05}.state", id))
+    }
+
+    fn write_state(&self, serve_state: &ServeState) -> Result<()> {
+        tokio::task::block_in_place(|| {
+            write_json_atomically(
+                &self.state_dir.join(SERVE_STATE_FILE),
+                serve_state,
+                "serve state file",
+            )
+        })
+    }
+
+    /// Runs the queued transfers until the process is stopping.
+    async fn run(&self, options: &UploadOptions) -> Result<()> {
+        loop {
+            let (id, request, progress) = {
+                let mut transfers = self.transfers.lock().await;
+                if transfers.stopping {
+                    return Ok(());
+                }
+                transfers.pause_requested = false;
+                transfers.abort_requested = false;
+                let Some(transfer) = transfers
+                    .serve_state
+                    .transfers
+                    .values_mut()
+                    .find(|transfer| matches!(transfer.status, TransferStatus::Queued))
+                else {
+                    drop(transfers);
+                    self.wake_up.notified().await;
+                    continue;
+                };
+                transfer.status = TransferStatus::Running;
+                let (id, request) = (transfer.id, transfer.request.clone());
+                let progress = Arc::new(ProgressTracker::new(options.reporter()));
+                transfers.progress = Some(Arc::clone(&progress));
+                self.write_state(&transfers.serve_state)?;
+                (id, request, progress)
+            };
+
+            info!(
+                "Starting transfer {}: {} to s3://{}/{}",
+                id,
+                request.file_to_upload().display(),
+                request.s3_bucket(),
+                request.s3_key(),
+            );
+            let state_file = self.state_file(id);
+            let result = options
+                .upload_reporting_to(request, &state_file, progress)
+                .await;
+            // A pause that was requested too late to take effect must not pause the transfer once
+            // it is resumed.
+            remove_pause_request_file(&pause_request_file(&state_file)).await?;
+
+            let mut transfers = self.transfers.lock().await;
+            transfers.progress = None;
+            let status = match result {
+                Ok(()) => TransferStatus::Completed,
+                Err(_) if transfers.abort_requested => {
+                    self.set_status(&mut transfers, id, TransferStatus::Aborting)?;
+                    // The abort has to reach S3, which the API mustn't wait for.
+                    drop(transfers);
+                    let status = self.abort(id).await;
+                    transfers = self.transfers.lock().await;
+                    status
+                }
+                Err(Error::Paused) if transfers.stopping && !transfers.pause_requested => {
+                    TransferStatus::Queued
+                }
+                Err(Error::Paused) => TransferStatus::Paused,
+                Err(Error::Interrupted) => {
+                    if let Some(transfer) = transfers.serve_state.transfers.get_mut(&id) {
+                        transfer.status = TransferStatus::Queued;
+                    }
+                    self.write_state(&transfers.serve_state)?;
+                    return Err(Error::Interrupted);
+                }
+                Err(error) => {
+                    error!("Failed to upload transfer {}: {}", id, error);
+                    if let Error::Unrecoverable(_) = error {
+                        // The multipart upload has been aborted or was never created, so the next
+                        // attempt has to start from scratch.
+                        batch::remove_file(&state_file).await?;
+                    }
+                    TransferStatus::Failed {
+                        error: error.to_string(),
+                    }
+                }
+            };
+            info!("Transfer {} is {}", id, status.name());
+            if let Some(transfer) = transfers.serve_state.transfers.get_mut(&id) {
+                transfer.status = status;
+            }
+            self.write_state(&transfers.serve_state)?;
+        }
+    }
+
+    /// Aborts the multipart upload of the given transfer, if it has been started.
+    async fn abort(&self, id: u64) -> TransferStatus {
+        let state_file = self.state_file(id);
+        match tokio::fs::try_exists(&state_file).await {
+            Ok(false) => return TransferStatus::Aborted,
+            Ok(true) => {}
+            Err(error) => {
+                return TransferStatus::Failed {
+                    error: error.to_string(),
+                }
+            }
+        }
+        let abort = Abort {
+            state_file: Some(state_file),
+            state_uri: None,
+            keep_state_file: false,
+            dry_run: false,
+            yes: true,
+            client: self.client.clone(),
+            record_history: true,
+        };
+        match abort.run().await {
+            Ok(()) => TransferStatus::Aborted,
+            Err(error) => {
+                error!("Failed to abort transfer {}: {}", id, error);
+                TransferStatus::Failed {
+                    error: error.to_string(),
+                }
+            }
+        }
+    }
+
+    /// Stops starting further transfers, and pauses the running one.
+    async fn stop(&self) {
+        let mut transfers = self.transfers.lock().await;
+        transfers.stopping = true;
+        if let Some(transfer) = transfers
+            .serve_state
+            .transfers
+            .values()
+            .find(|transfer| matches!(transfer.status, TransferStatus::Running))
+        {
+            let pause = Pause {
+                state_file: self.state_file(transfer.id),
+            };
+            if let Err(error) = pause.run().await {
+                warn!("Failed to pause transfer {}: {}", transfer.id, error);
+            }
+        }
+        self.wake_up.notify_one();
+    }
+
+    async fn list(&self) -> Vec<Transfer> {
+        let transfers = self.transfers.lock().await;
+        transfers
+            .serve_state
+            .transfers
+            .values()
+            .map(|transfer| transfers.show(transfer))
+            .collect()
+    }
+
+    async fn get(&self, id: u64) -> Result<Transfer, ApiError> {
+        let transfers = self.transfers.lock().await;
+        find(&transfers, id).map(|transfer| transfers.show(transfer))
+    }
+
+    async fn submit(&self, request: Entry) -> Result<Transfer, ApiError> {
+        if request.file_to_upload() == Path::new(spill::STDIN) {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "Uploading from stdin is not supported",
+            ));
+        }
+        if request.file_to_upload().is_relative() {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "The file to upload has to be an absolute path",
+            ));
+        }
+        let mut transfers = self.transfers.lock().await;
+        let serve_state = &mut transfers.serve_state;
+        serve_state.next_id += 1;
+        let transfer = Transfer {
+            id: serve_state.next_id,
+            status: TransferStatus::Queued,
+            request,
+            progress: None,
+        };
+        serve_state.transfers.insert(transfer.id, transfer.clone());
+        self.write_state(serve_state)?;
+        info!(
+            "Queued transfer {}: {} to s3://{}/{}",
+            transfer.id,
+            transfer.request.file_to_upload().display(),
+            transfer.request.s3_bucket(),
+            transfer.request.s3_key(),
+        );
+        self.wake_up.notify_one();
+        Ok(transfer)
+    }
+
+    async fn pause(&self, id: u64) -> Result<Transfer, ApiError> {
+        let mut transfers = self.transfers.lock().await;
+        match find(&transfers, id)?.status {
+            TransferStatus::Queued => {
+                self.set_status(&mut transfers, id, TransferStatus::Paused)?;
+            }
+            TransferStatus::Running => {
+                // The transfer pauses once the part in progress has finished.
+                self.request_pause(id).await?;
+                transfers.pause_requested = true;
+            }
+            _ => return Err(ApiError::conflict(find(&transfers, id)?, "pause")),
+        }
+        find(&transfers, id).cloned()
+    }
+
+    async fn resume(&self, id: u64) -> Result<Transfer, ApiError> {
+        let mut transfers = self.transfers.lock().await;
+        match find(&transfers, id)?.status {
+            TransferStatus::Paused | TransferStatus::Failed { .. } => {
+                self.set_status(&mut transfers, id, TransferStatus::Queued)?;
+                self.wake_up.notify_one();
+            }
+            _ => return Err(ApiError::conflict(find(&transfers, id)?, "resume")),
+        }
+        find(&transfers, id).cloned()
+    }
+
+    async fn abort_transfer(&self, id: u64) -> Result<Transfer, ApiError> {
+        let mut transfers = self.transfers.lock().await;
+        match find(&transfers, id)?.status {
+            TransferStatus::Queued | TransferStatus::Paused | TransferStatus::Failed { .. } => {
+                // While it is aborting, the transfer is neither started nor aborted again, so the
+                // lock can be released until the abort has reached S3.
+                self.set_status(&mut transfers, id, TransferStatus::Aborting)?;
+                drop(transfers);
+                let status = self.abort(id).await;
+                transfers = self.transfers.lock().await;
+                self.set_status(&mut transfers, id, status)?;
+            }
+            TransferStatus::Running => {
+                // The transfer is aborted once it has paused after the part in progress.
+                self.request_pause(id).await?;
+                transfers.abort_requested = true;
+            }
+            _ => return Err(ApiError::conflict(find(&transfers, id)?, "abort")),
+        }
+        find(&transfers, id).cloned()
+    }
+
+    /// Requests the running transfer to pause, which is only possible once its state-file exists.
+    async fn request_pause(&self, id: u64) -> Result<(), ApiError> {
+        let state_file = self.state_file(id);
+        if !tokio::fs::try_exists(&state_file).await.unwrap_or(false) {
+            return Err(ApiError::new(
+                StatusCode::CONFLICT,
+                format!("Transfer {} is still starting, try again shortly", id),
+            ));
+        }
+        Pause { state_file }.run().await?;
+        Ok(())
+    }
+
+    fn set_status(&self, transfers: &mut Transfers, id: u64, status: TransferStatus) -> Result<()> {
+        if let Some(transfer) = transfers.serve_state.transfers.get_mut(&id) {
+            transfer.status = status;
+        }
+        self.write_state(&transfers.serve_state)
+    }
+}
+
+fn find(transfers: &Transfers, id: u64) -> Result<&Transfer, ApiError> {
+    transfers
+        .serve_state
+        .transfers
+        .get(&id)
+        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, format!("Unknown transfer {}", id)))
+}
+
+impl Serve {
+    pub(crate) async fn run(&self) -> Result<()> {
+        debug!("Running serve command: {:?}", self);
+
+        let guard = Arc::new(Guard::new(self.listen, self.token_file.as_deref())?);
+        let scheduler = Arc::new(
+            Scheduler::new(
+                self.state_dir.clone(),
+                self.upload_options.client().cloned(),
+            )
+            .await?,
+        );
+        let listener = TcpListener::bind(self.listen)
+            .with_context(|| format!("Failed to listen on {}", self.listen))
+            .into_unrecoverable()?;
+        let server = Server::from_tcp(listener)
+            .with_context(|| format!("Failed to listen on {}", self.listen))
+            .into_unrecoverable()?
+            .serve(make_service_fn({
+                let scheduler = Arc::clone(&scheduler);
+                move |_| {
+                    let scheduler = Arc::clone(&scheduler);
+                    let guard = Arc::clone(&guard);
+                    async move {
+                        Ok::<_, Infallible>(service_fn(move |request| {
+                            let scheduler = Arc::clone(&scheduler);
+                            let guard = Arc::clone(&guard);
+                            async move {
+                                Ok::<_, Infallible>(respond(&scheduler, &guard, request).await)
+                            }
+                        }))
+                    }
+                }
+            }));
+        let server = tokio::spawn(async move {
+            if let Err(error) = server.await {
+                warn!("Failed to serve the API: {}", error);
+            }
+        });
+        info!("Serving the API on http://{}/transfers", self.listen);
+
+        let result = self.schedule(&scheduler).await;
+        server.abort();
+        result
+    }
+
+    /// Runs the scheduler until the process receives a signal.
+    ///
+    /// The first signal pauses the running transfer once the part in progress has finished, a
+    /// second one stops right away.
+    async fn schedule(&self, scheduler: &Scheduler) -> Result<()> {
+        let mut listener = Listener::install();
+        let run = scheduler.run(&self.upload_options);
+        tokio::pin!(run);
+        let signal = tokio::select! {
+            result = &mut run => return result,
+            signal = next_signal(&mut listener) => signal,
+        };
+        info!(
+            "Received {}, stopping once the running transfer has paused. Send it again to stop right away.",
+            signal,
+        );
+        scheduler.stop().await;
+        tokio::select! {
+            result = &mut run => result?,
+            signal = next_signal(&mut listener) => {
+                warn!("Received {} again, stopping right away", signal);
+                return Err(Error::Interrupted);
+            }
+        }
+        info!(target: verbosity::SUMMARY,
+            "Stopped serving. To continue with the queued transfers, run the same command again.",
+        );
+        Ok(())
+    }
+}
+
+async fn respond(scheduler: &Scheduler, guard: &Guard, request: Request<Body>) -> Response<Body> {
+    let method = request.method().clone();
+    let path = request.uri().path().to_owned();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let dashboard = method == Method::GET && segments == [""];
+    let result = match guard.check(&request, !dashboard) {
+        Err(error) => Err(error),
+        Ok(()) if dashboard => {
+            let mut response = Response::new(Body::from(DASHBOARD));
+            let headers = response.headers_mut();
+            headers.insert(
+                CONTENT_TYPE,
+                "text/html; charset=utf-8".parse().expect("valid header"),
+            );
+            // The dashboard only talks to the API, and can't be embedded by other websites.
+            headers.insert(
+                CONTENT_SECURITY_POLICY,
+                "default-src 'none'; script-src 'unsafe-inline'; style-src 'unsafe-inline'; connect-src 'self'; frame-ancestors 'none'"
+                    .parse()
+                    .expect("valid header"),
+            );
+            headers.insert(X_FRAME_OPTIONS, "DENY".parse().expect("valid header"));
+            return response;
+        }
+        Ok(()) => route(scheduler, request, &method, &segments).await,
+    };
+    let (status, body) = result.unwrap_or_else(|error| {
+        debug!("Responding to {} {} with: {}", method, path, error.message);
+        (
+            error.status,
+            json(&serde_json::json!({ "error": error.message })),
+        )
+    });
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        "application/json".parse().expect("valid header"),
+    );
+    response
+}
+
+/// Handles a request the guard has accepted, returning the status and body of the response.
+async fn route(
+    scheduler: &Scheduler,
+    request: Request<Body>,
+    method: &Method,
+    segments: &[&str],
+) -> Result<(StatusCode, Vec<u8>), ApiError> {
+    match (method, segments) {
+        (&Method::GET, ["transfers"]) => Ok((StatusCode::OK, json(&scheduler.list().await))),
+        (&Method::POST, ["transfers"]) => match read_request(request).await {
+            Ok(entry) => scheduler
+                .submit(entry)
+                .await
+                .map(|transfer| (StatusCode::CREATED, json(&transfer))),
+            Err(error) => Err(error),
+        },
+        (&Method::GET, ["transfers", id]) => match parse_id(id) {
+            Ok(id) => scheduler
+                .get(id)
+                .await
+                .map(|transfer| (StatusCode::OK, json(&transfer))),
+            Err(error) => Err(error),
+        },
+        (&Method::POST, ["transfers", id, action]) => match parse_id(id) {
+            Ok(id) => match *action {
+                "pause" => scheduler.pause(id).await,
+                "resume" => scheduler.resume(id).await,
+                "abort" => scheduler.abort_transfer(id).await,
+                _ => Err(not_found()),
+            }
+            .map(|transfer| (StatusCode::OK, json(&transfer))),
+            Err(error) => Err(error),
+        },
+        _ => Err(not_found()),
+    }
+}
+
+async fn read_request(request: Request<Body>) -> Result<Entry, ApiError> {
+    let content_type = request
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(str::trim);
+    if !content_type
+        .is_some_and(|content_type| content_type.eq_ignore_ascii_case("application/json"))
+    {
+        return Err(ApiError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Transfers have to be submitted with `Content-Type: application/json`",
+        ));
+    }
+    let too_large = || {
+        ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("The request exceeds {} bytes", MAXIMUM_REQUEST_SIZE),
+        )
+    };
+    let mut body = request.into_body();
+    if body.size_hint().lower() > MAXIMUM_REQUEST_SIZE {
+        return Err(too_large());
+    }
+    let mut contents = vec![];
+    while let Some(chunk) = body.data().await {
+        let chunk =
+            chunk.map_err(|error| ApiError::new(StatusCode::BAD_REQUEST, error.to_string()))?;
+        if (contents.len() + chunk.len()) as u64 > MAXIMUM_REQUEST_SIZE {
+            return Err(too_large());
+        }
+        contents.extend_from_slice(&chunk);
+    }
+    serde_json::from_slice(&contents).map_err(|error| {
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            format!("Invalid transfer: {}", error),
+        )
+    })
+}
+
+fn parse_id(id: &str) -> Result<u64, ApiError> {
+    id.parse().map_err(|_| not_found())
+}
+
+fn not_found() -> ApiError {
+    ApiError::new(StatusCode::NOT_FOUND, "Not found")
+}
+
+fn json(value: &impl Serialize) -> Vec<u8> {
+    serde_json::to_vec(value).expect("serializable value")
+}
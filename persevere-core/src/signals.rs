@@ -0,0 +1,144 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use tokio_util::sync::CancellationToken;
+use tracing::{
+    info,
+    warn,
+};
+
+/// Watches for SIGINT and SIGTERM while a transfer is running.
+///
+/// The first signal stops the transfer the same way a pause request does: the part in progress
+/// is finished and checkpointed before the transfer stops. A second signal cancels the part in
+/// progress as well, so that the process exits right away.
+#[derive(Debug, Default)]
+pub(crate) struct Signals {
+    received: CancellationToken,
+    forced: CancellationToken,
+}
+
+impl Signals {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether the process received a signal since the transfer started.
+    pub(crate) fn received(&self) -> bool {
+        self.received.is_cancelled()
+    }
+
+    /// Completes once a second signal asks to stop the part in progress.
+    pub(crate) async fn forced(&self) {
+        self.forced.cancelled().await
+    }
+
+    /// Cancels `cancellation` once the process receives its first signal.
+    ///
+    /// The returned task has to be aborted once the transfer is over.
+    pub(crate) fn watch(&self, cancellation: CancellationToken) -> tokio::task::JoinHandle<()> {
+        let received = self.received.clone();
+        let forced = self.forced.clone();
+        tokio::spawn(async move {
+            let Some(mut listener) = Listener::install() else {
+                return;
+            };
+            let name = listener.recv().await;
+            info!(
+                "Received {}, the upload will stop once the current part has finished. Send it again to stop right away.",
+                name,
+            );
+            received.cancel();
+            cancellation.cancel();
+            let name = listener.recv().await;
+            warn!("Received {} again, cancelling the current part", name);
+            forced.cancel();
+        })
+    }
+}
+
+/// Waits for the next SIGINT or SIGTERM received by `listener`, returning its name.
+///
+/// Never completes if the signal handlers could not be installed.
+pub(crate) async fn next_signal(listener: &mut Option<Listener>) -> &'static str {
+    match listener {
+        Some(listener) => listener.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Listener for the signals that stop a transfer.
+#[cfg(unix)]
+pub(crate) struct Listener {
+    interrupt: tokio::signal::unix::Signal,
+    terminate: tokio::signal::unix::Signal,
+}
+
+#[cfg(unix)]
+impl Listener {
+    /// Installs the signal handlers.
+    ///
+    /// Returns `None` if they could not be installed, in which case the signals keep their default
+    /// behaviour of terminating the process.
+    pub(crate) fn install() -> Option<Self> {
+        use tokio::signal::unix::{
+            signal,
+            SignalKind,
+        };
+
+        match (
+            signal(SignalKind::interrupt()),
+            signal(SignalKind::terminate()),
+        ) {
+            (Ok(interrupt), Ok(terminate)) => Some(Self {
+                interrupt,
+                terminate,
+            }),
+            (Err(err), _) | (_, Err(err)) => {
+                warn!("Failed to install signal handlers: {}", err);
+                None
+            }
+        }
+    }
+
+    /// Waits for the next signal, returning its name.
+    pub(crate) async fn recv(&mut self) -> &'static str {
+        tokio::select! {
+            _ = self.interrupt.recv() => "SIGINT",
+            _ = self.terminate.recv() => "SIGTERM",
+        }
+    }
+}
+
+/// Listener for the signals that stop a transfer.
+#[cfg(not(unix))]
+pub(crate) struct Listener;
+
+#[cfg(not(unix))]
+impl Listener {
+    pub(crate) fn install() -> Option<Self> {
+        Some(Self)
+    }
+
+    /// Waits for the next Ctrl-C, returning its name.
+    pub(crate) async fn recv(&mut self) -> &'static str {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            warn!("Failed to install Ctrl-C handler: {}", err);
+            std::future::pending::<()>().await;
+        }
+        "Ctrl-C"
+    }
+}
@@ -0,0 +1,100 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::consts::{
+    GiB,
+    KiB,
+    MiB,
+    TiB,
+};
+
+/// Parses a size like `5242880`, `64MiB`, `1G` or `1.5gb` into a number of bytes.
+///
+/// Units are case-insensitive and always binary, i.e. `1G`, `1GB` and `1GiB` all mean 1024³ bytes,
+/// matching how S3 documents its limits. A number without a unit is a number of bytes.
+pub(crate) fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (amount, unit) = value.split_at(split);
+    let factor = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" | "byte" | "bytes" => 1,
+        "k" | "kb" | "kib" => KiB,
+        "m" | "mb" | "mib" => MiB,
+        "g" | "gb" | "gib" => GiB,
+        "t" | "tb" | "tib" => TiB,
+        _ => {
+            return Err(format!(
+                "unknown unit `{}`, expected e.g. `B`, `KiB`, `MiB`, `GiB` or `TiB`",
+                unit.trim(),
+            ))
+        }
+    };
+    if let Ok(amount) = amount.parse::<u64>() {
+        return amount
+            .checked_mul(factor)
+            .ok_or_else(|| format!("the size `{}` is too large", value));
+    }
+    match amount.parse::<f64>() {
+        Ok(amount) if factor > 1 && amount * (factor as f64) < u64::MAX as f64 => {
+            Ok((amount * factor as f64).round() as u64)
+        }
+        _ => Err(format!(
+            "expected a size like `5242880`, `64MiB` or `1.5GiB`, got `{}`",
+            value,
+        )),
+    }
+}
+
+/// Formats a number of bytes with the largest binary unit that fits, e.g. `1.5 GiB`.
+pub(crate) fn format_size(bytes: u64) -> String {
+    let unit = [("TiB", TiB), ("GiB", GiB), ("MiB", MiB), ("KiB", KiB)]
+        .into_iter()
+        .find(|(_, factor)| bytes >= *factor);
+    match unit {
+        Some((unit, factor)) if bytes.is_multiple_of(factor) => {
+            format!("{} {}", bytes / factor, unit)
+        }
+        Some((unit, factor)) => format!("{:.1} {}", bytes as f64 / factor as f64, unit),
+        None => format!("{} B", bytes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_are_parsed_with_binary_units() {
+        assert_eq!(parse_size("5242880"), Ok(5 * MiB));
+        assert_eq!(parse_size("64MiB"), Ok(64 * MiB));
+        assert_eq!(parse_size("1G"), Ok(GiB));
+        assert_eq!(parse_size("500mb"), Ok(500 * MiB));
+        assert_eq!(parse_size("1.5 GiB"), Ok(GiB + 512 * MiB));
+        assert!(parse_size("1.5").is_err());
+        assert!(parse_size("64MB/s").is_err());
+        assert!(parse_size("MiB").is_err());
+        assert!(parse_size("99999999999TiB").is_err());
+    }
+
+    #[test]
+    fn sizes_are_formatted_with_the_largest_unit() {
+        assert_eq!(format_size(1000), "1000 B");
+        assert_eq!(format_size(5 * MiB), "5 MiB");
+        assert_eq!(format_size(GiB + 512 * MiB), "1.5 GiB");
+    }
+}
@@ -0,0 +1,236 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    consts::{
+        MiB,
+        MINIMUM_PART_NUMBER,
+    },
+    parts::Part,
+    result::{
+        bail,
+        AnyhowResultExt,
+        Result,
+        StdResultExt,
+    },
+};
+use anyhow::Context;
+use std::{
+    future::Future,
+    path::{
+        Path,
+        PathBuf,
+    },
+    pin::Pin,
+};
+use tokio::io::{
+    AsyncBufReadExt,
+    AsyncRead,
+    AsyncReadExt,
+    BufReader,
+};
+use tracing::{
+    debug,
+    warn,
+};
+
+/// The value of `--file-to-upload` that makes Persevere read the data to upload from stdin.
+pub(crate) const STDIN: &str = "-";
+
+/// Returns the file the part with the given number is spilled to.
+pub(crate) fn part_file(directory: &Path, number: i32) -> PathBuf {
+    directory.join(format!("part-{:05}", number))
+}
+
+/// Creates the directory the parts of an upload are spilled to, returning its absolute path.
+///
+/// The directory is named after the state-file, and created within `parent`, or the directory of
+/// the state-file if no parent is given.
+pub(crate) async fn create_directory(parent: Option<&Path>, state_file: &Path) -> Result<PathBuf> {
+    let parent = match parent {
+        Some(parent) => parent.to_owned(),
+        None => match state_file.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_owned(),
+            _ => PathBuf::from("."),
+        },
+    };
+    let Some(state_file_name) = state_file.file_name() else {
+        bail!("The state-file must be a path to a file");
+    };
+    let mut name = state_file_name.to_owned();
+    name.push(".spill");
+    let directory = parent.join(name);
+
+    debug!("Creating spill directory: {}", directory.display());
+    tokio::fs::create_dir_all(&directory)
+        .await
+        .into_unrecoverable()?;
+    if tokio::fs::read_dir(&directory)
+        .await
+        .into_unrecoverable()?
+        .next_entry()
+        .await
+        .into_unrecoverable()?
+        .is_some()
+    {
+        bail!(
+            "The spill directory {} is not empty. It is probably left over from a previous upload, which you should either resume or abort first.",
+            directory.display(),
+        );
+    }
+    directory
+        .canonicalize()
+        .context("Failed to canonicalize spill directory path")
+        .into_unrecoverable()
+}
+
+/// Reads a non-seekable stream, stdin or a compressed file, part by part into a spill directory.
+///
+/// Every part is written to its own file before it is uploaded, so that retries and resumes can
+/// read the part again, even though the stream itself can't be rewound. A part file is only ever
+/// visible under its final name once it is complete, which allows a resume to pick up a part that
+/// was spilled but not yet uploaded by a previous run.
+pub(crate) struct Spill {
+    directory: PathBuf,
+    source: BufReader<Pin<Box<dyn AsyncRead + Send>>>,
+}
+
+impl Spill {
+    /// Spills the data read from stdin.
+    pub(crate) fn new(directory: PathBuf) -> Self {
+        Self::with_source(directory, tokio::io::stdin())
+    }
+
+    /// Spills the data read from the given stream.
+    pub(crate) fn with_source(directory: PathBuf, source: impl AsyncRead + Send + 'static) -> Self {
+        let source: Pin<Box<dyn AsyncRead + Send>> = Box::pin(source);
+        Self {
+            directory,
+            source: BufReader::with_capacity(MiB as usize, source),
+        }
+    }
+
+    /// Whether the stream has ended, i.e. the part returned last is the last part of the stream.
+    ///
+    /// This waits until the stream either yields more data or ends.
+    pub(crate) async fn at_end(&mut self) -> Result<bool> {
+        let buffered = self
+            .source
+            .fill_buf()
+            .await
+            .context("Failed to read the stream")
+            .into_unrecoverable()?;
+        Ok(buffered.is_empty())
+    }
+
+    /// Returns the next part of the stream, or `None` if the stream has ended.
+    ///
+    /// All but the last part have exactly `part_size` bytes.
+    pub(crate) async fn next_part(
+        &mut self,
+        number: u64,
+        offset: u64,
+        part_size: u64,
+    ) -> Result<Option<Part>> {
+        let file = part_file(&self.directory, number as i32);
+        let size = match tokio::fs::metadata(&file).await {
+            Ok(metadata) => {
+                debug!(
+                    "Using part {} that was already spilled to {}",
+                    number,
+                    file.display(),
+                );
+                metadata.len()
+            }
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                self.spill(&file, part_size).await?
+            }
+            Err(error) => return Err(error).into_unrecoverable(),
+        };
+
+        // S3 requires at least one part, even for an empty stream, but a stream ending exactly at a
+        // part boundary must not result in an empty trailing part.
+        if size == 0 && number > MINIMUM_PART_NUMBER {
+            self.remove_part(number as i32).await;
+            return Ok(None);
+        }
+        Ok(Some(Part {
+            number: number as i32,
+            offset,
+            size,
+        }))
+    }
+
+    async fn spill(&mut self, file: &Path, part_size: u64) -> Result<u64> {
+        debug!("Spilling the next part of the stream to {}", file.display());
+        let temporary_file = file.with_extension("tmp");
+        let mut spilled = tokio::fs::File::create(&temporary_file)
+            .await
+            .into_unrecoverable()?;
+        let mut reader = (&mut self.source).take(part_size);
+        let size = tokio::io::copy_buf(&mut reader, &mut spilled)
+            .await
+            .context("Failed to spill the stream to disk")
+            .into_unrecoverable()?;
+        spilled.sync_all().await.into_unrecoverable()?;
+        tokio::fs::rename(&temporary_file, file)
+            .await
+            .into_unrecoverable()?;
+        Ok(size)
+    }
+
+    /// Removes the file of a part that is no longer needed, because it has been uploaded.
+    ///
+    /// The returned future doesn't borrow the spill, whose stream isn't `Sync`, so that the
+    /// transfer can be spawned onto another thread while it waits for the removal.
+    pub(crate) fn remove_part(&self, number: i32) -> impl Future<Output = ()> + Send + 'static {
+        let file = part_file(&self.directory, number);
+        async move {
+            if let Err(error) = tokio::fs::remove_file(&file).await {
+                warn!(
+                    "Failed to remove spilled part {}: {}",
+                    file.display(),
+                    error
+                );
+            }
+        }
+    }
+}
+
+/// Returns the offset within the stream at which it has to continue when resuming an upload.
+///
+/// This is the number of bytes that have already been uploaded, plus the size of the next part if a
+/// previous run has already spilled it completely.
+pub(crate) async fn stream_offset(
+    directory: &Path,
+    next_part_number: u64,
+    uploaded_bytes: u64,
+) -> Result<u64> {
+    match tokio::fs::metadata(part_file(directory, next_part_number as i32)).await {
+        Ok(metadata) => Ok(uploaded_bytes + metadata.len()),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(uploaded_bytes),
+        Err(error) => Err(error).into_unrecoverable(),
+    }
+}
+
+/// Removes the spill directory of an upload, including any parts that are left in it.
+pub(crate) async fn remove_directory(directory: &Path) -> Result<()> {
+    debug!("Removing spill directory: {}", directory.display());
+    match tokio::fs::remove_dir_all(directory).await {
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        result => result.into_unrecoverable(),
+    }
+}
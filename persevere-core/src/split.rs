@@ -0,0 +1,192 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    consts::MAXIMUM_OBJECT_SIZE,
+    parts::PartPlan,
+    result::{
+        AnyhowResultExt,
+        Result,
+    },
+    State,
+};
+use anyhow::Context;
+use aws_sdk_s3::primitives::ByteStream;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use tracing::info;
+
+/// A file larger than the maximum object size of S3, which is uploaded as multiple objects.
+///
+/// The objects are uploaded one after another, each through its own multipart upload, under the
+/// same state-file: the state always describes the object currently being uploaded, with the
+/// offsets of its parts relative to the start of the object. Just before the last object is
+/// completed, a manifest listing all objects is uploaded alongside them.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct Split {
+    /// The key the objects are named after.
+    s3_key: String,
+    /// Size of the whole file.
+    pub(crate) file_size: u64,
+    /// Size of every object but the last.
+    object_size: u64,
+    /// ETags of the objects that have been uploaded completely.
+    e_tags: Vec<String>,
+}
+
+/// The manifest of a split file, which lists the objects to concatenate to get the file back.
+#[derive(Debug, Serialize)]
+struct Manifest<'a> {
+    size: u64,
+    objects: Vec<ManifestObject<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct ManifestObject<'a> {
+    key: String,
+    size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    e_tag: Option<&'a str>,
+}
+
+impl Split {
+    pub(crate) fn new(s3_key: String, file_size: u64) -> Self {
+        Self {
+            s3_key,
+            file_size,
+            object_size: MAXIMUM_OBJECT_SIZE,
+            e_tags: vec![],
+        }
+    }
+
+    fn number_of_objects(&self) -> u64 {
+        self.file_size.div_ceil(self.object_size)
+    }
+
+    /// Returns the key of the object with the given (0-based) index, e.g. `big.iso.part0001`.
+    fn object_key(&self, index: u64) -> String {
+        format!("{}.part{:04}", self.s3_key, index + 1)
+    }
+
+    /// Returns the key of the manifest, e.g. `big.iso.manifest.json`.
+    fn manifest_key(&self) -> String {
+        format!("{}.manifest.json", self.s3_key)
+    }
+
+    /// Index of the object currently being uploaded.
+    fn current_object(&self) -> u64 {
+        self.e_tags.len() as u64
+    }
+
+    /// Offset of the object currently being uploaded within the file.
+    pub(crate) fn offset(&self) -> u64 {
+        self.current_object() * self.object_size
+    }
+
+    fn object_size(&self, index: u64) -> u64 {
+        self.object_size
+            .min(self.file_size - index * self.object_size)
+    }
+
+    /// Whether the object currently being uploaded is the last one.
+    pub(crate) fn is_last_object(&self) -> bool {
+        self.current_object() + 1 == self.number_of_objects()
+    }
+}
+
+/// Points the state to the next object of the split file that is yet to be uploaded.
+///
+/// The multipart upload of the object is not created yet, which is left to the upload itself.
+pub(crate) fn start_object(state: &mut State) {
+    let Some(split) = &state.split else {
+        return;
+    };
+    let index = split.current_object();
+    let size = split.object_size(index);
+    info!(
+        "Uploading object {} of {} of the split file",
+        index + 1,
+        split.number_of_objects(),
+    );
+    state.s3_key = split.object_key(index);
+    state.file_size_in_bytes = size;
+    state.number_of_parts = PartPlan::new(size, state.part_size).number_of_parts();
+    state.upload_id.clear();
+    state.last_successful_part = 0;
+    state.completed_parts.clear();
+}
+
+/// Records the ETag of the object that has just been completed, and points the state to the next
+/// object.
+pub(crate) fn object_completed(state: &mut State, e_tag: Option<&str>) {
+    if let Some(split) = &mut state.split {
+        split.e_tags.push(e_tag.unwrap_or_default().to_owned());
+    }
+    start_object(state);
+}
+
+/// Uploads the manifest of the split file, which lists all of its objects.
+///
+/// The ETags of the objects are listed as far as they are known, which is all objects but the last
+/// one, whose multipart upload is only completed after the manifest has been uploaded.
+pub(crate) async fn put_manifest(s3: &aws_sdk_s3::Client, state: &State) -> Result<()> {
+    let Some(split) = &state.split else {
+        return Ok(());
+    };
+    let manifest = Manifest {
+        size: split.file_size,
+        objects: (0..split.number_of_objects())
+            .map(|index| ManifestObject {
+                key: split.object_key(index),
+                size: split.object_size(index),
+                e_tag: split
+                    .e_tags
+                    .get(index as usize)
+                    .map(|e_tag| e_tag.trim_matches('"'))
+                    .filter(|e_tag| !e_tag.is_empty()),
+            })
+            .collect(),
+    };
+    let body = serde_json::to_vec_pretty(&manifest)
+        .context("Failed to serialize the manifest")
+        .into_unrecoverable()?;
+
+    let manifest_key = split.manifest_key();
+    let mut object_options = state.object_options.clone();
+    object_options.content_type = Some("application/json".to_owned());
+    object_options.content_encoding = None;
+    object_options
+        .apply_to_put_object(
+            s3.put_object()
+                .bucket(&state.s3_bucket)
+                .key(&manifest_key)
+                .set_request_payer(state.request_payer()),
+        )
+        .body(ByteStream::from(body))
+        .send()
+        .await
+        .context("Failed to upload the manifest of the split file")
+        .into_retryable()?;
+    info!(
+        "Uploaded the manifest of the {} objects to: s3://{}/{}",
+        split.number_of_objects(),
+        state.s3_bucket,
+        manifest_key,
+    );
+    Ok(())
+}
@@ -0,0 +1,143 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    duration::format_duration,
+    result::{
+        Error,
+        Result,
+        StdResultExt,
+    },
+};
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        Arc,
+        Mutex,
+    },
+    task::{
+        Context,
+        Poll,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{
+        AsyncRead,
+        ReadBuf,
+    },
+    time::Instant,
+};
+
+/// Detects requests whose body stops being sent, which happens when a connection hangs silently.
+///
+/// Only the body is watched: once it has been sent completely, waiting for the response is up to
+/// the read timeout of the SDK.
+#[derive(Clone)]
+pub(crate) struct StallDetector {
+    /// How long no bytes may be sent before the request is considered stalled, or zero to never
+    /// consider it stalled.
+    timeout: Duration,
+    activity: Arc<Mutex<Activity>>,
+}
+
+struct Activity {
+    last_read: Instant,
+    remaining_bytes: u64,
+}
+
+impl StallDetector {
+    pub(crate) fn new(timeout: Duration, body_size: u64) -> Self {
+        Self {
+            timeout,
+            activity: Arc::new(Mutex::new(Activity {
+                last_read: Instant::now(),
+                remaining_bytes: body_size,
+            })),
+        }
+    }
+
+    /// Wraps the reader of the body, such that every chunk read from it counts as activity.
+    pub(crate) fn reader<R>(&self, inner: R) -> StallReader<R> {
+        StallReader {
+            inner,
+            activity: Arc::clone(&self.activity),
+        }
+    }
+
+    /// Sends the request, failing it with a retryable error if its body stalls.
+    pub(crate) async fn send<T, E>(&self, request: impl Future<Output = Result<T, E>>) -> Result<T>
+    where
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        tokio::select! {
+            result = request => result.into_retryable(),
+            _ = self.stalled() => Err(Error::Retryable(anyhow::anyhow!(
+                "No data was sent for {}, the connection seems to have stalled",
+                format_duration(self.timeout),
+            ))),
+        }
+    }
+
+    /// Completes once no bytes of the body have been read for the timeout.
+    async fn stalled(&self) {
+        if self.timeout.is_zero() {
+            return std::future::pending().await;
+        }
+        loop {
+            let deadline = {
+                let activity = self.activity.lock().unwrap();
+                if activity.remaining_bytes == 0 {
+                    break;
+                }
+                activity.last_read + self.timeout
+            };
+            if Instant::now() >= deadline {
+                return;
+            }
+            tokio::time::sleep_until(deadline).await;
+        }
+        std::future::pending().await
+    }
+}
+
+/// Wraps the reader of a request body and records when bytes were last read from it.
+pub(crate) struct StallReader<R> {
+    inner: R,
+    activity: Arc<Mutex<Activity>>,
+}
+
+impl<R> AsyncRead for StallReader<R>
+where
+    R: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled_before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = (buf.filled().len() - filled_before) as u64;
+        if read > 0 {
+            let mut activity = self.activity.lock().unwrap();
+            activity.last_read = Instant::now();
+            activity.remaining_bytes = activity.remaining_bytes.saturating_sub(read);
+        }
+        result
+    }
+}
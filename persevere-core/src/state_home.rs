@@ -0,0 +1,121 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    checksum,
+    result::{
+        AnyhowResultExt,
+        Result,
+    },
+    s3_uri::S3Uri,
+};
+use anyhow::Context;
+use std::{
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::OnceLock,
+};
+
+/// The state directory set in the configuration file.
+static CONFIGURED_STATE_DIRECTORY: OnceLock<PathBuf> = OnceLock::new();
+
+/// Makes Persevere keep its state in the given directory, as set in the configuration file.
+pub(crate) fn configure_state_directory(directory: PathBuf) {
+    let _ = CONFIGURED_STATE_DIRECTORY.set(directory);
+}
+
+/// Returns the directory Persevere keeps its state in.
+///
+/// This is the directory set in the configuration file, if any, otherwise
+/// `$XDG_STATE_HOME/persevere`, falling back to `~/.local/state/persevere`.
+pub(crate) fn state_directory() -> Option<PathBuf> {
+    if let Some(directory) = CONFIGURED_STATE_DIRECTORY.get() {
+        return Some(directory.clone());
+    }
+    let state_home = std::env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .or_else(|| {
+            std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("state"))
+        })?;
+    Some(state_home.join("persevere"))
+}
+
+/// Returns the state-file to use for uploading the file to the given bucket and key if no
+/// state-file was provided explicitly, creating the directory it is kept in.
+///
+/// The name of the state-file is derived from a hash of the bucket, key and path of the file,
+/// such that uploading or resuming with the same arguments always finds the same state-file. The
+/// path of the file should be canonicalized.
+pub(crate) async fn default_state_file(
+    s3_bucket: &str,
+    s3_key: &str,
+    file_to_upload: &Path,
+) -> Result<PathBuf> {
+    state_file(
+        "",
+        &[
+            s3_bucket.as_bytes(),
+            s3_key.as_bytes(),
+            file_to_upload.as_os_str().as_encoded_bytes(),
+        ],
+    )
+    .await
+}
+
+/// Returns the local copy of the state stored at the given S3 URI if no state-file was provided
+/// explicitly, creating the directory it is kept in.
+pub(crate) async fn remote_state_file(state_uri: &S3Uri) -> Result<PathBuf> {
+    state_file(
+        "remote-",
+        &[state_uri.bucket.as_bytes(), state_uri.key.as_bytes()],
+    )
+    .await
+}
+
+async fn state_file(prefix: &str, components: &[&[u8]]) -> Result<PathBuf> {
+    let Some(state_directory) = state_directory() else {
+        return Err(anyhow::anyhow!(
+            "Unable to determine the directory for the state-file, because neither $XDG_STATE_HOME nor $HOME is set. Please provide the state-file with `--state-file`.",
+        ))
+        .into_unrecoverable();
+    };
+    let directory = state_directory.join("uploads");
+    tokio::fs::create_dir_all(&directory)
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to create the directory for the state-file: {}",
+                directory.display(),
+            )
+        })
+        .into_unrecoverable()?;
+
+    let mut identity = vec![];
+    for component in components {
+        // Prefixing every component with its length keeps e.g. the key `a/b` in bucket `x` apart
+        // from the key `b` in bucket `x/a`.
+        identity.extend_from_slice(&(component.len() as u64).to_le_bytes());
+        identity.extend_from_slice(component);
+    }
+    Ok(directory.join(format!(
+        "{}{}.state",
+        prefix,
+        checksum::sha256_hex(&identity),
+    )))
+}
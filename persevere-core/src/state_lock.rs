@@ -0,0 +1,141 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::result::{
+    AnyhowResultExt,
+    Result,
+};
+use anyhow::Context;
+use std::{
+    fs::{
+        File,
+        TryLockError,
+    },
+    io,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+use tracing::{
+    debug,
+    warn,
+};
+
+/// An exclusive lock on a state-file, held by the process transferring it.
+///
+/// The lock is taken on a separate file next to the state-file, since the state-file itself is
+/// replaced whenever it is written. The lock is released when this value is dropped, and the lock
+/// file is removed if the state-file no longer exists by then, i.e. once the transfer has been
+/// completed or aborted.
+///
+/// Since the lock file is removed while it is still locked, another process may have opened it
+/// just before and lock it once it is released. Such a lock is worthless, as a third process would
+/// create and lock a new lock file at the same path, so the lock is only held once the locked file
+/// is verified to still be the one at the path.
+#[derive(Debug)]
+pub(crate) struct StateLock {
+    state_file: PathBuf,
+    lock_file: PathBuf,
+    _file: File,
+}
+
+impl StateLock {
+    /// Takes the lock on the state-file, failing immediately if another process holds it.
+    pub(crate) fn acquire(state_file: &Path) -> Result<Self> {
+        let lock_file = lock_file(state_file);
+        debug!("Locking state-file through: {}", lock_file.display());
+        loop {
+            let file = File::options()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&lock_file)
+                .context("Failed to open lock file of the state-file")
+                .into_unrecoverable()?;
+            match file.try_lock() {
+                Ok(()) => {}
+                // Another process is likely to finish or pause its transfer, after which trying
+                // again will succeed.
+                Err(TryLockError::WouldBlock) => return Err(anyhow::anyhow!(
+                    "The state-file {} is in use by another Persevere process. Wait for its transfer to finish, or pause it with the 'pause' command first.",
+                    state_file.display(),
+                ))
+                .into_retryable(),
+                Err(TryLockError::Error(error)) => {
+                    return Err(error)
+                        .context("Failed to lock the state-file")
+                        .into_unrecoverable()
+                }
+            }
+            if !is_same_file(&file, &lock_file)
+                .context("Failed to verify the lock file of the state-file")
+                .into_unrecoverable()?
+            {
+                debug!("The lock file has been removed while it was locked, locking it again");
+                continue;
+            }
+            return Ok(Self {
+                state_file: state_file.to_owned(),
+                lock_file,
+                _file: file,
+            });
+        }
+    }
+}
+
+impl Drop for StateLock {
+    fn drop(&mut self) {
+        // Without a way to verify the identity of the locked file, it has to stay in place.
+        if !cfg!(unix) || self.state_file.exists() {
+            return;
+        }
+        debug!("Removing lock file: {}", self.lock_file.display());
+        if let Err(error) = std::fs::remove_file(&self.lock_file) {
+            warn!(
+                "Failed to remove lock file {}: {}",
+                self.lock_file.display(),
+                error,
+            );
+        }
+    }
+}
+
+/// Returns the path of the file that is locked for `state_file`.
+fn lock_file(state_file: &Path) -> PathBuf {
+    let mut file_name = state_file.as_os_str().to_owned();
+    file_name.push(".lock");
+    PathBuf::from(file_name)
+}
+
+/// Returns whether the open file is the one at the path, i.e. it hasn't been removed or replaced.
+#[cfg(unix)]
+fn is_same_file(file: &File, path: &Path) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    let opened = file.metadata()?;
+    match std::fs::metadata(path) {
+        Ok(current) => Ok(opened.dev() == current.dev() && opened.ino() == current.ino()),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(error) => Err(error),
+    }
+}
+
+#[cfg(not(unix))]
+fn is_same_file(_file: &File, _path: &Path) -> io::Result<bool> {
+    // Lock files are never removed on these platforms.
+    Ok(true)
+}
@@ -0,0 +1,223 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    result::{
+        bail,
+        AnyhowResultExt,
+        Result,
+        StdResultExt,
+    },
+    s3_uri::S3Uri,
+    state_home,
+    State,
+};
+use anyhow::Context;
+use aws_sdk_s3::{
+    error::SdkError,
+    operation::head_object::HeadObjectError,
+    primitives::ByteStream,
+};
+use std::path::{
+    Path,
+    PathBuf,
+};
+use tracing::{
+    debug,
+    info,
+    warn,
+};
+
+/// Where the state of an upload is kept.
+///
+/// The state-file on the local disk is always the working copy of the state, which the lock, the
+/// pause request and the spill directory are located next to. Additionally, a copy of the state
+/// can be stored in S3 (`--state-uri`), which allows resuming the upload from another machine that
+/// has access to the same file.
+#[derive(Debug)]
+pub(crate) struct StateStore {
+    file: PathBuf,
+    remote: Option<RemoteState>,
+}
+
+#[derive(Debug)]
+struct RemoteState {
+    s3: aws_sdk_s3::Client,
+    uri: S3Uri,
+}
+
+impl StateStore {
+    pub(crate) fn new(file: PathBuf) -> Self {
+        Self { file, remote: None }
+    }
+
+    /// Opens the state of an existing upload from its local state-file, the S3 URI it is stored
+    /// at, or both.
+    ///
+    /// Without a local state-file, the state is copied to a file in the state directory of
+    /// Persevere once it is written.
+    pub(crate) async fn open(
+        file: Option<PathBuf>,
+        uri: Option<S3Uri>,
+        s3: aws_sdk_s3::Client,
+    ) -> Result<Self> {
+        let file = match (file, &uri) {
+            (Some(file), _) => file,
+            (None, Some(uri)) => {
+                let file = state_home::remote_state_file(uri).await?;
+                info!("Using state-file: {}", file.display());
+                file
+            }
+            (None, None) => bail!("Either the state-file or the state URI has to be provided"),
+        };
+        Ok(Self::new(file).with_remote(s3, uri))
+    }
+
+    /// Additionally stores the state at the given S3 URI.
+    pub(crate) fn with_remote(mut self, s3: aws_sdk_s3::Client, uri: Option<S3Uri>) -> Self {
+        self.remote = uri.map(|uri| RemoteState { s3, uri });
+        self
+    }
+
+    /// The local state-file.
+    pub(crate) fn file(&self) -> &Path {
+        &self.file
+    }
+
+    /// The S3 URI the state is additionally stored at, if any.
+    pub(crate) fn uri(&self) -> Option<&S3Uri> {
+        self.remote.as_ref().map(|remote| &remote.uri)
+    }
+
+    /// Returns whether a state exists, either locally or in S3.
+    pub(crate) async fn exists(&self) -> Result<bool> {
+        if self.local_exists().await? {
+            return Ok(true);
+        }
+        let Some(remote) = &self.remote else {
+            return Ok(false);
+        };
+        match remote
+            .s3
+            .head_object()
+            .bucket(&remote.uri.bucket)
+            .key(&remote.uri.key)
+            .send()
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(SdkError::ServiceError(error))
+                if matches!(error.err(), HeadObjectError::NotFound(_)) =>
+            {
+                Ok(false)
+            }
+            Err(error) => Err(error)
+                .with_context(|| format!("Failed to check for the state at {}", remote.uri))
+                .into_retryable(),
+        }
+    }
+
+    /// Reads the state, preferring the local state-file over the copy in S3.
+    ///
+    /// The local state-file is at least as recent as the copy in S3 if it exists, since it is
+    /// always written first.
+    pub(crate) async fn read(&self) -> Result<State> {
+        let remote = match &self.remote {
+            Some(remote) if !self.local_exists().await? => remote,
+            _ => return State::from_file(&self.file).await,
+        };
+        debug!("Reading the state from {}", remote.uri);
+        let contents = remote
+            .s3
+            .get_object()
+            .bucket(&remote.uri.bucket)
+            .key(&remote.uri.key)
+            .send()
+            .await
+            .with_context(|| format!("Failed to read the state from {}", remote.uri))
+            .into_retryable()?
+            .body
+            .collect()
+            .await
+            .with_context(|| format!("Failed to read the state from {}", remote.uri))
+            .into_retryable()?
+            .into_bytes();
+        State::from_slice(&contents)
+    }
+
+    /// Writes the state to the local state-file, and to S3 if requested.
+    ///
+    /// Failing to store the state in S3 only logs a warning: the state is stored again with the
+    /// next checkpoint, and resuming from an outdated state only means that the parts S3 already
+    /// holds are adopted when reconciling the state with S3.
+    pub(crate) async fn write(&self, state: &mut State) -> Result<()> {
+        state.write_to_file(&self.file).await?;
+        let Some(remote) = &self.remote else {
+            return Ok(());
+        };
+        let contents = tokio::fs::read(&self.file).await.into_unrecoverable()?;
+        if let Err(error) = remote
+            .s3
+            .put_object()
+            .bucket(&remote.uri.bucket)
+            .key(&remote.uri.key)
+            .content_type("application/json")
+            .body(ByteStream::from(contents))
+            .send()
+            .await
+        {
+            warn!(
+                "Failed to store the state at {}, it will be stored again with the next checkpoint: {}",
+                remote.uri,
+                error,
+            );
+        }
+        Ok(())
+    }
+
+    async fn local_exists(&self) -> Result<bool> {
+        tokio::fs::try_exists(&self.file).await.into_unrecoverable()
+    }
+
+    /// Removes the state, once the upload has been completed or aborted.
+    pub(crate) async fn remove(&self) -> Result<()> {
+        debug!("Removing state-file: {}", self.file.display());
+        match tokio::fs::remove_file(&self.file).await {
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                debug!("The state-file did not exist, probably because it was never written, likely because the upload worked first try.")
+            }
+            result => result.into_unrecoverable()?,
+        }
+        let Some(remote) = &self.remote else {
+            return Ok(());
+        };
+        debug!("Removing the state from {}", remote.uri);
+        if let Err(error) = remote
+            .s3
+            .delete_object()
+            .bucket(&remote.uri.bucket)
+            .key(&remote.uri.key)
+            .send()
+            .await
+        {
+            warn!(
+                "Failed to remove the state from {}, please remove it yourself: {}",
+                remote.uri, error,
+            );
+        }
+        Ok(())
+    }
+}
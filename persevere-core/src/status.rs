@@ -0,0 +1,220 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    duration::format_duration,
+    history,
+    parts::PartPlan,
+    result::{
+        AnyhowResultExt,
+        Result,
+    },
+    s3_uri::S3Uri,
+    sdk,
+    size::format_size,
+    state_store::StateStore,
+    State,
+};
+use anyhow::Context;
+use clap::Args;
+use serde::Serialize;
+use std::{
+    path::PathBuf,
+    time::Duration,
+};
+use tracing::debug;
+
+#[derive(Debug, Args)]
+pub(crate) struct Status {
+    /// Path to the state-file of the upload.
+    #[arg(long, required_unless_present = "state_uri")]
+    state_file: Option<PathBuf>,
+    /// The S3 URI the state of the upload is stored at, if it was started with `--state-uri`.
+    #[arg(long, value_name = "S3_URI")]
+    state_uri: Option<S3Uri>,
+    /// Print the status as JSON.
+    #[arg(long)]
+    json: bool,
+}
+
+/// The status of an upload, as it is printed by `status`.
+#[derive(Debug, Serialize)]
+struct UploadStatus {
+    s3_bucket: String,
+    s3_key: String,
+    upload_id: String,
+    file_to_upload: PathBuf,
+    /// Absent for uploads from stdin and of compressed files, whose size is only known once the
+    /// stream has been read.
+    file_size_in_bytes: Option<u64>,
+    /// Whether the file is compressed before it is uploaded, in which case the bytes uploaded are
+    /// compressed bytes.
+    compressed: bool,
+    bytes_uploaded: u64,
+    bytes_remaining: Option<u64>,
+    percent_complete: Option<f64>,
+    /// The size of the parts, or of the first part if the part size is tuned to the throughput.
+    part_size: u64,
+    auto_tune: bool,
+    parts_uploaded: u64,
+    /// Estimated number of parts, for uploads that tune their part size.
+    number_of_parts: Option<u64>,
+    /// Throughput of the previous invocations of the upload, according to the transfer history.
+    bytes_per_second: Option<f64>,
+    estimated_seconds_remaining: Option<f64>,
+}
+
+impl Status {
+    pub(crate) async fn run(&self) -> Result<()> {
+        debug!("Running status command: {:?}", self);
+
+        let config = sdk::load_config().await;
+        let store = StateStore::open(
+            self.state_file.clone(),
+            self.state_uri.clone(),
+            sdk::s3_client(&config),
+        )
+        .await?;
+        let state = store.read().await?;
+        let status = UploadStatus::of(&state).await?;
+        if self.json {
+            println!(
+                "{}",
+                serde_json::to_string(&status)
+                    .context("Failed to serialize status")
+                    .into_unrecoverable()?
+            );
+        } else {
+            status.print();
+        }
+        Ok(())
+    }
+}
+
+impl UploadStatus {
+    async fn of(state: &State) -> Result<Self> {
+        let from_stdin = state.spill_directory.is_some();
+        let bytes_uploaded = state.uploaded_bytes();
+        // The size of an archive is known upfront, even though it is uploaded like a stream.
+        let archive_size = state.archive.as_ref().map(|archive| archive.size);
+        let file_size_in_bytes = archive_size.or((!from_stdin).then_some(state.file_size_in_bytes));
+        let bytes_remaining =
+            file_size_in_bytes.map(|file_size| file_size.saturating_sub(bytes_uploaded));
+        let number_of_parts = match &state.auto_tune {
+            _ if from_stdin => archive_size
+                .map(|archive_size| PartPlan::new(archive_size, state.part_size).number_of_parts()),
+            Some(auto_tune) => Some(auto_tune.estimated_number_of_parts(state.file_size_in_bytes)),
+            None => Some(state.number_of_parts),
+        };
+        let bytes_per_second = throughput(state).await;
+
+        Ok(Self {
+            s3_bucket: state.s3_bucket.clone(),
+            s3_key: state.s3_key.clone(),
+            upload_id: state.upload_id.clone(),
+            file_to_upload: state.file_to_upload.clone(),
+            file_size_in_bytes,
+            compressed: state.compression.is_some(),
+            bytes_uploaded,
+            bytes_remaining,
+            percent_complete: file_size_in_bytes
+                .filter(|file_size| *file_size > 0)
+                .map(|file_size| bytes_uploaded as f64 * 100.0 / file_size as f64),
+            part_size: state.part_size,
+            auto_tune: state.auto_tune.is_some(),
+            parts_uploaded: state.last_successful_part,
+            number_of_parts,
+            bytes_per_second,
+            estimated_seconds_remaining: bytes_remaining
+                .zip(bytes_per_second)
+                .map(|(remaining, bytes_per_second)| remaining as f64 / bytes_per_second),
+        })
+    }
+
+    fn print(&self) {
+        println!("s3://{}/{}", self.s3_bucket, self.s3_key);
+        println!("  Upload ID:  {}", self.upload_id);
+        println!("  File:       {}", self.file_to_upload.display());
+        match (self.file_size_in_bytes, self.bytes_remaining) {
+            (Some(file_size), Some(remaining)) => println!(
+                "  Progress:   {:.1}%, {} of {} uploaded, {} remaining",
+                self.percent_complete.unwrap_or(100.0),
+                format_size(self.bytes_uploaded),
+                format_size(file_size),
+                format_size(remaining),
+            ),
+            _ if self.compressed => println!(
+                "  Progress:   {} of compressed data uploaded",
+                format_size(self.bytes_uploaded),
+            ),
+            _ => println!(
+                "  Progress:   {} uploaded from stdin",
+                format_size(self.bytes_uploaded),
+            ),
+        }
+        let part_size = if self.auto_tune {
+            format!(
+                "tuned to the throughput, starting at {}",
+                format_size(self.part_size)
+            )
+        } else {
+            format!("{} each", format_size(self.part_size))
+        };
+        match self.number_of_parts {
+            Some(number_of_parts) => println!(
+                "  Parts:      {} of {}{}, {}",
+                self.parts_uploaded,
+                if self.auto_tune { "about " } else { "" },
+                number_of_parts,
+                part_size,
+            ),
+            None => println!("  Parts:      {}, {}", self.parts_uploaded, part_size),
+        }
+        match (self.estimated_seconds_remaining, self.bytes_per_second) {
+            (Some(seconds_remaining), Some(bytes_per_second)) => println!(
+                "  ETA:        {} at {}/s, the throughput of previous attempts",
+                format_duration(Duration::from_secs_f64(seconds_remaining)),
+                format_size(bytes_per_second as u64),
+            ),
+            _ => println!("  ETA:        unknown, no throughput recorded in the transfer history"),
+        }
+    }
+}
+
+/// Returns the average throughput of all previous invocations of the upload, according to the
+/// transfer history.
+async fn throughput(state: &State) -> Option<f64> {
+    let history_file = history::history_file()?;
+    let entries = match history::read(&history_file).await {
+        Ok(entries) => entries,
+        Err(err) => {
+            debug!("Failed to read the transfer history: {}", err);
+            return None;
+        }
+    };
+    let (bytes_uploaded, duration_seconds) = entries
+        .iter()
+        .filter(|entry| entry.upload_id == state.upload_id && entry.s3_key == state.s3_key)
+        .fold((0, 0.0), |(_, duration_seconds), entry| {
+            // The number of bytes uploaded is recorded across all invocations, so the most
+            // recent entry covers the durations of all of them.
+            (
+                entry.bytes_uploaded,
+                duration_seconds + entry.duration_seconds,
+            )
+        });
+    (bytes_uploaded > 0 && duration_seconds > 0.0).then(|| bytes_uploaded as f64 / duration_seconds)
+}
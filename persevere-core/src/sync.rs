@@ -0,0 +1,255 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    batch::{
+        self,
+        Entry,
+        UploadOptions,
+    },
+    checksum,
+    headers,
+    result::{
+        bail,
+        AnyhowResultExt,
+        Result,
+        StdResultExt,
+    },
+    s3_uri::S3Uri,
+    sdk,
+    verbosity,
+};
+use anyhow::Context;
+use aws_sdk_s3::types::{
+    Object,
+    RequestPayer,
+};
+use clap::Args;
+use std::{
+    collections::BTreeMap,
+    num::NonZeroUsize,
+    path::{
+        Path,
+        PathBuf,
+    },
+    time::SystemTime,
+};
+use tracing::{
+    debug,
+    info,
+};
+
+#[derive(Debug, Args)]
+pub(crate) struct Sync {
+    /// The local directory to upload, including all of its subdirectories.
+    local_dir: PathBuf,
+    /// The destination in S3, in the form `s3://bucket/prefix`.
+    ///
+    /// The path of every file relative to the local directory is appended to the prefix to form
+    /// its key.
+    destination: S3Uri,
+    /// Directory to keep the state of the sync in.
+    ///
+    /// The list of files to transfer is determined once and kept in this directory together with
+    /// the state-file of every upload, so that an interrupted sync can be resumed by running the
+    /// same command again. The directory is removed once all files have been uploaded.
+    #[arg(long)]
+    state_dir: PathBuf,
+    /// Compare the contents of files that have the same size as their object in S3, rather than
+    /// their modification time.
+    ///
+    /// The MD5 digest of the file is compared with the ETag of the object, which requires reading
+    /// the file completely. If the ETag can't be compared, e.g. because the object is encrypted
+    /// with SSE-KMS, the modification time is compared instead.
+    #[arg(long)]
+    checksum: bool,
+    /// How many files to upload at the same time.
+    ///
+    /// The uploads share `--limit-rate` between them, so that they don't use more bandwidth
+    /// together than a single upload would.
+    #[arg(long, default_value = "1")]
+    jobs: NonZeroUsize,
+    #[command(flatten)]
+    upload_options: UploadOptions,
+}
+
+impl Sync {
+    pub(crate) async fn run(&self) -> Result<()> {
+        debug!("Running sync command: {:?}", self);
+
+        if let Some(entries) = batch::started_entries(&self.state_dir).await? {
+            info!(
+                "Resuming the sync of {} files from {}",
+                entries.len(),
+                self.state_dir.display(),
+            );
+            return batch::run(&self.state_dir, entries, &self.upload_options, self.jobs).await;
+        }
+
+        let S3Uri {
+            bucket: s3_bucket,
+            key: prefix,
+        } = &self.destination;
+        let local_files = local_files(&self.local_dir, &self.state_dir).await?;
+        let config = sdk::load_config().await;
+        let s3 = headers::s3_client(&config, &self.upload_options.headers);
+        let objects: BTreeMap<_, _> = s3
+            .list_objects_v2()
+            .bucket(s3_bucket)
+            .prefix(prefix)
+            .set_request_payer(
+                self.upload_options
+                    .request_payer
+                    .as_deref()
+                    .map(RequestPayer::from),
+            )
+            .into_paginator()
+            .send()
+            .try_collect()
+            .await
+            .context("Failed to list the objects in S3")
+            .into_retryable()?
+            .into_iter()
+            .flat_map(|page| page.contents.unwrap_or_default())
+            .filter_map(|object| Some((object.key.clone()?, object)))
+            .collect();
+        debug!(
+            "Found {} local files and {} objects in S3",
+            local_files.len(),
+            objects.len(),
+        );
+
+        let mut entries = vec![];
+        for (path, relative_path) in local_files {
+            let s3_key = join_key(prefix, &relative_path);
+            if let Some(reason) = self.changed(&path, objects.get(&s3_key)).await? {
+                info!("{}: {}", relative_path, reason);
+                entries.push(Entry::new(path, s3_bucket.clone(), s3_key));
+            }
+        }
+        if entries.is_empty() {
+            info!(target: verbosity::SUMMARY, "All files are up to date, nothing to upload");
+            return Ok(());
+        }
+
+        info!("Uploading {} changed files", entries.len());
+        batch::run(&self.state_dir, entries, &self.upload_options, self.jobs).await
+    }
+
+    /// Returns why the file has to be uploaded, or `None` if the object in S3 is up to date.
+    async fn changed(&self, path: &Path, object: Option<&Object>) -> Result<Option<&'static str>> {
+        let Some(object) = object else {
+            return Ok(Some("new file"));
+        };
+        let metadata = tokio::fs::metadata(path).await.into_unrecoverable()?;
+        if object.size() != Some(metadata.len() as i64) {
+            return Ok(Some("size differs"));
+        }
+
+        if self.checksum {
+            let e_tag = object.e_tag().unwrap_or_default().trim_matches('"');
+            match checksum::expected_part_plan(metadata.len(), e_tag) {
+                Some(plan) => {
+                    let local_e_tag = checksum::e_tag_of_file(path, plan).await?;
+                    return Ok(
+                        (!local_e_tag.eq_ignore_ascii_case(e_tag)).then_some("contents differ")
+                    );
+                }
+                None => debug!(
+                    "Unable to compare the ETag {} of {}, comparing the modification time instead",
+                    e_tag,
+                    path.display(),
+                ),
+            }
+        }
+
+        // S3 lists the modification time of objects with a precision of seconds only.
+        let modified = metadata
+            .modified()
+            .into_unrecoverable()?
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |modified| modified.as_secs() as i64);
+        Ok(match object.last_modified() {
+            Some(last_modified) if modified <= last_modified.secs() => None,
+            _ => Some("modified since the last upload"),
+        })
+    }
+}
+
+/// Returns all files within the local directory, as absolute paths and as paths relative to
+/// the local directory with `/` as separator.
+pub(crate) async fn local_files(
+    local_dir: &Path,
+    state_dir: &Path,
+) -> Result<Vec<(PathBuf, String)>> {
+    let local_dir = local_dir
+        .canonicalize()
+        .context("Failed to canonicalize the local directory")
+        .into_unrecoverable()?;
+    // The state directory might be within the directory that is synced, but must not be
+    // uploaded itself.
+    let state_dir = state_dir.canonicalize().ok();
+
+    let mut files = vec![];
+    let mut directories = vec![local_dir.clone()];
+    while let Some(directory) = directories.pop() {
+        let mut read_dir = tokio::fs::read_dir(&directory)
+            .await
+            .with_context(|| format!("Failed to read directory {}", directory.display()))
+            .into_unrecoverable()?;
+        while let Some(dir_entry) = read_dir.next_entry().await.into_unrecoverable()? {
+            let path = dir_entry.path();
+            // Symbolic links are followed, i.e. the file they point to is uploaded. Files can
+            // vanish while the directory is read, e.g. when they are moved elsewhere.
+            let metadata = match tokio::fs::metadata(&path).await {
+                Err(error) if error.kind() == std::io::ErrorKind::NotFound => continue,
+                result => result.into_unrecoverable()?,
+            };
+            if metadata.is_dir() {
+                if Some(&path) != state_dir.as_ref() {
+                    directories.push(path);
+                }
+            } else if metadata.is_file() {
+                let relative_path = path
+                    .strip_prefix(&local_dir)
+                    .expect("file within the local directory")
+                    .components()
+                    .map(|component| component.as_os_str().to_str())
+                    .collect::<Option<Vec<_>>>();
+                let Some(relative_path) = relative_path else {
+                    bail!(
+                        "The path {} is not valid UTF-8 and can't be used as an S3 key",
+                        path.display(),
+                    );
+                };
+                let relative_path = relative_path.join("/");
+                files.push((path, relative_path));
+            }
+        }
+    }
+    files.sort_by(|(_, a), (_, b)| a.cmp(b));
+    Ok(files)
+}
+
+/// Appends a relative path to a key prefix, separating them with a `/` unless the prefix is empty
+/// or already ends with one.
+pub(crate) fn join_key(prefix: &str, relative_path: &str) -> String {
+    if prefix.is_empty() || prefix.ends_with('/') {
+        format!("{}{}", prefix, relative_path)
+    } else {
+        format!("{}/{}", prefix, relative_path)
+    }
+}
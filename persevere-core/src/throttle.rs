@@ -0,0 +1,157 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    clock::SharedClock,
+    size::parse_size,
+};
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        Arc,
+        Mutex,
+    },
+    task::{
+        ready,
+        Context,
+        Poll,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+use tokio::io::{
+    AsyncRead,
+    ReadBuf,
+};
+
+/// Limits the throughput of all readers sharing it to a number of bytes per second.
+///
+/// This is a token bucket that holds at most one second worth of bytes. Reads are never split:
+/// a read may take more bytes than the bucket holds, after which the next read waits until the
+/// bucket has been refilled enough to pay off the debt.
+#[derive(Clone)]
+pub(crate) struct RateLimiter {
+    bytes_per_second: f64,
+    bucket: Arc<Mutex<Bucket>>,
+    clock: SharedClock,
+}
+
+impl std::fmt::Debug for RateLimiter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RateLimiter")
+            .field("bytes_per_second", &self.bytes_per_second)
+            .finish_non_exhaustive()
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(bytes_per_second: u64, clock: SharedClock) -> Self {
+        Self {
+            bytes_per_second: bytes_per_second as f64,
+            bucket: Arc::new(Mutex::new(Bucket {
+                tokens: bytes_per_second as f64,
+                refilled: clock.now(),
+            })),
+            clock,
+        }
+    }
+
+    /// Returns how long to wait before the next read, if the bucket is in debt.
+    fn delay(&self) -> Option<Duration> {
+        let mut bucket = self.bucket.lock().expect("poisoned lock");
+        let now = self.clock.now();
+        let refill =
+            now.saturating_duration_since(bucket.refilled).as_secs_f64() * self.bytes_per_second;
+        bucket.tokens = (bucket.tokens + refill).min(self.bytes_per_second);
+        bucket.refilled = now;
+        if bucket.tokens >= 0.0 {
+            None
+        } else {
+            Some(Duration::from_secs_f64(
+                -bucket.tokens / self.bytes_per_second,
+            ))
+        }
+    }
+
+    fn consume(&self, bytes: usize) {
+        self.bucket.lock().expect("poisoned lock").tokens -= bytes as f64;
+    }
+}
+
+/// Wraps a reader and delays reads as necessary to stay within the rate of the [`RateLimiter`].
+pub(crate) struct ThrottledReader<R> {
+    inner: R,
+    limiter: Option<RateLimiter>,
+    sleep: Option<Pin<Box<dyn Future<Output = ()> + Send + Sync>>>,
+}
+
+impl<R> ThrottledReader<R> {
+    pub(crate) fn new(inner: R, limiter: Option<RateLimiter>) -> Self {
+        Self {
+            inner,
+            limiter,
+            sleep: None,
+        }
+    }
+}
+
+impl<R> AsyncRead for ThrottledReader<R>
+where
+    R: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        let Some(limiter) = &this.limiter else {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        };
+        loop {
+            if let Some(sleep) = &mut this.sleep {
+                ready!(sleep.as_mut().poll(cx));
+                this.sleep = None;
+            }
+            match limiter.delay() {
+                Some(delay) => this.sleep = Some(limiter.clock.sleep(delay)),
+                None => break,
+            }
+        }
+
+        let filled_before = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        limiter.consume(buf.filled().len() - filled_before);
+        result
+    }
+}
+
+/// Parses a rate like `512KiB`, `50MiB` or `1GiB/s` into bytes per second.
+pub(crate) fn parse_rate(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    match parse_size(value.strip_suffix("/s").unwrap_or(value))? {
+        0 => Err("the rate must be greater than zero".to_owned()),
+        rate => Ok(rate),
+    }
+}
@@ -0,0 +1,346 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    clock::{
+        Clock,
+        SharedClock,
+    },
+    object_options::ObjectOptions,
+    output::{
+        OutputFormat,
+        TransferResult,
+    },
+    progress::{
+        EventReporter,
+        ProgressReporter,
+        TransferEvent,
+    },
+    result::Result,
+    sdk::SdkOptions,
+    Abort,
+    Resume,
+    SharedReporter,
+    TransferOptions,
+    Upload,
+};
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+use tokio_util::sync::CancellationToken;
+
+/// Uploads a file to S3 through a resumable multipart upload, like the `upload` command does.
+///
+/// The upload keeps its progress in a state-file, which allows resuming it through
+/// [`Uploader::resume`] after it failed with a [`Error::Retryable`](crate::Error::Retryable)
+/// error, even from another process.
+#[derive(Debug)]
+pub struct Uploader {
+    upload: Upload,
+}
+
+impl Uploader {
+    /// Prepares the upload of the local file to the given bucket and key.
+    pub fn new(
+        file_to_upload: impl Into<PathBuf>,
+        s3_bucket: impl Into<String>,
+        s3_key: impl Into<String>,
+    ) -> Self {
+        Self {
+            upload: Upload {
+                file: None,
+                destination: None,
+                s3_bucket: Some(s3_bucket.into()),
+                s3_key: Some(s3_key.into()),
+                file_to_upload: Some(file_to_upload.into()),
+                override_part_size: None,
+                auto_tune: false,
+                expected_object_size: None,
+                state_file: None,
+                state_uri: None,
+                spill_dir: None,
+                labels: vec![],
+                object_options: ObjectOptions::default(),
+                headers: vec![],
+                request_payer: None,
+                checksum_algorithm: None,
+                encryption_key_file: None,
+                compress: None,
+                split: false,
+                replicate_to: vec![],
+                output: OutputFormat::Text,
+                transfer_options: transfer_options(),
+            },
+        }
+    }
+
+    /// Path to where the state-file will be saved.
+    ///
+    /// Defaults to a file in `$XDG_STATE_HOME/persevere/uploads/` named after a hash of the bucket,
+    /// key and file.
+    pub fn state_file(mut self, state_file: impl Into<PathBuf>) -> Self {
+        self.upload.state_file = Some(state_file.into());
+        self
+    }
+
+    /// Explicit part-size to use, instead of the smallest one possible.
+    pub fn part_size(mut self, part_size: u64) -> Self {
+        self.upload.override_part_size = Some(part_size);
+        self
+    }
+
+    /// Tune the part size to the observed throughput during the upload.
+    pub fn auto_tune(mut self, auto_tune: bool) -> Self {
+        self.upload.auto_tune = auto_tune;
+        self
+    }
+
+    /// Encrypts the file on the client with AES-256-GCM, using the 256-bit key in the given file.
+    pub fn encryption_key_file(mut self, key_file: impl Into<PathBuf>) -> Self {
+        self.upload.encryption_key_file = Some(key_file.into());
+        self
+    }
+
+    /// Attaches a label to the upload, which is stored in the state-file and the transfer history.
+    pub fn label(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.upload.labels.push((key.into(), value.into()));
+        self
+    }
+
+    /// Fails the upload instead of overwriting the object if one already exists under the key.
+    pub fn if_none_match(mut self, if_none_match: bool) -> Self {
+        self.upload.object_options.if_none_match = if_none_match;
+        self
+    }
+
+    /// How often a failed part is retried before the upload fails with a retryable error.
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.upload.transfer_options.retry.max_retries = max_retries;
+        self
+    }
+
+    /// Keeps retrying failed parts until `max_duration` has passed, or indefinitely if it is `None`,
+    /// instead of failing after the maximum number of retries.
+    pub fn keep_trying(mut self, max_duration: Option<Duration>) -> Self {
+        self.upload.transfer_options.retry.keep_trying = true;
+        self.upload.transfer_options.retry.max_duration = max_duration;
+        self
+    }
+
+    /// Limits the throughput of the upload to the given number of bytes per second.
+    pub fn limit_rate(mut self, bytes_per_second: u64) -> Self {
+        self.upload.transfer_options.limit_rate = Some(bytes_per_second);
+        self
+    }
+
+    /// Calls `on_event` for every progress update of the upload, instead of logging it.
+    ///
+    /// This allows rendering the progress in your own way, or forwarding it to a channel to
+    /// consume it elsewhere.
+    pub fn on_event(self, on_event: impl Fn(&TransferEvent) + Send + Sync + 'static) -> Self {
+        self.reporter(EventReporter::new(on_event))
+    }
+
+    /// Passes every progress update of the upload to `reporter`, instead of logging it.
+    ///
+    /// This replaces a callback given through [`Uploader::on_event`], and the other way around.
+    pub fn reporter(mut self, reporter: impl ProgressReporter + 'static) -> Self {
+        self.upload.transfer_options.reporter = Some(SharedReporter(Arc::new(reporter)));
+        self
+    }
+
+    /// Uploads with the given S3 client, instead of one created from the AWS configuration of the
+    /// environment.
+    ///
+    /// This allows customizing the client, e.g. with interceptors or another HTTP client. To resume
+    /// or abort the upload with a custom client as well, use [`Uploader::resume_with_client`] and
+    /// [`Uploader::abort_with_client`].
+    pub fn client(mut self, client: aws_sdk_s3::Client) -> Self {
+        self.upload.transfer_options.client = Some(client);
+        self
+    }
+
+    /// Pauses the upload once the token is cancelled.
+    ///
+    /// The part in progress is finished and recorded in the state-file first, after which the
+    /// upload fails with [`Error::Paused`](crate::Error::Paused), and can be resumed later on.
+    pub fn cancellation_token(mut self, cancellation: CancellationToken) -> Self {
+        self.upload.transfer_options.cancellation = Some(cancellation);
+        self
+    }
+
+    /// Measures the backoff between retries, the maximum duration and the rate limit with the given
+    /// clock instead of Tokio's.
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.upload.transfer_options.clock = SharedClock::new(clock);
+        self
+    }
+
+    /// Records the outcome of the upload in the transfer history, like the `upload` command does.
+    ///
+    /// The history is kept in `$XDG_STATE_HOME/persevere/history.jsonl`, and is shown by
+    /// `persevere history`. It isn't written by default.
+    pub fn record_history(mut self, record_history: bool) -> Self {
+        self.upload.transfer_options.record_history = record_history;
+        self
+    }
+
+    /// The AWS profile to load the credentials and region from, instead of the one of the
+    /// environment.
+    pub fn profile(mut self, profile: impl Into<String>) -> Self {
+        self.sdk().profile = Some(profile.into());
+        self
+    }
+
+    /// The AWS region to send the requests to, instead of the one of the profile.
+    pub fn region(mut self, region: impl Into<String>) -> Self {
+        self.sdk().region = Some(region.into());
+        self
+    }
+
+    /// The URL to send the requests to, instead of the AWS endpoint of the region, e.g. to upload
+    /// to an S3-compatible store.
+    pub fn endpoint_url(mut self, endpoint_url: impl Into<String>) -> Self {
+        self.sdk().endpoint_url = Some(endpoint_url.into());
+        self
+    }
+
+    /// Addresses the bucket in the path of the requests instead of through the host name.
+    pub fn force_path_style(mut self, force_path_style: bool) -> Self {
+        self.sdk().force_path_style = force_path_style;
+        self
+    }
+
+    /// How long to wait for a connection to S3 to be established.
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.sdk().connect_timeout = Some(connect_timeout);
+        self
+    }
+
+    /// How long to wait for S3 to respond to a request once it has been sent.
+    pub fn read_timeout(mut self, read_timeout: Duration) -> Self {
+        self.sdk().read_timeout = Some(read_timeout);
+        self
+    }
+
+    /// Returns the options of the AWS SDK of this upload, which replace the ones of the environment.
+    fn sdk(&mut self) -> &mut SdkOptions {
+        self.upload
+            .transfer_options
+            .sdk
+            .get_or_insert_with(SdkOptions::default)
+    }
+
+    /// Runs the upload until it has completed or failed.
+    pub async fn upload(self) -> Result<TransferResult> {
+        self.upload.transfer().await
+    }
+
+    /// Resumes the upload of the file that has been started before, e.g. by an earlier run of the
+    /// process, until it has completed or failed.
+    ///
+    /// The upload is continued with the state-file, the S3 client, the callback, the cancellation
+    /// token and the retry and rate limits of this uploader. How the file is uploaded, e.g. its
+    /// part size and labels, is taken from the state-file instead.
+    pub async fn resume_upload(self) -> Result<TransferResult> {
+        let upload = self.upload;
+        Resume {
+            state_file: upload.state_file,
+            state_uri: None,
+            s3_bucket: upload.s3_bucket,
+            s3_key: upload.s3_key,
+            file_to_upload: upload.file_to_upload,
+            force: false,
+            encryption_key_file: upload.encryption_key_file,
+            output: OutputFormat::Text,
+            transfer_options: upload.transfer_options,
+        }
+        .transfer()
+        .await
+    }
+
+    /// Resumes the upload the given state-file belongs to.
+    pub async fn resume(state_file: impl Into<PathBuf>) -> Result<TransferResult> {
+        resume(state_file.into(), None).await
+    }
+
+    /// Resumes the upload the given state-file belongs to with the given S3 client.
+    pub async fn resume_with_client(
+        state_file: impl Into<PathBuf>,
+        client: aws_sdk_s3::Client,
+    ) -> Result<TransferResult> {
+        resume(state_file.into(), Some(client)).await
+    }
+
+    /// Aborts the upload the given state-file belongs to, removing the parts uploaded so far from
+    /// S3.
+    pub async fn abort(state_file: impl Into<PathBuf>) -> Result<()> {
+        abort(state_file.into(), None).await
+    }
+
+    /// Aborts the upload the given state-file belongs to with the given S3 client.
+    pub async fn abort_with_client(
+        state_file: impl Into<PathBuf>,
+        client: aws_sdk_s3::Client,
+    ) -> Result<()> {
+        abort(state_file.into(), Some(client)).await
+    }
+}
+
+async fn resume(state_file: PathBuf, client: Option<aws_sdk_s3::Client>) -> Result<TransferResult> {
+    Resume {
+        state_file: Some(state_file),
+        state_uri: None,
+        s3_bucket: None,
+        s3_key: None,
+        file_to_upload: None,
+        force: false,
+        encryption_key_file: None,
+        output: OutputFormat::Text,
+        transfer_options: TransferOptions {
+            client,
+            ..transfer_options()
+        },
+    }
+    .transfer()
+    .await
+}
+
+async fn abort(state_file: PathBuf, client: Option<aws_sdk_s3::Client>) -> Result<()> {
+    Abort {
+        state_file: Some(state_file),
+        state_uri: None,
+        keep_state_file: false,
+        dry_run: false,
+        yes: true,
+        client,
+        record_history: false,
+    }
+    .run()
+    .await
+}
+
+/// The defaults of the command line options, except for the handling of signals, which is left to
+/// the embedding process, and the transfer history, which embedders opt into.
+fn transfer_options() -> TransferOptions {
+    TransferOptions {
+        stop_on_signal: false,
+        record_history: false,
+        ..TransferOptions::default()
+    }
+}
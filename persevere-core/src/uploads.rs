@@ -0,0 +1,506 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    checksum::ChecksumAlgorithm,
+    duration::parse_duration,
+    fingerprint::Fingerprint,
+    headers::{
+        self,
+        Header,
+    },
+    migration,
+    parts::{
+        self,
+        PartPlan,
+    },
+    reconcile,
+    result::{
+        bail,
+        AnyhowResultExt,
+        Result,
+        StdResultExt,
+    },
+    sdk,
+    size,
+    spill,
+    state_lock::StateLock,
+    state_store::StateStore,
+    upload_and_record,
+    State,
+    TransferOptions,
+};
+use anyhow::Context;
+use aws_sdk_s3::{
+    primitives::{
+        DateTime,
+        DateTimeFormat,
+    },
+    types::{
+        MultipartUpload,
+        RequestPayer,
+    },
+};
+use clap::{
+    builder::PossibleValuesParser,
+    Args,
+};
+use serde::Serialize;
+use std::{
+    path::{
+        Path,
+        PathBuf,
+    },
+    time::{
+        Duration,
+        Instant,
+        SystemTime,
+    },
+};
+use tracing::{
+    debug,
+    error,
+    info,
+};
+
+/// Lists all multipart uploads in the bucket that have been started but neither completed nor
+/// aborted, optionally only those whose key starts with `prefix`.
+pub(crate) async fn list_multipart_uploads(
+    s3: &aws_sdk_s3::Client,
+    s3_bucket: &str,
+    prefix: Option<&str>,
+    request_payer: Option<RequestPayer>,
+) -> Result<Vec<MultipartUpload>> {
+    let mut uploads = vec![];
+    let mut key_marker = None;
+    let mut upload_id_marker = None;
+    loop {
+        let page = s3
+            .list_multipart_uploads()
+            .bucket(s3_bucket)
+            .set_prefix(prefix.map(str::to_owned))
+            .set_key_marker(key_marker)
+            .set_upload_id_marker(upload_id_marker)
+            .set_request_payer(request_payer.clone())
+            .send()
+            .await
+            .context("Failed to list the multipart uploads")
+            .into_retryable()?;
+        uploads.extend(page.uploads.unwrap_or_default());
+        if page.is_truncated != Some(true) {
+            return Ok(uploads);
+        }
+        key_marker = page.next_key_marker;
+        upload_id_marker = page.next_upload_id_marker;
+    }
+}
+
+#[derive(Debug, Args)]
+pub(crate) struct Adopt {
+    /// The name of the S3 bucket the multipart upload was started in.
+    #[arg(long)]
+    s3_bucket: String,
+    /// The S3 key the multipart upload was started for.
+    #[arg(long)]
+    s3_key: String,
+    /// Path to the local file that was being uploaded.
+    ///
+    /// The parts S3 already holds are verified against this file, and only the parts that match
+    /// are kept.
+    #[arg(long)]
+    file_to_upload: PathBuf,
+    /// Path to where the rebuilt state-file will be saved.
+    #[arg(long)]
+    state_file: PathBuf,
+    /// The ID of the multipart upload to adopt.
+    ///
+    /// Only required if there is more than one multipart upload in progress for the key.
+    #[arg(long)]
+    upload_id: Option<String>,
+    /// Additional HTTP header to send with every S3 request of the upload, in the form
+    /// `name: value`.
+    #[arg(long = "header", value_name = "NAME: VALUE", value_parser = headers::parse_header)]
+    headers: Vec<Header>,
+    /// Confirm that you will be charged for the requests to a requester-pays bucket.
+    #[arg(long, value_parser = PossibleValuesParser::new(RequestPayer::values()))]
+    request_payer: Option<String>,
+    #[command(flatten)]
+    transfer_options: TransferOptions,
+}
+
+impl Adopt {
+    pub(crate) async fn run(self) -> Result<()> {
+        debug!("Running adopt command: {:?}", self);
+        let started = Instant::now();
+
+        let _lock = StateLock::acquire(&self.state_file)?;
+        if tokio::fs::try_exists(&self.state_file)
+            .await
+            .into_unrecoverable()?
+        {
+            bail!("The state-file already exists. If you want to resume the upload, use the 'resume' command instead.");
+        }
+        if self.file_to_upload == Path::new(spill::STDIN) {
+            bail!("Uploads from stdin can't be adopted, since the parts S3 already holds can't be verified against the data");
+        }
+        let file_to_upload = self
+            .file_to_upload
+            .canonicalize()
+            .context("Failed to canonicalize file path")
+            .into_unrecoverable()?;
+        let file_size_in_bytes = tokio::fs::metadata(&file_to_upload)
+            .await
+            .into_unrecoverable()?
+            .len();
+
+        let config = sdk::load_config().await;
+        let s3 = headers::s3_client(&config, &self.headers);
+        let request_payer = self.request_payer.as_deref().map(RequestPayer::from);
+        let upload = self.find_upload(&s3, request_payer.clone()).await?;
+        let upload_id = upload.upload_id.unwrap_or_default();
+
+        // S3 doesn't record the part size, but all parts except for the last one have the size of
+        // the first part.
+        let first_part = s3
+            .list_parts()
+            .bucket(&self.s3_bucket)
+            .key(&self.s3_key)
+            .upload_id(&upload_id)
+            .max_parts(1)
+            .set_request_payer(request_payer)
+            .send()
+            .await
+            .context("Failed to list the parts of the multipart upload")
+            .into_retryable()?
+            .parts
+            .unwrap_or_default()
+            .into_iter()
+            .find(|part| part.part_number() == Some(1));
+        let part_size = match first_part.and_then(|part| part.size) {
+            Some(size) if size as u64 > file_size_in_bytes => bail!(
+                "The first part of the multipart upload is larger than the file, so it is not an upload of this file"
+            ),
+            Some(size) => parts::choose_part_size(file_size_in_bytes, Some(size as u64))?,
+            None => parts::choose_part_size(file_size_in_bytes, None)?,
+        };
+        let plan = PartPlan::new(file_size_in_bytes, part_size);
+
+        let mut state = State {
+            version: migration::STATE_VERSION,
+            s3_bucket: self.s3_bucket,
+            s3_key: self.s3_key,
+            fingerprint: Some(Fingerprint::of(&file_to_upload, plan).await?),
+            file_to_upload,
+            file_size_in_bytes,
+            part_size,
+            number_of_parts: plan.number_of_parts(),
+            upload_id,
+            last_successful_part: 0,
+            completed_parts: vec![],
+            labels: Default::default(),
+            object_options: Default::default(),
+            headers: self.headers,
+            spill_directory: None,
+            request_payer: self.request_payer,
+            checksum_algorithm: upload
+                .checksum_algorithm
+                .as_ref()
+                .and_then(ChecksumAlgorithm::from_sdk),
+            auto_tune: None,
+            aws_profile: Some(sdk::profile_name()),
+            aws_region: config.region().map(ToString::to_string),
+            use_fips_endpoint: Some(config.use_fips().unwrap_or(false)),
+            use_dualstack_endpoint: Some(config.use_dual_stack().unwrap_or(false)),
+            copy_source: None,
+            encryption: None,
+            compression: None,
+            archive: None,
+            split: None,
+            replicas: vec![],
+        };
+        // Adopts all parts S3 holds that match the file, starting with the first part.
+        reconcile::reconcile(&s3, &mut state).await?;
+        let store = StateStore::new(self.state_file.clone());
+        store.write(&mut state).await?;
+        info!(
+            "Adopted multipart upload with ID {} for s3://{}/{}, continuing after part {} of {}",
+            state.upload_id,
+            state.s3_bucket,
+            state.s3_key,
+            state.last_successful_part,
+            state.number_of_parts,
+        );
+
+        upload_and_record(
+            &s3,
+            "adopt",
+            &store,
+            &mut state,
+            &self.transfer_options,
+            started,
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Finds the multipart upload in progress for the key, which has to be unambiguous.
+    async fn find_upload(
+        &self,
+        s3: &aws_sdk_s3::Client,
+        request_payer: Option<RequestPayer>,
+    ) -> Result<MultipartUpload> {
+        let mut uploads: Vec<_> =
+            list_multipart_uploads(s3, &self.s3_bucket, Some(&self.s3_key), request_payer)
+                .await?
+                .into_iter()
+                .filter(|upload| upload.key() == Some(self.s3_key.as_str()))
+                .filter(|upload| {
+                    self.upload_id
+                        .as_ref()
+                        .is_none_or(|upload_id| upload.upload_id() == Some(upload_id))
+                })
+                .collect();
+        match uploads.len() {
+            0 => bail!(
+                "No multipart upload in progress found for s3://{}/{}{}",
+                self.s3_bucket,
+                self.s3_key,
+                self.upload_id
+                    .as_ref()
+                    .map(|upload_id| format!(" with ID {}", upload_id))
+                    .unwrap_or_default(),
+            ),
+            1 => Ok(uploads.remove(0)),
+            _ => bail!(
+                "There are {} multipart uploads in progress for s3://{}/{}, choose the one to adopt with `--upload-id`: {}",
+                uploads.len(),
+                self.s3_bucket,
+                self.s3_key,
+                uploads
+                    .iter()
+                    .map(|upload| format!(
+                        "{} (initiated {})",
+                        upload.upload_id().unwrap_or_default(),
+                        upload
+                            .initiated()
+                            .map(ToString::to_string)
+                            .unwrap_or_else(|| "<unknown>".to_owned()),
+                    ))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+pub(crate) struct ListUploads {
+    /// The name of the S3 bucket to list the multipart uploads of.
+    #[arg(long)]
+    s3_bucket: String,
+    /// Only list multipart uploads for keys starting with this prefix.
+    #[arg(long)]
+    prefix: Option<String>,
+    /// Confirm that you will be charged for the requests to a requester-pays bucket.
+    #[arg(long, value_parser = PossibleValuesParser::new(RequestPayer::values()))]
+    request_payer: Option<String>,
+    /// Print the multipart uploads as JSON, one document per line.
+    #[arg(long)]
+    json: bool,
+}
+
+/// A multipart upload in progress, as it is printed by `list-uploads`.
+#[derive(Debug, Serialize)]
+struct ListedUpload {
+    s3_key: String,
+    upload_id: String,
+    /// RFC 3339 timestamp of when the multipart upload was created.
+    initiated: String,
+    parts: usize,
+    /// Number of bytes in all parts uploaded so far, which S3 charges storage for.
+    size_in_bytes: u64,
+}
+
+impl ListUploads {
+    pub(crate) async fn run(&self) -> Result<()> {
+        debug!("Running list-uploads command: {:?}", self);
+
+        let config = sdk::load_config().await;
+        let s3 = sdk::s3_client(&config);
+        let request_payer = self.request_payer.as_deref().map(RequestPayer::from);
+        let uploads = list_multipart_uploads(
+            &s3,
+            &self.s3_bucket,
+            self.prefix.as_deref(),
+            request_payer.clone(),
+        )
+        .await?;
+        if uploads.is_empty() && !self.json {
+            info!("There are no multipart uploads in progress");
+        }
+
+        for upload in uploads {
+            let s3_key = upload.key.unwrap_or_default();
+            let upload_id = upload.upload_id.unwrap_or_default();
+            let parts = s3
+                .list_parts()
+                .bucket(&self.s3_bucket)
+                .key(&s3_key)
+                .upload_id(&upload_id)
+                .set_request_payer(request_payer.clone())
+                .into_paginator()
+                .items()
+                .send()
+                .try_collect()
+                .await
+                .context("Failed to list the parts of the multipart upload")
+                .into_retryable()?;
+            let listed = ListedUpload {
+                s3_key,
+                upload_id,
+                initiated: upload
+                    .initiated
+                    .and_then(|initiated| initiated.fmt(DateTimeFormat::DateTime).ok())
+                    .unwrap_or_default(),
+                parts: parts.len(),
+                size_in_bytes: parts
+                    .iter()
+                    .map(|part| part.size().unwrap_or_default() as u64)
+                    .sum(),
+            };
+
+            if self.json {
+                println!(
+                    "{}",
+                    serde_json::to_string(&listed)
+                        .context("Failed to serialize multipart upload")
+                        .into_unrecoverable()?
+                );
+            } else {
+                println!(
+                    "{}  {}  s3://{}/{}  {} parts, {}",
+                    listed.initiated,
+                    listed.upload_id,
+                    self.s3_bucket,
+                    listed.s3_key,
+                    listed.parts,
+                    size::format_size(listed.size_in_bytes),
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Args)]
+pub(crate) struct Cleanup {
+    /// The name of the S3 bucket to abort the stale multipart uploads in.
+    #[arg(long)]
+    s3_bucket: String,
+    /// Abort multipart uploads that were initiated longer ago than this, e.g. `12h` or `7d`.
+    #[arg(long, value_parser = parse_duration)]
+    older_than: Duration,
+    /// Only abort multipart uploads for keys starting with this prefix.
+    #[arg(long)]
+    prefix: Option<String>,
+    /// Only show which multipart uploads would be aborted, without aborting them.
+    #[arg(long)]
+    dry_run: bool,
+    /// Confirm that you will be charged for the requests to a requester-pays bucket.
+    #[arg(long, value_parser = PossibleValuesParser::new(RequestPayer::values()))]
+    request_payer: Option<String>,
+}
+
+impl Cleanup {
+    pub(crate) async fn run(&self) -> Result<()> {
+        debug!("Running cleanup command: {:?}", self);
+
+        let Some(cutoff) = SystemTime::now().checked_sub(self.older_than) else {
+            bail!("The age given with `--older-than` is too large");
+        };
+        let cutoff = DateTime::from(cutoff);
+        let config = sdk::load_config().await;
+        let s3 = sdk::s3_client(&config);
+        let request_payer = self.request_payer.as_deref().map(RequestPayer::from);
+        let stale_uploads: Vec<_> = list_multipart_uploads(
+            &s3,
+            &self.s3_bucket,
+            self.prefix.as_deref(),
+            request_payer.clone(),
+        )
+        .await?
+        .into_iter()
+        .filter(|upload| {
+            upload
+                .initiated()
+                .is_some_and(|initiated| initiated.secs() < cutoff.secs())
+        })
+        .collect();
+        if stale_uploads.is_empty() {
+            info!("There are no multipart uploads older than the given age");
+            return Ok(());
+        }
+
+        let mut failed = 0;
+        for upload in &stale_uploads {
+            let s3_key = upload.key().unwrap_or_default();
+            let upload_id = upload.upload_id().unwrap_or_default();
+            let initiated = upload
+                .initiated()
+                .and_then(|initiated| initiated.fmt(DateTimeFormat::DateTime).ok())
+                .unwrap_or_default();
+            if self.dry_run {
+                info!(
+                    "Would abort multipart upload with ID {} for s3://{}/{}, initiated {}",
+                    upload_id, self.s3_bucket, s3_key, initiated,
+                );
+                continue;
+            }
+            let result = s3
+                .abort_multipart_upload()
+                .bucket(&self.s3_bucket)
+                .key(s3_key)
+                .upload_id(upload_id)
+                .set_request_payer(request_payer.clone())
+                .send()
+                .await;
+            match result {
+                Ok(_) => info!(
+                    "Aborted multipart upload with ID {} for s3://{}/{}, initiated {}",
+                    upload_id, self.s3_bucket, s3_key, initiated,
+                ),
+                Err(err) => {
+                    error!(
+                        "Failed to abort multipart upload with ID {} for s3://{}/{}: {}",
+                        upload_id, self.s3_bucket, s3_key, err,
+                    );
+                    failed += 1;
+                }
+            }
+        }
+
+        if failed > 0 {
+            return Err(anyhow::anyhow!(
+                "Failed to abort {} of {} stale multipart uploads",
+                failed,
+                stale_uploads.len(),
+            ))
+            .into_retryable();
+        }
+        Ok(())
+    }
+}
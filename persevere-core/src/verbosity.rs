@@ -0,0 +1,64 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use clap::{
+    ArgAction,
+    Args,
+};
+use tracing::Level;
+use tracing_subscriber::EnvFilter;
+
+/// Target of the log messages that summarize how a command ended, which are logged even with
+/// `--quiet`.
+pub(crate) const SUMMARY: &str = "persevere::summary";
+
+/// How much Persevere logs.
+#[derive(Debug, Args)]
+pub(crate) struct Verbosity {
+    /// Only log warnings and errors, and a summary once the command has finished.
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Log more details: `-v` for debug output, `-vv` for trace output.
+    ///
+    /// For more fine-grained control, use the `RUST_LOG` environment variable instead, e.g.
+    /// `RUST_LOG=persevere_core=debug`, which takes precedence over this option.
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    verbose: u8,
+}
+
+impl Verbosity {
+    /// The filter for the log messages, which `RUST_LOG` can refine.
+    pub(crate) fn filter(&self) -> EnvFilter {
+        let level = match (self.quiet, self.verbose) {
+            (true, _) => Level::WARN,
+            (false, 0) => Level::INFO,
+            (false, 1) => Level::DEBUG,
+            (false, _) => Level::TRACE,
+        };
+        let filter = EnvFilter::builder()
+            .with_default_directive(level.into())
+            .from_env_lossy();
+        if self.quiet {
+            filter.add_directive(
+                format!("{}=info", SUMMARY)
+                    .parse()
+                    .expect("valid directive"),
+            )
+        } else {
+            filter
+        }
+    }
+}
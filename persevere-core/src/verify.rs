@@ -0,0 +1,285 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    checksum::{
+        self,
+        ChecksumAlgorithm,
+        FileDigests,
+        ReturnedChecksums,
+    },
+    result::{
+        bail,
+        AnyhowResultExt,
+        Result,
+    },
+    s3_uri::S3Uri,
+    sdk,
+    size::format_size,
+    verbosity,
+};
+use anyhow::Context;
+use aws_sdk_s3::types::{
+    Checksum,
+    ObjectAttributes,
+    ObjectPart,
+    RequestPayer,
+};
+use clap::{
+    builder::PossibleValuesParser,
+    Args,
+    ValueEnum,
+};
+use std::path::PathBuf;
+use tracing::{
+    debug,
+    info,
+};
+
+/// The maximum number of parts S3 returns with a single `GetObjectAttributes` request.
+const MAX_PARTS_PER_REQUEST: i32 = 1000;
+
+#[derive(Debug, Args)]
+pub(crate) struct Verify {
+    /// The local file to compare the object with.
+    #[arg(long)]
+    file: PathBuf,
+    /// S3 URI of the object, e.g. `s3://my-bucket/path/big.iso`.
+    #[arg(value_name = "OBJECT")]
+    object: S3Uri,
+    /// The version of the object to verify, instead of its latest version.
+    #[arg(long)]
+    version_id: Option<String>,
+    /// Confirm that you will be charged for the requests to a requester-pays bucket.
+    #[arg(long, value_parser = PossibleValuesParser::new(RequestPayer::values()))]
+    request_payer: Option<String>,
+}
+
+/// What S3 knows about the contents of an object.
+#[derive(Debug)]
+struct ObjectDigests {
+    e_tag: String,
+    size: u64,
+    checksum: Option<Checksum>,
+    /// The number of parts, if the object was uploaded as a multipart upload.
+    total_parts_count: Option<usize>,
+    /// The parts of the object, which S3 only lists if it was uploaded with checksums.
+    parts: Vec<ObjectPart>,
+}
+
+impl Verify {
+    pub(crate) async fn run(&self) -> Result<()> {
+        debug!("Running verify command: {:?}", self);
+        let config = sdk::load_config().await;
+        let s3 = sdk::s3_client(&config);
+
+        let file_size = tokio::fs::metadata(&self.file)
+            .await
+            .with_context(|| format!("Failed to read {}", self.file.display()))
+            .into_unrecoverable()?
+            .len();
+        let object = self.object_digests(&s3).await?;
+        if object.size != file_size {
+            bail!(
+                "The size of {} ({}) doesn't match the size of {} ({})",
+                self.file.display(),
+                format_size(file_size),
+                self.object,
+                format_size(object.size),
+            );
+        }
+
+        let algorithm = object.checksum.as_ref().and_then(|returned| {
+            ChecksumAlgorithm::value_variants()
+                .iter()
+                .copied()
+                .find(|algorithm| returned.checksum(*algorithm).is_some())
+        });
+        let part_sizes = object.part_sizes()?;
+        info!(
+            "Reading {} in {} part(s) to compare it with {}",
+            self.file.display(),
+            part_sizes.len(),
+            self.object,
+        );
+        let digests = FileDigests::of(
+            &self.file,
+            &part_sizes,
+            object.total_parts_count.is_some(),
+            algorithm,
+        )
+        .await?;
+
+        let mut verified = vec![];
+        if let Some(algorithm) = algorithm {
+            for (index, part) in object.parts.iter().enumerate() {
+                let calculated = digests.part_checksum(index);
+                if part.checksum(algorithm) != calculated.as_deref() {
+                    bail!(
+                        "Part {} of {} doesn't match the file: its {} checksum is {}, but the file has {}",
+                        part.part_number().unwrap_or(index as i32 + 1),
+                        self.object,
+                        algorithm.sdk(),
+                        part.checksum(algorithm).unwrap_or("<none>"),
+                        calculated.unwrap_or_default(),
+                    );
+                }
+            }
+            let returned = object
+                .checksum
+                .as_ref()
+                .and_then(|returned| returned.checksum(algorithm))
+                .unwrap_or_default();
+            if !digests.matches_checksum(returned) {
+                bail!(
+                    "The {} checksum of {} ({}) doesn't match the file",
+                    algorithm.sdk(),
+                    self.object,
+                    returned,
+                );
+            }
+            verified.push(format!("{} checksum", algorithm.sdk()));
+        }
+
+        // The ETag is only an MD5 digest if the object isn't encrypted with a KMS key, which
+        // `GetObjectAttributes` doesn't tell, so a mismatch only counts if there was no checksum.
+        let calculated = digests.e_tag();
+        if object.e_tag.eq_ignore_ascii_case(&calculated) {
+            verified.push("ETag".to_owned());
+        } else if verified.is_empty() {
+            let encryption = self.server_side_encryption(&s3).await?;
+            if checksum::e_tag_is_md5(encryption.as_ref()) {
+                bail!(
+                    "The ETag of {} ({}) doesn't match the ETag calculated for the file ({})",
+                    self.object,
+                    object.e_tag,
+                    calculated,
+                );
+            }
+            bail!(
+                "{} is encrypted with a KMS key and has no checksum, so its contents can't be compared with the file",
+                self.object,
+            );
+        } else {
+            debug!(
+                "The ETag of {} ({}) is not the one calculated for the file ({}), it is likely encrypted with a KMS key",
+                self.object, object.e_tag, calculated,
+            );
+        }
+
+        info!(target: verbosity::SUMMARY,
+            "{} matches {} (verified the {})",
+            self.file.display(),
+            self.object,
+            verified.join(" and "),
+        );
+        Ok(())
+    }
+
+    /// Retrieves the ETag, checksum and part layout of the object, listing all of its parts.
+    async fn object_digests(&self, s3: &aws_sdk_s3::Client) -> Result<ObjectDigests> {
+        let mut part_number_marker = None;
+        let mut parts = vec![];
+        loop {
+            let output = s3
+                .get_object_attributes()
+                .bucket(&self.object.bucket)
+                .key(&self.object.key)
+                .set_version_id(self.version_id.clone())
+                .set_request_payer(self.request_payer.as_deref().map(RequestPayer::from))
+                .object_attributes(ObjectAttributes::Etag)
+                .object_attributes(ObjectAttributes::Checksum)
+                .object_attributes(ObjectAttributes::ObjectParts)
+                .object_attributes(ObjectAttributes::ObjectSize)
+                .max_parts(MAX_PARTS_PER_REQUEST)
+                .set_part_number_marker(part_number_marker.take())
+                .send()
+                .await
+                .with_context(|| format!("Failed to read the attributes of {}", self.object))
+                .into_retryable()?;
+            let object_parts = output.object_parts();
+            parts.extend(
+                object_parts
+                    .map(|parts| parts.parts().to_vec())
+                    .unwrap_or_default(),
+            );
+            part_number_marker = object_parts
+                .filter(|parts| parts.is_truncated() == Some(true))
+                .and_then(|parts| parts.next_part_number_marker())
+                .map(str::to_owned);
+            if part_number_marker.is_none() {
+                return Ok(ObjectDigests {
+                    e_tag: output
+                        .e_tag()
+                        .unwrap_or_default()
+                        .trim_matches('"')
+                        .to_owned(),
+                    size: output.object_size().unwrap_or_default() as u64,
+                    checksum: output.checksum().cloned(),
+                    total_parts_count: object_parts
+                        .and_then(|parts| parts.total_parts_count())
+                        .map(|count| count as usize),
+                    parts,
+                });
+            }
+        }
+    }
+
+    async fn server_side_encryption(
+        &self,
+        s3: &aws_sdk_s3::Client,
+    ) -> Result<Option<aws_sdk_s3::types::ServerSideEncryption>> {
+        let head = s3
+            .head_object()
+            .bucket(&self.object.bucket)
+            .key(&self.object.key)
+            .set_version_id(self.version_id.clone())
+            .set_request_payer(self.request_payer.as_deref().map(RequestPayer::from))
+            .send()
+            .await
+            .with_context(|| format!("Failed to read the object {}", self.object))
+            .into_retryable()?;
+        Ok(head.server_side_encryption)
+    }
+}
+
+impl ObjectDigests {
+    /// The sizes of the parts the object consists of.
+    ///
+    /// S3 only lists the parts of objects uploaded with checksums. For other multipart uploads,
+    /// the part size is derived from the number of parts instead.
+    fn part_sizes(&self) -> Result<Vec<u64>> {
+        let Some(total_parts_count) = self.total_parts_count else {
+            return Ok(vec![self.size]);
+        };
+        if self.parts.len() == total_parts_count
+            && self.parts.iter().all(|part| part.size().is_some())
+        {
+            return Ok(self
+                .parts
+                .iter()
+                .map(|part| part.size().unwrap_or_default() as u64)
+                .collect());
+        }
+        match checksum::expected_part_plan(self.size, &self.e_tag) {
+            Some(Some(plan)) => Ok(plan.parts_from(1).map(|part| part.size).collect()),
+            _ => bail!(
+                "Unable to determine the sizes of the {} parts the object consists of",
+                total_parts_count,
+            ),
+        }
+    }
+}
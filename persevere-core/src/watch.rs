@@ -0,0 +1,330 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    batch::{
+        Entry,
+        UploadOptions,
+    },
+    checksum,
+    duration::parse_duration,
+    result::{
+        AnyhowResultExt,
+        Error,
+        Result,
+        StdResultExt,
+    },
+    s3_uri::S3Uri,
+    signals::{
+        next_signal,
+        Listener,
+    },
+    sync,
+    verbosity,
+    write_json_atomically,
+};
+use anyhow::Context;
+use clap::Args;
+use futures_util::FutureExt;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::{
+    collections::{
+        BTreeMap,
+        BTreeSet,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+    time::{
+        Duration,
+        Instant,
+        SystemTime,
+    },
+};
+use tracing::{
+    debug,
+    error,
+    info,
+};
+
+/// Name of the file within the state directory that tracks the files that have been handled.
+const WATCH_STATE_FILE: &str = "watch.json";
+
+#[derive(Debug, Args)]
+pub(crate) struct Watch {
+    /// The local directory to watch, including all of its subdirectories.
+    #[arg(long)]
+    dir: PathBuf,
+    /// The destination in S3, in the form `s3://bucket/prefix`.
+    ///
+    /// The path of every file relative to the watched directory is appended to the prefix to form
+    /// its key.
+    #[arg(long, value_name = "S3_URI")]
+    s3_prefix: S3Uri,
+    /// Directory to keep the state in.
+    ///
+    /// The directory holds the state-file of every upload that is in progress, and the list of
+    /// files that have been uploaded already, so that restarting the command resumes where it left
+    /// off instead of uploading files again.
+    #[arg(long)]
+    state_dir: PathBuf,
+    /// How often the directory is scanned for new files, e.g. `30s`.
+    #[arg(long, default_value = "10s", value_parser = parse_duration)]
+    poll_interval: Duration,
+    /// How long the size and modification time of a new file have to stay the same before it is
+    /// uploaded, e.g. `5min`.
+    ///
+    /// This keeps files that are still being written from being uploaded prematurely.
+    #[arg(long, default_value = "1min", value_parser = parse_duration)]
+    settle_time: Duration,
+    #[command(flatten)]
+    upload_options: UploadOptions,
+}
+
+/// The files that have been handled, which is persisted in the state directory.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct WatchState {
+    /// The files by their path relative to the watched directory.
+    files: BTreeMap<String, HandledFile>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct HandledFile {
+    #[serde(flatten)]
+    stat: Stat,
+    #[serde(flatten)]
+    status: FileStatus,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case", tag = "status")]
+enum FileStatus {
+    Uploaded,
+    /// The upload failed with an unrecoverable error, and is only attempted again once the file
+    /// changes.
+    Failed {
+        error: String,
+    },
+}
+
+/// The size and modification time of a file, which tell whether it has changed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+struct Stat {
+    size: u64,
+    /// Time since the Unix epoch, if the platform provides it.
+    modified: Option<Duration>,
+}
+
+impl Stat {
+    async fn of(path: &Path) -> Option<Self> {
+        let metadata = tokio::fs::metadata(path).await.ok()?;
+        Some(Self {
+            size: metadata.len(),
+            modified: metadata
+                .modified()
+                .ok()
+                .and_then(|modified| modified.duration_since(SystemTime::UNIX_EPOCH).ok()),
+        })
+    }
+}
+
+/// A new or changed file, which is uploaded once it has settled.
+#[derive(Debug)]
+struct Candidate {
+    stat: Stat,
+    /// Since when the file has had this size and modification time.
+    since: Instant,
+}
+
+impl WatchState {
+    async fn from_file(file: &Path) -> Result<Self> {
+        let contents = match tokio::fs::read(file).await {
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Self::default())
+            }
+            result => result
+                .context("Failed to read watch state file")
+                .into_unrecoverable()?,
+        };
+        serde_json::from_slice(&contents)
+            .context("Failed to deserialize watch state file")
+            .into_unrecoverable()
+    }
+
+    async fn write_to_file(&self, file: &Path) -> Result<()> {
+        tokio::task::block_in_place(|| write_json_atomically(file, self, "watch state file"))
+    }
+}
+
+impl Watch {
+    pub(crate) async fn run(&self) -> Result<()> {
+        debug!("Running watch command: {:?}", self);
+
+        tokio::fs::create_dir_all(&self.state_dir)
+            .await
+            .context("Failed to create state directory")
+            .into_unrecoverable()?;
+        let watch_state_file = self.state_dir.join(WATCH_STATE_FILE);
+        let mut watch_state = WatchState::from_file(&watch_state_file).await?;
+        let mut candidates = BTreeMap::new();
+        info!(
+            "Watching {} for new files to upload to s3://{}/{}",
+            self.dir.display(),
+            self.s3_prefix.bucket,
+            self.s3_prefix.key,
+        );
+
+        // The listener is kept for the whole run, so that signals received while a file is being
+        // uploaded aren't lost.
+        let mut listener = Listener::install();
+        loop {
+            let signal = match self
+                .scan(
+                    &watch_state_file,
+                    &mut watch_state,
+                    &mut candidates,
+                    &mut listener,
+                )
+                .await?
+            {
+                Some(signal) => signal,
+                None => tokio::select! {
+                    _ = tokio::time::sleep(self.poll_interval) => continue,
+                    signal = next_signal(&mut listener) => signal,
+                },
+            };
+            info!(target: verbosity::SUMMARY,
+                "Received {}, stopped watching {}. To continue, run the same command again.",
+                signal,
+                self.dir.display(),
+            );
+            return Ok(());
+        }
+    }
+
+    /// Scans the directory once, uploading all new or changed files that have settled.
+    ///
+    /// Returns the name of the signal if one was received in the meantime.
+    async fn scan(
+        &self,
+        watch_state_file: &Path,
+        watch_state: &mut WatchState,
+        candidates: &mut BTreeMap<String, Candidate>,
+        listener: &mut Option<Listener>,
+    ) -> Result<Option<&'static str>> {
+        let local_files = sync::local_files(&self.dir, &self.state_dir).await?;
+        let now = Instant::now();
+        let mut settled = vec![];
+        let mut present = BTreeSet::new();
+        for (path, relative_path) in local_files {
+            let Some(stat) = Stat::of(&path).await else {
+                continue;
+            };
+            present.insert(relative_path.clone());
+            if watch_state
+                .files
+                .get(&relative_path)
+                .is_some_and(|file| file.stat == stat)
+            {
+                continue;
+            }
+            match candidates.get(&relative_path) {
+                Some(candidate) if candidate.stat == stat => {
+                    if now.duration_since(candidate.since) >= self.settle_time {
+                        settled.push((path, relative_path, stat));
+                    }
+                }
+                _ => {
+                    debug!("Waiting for {} to settle", relative_path);
+                    candidates.insert(relative_path, Candidate { stat, since: now });
+                }
+            }
+        }
+        // Files that are gone don't have to be remembered anymore.
+        candidates.retain(|relative_path, _| present.contains(relative_path));
+        let files_before = watch_state.files.len();
+        watch_state
+            .files
+            .retain(|relative_path, _| present.contains(relative_path));
+        if watch_state.files.len() != files_before {
+            watch_state.write_to_file(watch_state_file).await?;
+        }
+
+        for (path, relative_path, stat) in settled {
+            if let Some(signal) = next_signal(listener).now_or_never() {
+                return Ok(Some(signal));
+            }
+            candidates.remove(&relative_path);
+            let s3_key = sync::join_key(&self.s3_prefix.key, &relative_path);
+            info!(
+                "Uploading {} to s3://{}/{}",
+                relative_path, self.s3_prefix.bucket, s3_key,
+            );
+            let state_file = self.state_dir.join(format!(
+                "{}.state",
+                checksum::sha256_hex(relative_path.as_bytes()),
+            ));
+            let entry = Entry::new(path, self.s3_prefix.bucket.clone(), s3_key);
+            let status = match self.upload_options.upload(entry, &state_file).await {
+                Ok(()) => FileStatus::Uploaded,
+                Err(error @ (Error::Paused | Error::Interrupted)) => {
+                    info!(target: verbosity::SUMMARY, "To continue watching, run the same command again");
+                    return Err(error);
+                }
+                Err(error @ Error::Retryable(_)) => {
+                    // The state-file is kept, so the upload is resumed once the file has settled
+                    // again.
+                    error!(
+                        "Failed to upload {}, retrying later: {}",
+                        relative_path, error,
+                    );
+                    candidates.insert(
+                        relative_path,
+                        Candidate {
+                            stat,
+                            since: Instant::now(),
+                        },
+                    );
+                    continue;
+                }
+                Err(error) => {
+                    error!(
+                        "Failed to upload {}, retrying once it changes: {}",
+                        relative_path, error,
+                    );
+                    // The multipart upload has been aborted or was never created, so the next
+                    // attempt has to start from scratch.
+                    match tokio::fs::remove_file(&state_file).await {
+                        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+                        result => result.into_unrecoverable()?,
+                    }
+                    FileStatus::Failed {
+                        error: error.to_string(),
+                    }
+                }
+            };
+            watch_state
+                .files
+                .insert(relative_path, HandledFile { stat, status });
+            watch_state.write_to_file(watch_state_file).await?;
+        }
+        Ok(None)
+    }
+}
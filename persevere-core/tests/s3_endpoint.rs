@@ -0,0 +1,304 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+// Tests uploading, resuming and aborting against an S3-compatible endpoint, e.g. MinIO:
+//
+// ```sh
+// docker run -d -p 9000:9000 minio/minio server /data
+// AWS_ACCESS_KEY_ID=minioadmin AWS_SECRET_ACCESS_KEY=minioadmin AWS_REGION=us-east-1 \
+//     PERSEVERE_TEST_ENDPOINT_URL=http://127.0.0.1:9000 \
+//     cargo test -p persevere-core --features integration-tests --test s3_endpoint
+// ```
+
+#![cfg(feature = "integration-tests")]
+
+use aws_config::BehaviorVersion;
+use aws_sdk_s3::{
+    config::{
+        interceptors::BeforeSerializationInterceptorContextRef,
+        ConfigBag,
+        Intercept,
+    },
+    error::{
+        BoxError,
+        ProvideErrorMetadata,
+    },
+    operation::upload_part::UploadPartInput,
+};
+use persevere_core::{
+    Error,
+    Part,
+    ProgressReporter,
+    Uploader,
+};
+use std::{
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::{
+        atomic::{
+            AtomicU64,
+            Ordering,
+        },
+        Arc,
+    },
+};
+use tokio_util::sync::CancellationToken;
+
+/// The bucket the tests upload to, which is created if it doesn't exist.
+fn bucket() -> String {
+    std::env::var("PERSEVERE_TEST_BUCKET").unwrap_or_else(|_| "persevere-test".to_owned())
+}
+
+/// Creates a client for the endpoint, failing every attempt to upload the given part.
+async fn client(failing_part: Option<i32>) -> aws_sdk_s3::Client {
+    let endpoint_url = std::env::var("PERSEVERE_TEST_ENDPOINT_URL")
+        .expect("PERSEVERE_TEST_ENDPOINT_URL must point to an S3-compatible endpoint");
+    let config = aws_config::load_defaults(BehaviorVersion::v2024_03_28()).await;
+    let mut s3_config = aws_sdk_s3::config::Builder::from(&config)
+        .endpoint_url(endpoint_url)
+        .force_path_style(true);
+    if let Some(part_number) = failing_part {
+        s3_config = s3_config.interceptor(FailPart(part_number));
+    }
+    aws_sdk_s3::Client::from_conf(s3_config.build())
+}
+
+async fn create_bucket(s3: &aws_sdk_s3::Client) {
+    if let Err(error) = s3.create_bucket().bucket(bucket()).send().await {
+        let code = error.code().unwrap_or_default();
+        assert!(
+            code == "BucketAlreadyOwnedByYou" || code == "BucketAlreadyExists",
+            "Failed to create the bucket: {:?}",
+            error,
+        );
+    }
+}
+
+/// Fails every request uploading the part with the given number.
+#[derive(Debug)]
+struct FailPart(i32);
+
+impl Intercept for FailPart {
+    fn name(&self) -> &'static str {
+        "FailPart"
+    }
+
+    fn read_before_execution(
+        &self,
+        context: &BeforeSerializationInterceptorContextRef<'_>,
+        _cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        match context.input().downcast_ref::<UploadPartInput>() {
+            Some(input) if input.part_number() == Some(self.0) => {
+                Err(format!("Injected failure of part {}", self.0).into())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Counts the bytes of the parts that have been completed.
+struct CompletedBytes(Arc<AtomicU64>);
+
+impl ProgressReporter for CompletedBytes {
+    fn part_completed(&self, part: &Part, _number_of_parts: u64) {
+        self.0.fetch_add(part.size(), Ordering::Relaxed);
+    }
+}
+
+/// A directory holding the file to upload and the state-file, which is removed once dropped.
+struct Workspace {
+    directory: PathBuf,
+    contents: Vec<u8>,
+}
+
+impl Workspace {
+    /// Creates a file of three parts, the last of which is shorter than the others.
+    fn new(name: &str) -> Self {
+        let directory =
+            std::env::temp_dir().join(format!("persevere-test-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let contents: Vec<u8> = (0..12 * 1024 * 1024 + 5)
+            .map(|index: u32| (index % 251) as u8)
+            .collect();
+        std::fs::write(directory.join("file"), &contents).unwrap();
+        Self {
+            directory,
+            contents,
+        }
+    }
+
+    fn file(&self) -> PathBuf {
+        self.directory.join("file")
+    }
+
+    fn state_file(&self) -> PathBuf {
+        self.directory.join("file.state")
+    }
+
+    fn uploader(&self, key: &str, s3: aws_sdk_s3::Client) -> Uploader {
+        Uploader::new(self.file(), bucket(), key)
+            .state_file(self.state_file())
+            .max_retries(0)
+            .client(s3)
+    }
+}
+
+impl Drop for Workspace {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.directory);
+    }
+}
+
+async fn object_contents(s3: &aws_sdk_s3::Client, key: &str) -> Vec<u8> {
+    let object = s3
+        .get_object()
+        .bucket(bucket())
+        .key(key)
+        .send()
+        .await
+        .unwrap();
+    object.body.collect().await.unwrap().to_vec()
+}
+
+fn upload_id(state_file: &Path) -> String {
+    let state: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(state_file).unwrap()).unwrap();
+    state["upload_id"].as_str().unwrap().to_owned()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn upload() {
+    let s3 = client(None).await;
+    create_bucket(&s3).await;
+    let workspace = Workspace::new("upload");
+    let completed_bytes = Arc::new(AtomicU64::new(0));
+
+    let result = workspace
+        .uploader("upload", s3.clone())
+        .reporter(CompletedBytes(Arc::clone(&completed_bytes)))
+        .upload()
+        .await
+        .unwrap();
+
+    assert_eq!(result.s3_key(), "upload");
+    assert_eq!(result.bytes(), workspace.contents.len() as u64);
+    assert_eq!(result.parts(), 3);
+    assert_eq!(completed_bytes.load(Ordering::Relaxed), result.bytes());
+    assert!(result.e_tag().is_some_and(|e_tag| e_tag.ends_with("-3\"")));
+    assert_eq!(object_contents(&s3, "upload").await, workspace.contents);
+    assert!(!workspace.state_file().exists());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn endpoint_settings() {
+    let s3 = client(None).await;
+    create_bucket(&s3).await;
+    let workspace = Workspace::new("endpoint-settings");
+
+    Uploader::new(workspace.file(), bucket(), "endpoint-settings")
+        .state_file(workspace.state_file())
+        .endpoint_url(std::env::var("PERSEVERE_TEST_ENDPOINT_URL").unwrap())
+        .force_path_style(true)
+        .upload()
+        .await
+        .unwrap();
+
+    assert_eq!(
+        object_contents(&s3, "endpoint-settings").await,
+        workspace.contents,
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn resume() {
+    let s3 = client(None).await;
+    create_bucket(&s3).await;
+    let workspace = Workspace::new("resume");
+
+    let result = workspace
+        .uploader("resume", client(Some(2)).await)
+        .upload()
+        .await;
+    assert!(matches!(result, Err(Error::Retryable(_))), "{:?}", result);
+    assert!(workspace.state_file().exists());
+
+    Uploader::resume_with_client(workspace.state_file(), s3.clone())
+        .await
+        .unwrap();
+
+    assert_eq!(object_contents(&s3, "resume").await, workspace.contents);
+    assert!(!workspace.state_file().exists());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn abort() {
+    let s3 = client(None).await;
+    create_bucket(&s3).await;
+    let workspace = Workspace::new("abort");
+
+    let result = workspace
+        .uploader("abort", client(Some(2)).await)
+        .upload()
+        .await;
+    assert!(matches!(result, Err(Error::Retryable(_))), "{:?}", result);
+    let upload_id = upload_id(&workspace.state_file());
+
+    Uploader::abort_with_client(workspace.state_file(), s3.clone())
+        .await
+        .unwrap();
+
+    assert!(!workspace.state_file().exists());
+    let error = s3
+        .list_parts()
+        .bucket(bucket())
+        .key("abort")
+        .upload_id(upload_id)
+        .send()
+        .await
+        .unwrap_err();
+    assert_eq!(error.code(), Some("NoSuchUpload"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn cancel() {
+    let s3 = client(None).await;
+    create_bucket(&s3).await;
+    let workspace = Workspace::new("cancel");
+
+    // A token that is cancelled already pauses the upload after its first part.
+    let cancellation = CancellationToken::new();
+    cancellation.cancel();
+    let result = workspace
+        .uploader("cancel", s3.clone())
+        .cancellation_token(cancellation)
+        .upload()
+        .await;
+    assert!(matches!(result, Err(Error::Paused)), "{:?}", result);
+    assert!(workspace.state_file().exists());
+
+    workspace
+        .uploader("cancel", s3.clone())
+        .cancellation_token(CancellationToken::new())
+        .resume_upload()
+        .await
+        .unwrap();
+
+    assert_eq!(object_contents(&s3, "cancel").await, workspace.contents);
+    assert!(!workspace.state_file().exists());
+}
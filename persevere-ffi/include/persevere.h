@@ -0,0 +1,91 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+// The C ABI of Persevere, for embedding resumable S3 uploads.
+//
+// Transfers run in the background and are controlled through the handle returned when they are
+// started. The AWS credentials, region and endpoint are taken from the environment, like the AWS
+// SDKs do. All strings are nul-terminated UTF-8.
+
+#ifndef PERSEVERE_H
+#define PERSEVERE_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+// The status of a transfer.
+#define PERSEVERE_RUNNING 0
+#define PERSEVERE_COMPLETED 1
+// Paused through persevere_transfer_cancel; continue with persevere_resume_start.
+#define PERSEVERE_PAUSED 2
+// Interrupted; continue with persevere_resume_start.
+#define PERSEVERE_INTERRUPTED 3
+// Failed, but can be continued with persevere_resume_start.
+#define PERSEVERE_FAILED_RETRYABLE 4
+// Failed, and has to be started again.
+#define PERSEVERE_FAILED_UNRECOVERABLE 5
+
+typedef struct PersevereTransfer persevere_transfer;
+
+typedef struct {
+    uint64_t total_bytes;
+    // Bytes of the parts that have been completed, including the ones of previous attempts.
+    uint64_t transferred_bytes;
+    uint64_t number_of_parts;
+    uint64_t completed_parts;
+} persevere_progress;
+
+// Starts uploading the file to the bucket and key, keeping its progress in state_file, which may
+// be NULL to use the default state-file. Returns NULL if an argument is NULL or not valid UTF-8.
+persevere_transfer *persevere_upload_start(const char *file, const char *bucket, const char *key,
+                                           const char *state_file);
+
+// Resumes the upload that was started with the same arguments before, e.g. after it was paused or
+// failed with a retryable error. Returns NULL if an argument is NULL or not valid UTF-8.
+persevere_transfer *persevere_resume_start(const char *file, const char *bucket, const char *key,
+                                           const char *state_file);
+
+// Starts aborting the upload the state-file belongs to, removing its parts from S3 and the
+// state-file. Returns NULL if state_file is NULL or not valid UTF-8.
+persevere_transfer *persevere_abort_start(const char *state_file);
+
+// Returns the status of the transfer, and writes its progress to progress unless it is NULL.
+int32_t persevere_transfer_poll(const persevere_transfer *transfer, persevere_progress *progress);
+
+// Blocks until the transfer has finished, and returns its status.
+int32_t persevere_transfer_wait(const persevere_transfer *transfer);
+
+// Pauses the transfer: the part in progress is finished first, after which the transfer stops with
+// PERSEVERE_PAUSED. Aborts can't be cancelled.
+void persevere_transfer_cancel(const persevere_transfer *transfer);
+
+// Returns the error the transfer stopped with, e.g. why it failed or that it was paused, or NULL if
+// it is still running or has completed. The string is owned by the transfer and valid until it is
+// freed.
+const char *persevere_transfer_error(const persevere_transfer *transfer);
+
+// Frees the handle of the transfer. A transfer that is still running keeps running in the
+// background, so cancel it and wait for it first to stop it.
+void persevere_transfer_free(persevere_transfer *transfer);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
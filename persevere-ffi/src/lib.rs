@@ -0,0 +1,429 @@
+// Copyright 2024 TAKKT Industrial & Packaging GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! A C ABI around the `Uploader` of persevere-core, for C and C++ programs that want to embed
+//! resumable uploads without spawning the command line interface.
+//!
+//! Every transfer runs in the background on a runtime shared by all transfers, and is controlled
+//! through the handle returned when it is started. See `include/persevere.h` for the declarations.
+
+use persevere_core::{
+    Error,
+    TransferEvent,
+    Uploader,
+};
+use std::{
+    ffi::{
+        c_char,
+        CStr,
+        CString,
+    },
+    future::Future,
+    path::PathBuf,
+    sync::{
+        atomic::{
+            AtomicU64,
+            Ordering,
+        },
+        Arc,
+        Condvar,
+        Mutex,
+        OnceLock,
+    },
+};
+use tokio::runtime::Runtime;
+use tokio_util::sync::CancellationToken;
+
+/// The transfer is still running.
+pub const PERSEVERE_RUNNING: i32 = 0;
+/// The transfer has completed successfully.
+pub const PERSEVERE_COMPLETED: i32 = 1;
+/// The transfer has been paused through `persevere_transfer_cancel`, and can be resumed.
+pub const PERSEVERE_PAUSED: i32 = 2;
+/// The transfer has been interrupted, and can be resumed.
+pub const PERSEVERE_INTERRUPTED: i32 = 3;
+/// The transfer has failed, but can be resumed.
+pub const PERSEVERE_FAILED_RETRYABLE: i32 = 4;
+/// The transfer has failed, and has to be started again.
+pub const PERSEVERE_FAILED_UNRECOVERABLE: i32 = 5;
+
+/// The runtime all transfers run on, which is started with the first transfer.
+fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| Runtime::new().expect("failed to start the Tokio runtime"))
+}
+
+/// The progress of a transfer, as returned by `persevere_transfer_poll`.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct PersevereProgress {
+    pub total_bytes: u64,
+    /// Bytes of the parts that have been completed, including the ones of previous attempts.
+    pub transferred_bytes: u64,
+    pub number_of_parts: u64,
+    pub completed_parts: u64,
+}
+
+/// A transfer running in the background.
+pub struct PersevereTransfer {
+    cancellation: CancellationToken,
+    progress: Arc<Progress>,
+    outcome: Arc<(Mutex<Option<Outcome>>, Condvar)>,
+}
+
+#[derive(Default)]
+struct Progress {
+    total_bytes: AtomicU64,
+    transferred_bytes: AtomicU64,
+    number_of_parts: AtomicU64,
+    completed_parts: AtomicU64,
+}
+
+impl Progress {
+    fn update(&self, event: &TransferEvent) {
+        match event {
+            TransferEvent::Started {
+                total_bytes,
+                transferred_bytes,
+                number_of_parts,
+            } => {
+                self.total_bytes.store(*total_bytes, Ordering::Relaxed);
+                self.transferred_bytes
+                    .store(*transferred_bytes, Ordering::Relaxed);
+                self.number_of_parts
+                    .store(*number_of_parts, Ordering::Relaxed);
+            }
+            TransferEvent::PartCompleted {
+                transferred_bytes, ..
+            } => {
+                self.transferred_bytes
+                    .store(*transferred_bytes, Ordering::Relaxed);
+                self.completed_parts.fetch_add(1, Ordering::Relaxed);
+            }
+            _ => {}
+        }
+    }
+}
+
+struct Outcome {
+    status: i32,
+    error: Option<CString>,
+}
+
+impl Outcome {
+    fn of<T>(result: Result<T, Error>) -> Self {
+        let status = match &result {
+            Ok(_) => PERSEVERE_COMPLETED,
+            Err(Error::Paused) => PERSEVERE_PAUSED,
+            Err(Error::Interrupted) => PERSEVERE_INTERRUPTED,
+            Err(Error::Retryable(_)) => PERSEVERE_FAILED_RETRYABLE,
+            Err(Error::Unrecoverable(_)) => PERSEVERE_FAILED_UNRECOVERABLE,
+        };
+        Self {
+            status,
+            error: result.err().map(|error| error_message(&error.to_string())),
+        }
+    }
+}
+
+/// Converts an error message to a C string, dropping nul bytes it can't hold.
+fn error_message(message: &str) -> CString {
+    CString::new(message.replace('\0', "")).unwrap_or_default()
+}
+
+impl PersevereTransfer {
+    /// Runs the transfer in the background, recording its outcome once it has finished.
+    fn spawn<F, T>(
+        cancellation: CancellationToken,
+        progress: Arc<Progress>,
+        transfer: F,
+    ) -> *mut PersevereTransfer
+    where
+        F: Future<Output = Result<T, Error>> + Send + 'static,
+        T: Send + 'static,
+    {
+        let outcome = Arc::new((Mutex::new(None), Condvar::new()));
+        let finished = Arc::clone(&outcome);
+        runtime().spawn(async move {
+            // A panic must not leave the transfer running forever in the eyes of the caller.
+            let result = match tokio::spawn(transfer).await {
+                Ok(result) => Outcome::of(result),
+                Err(error) => Outcome {
+                    status: PERSEVERE_FAILED_UNRECOVERABLE,
+                    error: Some(error_message(&error.to_string())),
+                },
+            };
+            let (outcome, condvar) = &*finished;
+            *outcome.lock().expect("poisoned lock") = Some(result);
+            condvar.notify_all();
+        });
+        Box::into_raw(Box::new(PersevereTransfer {
+            cancellation,
+            progress,
+            outcome,
+        }))
+    }
+
+    fn status(&self) -> i32 {
+        self.outcome
+            .0
+            .lock()
+            .expect("poisoned lock")
+            .as_ref()
+            .map_or(PERSEVERE_RUNNING, |outcome| outcome.status)
+    }
+}
+
+/// Reads a required string argument, returning `None` if it is null or not valid UTF-8.
+///
+/// # Safety
+///
+/// `value` has to be null or point to a nul-terminated string.
+unsafe fn string(value: *const c_char) -> Option<String> {
+    if value.is_null() {
+        return None;
+    }
+    CStr::from_ptr(value).to_str().ok().map(ToOwned::to_owned)
+}
+
+/// Prepares the upload of the file, reporting its progress to `progress`.
+///
+/// # Safety
+///
+/// All arguments have to be null or point to nul-terminated strings.
+unsafe fn uploader(
+    file: *const c_char,
+    bucket: *const c_char,
+    key: *const c_char,
+    state_file: *const c_char,
+    cancellation: &CancellationToken,
+    progress: &Arc<Progress>,
+) -> Option<Uploader> {
+    let mut uploader = Uploader::new(PathBuf::from(string(file)?), string(bucket)?, string(key)?);
+    if !state_file.is_null() {
+        uploader = uploader.state_file(PathBuf::from(string(state_file)?));
+    }
+    let progress = Arc::clone(progress);
+    Some(
+        uploader
+            .cancellation_token(cancellation.clone())
+            .on_event(move |event| progress.update(event)),
+    )
+}
+
+/// Starts uploading the file to the given bucket and key, keeping its progress in `state_file`.
+///
+/// Returns null if an argument is null or not valid UTF-8. `state_file` may be null to use the
+/// default state-file.
+///
+/// # Safety
+///
+/// All arguments have to be null or point to nul-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn persevere_upload_start(
+    file: *const c_char,
+    bucket: *const c_char,
+    key: *const c_char,
+    state_file: *const c_char,
+) -> *mut PersevereTransfer {
+    let cancellation = CancellationToken::new();
+    let progress = Arc::new(Progress::default());
+    match uploader(file, bucket, key, state_file, &cancellation, &progress) {
+        Some(uploader) => PersevereTransfer::spawn(cancellation, progress, uploader.upload()),
+        None => std::ptr::null_mut(),
+    }
+}
+
+/// Resumes the upload of the file that was started with the same arguments before, e.g. after it
+/// was paused or failed with a retryable error.
+///
+/// Returns null if an argument is null or not valid UTF-8.
+///
+/// # Safety
+///
+/// All arguments have to be null or point to nul-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn persevere_resume_start(
+    file: *const c_char,
+    bucket: *const c_char,
+    key: *const c_char,
+    state_file: *const c_char,
+) -> *mut PersevereTransfer {
+    let cancellation = CancellationToken::new();
+    let progress = Arc::new(Progress::default());
+    match uploader(file, bucket, key, state_file, &cancellation, &progress) {
+        Some(uploader) => {
+            PersevereTransfer::spawn(cancellation, progress, uploader.resume_upload())
+        }
+        None => std::ptr::null_mut(),
+    }
+}
+
+/// Starts aborting the upload the state-file belongs to, removing its parts from S3 and the
+/// state-file.
+///
+/// Returns null if `state_file` is null or not valid UTF-8.
+///
+/// # Safety
+///
+/// `state_file` has to be null or point to a nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn persevere_abort_start(
+    state_file: *const c_char,
+) -> *mut PersevereTransfer {
+    match string(state_file) {
+        Some(state_file) => PersevereTransfer::spawn(
+            CancellationToken::new(),
+            Arc::new(Progress::default()),
+            Uploader::abort(state_file),
+        ),
+        None => std::ptr::null_mut(),
+    }
+}
+
+/// Returns the status of the transfer, one of the `PERSEVERE_*` constants, and writes its progress
+/// to `progress` unless it is null.
+///
+/// # Safety
+///
+/// `transfer` has to be a handle returned by one of the `*_start` functions that hasn't been freed
+/// yet, and `progress` has to be null or point to a `persevere_progress`.
+#[no_mangle]
+pub unsafe extern "C" fn persevere_transfer_poll(
+    transfer: *const PersevereTransfer,
+    progress: *mut PersevereProgress,
+) -> i32 {
+    let transfer = &*transfer;
+    if let Some(progress) = progress.as_mut() {
+        *progress = PersevereProgress {
+            total_bytes: transfer.progress.total_bytes.load(Ordering::Relaxed),
+            transferred_bytes: transfer.progress.transferred_bytes.load(Ordering::Relaxed),
+            number_of_parts: transfer.progress.number_of_parts.load(Ordering::Relaxed),
+            completed_parts: transfer.progress.completed_parts.load(Ordering::Relaxed),
+        };
+    }
+    transfer.status()
+}
+
+/// Blocks until the transfer has finished, and returns its status.
+///
+/// # Safety
+///
+/// `transfer` has to be a handle returned by one of the `*_start` functions that hasn't been freed
+/// yet.
+#[no_mangle]
+pub unsafe extern "C" fn persevere_transfer_wait(transfer: *const PersevereTransfer) -> i32 {
+    let transfer = &*transfer;
+    let (outcome, condvar) = &*transfer.outcome;
+    let outcome = condvar
+        .wait_while(outcome.lock().expect("poisoned lock"), |outcome| {
+            outcome.is_none()
+        })
+        .expect("poisoned lock");
+    outcome
+        .as_ref()
+        .map_or(PERSEVERE_RUNNING, |outcome| outcome.status)
+}
+
+/// Pauses the transfer: the part in progress is finished first, after which the transfer stops
+/// with `PERSEVERE_PAUSED` and can be resumed later on. Aborts can't be cancelled.
+///
+/// # Safety
+///
+/// `transfer` has to be a handle returned by one of the `*_start` functions that hasn't been freed
+/// yet.
+#[no_mangle]
+pub unsafe extern "C" fn persevere_transfer_cancel(transfer: *const PersevereTransfer) {
+    let transfer = &*transfer;
+    transfer.cancellation.cancel();
+}
+
+/// Returns the error the transfer stopped with, e.g. why it failed or that it was paused, or null
+/// if it is still running or has completed.
+///
+/// The string is owned by the transfer and valid until it is freed.
+///
+/// # Safety
+///
+/// `transfer` has to be a handle returned by one of the `*_start` functions that hasn't been freed
+/// yet.
+#[no_mangle]
+pub unsafe extern "C" fn persevere_transfer_error(
+    transfer: *const PersevereTransfer,
+) -> *const c_char {
+    let transfer = &*transfer;
+    // The error is never replaced once it is set, so the pointer stays valid after the lock is
+    // released.
+    transfer
+        .outcome
+        .0
+        .lock()
+        .expect("poisoned lock")
+        .as_ref()
+        .and_then(|outcome| outcome.error.as_ref())
+        .map_or(std::ptr::null(), |error| error.as_ptr())
+}
+
+/// Frees the handle of the transfer. A transfer that is still running keeps running in the
+/// background, so cancel it and wait for it first to stop it.
+///
+/// # Safety
+///
+/// `transfer` has to be null or a handle returned by one of the `*_start` functions that hasn't
+/// been freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn persevere_transfer_free(transfer: *mut PersevereTransfer) {
+    if !transfer.is_null() {
+        drop(Box::from_raw(transfer));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn null_arguments_are_rejected() {
+        unsafe {
+            assert!(persevere_abort_start(std::ptr::null()).is_null());
+            assert!(persevere_upload_start(
+                c"file".as_ptr(),
+                std::ptr::null(),
+                c"key".as_ptr(),
+                std::ptr::null(),
+            )
+            .is_null());
+        }
+    }
+
+    #[test]
+    fn missing_state_file_fails_unrecoverably() {
+        unsafe {
+            let transfer = persevere_abort_start(c"/nonexistent/persevere-state".as_ptr());
+            assert_eq!(
+                persevere_transfer_wait(transfer),
+                PERSEVERE_FAILED_UNRECOVERABLE
+            );
+            assert_eq!(
+                persevere_transfer_poll(transfer, std::ptr::null_mut()),
+                PERSEVERE_FAILED_UNRECOVERABLE
+            );
+            let error = CStr::from_ptr(persevere_transfer_error(transfer));
+            assert!(error.to_str().unwrap().starts_with("Unrecoverable error"));
+            persevere_transfer_free(transfer);
+        }
+    }
+}